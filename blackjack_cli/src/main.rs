@@ -0,0 +1,134 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Evaluates a `.bjk` file without opening the editor, and writes the
+//! resulting mesh to a file. Useful to run blackjack in build pipelines.
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use blackjack_engine::{
+    graph::serialization::SerializedBjkGraph,
+    graph_interpreter::{run_graph_with_options, EvaluationOptions, RunGraphOptions},
+    lua_engine::LuaRuntime,
+    prelude::TriangulationStrategy,
+};
+use clap::{Parser, ValueEnum};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The `.bjk` file to evaluate. The values for the graph's parameters are
+    /// read from the same file.
+    graph: PathBuf,
+
+    /// The file the result is written to.
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// The format of the output file. When not set, the format is guessed
+    /// from the extension of the output file.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+
+    /// The index of the node to evaluate, in the order nodes are stored in the
+    /// `.bjk` file. Defaults to the graph's default node.
+    #[arg(long)]
+    target: Option<usize>,
+
+    /// The folder containing the Lua node libraries.
+    #[arg(long, default_value = "./blackjack_lua/")]
+    node_libraries: String,
+
+    /// Seeds the random number generator, so the result is reproducible.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Wavefront OBJ
+    Obj,
+    /// Binary glTF. Text `.gltf` files are not supported.
+    Glb,
+}
+
+impl Format {
+    fn from_extension(path: &std::path::Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("obj") => Ok(Format::Obj),
+            Some("glb") => Ok(Format::Glb),
+            _ => bail!(
+                "Could not guess the output format of {}. Use the --format flag.",
+                path.display()
+            ),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    // Tools expect `.gltf` files to be JSON, so the binary output can't use
+    // that extension.
+    if args.output.extension().and_then(|ext| ext.to_str()) == Some("gltf") {
+        bail!("Only binary glTF is supported. Use the .glb extension for the output file.");
+    }
+    let format = match args.format {
+        Some(format) => format,
+        None => Format::from_extension(&args.output)?,
+    };
+
+    let lua_runtime = LuaRuntime::initialize_with_std(args.node_libraries.clone())
+        .context("Failed to load the node libraries")?;
+
+    let (runtime_data, _, mappings) = SerializedBjkGraph::load_from_file(&args.graph)
+        .and_then(|graph| {
+            graph.register_baked_nodes(&lua_runtime.node_definitions)?;
+            graph.into_runtime()
+        })
+        .with_context(|| format!("Failed to load {}", args.graph.display()))?;
+    let graph = runtime_data.graph;
+    let params = runtime_data.external_parameters.unwrap_or_default();
+    let target = match args.target {
+        Some(idx) => mappings.get_id(idx)?,
+        None => graph
+            .default_node
+            .ok_or_else(|| anyhow!("The graph has no default node. Use the --target flag."))?,
+    };
+
+    let result = run_graph_with_options(
+        &lua_runtime.lua,
+        &graph,
+        target,
+        params,
+        &lua_runtime.node_definitions,
+        None,
+        RunGraphOptions {
+            evaluation: EvaluationOptions {
+                seed: args.seed,
+                // Paths in the graph's parameters are relative to the graph
+                // file.
+                project_root: args.graph.parent().map(|parent| parent.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )?;
+    let renderable = result
+        .renderable
+        .ok_or_else(|| anyhow!("The target node has no return value"))?;
+
+    let writer = BufWriter::new(
+        File::create(&args.output)
+            .with_context(|| format!("Failed to create {}", args.output.display()))?,
+    );
+    match format {
+        Format::Obj => renderable.export_obj(writer)?,
+        Format::Glb => renderable.export_gltf(writer, TriangulationStrategy::default())?,
+    }
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
@@ -4,8 +4,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use mlua::{FromLua, Lua, ToLua};
+use mlua::{FromLua, IntoLua, Lua};
 
+use crate::graph::BjkNodeId;
 use crate::prelude::*;
 
 #[derive(Debug, Copy, Clone)]
@@ -193,6 +194,25 @@ mod tr_gizmo {
     }
 }
 
+/// Identifies a gizmo across runs, so hosts can tell which gizmo of the
+/// previous run a new gizmo corresponds to, even when the gizmos are rebuilt
+/// from scratch on every run.
+///
+/// Gizmos are identified by the node declaring them, and by the `name` in
+/// their descriptor when they have one. Unnamed gizmos use their position in
+/// the node's `gizmos` table instead.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GizmoId {
+    pub node_id: BjkNodeId,
+    pub key: GizmoKey,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GizmoKey {
+    Name(String),
+    Index(usize),
+}
+
 #[derive(Clone, Debug)]
 pub enum BlackjackGizmo {
     Transform(TransformGizmo),
@@ -201,6 +221,64 @@ pub enum BlackjackGizmo {
     None,
 }
 
+/// A transform as plain arrays, so hosts can convert gizmos to the types of
+/// their renderer, like Bevy's `Transform`, without depending on `glam`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlainTransform {
+    /// The translation, as `[x, y, z]`.
+    pub translation: [f32; 3],
+    /// The rotation, as a unit quaternion `[x, y, z, w]`.
+    pub rotation: [f32; 4],
+    /// The scale, as `[x, y, z]`.
+    pub scale: [f32; 3],
+}
+
+impl BlackjackGizmo {
+    /// Returns the full transform of a transform gizmo, combining the
+    /// transform and the pre-transform like `TransformGizmo::matrix`. Returns
+    /// `None` for other gizmos.
+    pub fn as_transform(&self) -> Option<PlainTransform> {
+        match self {
+            BlackjackGizmo::Transform(t) => {
+                let (scale, rotation, translation) = t.matrix().to_scale_rotation_translation();
+                Some(PlainTransform {
+                    translation: translation.to_array(),
+                    rotation: rotation.to_array(),
+                    scale: scale.to_array(),
+                })
+            }
+            BlackjackGizmo::None => None,
+        }
+    }
+
+    /// Returns the full transform matrix of a transform gizmo, in column-major
+    /// order, like `TransformGizmo::matrix`. Returns `None` for other gizmos.
+    pub fn as_matrix(&self) -> Option<[f32; 16]> {
+        match self {
+            BlackjackGizmo::Transform(t) => Some(t.matrix().to_cols_array()),
+            BlackjackGizmo::None => None,
+        }
+    }
+
+    /// Updates a transform gizmo from a full transform, e.g. after the user
+    /// dragged it in the host. The inverse of `as_transform`, see
+    /// `TransformGizmo::set_from_matrix`. Returns false, without doing
+    /// anything, for other gizmos.
+    pub fn set_transform(&mut self, transform: PlainTransform) -> bool {
+        match self {
+            BlackjackGizmo::Transform(t) => {
+                t.set_from_matrix(Mat4::from_scale_rotation_translation(
+                    Vec3::from(transform.scale),
+                    Quat::from_array(transform.rotation),
+                    Vec3::from(transform.translation),
+                ));
+                true
+            }
+            BlackjackGizmo::None => false,
+        }
+    }
+}
+
 /// Boilerplate: Implement FromLua by attempting downcast of each UserData type
 impl<'lua> FromLua<'lua> for BlackjackGizmo {
     fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
@@ -224,15 +302,53 @@ impl<'lua> FromLua<'lua> for BlackjackGizmo {
     }
 }
 
-/// Boilerplate: Implement ToLua by deferring to each variant's ToLua
+/// Boilerplate: Implement IntoLua by deferring to each variant's IntoLua
 /// implementation
-impl<'lua> ToLua<'lua> for BlackjackGizmo {
-    fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+impl<'lua> IntoLua<'lua> for BlackjackGizmo {
+    fn into_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
         match self {
-            BlackjackGizmo::Transform(t) => t.to_lua(lua),
+            BlackjackGizmo::Transform(t) => t.into_lua(lua),
             // The special gizmo value "None" is encoded as nil. Lua functions
             // know that the nil value represents an uninitialized gizmo.
             BlackjackGizmo::None => Ok(mlua::Value::Nil),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_plain_transform() {
+        let mut gizmo = BlackjackGizmo::Transform(TransformGizmo {
+            translation: Vec3::new(1.0, 2.0, 3.0),
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            pre_translation: Vec3::X,
+            pre_rotation: Quat::IDENTITY,
+            pre_scale: Vec3::splat(2.0),
+            translation_enabled: true,
+            rotation_enabled: true,
+            scale_enabled: true,
+            gizmo_mode: TransformGizmoMode::Translate,
+        });
+        let transform = gizmo.as_transform().unwrap();
+        assert_eq!(transform.translation, [2.0, 2.0, 3.0]);
+        assert_eq!(transform.rotation, [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(transform.scale, [2.0, 2.0, 2.0]);
+        assert_eq!(gizmo.as_matrix().unwrap()[12..15], [2.0, 2.0, 3.0]);
+
+        assert!(gizmo.set_transform(PlainTransform {
+            translation: [5.0, 0.0, 0.0],
+            ..transform
+        }));
+        match &gizmo {
+            BlackjackGizmo::Transform(t) => assert_eq!(t.translation, Vec3::new(4.0, 0.0, 0.0)),
+            BlackjackGizmo::None => unreachable!(),
+        }
+
+        assert!(BlackjackGizmo::None.as_transform().is_none());
+        assert!(!BlackjackGizmo::None.set_transform(transform));
+    }
+}
@@ -5,24 +5,41 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::cell::{Ref, RefCell};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::rc::Rc;
 
+use crate::graph_interpreter::SubGraph;
 use crate::prelude::*;
 use crate::{lua_engine::lua_stdlib::LVec3, mesh::halfedge::selection::SelectionExpression};
 use anyhow::{anyhow, Result};
-use mlua::{FromLua, Table, ToLua};
+use mlua::{FromLua, IntoLua, Table};
 use slotmap::SlotMap;
 
 /// The core `bjk` file format
 pub mod serialization;
 
+/// Comparing versions of the same graph
+mod diff;
+pub use diff::*;
+
+/// Upgrading graphs saved with older node libraries
+mod migration;
+pub use migration::*;
+
+/// Stable hashes of graphs, for caching their results
+mod fingerprint;
+
+/// A hasher whose output doesn't change across platforms or Rust versions
+mod stable_hasher;
+pub(crate) use stable_hasher::StableHasher;
+
 pub struct LuaExpression(pub String);
 
 /// A node has inputs (dependencies) that need to be met. A dependency can be
 /// met in three different ways.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DependencyKind {
     /// Taking the value of an external parameter, from the inputs to the graph
     /// function itself.
@@ -31,7 +48,16 @@ pub enum DependencyKind {
     /// shown to the user of the graph.
     External { promoted: Option<String> },
     /// Taking the value from another node's outputs.
-    Connection { node: BjkNodeId, param_name: String },
+    ///
+    /// When optional, a failure of the other node doesn't make this node fail:
+    /// The input takes its default value instead. The same happens when the
+    /// other node is bypassed. See
+    /// `BjkGraph::set_connection_optional`.
+    Connection {
+        node: BjkNodeId,
+        param_name: String,
+        optional: bool,
+    },
 }
 
 /// The data types available for graph parameters
@@ -39,10 +65,14 @@ pub enum DependencyKind {
 pub enum DataType {
     Vector,
     Scalar,
+    Int,
     Selection,
     Mesh,
     String,
     HeightMap,
+    Color,
+    Path,
+    List,
 }
 
 impl DataType {
@@ -50,7 +80,14 @@ impl DataType {
     pub fn can_be_enabled(&self) -> bool {
         match self {
             DataType::Mesh | DataType::HeightMap => true,
-            DataType::Vector | DataType::Scalar | DataType::Selection | DataType::String => false,
+            DataType::Vector
+            | DataType::Scalar
+            | DataType::Int
+            | DataType::Selection
+            | DataType::String
+            | DataType::Color
+            | DataType::Path
+            | DataType::List => false,
         }
     }
 
@@ -59,31 +96,99 @@ impl DataType {
         match self {
             DataType::Vector => matches!(value, BlackjackValue::Vector(_)),
             DataType::Scalar => matches!(value, BlackjackValue::Scalar(_)),
+            DataType::Int => matches!(value, BlackjackValue::Int(_)),
             DataType::Selection => matches!(value, BlackjackValue::Selection(_, _)),
             DataType::String => matches!(value, BlackjackValue::String(_)),
             DataType::Mesh => matches!(value, BlackjackValue::None),
             DataType::HeightMap => matches!(value, BlackjackValue::None),
+            DataType::Color => matches!(value, BlackjackValue::Color(_)),
+            DataType::Path => matches!(value, BlackjackValue::Path(_)),
+            DataType::List => match value {
+                BlackjackValue::List(items) => is_homogeneous(items),
+                _ => false,
+            },
+        }
+    }
+
+    /// Converts `value` to this data type, for the values that don't keep
+    /// their type when they go through Lua. Lua has a single number type, so
+    /// numbers read from Lua are `Int` when they have no fractional part, and
+    /// `Scalar` otherwise. `Int` values are converted for `Scalar`
+    /// parameters, and strings for `Path` parameters. Scalars are never
+    /// converted to `Int`. Returns `None` when `value` is not valid for this
+    /// data type and can't be converted.
+    pub fn coerce_value(&self, value: BlackjackValue) -> Option<BlackjackValue> {
+        match (self, value) {
+            // NOTE: Like any other number given to a scalar parameter,
+            // integers past 2^24 are rounded to the nearest f32.
+            (DataType::Scalar, BlackjackValue::Int(i)) => Some(BlackjackValue::Scalar(i as f32)),
+            (DataType::Path, BlackjackValue::String(s)) => Some(BlackjackValue::Path(s.into())),
+            (_, value) if self.is_valid_value(&value) => Some(value),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Returns `x` as an integer, if it has no fractional part and is in range.
+fn number_to_int(x: f64) -> Option<i64> {
+    // NOTE: i64::MAX is not representable as f64, the comparison happens
+    // against 2^63.
+    if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 {
+        Some(x as i64)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BlackjackValue {
     Vector(glam::Vec3),
     Scalar(f32),
+    /// An integer, for parameters such as counts or indices, which would
+    /// lose precision or intent if stored as a `Scalar`.
+    Int(i64),
     String(String),
     Selection(String, Option<SelectionExpression>),
+    /// An RGBA color, with components in the [0, 1] range.
+    Color(glam::Vec4),
+    /// A path to a file. Relative paths are resolved against the project root
+    /// before being passed to nodes. See `EvaluationOptions::project_root`.
+    Path(PathBuf),
+    /// A variable-length list of values, all of them of the same type. Nodes
+    /// receive lists as Lua arrays.
+    List(Vec<BlackjackValue>),
     None,
+    /// Only used for external parameters, to mark a parameter that was left
+    /// unset on purpose. The interpreter uses the default value of the input
+    /// instead. Unlike a missing parameter, this is never an error.
+    Unset,
+}
+
+/// Returns whether all of `items` are values of the same type.
+pub fn is_homogeneous(items: &[BlackjackValue]) -> bool {
+    items.iter().map(std::mem::discriminant).all_equal()
 }
 
-impl<'lua> ToLua<'lua> for BlackjackValue {
-    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+impl<'lua> IntoLua<'lua> for BlackjackValue {
+    fn into_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
         match self {
             BlackjackValue::Vector(v) => Ok(v.cast_to_lua(lua)),
             BlackjackValue::Scalar(s) => Ok(s.cast_to_lua(lua)),
-            BlackjackValue::String(s) => s.to_lua(lua),
-            BlackjackValue::Selection(_, sel) => sel.to_lua(lua),
-            BlackjackValue::None => Ok(mlua::Value::Nil),
+            // NOTE: Luau numbers are doubles, integers past 2^53 are rounded.
+            BlackjackValue::Int(i) => Ok(mlua::Value::Number(i as f64)),
+            BlackjackValue::String(s) => s.into_lua(lua),
+            BlackjackValue::Selection(_, sel) => sel.into_lua(lua),
+            BlackjackValue::Color(c) => {
+                let table = lua.create_table()?;
+                table.set("r", c.x)?;
+                table.set("g", c.y)?;
+                table.set("b", c.z)?;
+                table.set("a", c.w)?;
+                Ok(mlua::Value::Table(table))
+            }
+            BlackjackValue::Path(p) => p.to_string_lossy().into_lua(lua),
+            BlackjackValue::List(items) => Ok(mlua::Value::Table(lua.create_sequence_from(items)?)),
+            BlackjackValue::None | BlackjackValue::Unset => Ok(mlua::Value::Nil),
         }
     }
 }
@@ -93,10 +198,15 @@ impl<'lua> FromLua<'lua> for BlackjackValue {
         let type_name = lua_value.type_name();
         match lua_value {
             mlua::Value::Nil => return Ok(BlackjackValue::None),
-            mlua::Value::Integer(i) => return Ok(BlackjackValue::Scalar(i as f32)),
-            mlua::Value::Number(n) => return Ok(BlackjackValue::Scalar(n as f32)),
-            mlua::Value::Vector(x, y, z) => {
-                return Ok(BlackjackValue::Vector(glam::Vec3::new(x, y, z)))
+            mlua::Value::Integer(i) => return Ok(BlackjackValue::Int(i.into())),
+            mlua::Value::Number(n) => {
+                return Ok(match number_to_int(n) {
+                    Some(i) => BlackjackValue::Int(i),
+                    None => BlackjackValue::Scalar(n as f32),
+                })
+            }
+            mlua::Value::Vector(v) => {
+                return Ok(BlackjackValue::Vector(glam::Vec3::new(v.x(), v.y(), v.z())))
             }
             mlua::Value::String(s) => return Ok(BlackjackValue::String(s.to_str()?.into())),
             mlua::Value::UserData(u) => {
@@ -105,6 +215,48 @@ impl<'lua> FromLua<'lua> for BlackjackValue {
                     return Ok(BlackjackValue::Selection(sel.unparse(), Some(sel)));
                 }
             }
+            // Colors are the only tables with named fields. Anything else is
+            // read as a list.
+            mlua::Value::Table(t) if t.contains_key("r")? => {
+                return Ok(BlackjackValue::Color(color_from_table(&t)?))
+            }
+            mlua::Value::Table(t) => {
+                let num_pairs = t.clone().pairs::<mlua::Value, mlua::Value>().count();
+                let mut items = t
+                    .sequence_values::<BlackjackValue>()
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                if num_pairs != items.len() {
+                    return Err(mlua::Error::FromLuaConversionError {
+                        from: type_name,
+                        to: "BlackjackValue",
+                        message: Some("Only colors and arrays can be converted".into()),
+                    });
+                }
+                // Numbers with and without a fractional part are read as
+                // different types, but a list mixing them is a list of scalars.
+                let is_number = |item: &BlackjackValue| {
+                    matches!(item, BlackjackValue::Scalar(_) | BlackjackValue::Int(_))
+                };
+                if items.iter().all(is_number)
+                    && items
+                        .iter()
+                        .any(|item| matches!(item, BlackjackValue::Scalar(_)))
+                {
+                    for item in &mut items {
+                        if let BlackjackValue::Int(i) = *item {
+                            *item = BlackjackValue::Scalar(i as f32);
+                        }
+                    }
+                }
+                if !is_homogeneous(&items) {
+                    return Err(mlua::Error::FromLuaConversionError {
+                        from: type_name,
+                        to: "BlackjackValue",
+                        message: Some("All the elements of a list must have the same type".into()),
+                    });
+                }
+                return Ok(BlackjackValue::List(items));
+            }
             _ => {}
         }
         Err(mlua::Error::FromLuaConversionError {
@@ -115,9 +267,20 @@ impl<'lua> FromLua<'lua> for BlackjackValue {
     }
 }
 
+/// Reads a color from a Lua table with `r`, `g`, `b` and, optionally, `a`
+/// fields. Alpha defaults to 1.
+pub fn color_from_table(table: &Table) -> mlua::Result<glam::Vec4> {
+    Ok(glam::Vec4::new(
+        table.get("r")?,
+        table.get("g")?,
+        table.get("b")?,
+        table.get::<_, Option<f32>>("a")?.unwrap_or(1.0),
+    ))
+}
+
 /// An input parameter in the graph. Inputs represent data dependencies that
 /// need to be met before executing a node.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InputParameter {
     pub name: String,
     pub data_type: DataType,
@@ -126,14 +289,14 @@ pub struct InputParameter {
 
 /// An output parameter. Outputs are pieces of data produced by a node, which
 /// can be used to feed into another nodes as inputs.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Output {
     pub name: String,
     pub data_type: DataType,
 }
 
 /// A node in the blackjack graph
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BjkNode {
     pub op_name: String,
     /// When this node is the target of a graph, this stores the name of the
@@ -141,6 +304,23 @@ pub struct BjkNode {
     pub return_value: Option<String>,
     pub inputs: Vec<InputParameter>,
     pub outputs: Vec<Output>,
+    /// Bypassed nodes are disabled without removing them from the graph. The
+    /// interpreter doesn't run their `op`, and instead forwards their inputs
+    /// to their outputs. Each output takes the value of the input with the
+    /// same name or, if there is none, of the only input with the same data
+    /// type.
+    pub bypass: bool,
+}
+
+/// The changes `BjkGraph::map_ops` makes to a node. The default rewrite
+/// leaves the node as it is.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NodeRewrite {
+    /// The new op name of the node, if it changes.
+    pub op_name: Option<String>,
+    /// The new names of the node's inputs, by their current name. Inputs not
+    /// in the map keep their name.
+    pub input_names: HashMap<String, String>,
 }
 
 slotmap::new_key_type! { pub struct BjkNodeId; }
@@ -194,6 +374,11 @@ pub enum InputValueConfig {
         soft_max: Option<f32>,
         num_decimals: Option<u32>,
     },
+    Int {
+        default: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    },
     Selection {
         default_selection: SelectionExpression,
     },
@@ -210,6 +395,12 @@ pub enum InputValueConfig {
         default_text: String,
     },
     LuaString {},
+    Color {
+        default: glam::Vec4,
+    },
+    List {
+        default: Vec<BlackjackValue>,
+    },
     None,
 }
 
@@ -229,17 +420,38 @@ impl DataType {
         match self {
             DataType::Vector => BlackjackValue::Vector(Vec3::default()),
             DataType::Scalar => BlackjackValue::Scalar(0.0),
+            DataType::Int => BlackjackValue::Int(0),
             DataType::Selection => {
                 BlackjackValue::Selection("".into(), Some(SelectionExpression::None))
             }
             DataType::String => BlackjackValue::String("".into()),
             DataType::Mesh => BlackjackValue::None,
             DataType::HeightMap => BlackjackValue::None,
+            DataType::Color => BlackjackValue::Color(glam::Vec4::ONE),
+            DataType::Path => BlackjackValue::Path(PathBuf::new()),
+            DataType::List => BlackjackValue::List(Vec::new()),
         }
     }
 }
 
 impl InputDefinition {
+    /// For inputs with an `Enum` config, returns the allowed values.
+    pub fn enum_values(&self) -> Option<&[String]> {
+        match &self.config {
+            InputValueConfig::Enum { values, .. } => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Returns whether `value` is one of the allowed values for an `Enum`
+    /// input. Inputs with a different config allow any value.
+    pub fn allows_value(&self, value: &BlackjackValue) -> bool {
+        match (self.enum_values(), value) {
+            (Some(values), BlackjackValue::String(s)) => values.contains(s),
+            _ => true,
+        }
+    }
+
     pub fn default_value(&self) -> BlackjackValue {
         let default_string = || BlackjackValue::String("".into());
 
@@ -250,6 +462,7 @@ impl InputDefinition {
             (DataType::Scalar, InputValueConfig::Scalar { default, .. }) => {
                 BlackjackValue::Scalar(*default)
             }
+            (DataType::Int, InputValueConfig::Int { default, .. }) => BlackjackValue::Int(*default),
             (DataType::Selection, InputValueConfig::Selection { default_selection }) => {
                 BlackjackValue::Selection(
                     default_selection.unparse(),
@@ -284,6 +497,15 @@ impl InputDefinition {
             }
             (DataType::String, InputValueConfig::LuaString {}) => default_string(),
             (DataType::HeightMap, InputValueConfig::None) => BlackjackValue::None,
+            (DataType::Color, InputValueConfig::Color { default }) => {
+                BlackjackValue::Color(*default)
+            }
+            (DataType::Path, InputValueConfig::FilePath { default_path, .. }) => {
+                BlackjackValue::Path(default_path.as_ref().map(PathBuf::from).unwrap_or_default())
+            }
+            (DataType::List, InputValueConfig::List { default }) => {
+                BlackjackValue::List(default.clone())
+            }
 
             // Fallback: When config is not valud, return some valid value
             (data_type, _) => data_type.default_value(),
@@ -305,6 +527,8 @@ pub struct NodeDefinition {
     pub op_name: String,
     /// The name of the node that will be displayed to users
     pub label: String,
+    /// The group this node is listed under in node menus, if any.
+    pub category: Option<String>,
     /// The definitions for this node's input parameters
     pub inputs: Vec<InputDefinition>,
     /// The definitions for this node's output parameters
@@ -319,10 +543,64 @@ pub struct NodeDefinition {
     pub executable: bool,
     /// This node has an available interactive gizmo.
     pub has_gizmo: bool,
+    /// When present, this node is implemented in Rust, and this function is
+    /// called instead of looking up its `op` in the Lua node library.
+    pub native_op: Option<NativeOp>,
+    /// When present, this node wraps another graph, which is evaluated
+    /// instead of an `op`. See `wrap_graph`.
+    pub subgraph: Option<SubGraph>,
+    /// Passthrough nodes have no `op`. Instead, each of their outputs takes
+    /// the value of the input with the same name. This is used for reroute
+    /// nodes, which only help with the layout of a graph.
+    pub passthrough: bool,
+    /// Pure nodes always produce the same outputs for the same inputs, and
+    /// have no side effects. Only pure nodes have their outputs memoized, see
+    /// `CachedInterpreter::set_memoize`. Nodes are pure unless declared
+    /// otherwise, with `pure = false`. This is needed for things like nodes
+    /// reading files that may change, or using the current time.
+    pub pure: bool,
+}
+
+/// The inputs of a node, by name, as received by its `op` function.
+pub type InputMap<'lua> = HashMap<String, mlua::Value<'lua>>;
+
+/// The outputs of a node, by name, as returned by its `op` function.
+pub type OutputMap<'lua> = HashMap<String, mlua::Value<'lua>>;
+
+/// The `op` function of a node implemented in Rust. Native nodes are useful
+/// for operations that are too slow in Lua, or that need to call into other
+/// libraries.
+#[derive(Clone)]
+pub struct NativeOp(Rc<NativeOpFn>);
+
+type NativeOpFn = dyn for<'lua> Fn(&'lua mlua::Lua, &InputMap<'lua>) -> Result<OutputMap<'lua>>;
+
+impl NativeOp {
+    pub fn new(
+        op: impl for<'lua> Fn(&'lua mlua::Lua, &InputMap<'lua>) -> Result<OutputMap<'lua>> + 'static,
+    ) -> Self {
+        Self(Rc::new(op))
+    }
+
+    /// Calls this op with the inputs in `input_map`, and returns its outputs
+    /// as a table, like a Lua node would.
+    pub fn call<'lua>(&self, lua: &'lua mlua::Lua, input_map: &Table<'lua>) -> Result<Table<'lua>> {
+        let inputs = input_map
+            .clone()
+            .pairs::<String, mlua::Value>()
+            .collect::<mlua::Result<InputMap>>()?;
+        Ok(lua.create_table_from((self.0)(lua, &inputs)?)?)
+    }
+}
+
+impl std::fmt::Debug for NativeOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NativeOp")
+    }
 }
 
 #[derive(Default)]
-pub struct NodeDefinitionsInner(BTreeMap<String, NodeDefinition>);
+pub struct NodeDefinitionsInner(pub(crate) BTreeMap<String, NodeDefinition>);
 
 /// A collection of node definitions. This struct is the Rust counterpart to the
 /// node library in Lua.
@@ -330,35 +608,142 @@ pub struct NodeDefinitionsInner(BTreeMap<String, NodeDefinition>);
 /// This struct acts like a pointer with multiple ownership and interior
 /// mutability, allowing multiple locations in the codebase to store and receive
 /// updates to the node definitions when hot-reloading detects changes.
+///
+/// Definitions can also be layered on top of other definitions, see
+/// `layered`.
 #[derive(Default)]
 pub struct NodeDefinitions {
     pub inner: Rc<RefCell<NodeDefinitionsInner>>,
+    /// The layers below `inner`, searched in order when `inner` has no
+    /// definition for a node.
+    base_layers: Vec<Rc<RefCell<NodeDefinitionsInner>>>,
 }
 
 impl NodeDefinitions {
     pub fn new(inner: NodeDefinitionsInner) -> Self {
         Self {
             inner: Rc::new(RefCell::new(inner)),
+            base_layers: Vec::new(),
         }
     }
     pub fn share(&self) -> Self {
         Self {
             inner: Rc::clone(&self.inner),
+            base_layers: self.base_layers.iter().map(Rc::clone).collect(),
+        }
+    }
+    /// Combines several node libraries, like a core library and a user's
+    /// extensions to it. The `layers` go from lowest to highest priority:
+    /// When more than one layer defines the same node, the definition from
+    /// the last of them is used, the same as if the libraries were loaded in
+    /// that order.
+    ///
+    /// The layers are shared, not copied, so hot-reloading any of them is
+    /// seen by the combined definitions. Updates and native nodes registered
+    /// through the combined definitions go to the last layer.
+    ///
+    /// Only the definitions are layered: The `op` of a Lua node is still
+    /// looked up by name in the Lua node library. The Lua code of the layers
+    /// should be loaded in the same order, so the node library ends up with
+    /// the same definitions.
+    pub fn layered(layers: &[&NodeDefinitions]) -> Self {
+        let mut all_layers = layers
+            .iter()
+            .rev()
+            .copied()
+            .flat_map(NodeDefinitions::layers)
+            .map(Rc::clone);
+        match all_layers.next() {
+            Some(inner) => Self {
+                inner,
+                base_layers: all_layers.collect(),
+            },
+            None => Self::default(),
         }
     }
+    /// The layers of these definitions, from highest to lowest priority.
+    fn layers(&self) -> impl Iterator<Item = &Rc<RefCell<NodeDefinitionsInner>>> {
+        std::iter::once(&self.inner).chain(&self.base_layers)
+    }
+    /// Returns the layer with the definition used for `op_name`, if any.
+    fn layer_for(&self, op_name: &str) -> Option<Ref<'_, NodeDefinitionsInner>> {
+        self.layers()
+            .map(|layer| layer.borrow())
+            .find(|layer| layer.0.contains_key(op_name))
+    }
+    /// The names of all the defined nodes, sorted. Nodes defined in more than
+    /// one layer are only listed once.
     pub fn node_names(&self) -> Vec<String> {
-        self.inner.borrow().0.keys().cloned().collect()
+        self.layers()
+            .flat_map(|layer| layer.borrow().0.keys().cloned().collect_vec())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+    /// All the node definitions, sorted by name, as `node_def` returns them.
+    /// This is what an editor needs to list the available nodes.
+    pub fn all(&self) -> Vec<NodeDefinition> {
+        self.node_names()
+            .iter()
+            .filter_map(|op_name| self.node_def(op_name).map(|node_def| node_def.clone()))
+            .collect()
     }
     pub fn node_def(&self, op_name: &str) -> Option<impl Deref<Target = NodeDefinition> + '_> {
-        let guard = self.inner.borrow();
-        if guard.0.contains_key(op_name) {
-            Some(Ref::map(guard, |x| x.0.get(op_name).unwrap()))
-        } else {
-            None
+        self.layer_for(op_name)
+            .map(|layer| Ref::map(layer, |x| x.0.get(op_name).unwrap()))
+    }
+    /// Returns the outputs declared by the node definition for `op_name`.
+    /// These are the outputs a `Connection` from a node of this type can
+    /// refer to.
+    pub fn outputs(&self, op_name: &str) -> Option<impl Deref<Target = [OutputDefinition]> + '_> {
+        self.layer_for(op_name)
+            .map(|layer| Ref::map(layer, |x| x.0[op_name].outputs.as_slice()))
+    }
+    /// Returns the data type the node definition for `op_name` declares for
+    /// its input named `input_name`, if both exist.
+    pub fn input_type(&self, op_name: &str, input_name: &str) -> Option<DataType> {
+        self.node_def(op_name)?
+            .inputs
+            .iter()
+            .find(|input| input.name == input_name)
+            .map(|input| input.data_type)
+    }
+    /// Returns the data type the node definition for `op_name` declares for
+    /// its output named `output_name`, if both exist.
+    pub fn output_type(&self, op_name: &str, output_name: &str) -> Option<DataType> {
+        self.outputs(op_name)?
+            .iter()
+            .find(|output| output.name == output_name)
+            .map(|output| output.data_type)
+    }
+    /// Replaces the node definitions with `new_data`. Native and sub-graph
+    /// nodes are kept, unless `new_data` has a node with the same name.
+    pub fn update(&self, mut new_data: NodeDefinitionsInner) {
+        let mut inner = self.inner.borrow_mut();
+        for (op_name, node_def) in std::mem::take(&mut inner.0) {
+            if node_def.native_op.is_some() || node_def.subgraph.is_some() {
+                new_data.0.entry(op_name).or_insert(node_def);
+            }
         }
+        *inner = new_data;
+    }
+    /// Registers a node implemented in Rust. Its `op` is `native_op`, and the
+    /// rest of its definition is taken from `node_def`.
+    pub fn register_native(&self, mut node_def: NodeDefinition, native_op: NativeOp) {
+        node_def.native_op = Some(native_op);
+        self.inner
+            .borrow_mut()
+            .0
+            .insert(node_def.op_name.clone(), node_def);
     }
-    pub fn update(&self, new_data: NodeDefinitionsInner) {
-        *self.inner.borrow_mut() = new_data;
+    /// Registers a node evaluating another graph. The rest of its definition
+    /// is taken from `node_def`. See `wrap_graph`.
+    pub fn register_subgraph(&self, mut node_def: NodeDefinition, subgraph: SubGraph) {
+        node_def.subgraph = Some(subgraph);
+        self.inner
+            .borrow_mut()
+            .0
+            .insert(node_def.op_name.clone(), node_def);
     }
 }
 
@@ -368,9 +753,13 @@ fn data_type_from_str(s: &str) -> Result<DataType> {
     match s {
         "vec3" => Ok(DataType::Vector),
         "scalar" => Ok(DataType::Scalar),
+        "int" => Ok(DataType::Int),
         "selection" => Ok(DataType::Selection),
         "mesh" => Ok(DataType::Mesh),
         "heightmap" => Ok(DataType::HeightMap),
+        "color" => Ok(DataType::Color),
+        "path" => Ok(DataType::Path),
+        "list" => Ok(DataType::List),
         "enum" => Ok(DataType::String),
         "file" => Ok(DataType::String),
         "string" => Ok(DataType::String),
@@ -384,6 +773,19 @@ impl InputDefinition {
     pub fn from_lua(table: Table) -> Result<Self> {
         let type_str: String = table.get::<_, String>("type")?;
         let data_type = data_type_from_str(&type_str)?;
+        let file_path_config = || -> Result<InputValueConfig> {
+            let mode = table.get::<_, String>("mode")?;
+            Ok(InputValueConfig::FilePath {
+                default_path: None,
+                file_path_mode: if mode == "open" {
+                    FilePathMode::Open
+                } else if mode == "save" {
+                    FilePathMode::Save
+                } else {
+                    bail!("Undefined mode {mode}")
+                },
+            })
+        };
         let value = match data_type {
             DataType::Vector => InputValueConfig::Vector {
                 default: table.get::<_, LVec3>("default")?.0,
@@ -396,11 +798,22 @@ impl InputDefinition {
                 soft_max: table.get::<_, Option<f32>>("soft_max")?,
                 num_decimals: table.get::<_, Option<u32>>("num_decimals")?,
             },
+            DataType::Int => InputValueConfig::Int {
+                default: table.get::<_, i64>("default")?,
+                min: table.get::<_, Option<i64>>("min")?,
+                max: table.get::<_, Option<i64>>("max")?,
+            },
             DataType::Selection => InputValueConfig::Selection {
                 default_selection: SelectionExpression::None,
             },
             DataType::Mesh => InputValueConfig::None,
             DataType::HeightMap => InputValueConfig::None,
+            DataType::Color => InputValueConfig::Color {
+                default: match table.get::<_, Option<Table>>("default")? {
+                    Some(default) => color_from_table(&default)?,
+                    None => glam::Vec4::ONE,
+                },
+            },
             DataType::String if type_str == "enum" => InputValueConfig::Enum {
                 values: table
                     .get::<_, Table>("values")?
@@ -408,19 +821,17 @@ impl InputDefinition {
                     .collect::<Result<Vec<_>, _>>()?,
                 default_selection: table.get::<_, Option<u32>>("selected")?,
             },
-            DataType::String if type_str == "file" => {
-                let mode = table.get::<_, String>("mode")?;
-                InputValueConfig::FilePath {
-                    default_path: None,
-                    file_path_mode: if mode == "open" {
-                        FilePathMode::Open
-                    } else if mode == "save" {
-                        FilePathMode::Save
-                    } else {
-                        bail!("Undefined mode {mode}")
-                    },
-                }
-            }
+            DataType::String if type_str == "file" => file_path_config()?,
+            DataType::Path => file_path_config()?,
+            DataType::List => InputValueConfig::List {
+                default: match table.get::<_, Option<BlackjackValue>>("default")? {
+                    Some(BlackjackValue::List(items)) => items,
+                    Some(other) => {
+                        bail!("The default of a list parameter must be a list, got {other:?}")
+                    }
+                    None => Vec::new(),
+                },
+            },
             DataType::String if type_str == "lua_string" => InputValueConfig::LuaString {},
             DataType::String => InputValueConfig::String {
                 default_text: table.get::<_, String>("default")?,
@@ -466,9 +877,16 @@ impl NodeDefinition {
             inputs,
             outputs,
             label: table.get("label")?,
+            category: table.get::<_, Option<String>>("category")?,
             returns: table.get::<_, Option<String>>("returns")?,
             executable: table.get::<_, Option<bool>>("executable")?.unwrap_or(false),
             has_gizmo: table.get::<_, mlua::Value>("gizmos")? != mlua::Value::Nil,
+            native_op: None,
+            subgraph: None,
+            passthrough: table
+                .get::<_, Option<bool>>("passthrough")?
+                .unwrap_or(false),
+            pure: table.get::<_, Option<bool>>("pure")?.unwrap_or(true),
         })
     }
 
@@ -501,6 +919,7 @@ impl BjkGraph {
             return_value,
             inputs: vec![],
             outputs: vec![],
+            bypass: false,
         })
     }
 
@@ -577,10 +996,67 @@ impl BjkGraph {
             input.kind = DependencyKind::Connection {
                 node: src_node,
                 param_name: src_param.into(),
+                optional: false,
             }
         } else {
             bail!("Input parameter named {dst_param} does not exist for node {dst_node:?}");
         }
         Ok(())
     }
+
+    /// Marks the connection feeding the `param_name` input of `node_id` as
+    /// optional, or as mandatory again. Fails if the input is not connected.
+    pub fn set_connection_optional(
+        &mut self,
+        node_id: BjkNodeId,
+        param_name: &str,
+        optional: bool,
+    ) -> Result<()> {
+        let input = self.nodes[node_id]
+            .inputs
+            .iter_mut()
+            .find(|input| input.name == param_name)
+            .ok_or_else(|| {
+                anyhow!("Input parameter named {param_name} does not exist for node {node_id:?}")
+            })?;
+        match &mut input.kind {
+            DependencyKind::Connection {
+                optional: old_optional,
+                ..
+            } => *old_optional = optional,
+            DependencyKind::External { .. } => {
+                bail!("Input parameter named {param_name} of node {node_id:?} is not connected")
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the graph where every node is rewritten as returned
+    /// by `f`, which is called once per node. Node ids, connections and the
+    /// default node are kept, so the result can be compared with `diff_graphs`.
+    ///
+    /// Inputs are renamed along with their dependency. External parameter
+    /// values are stored by input name, so they need to be moved separately.
+    /// See `GraphMigrations::rename_input`.
+    pub fn map_ops(&self, mut f: impl FnMut(&BjkNode) -> NodeRewrite) -> BjkGraph {
+        let mut nodes = self.nodes.clone();
+        for (_, node) in nodes.iter_mut() {
+            let NodeRewrite {
+                op_name,
+                input_names,
+            } = f(node);
+            if let Some(op_name) = op_name {
+                node.op_name = op_name;
+            }
+            for input in &mut node.inputs {
+                if let Some(new_name) = input_names.get(&input.name) {
+                    input.name = new_name.clone();
+                }
+            }
+        }
+        BjkGraph {
+            nodes,
+            default_node: self.default_node,
+        }
+    }
 }
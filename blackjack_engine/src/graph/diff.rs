@@ -0,0 +1,117 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// The differences between two versions of a graph, as returned by
+/// `diff_graphs`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GraphDelta {
+    /// The nodes that are only in the new graph.
+    pub added: Vec<BjkNodeId>,
+    /// The nodes that are only in the old graph.
+    pub removed: Vec<BjkNodeId>,
+    /// The nodes in both graphs that have a different op name, return value,
+    /// inputs, outputs or bypass flag in the new graph. This includes rewired
+    /// nodes.
+    pub changed: Vec<BjkNodeId>,
+    /// The inputs, as node and input name, whose dependency is different in
+    /// the new graph. Inputs that were added or removed are also included.
+    pub rewired: Vec<(BjkNodeId, String)>,
+}
+
+impl GraphDelta {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Returns the nodes that need to be evaluated again after the change,
+    /// not counting the nodes that depend on them.
+    pub fn dirty_nodes(&self) -> impl Iterator<Item = BjkNodeId> + '_ {
+        self.added.iter().chain(self.changed.iter()).copied()
+    }
+}
+
+/// Compares two versions of a graph. Nodes are matched by their id, so this
+/// is meant to compare a graph with an edited version of itself.
+pub fn diff_graphs(old: &BjkGraph, new: &BjkGraph) -> GraphDelta {
+    let mut delta = GraphDelta::default();
+
+    for (node_id, new_node) in &new.nodes {
+        let old_node = match old.nodes.get(node_id) {
+            Some(old_node) => old_node,
+            None => {
+                delta.added.push(node_id);
+                continue;
+            }
+        };
+        if old_node == new_node {
+            continue;
+        }
+        delta.changed.push(node_id);
+
+        let input_names = new_node
+            .inputs
+            .iter()
+            .chain(old_node.inputs.iter())
+            .map(|input| &input.name)
+            .unique();
+        for name in input_names {
+            let old_kind = old_node.inputs.iter().find(|i| &i.name == name);
+            let new_kind = new_node.inputs.iter().find(|i| &i.name == name);
+            if old_kind.map(|i| &i.kind) != new_kind.map(|i| &i.kind) {
+                delta.rewired.push((node_id, name.clone()));
+            }
+        }
+    }
+
+    delta.removed = old
+        .nodes
+        .keys()
+        .filter(|node_id| !new.nodes.contains_key(*node_id))
+        .collect();
+
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a graph with a `Source` node connected to a `Sink` node.
+    fn source_sink() -> (BjkGraph, BjkNodeId, BjkNodeId) {
+        let mut graph = BjkGraph::new();
+        let source = graph.add_node("Source", None);
+        graph.add_output(source, "out", DataType::Scalar).unwrap();
+        let sink = graph.add_node("Sink", None);
+        graph.add_input(sink, "x", DataType::Scalar, None).unwrap();
+        graph.add_connection(source, "out", sink, "x").unwrap();
+        (graph, source, sink)
+    }
+
+    #[test]
+    pub fn test_diff_graphs() {
+        let (old, source, sink) = source_sink();
+        let (mut new, _, _) = source_sink();
+        assert!(diff_graphs(&old, &new).is_empty());
+
+        new.nodes[source].return_value = Some("out".into());
+        new.nodes.remove(sink);
+        let other = new.add_node("Sink", None);
+        let delta = diff_graphs(&old, &new);
+        assert_eq!(delta.added, vec![other]);
+        assert_eq!(delta.removed, vec![sink]);
+        assert_eq!(delta.changed, vec![source]);
+        assert!(delta.rewired.is_empty());
+
+        let (mut new, _, _) = source_sink();
+        new.nodes[sink].inputs[0].kind = DependencyKind::External { promoted: None };
+        let delta = diff_graphs(&old, &new);
+        assert_eq!(delta.changed, vec![sink]);
+        assert_eq!(delta.rewired, vec![(sink, "x".to_string())]);
+        assert_eq!(delta.dirty_nodes().collect_vec(), vec![sink]);
+    }
+}
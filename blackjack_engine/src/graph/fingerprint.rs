@@ -0,0 +1,137 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::graph_interpreter::{reachable_nodes, ExternalParameter, ExternalParameterValues};
+
+impl BjkGraph {
+    /// Returns a hash of everything that affects the result of evaluating
+    /// `target` with the given parameter values: The op names and connections
+    /// of the nodes `target` depends on, the values of their external
+    /// parameters, and the target's return value. Nodes `target` doesn't
+    /// depend on, and the values of their parameters, don't affect it.
+    ///
+    /// The fingerprint is stable across runs and platforms, so it can be used
+    /// as the key of a cache stored on disk. Node ids are not part of it, so
+    /// the same graph gives the same fingerprint after being saved and loaded.
+    /// Floats are compared by value, so `0.0` and `-0.0` are the same, and so
+    /// are all the NaNs.
+    ///
+    /// The fingerprint doesn't cover the node library. When the definition of
+    /// a node changes, cached results must be invalidated some other way.
+    pub fn fingerprint(&self, target: BjkNodeId, params: &ExternalParameterValues) -> u64 {
+        let mut node_keys = HashMap::<BjkNodeId, u64>::new();
+        for node_id in reachable_nodes(self, target) {
+            let node = &self.nodes[node_id];
+            let mut hasher = StableHasher::new();
+            hasher.str(&node.op_name);
+            // Only tagged when set, so fingerprints of graphs without bypassed
+            // nodes don't change.
+            if node.bypass {
+                hasher.tag(3);
+            }
+            // Inputs are identified by name, so their order doesn't matter.
+            for input in node.inputs.iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+                hasher.str(&input.name);
+                match &input.kind {
+                    DependencyKind::Connection {
+                        node,
+                        param_name,
+                        optional,
+                    } => {
+                        hasher.tag(0);
+                        // NOTE: Dependencies come first in the order, unless
+                        // they are part of a cycle.
+                        match node_keys.get(node) {
+                            Some(key) => hasher.u64(*key),
+                            None => hasher.tag(0xff),
+                        }
+                        hasher.str(param_name);
+                        // Only tagged when set, so fingerprints of graphs
+                        // without optional connections don't change.
+                        if *optional {
+                            hasher.tag(2);
+                        }
+                    }
+                    DependencyKind::External { .. } => {
+                        hasher.tag(1);
+                        let param = ExternalParameter::new(node_id, input.name.clone());
+                        match params.0.get(&param) {
+                            Some(value) => hasher.value(value),
+                            None => hasher.tag(0xff),
+                        }
+                    }
+                }
+            }
+            node_keys.insert(node_id, hasher.finish());
+        }
+
+        let mut hasher = StableHasher::new();
+        hasher.u64(node_keys[&target]);
+        match &self.nodes[target].return_value {
+            Some(return_value) => hasher.str(return_value),
+            None => hasher.tag(0xff),
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A box feeding a bevel. When `unrelated` is set, a node that the bevel
+    /// doesn't depend on is added first, so the ids of the other nodes change.
+    fn bevel_graph(unrelated: bool, size: f32) -> (BjkGraph, BjkNodeId, ExternalParameterValues) {
+        let mut graph = BjkGraph::new();
+        let mut builder = ExternalParameterValues::builder();
+        if unrelated {
+            let other = graph.add_node("MakeBox", None);
+            graph
+                .add_input(other, "size", DataType::Scalar, None)
+                .unwrap();
+            builder = builder.scalar(other, "size", 100.0);
+        }
+        let a = graph.add_node("MakeBox", None);
+        graph.add_input(a, "size", DataType::Scalar, None).unwrap();
+        graph.add_output(a, "out", DataType::Mesh).unwrap();
+        let b = graph.add_node("Bevel", Some("out_mesh".into()));
+        graph.add_input(b, "mesh", DataType::Mesh, None).unwrap();
+        graph
+            .add_input(b, "amount", DataType::Scalar, None)
+            .unwrap();
+        graph.add_output(b, "out_mesh", DataType::Mesh).unwrap();
+        graph.add_connection(a, "out", b, "mesh").unwrap();
+        let params = builder
+            .scalar(a, "size", size)
+            .scalar(b, "amount", 0.1)
+            .build();
+        (graph, b, params)
+    }
+
+    #[test]
+    pub fn test_fingerprint() {
+        let fingerprint =
+            |(graph, target, params): (BjkGraph, BjkNodeId, ExternalParameterValues)| {
+                graph.fingerprint(target, &params)
+            };
+        let base = fingerprint(bevel_graph(false, 1.0));
+        assert_eq!(base, fingerprint(bevel_graph(false, 1.0)));
+        assert_eq!(base, fingerprint(bevel_graph(true, 1.0)));
+        assert_ne!(base, fingerprint(bevel_graph(false, 2.0)));
+        assert_eq!(
+            fingerprint(bevel_graph(false, 0.0)),
+            fingerprint(bevel_graph(false, -0.0))
+        );
+
+        let (mut graph, target, params) = bevel_graph(false, 1.0);
+        graph.nodes[target].op_name = "Chamfer".into();
+        assert_ne!(base, graph.fingerprint(target, &params));
+        graph.nodes[target].op_name = "Bevel".into();
+        graph.nodes[target].return_value = None;
+        assert_ne!(base, graph.fingerprint(target, &params));
+    }
+}
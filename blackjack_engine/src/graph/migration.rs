@@ -0,0 +1,291 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::serialization::RuntimeData;
+use super::*;
+use crate::graph_interpreter::ExternalParameter;
+
+/// A function updating a loaded graph from the previous schema version. See
+/// `GraphMigrations::register`.
+pub type MigrationFn = Box<dyn Fn(&mut RuntimeData, &NodeDefinitions) -> Result<()>>;
+
+/// The migrations that keep graphs saved with older versions of the node
+/// libraries loadable.
+///
+/// Graph files store the schema version they were saved with. Each migration
+/// is registered for the version it upgrades graphs to, and loading a graph
+/// applies all the migrations for versions after the one in the file, in
+/// increasing version order. Migrations for the same version run in the same
+/// order they were registered.
+///
+/// The common cases, like renaming ops or parameters, have their own helpers.
+/// Anything else can be done with a custom migration.
+#[derive(Default)]
+pub struct GraphMigrations {
+    migrations: Vec<(u32, MigrationFn)>,
+}
+
+impl GraphMigrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a migration upgrading graphs to `version`.
+    pub fn register(
+        &mut self,
+        version: u32,
+        migration: impl Fn(&mut RuntimeData, &NodeDefinitions) -> Result<()> + 'static,
+    ) {
+        // Keeps the list sorted by version, after any migration already
+        // registered for the same version.
+        let idx = self.migrations.partition_point(|(v, _)| *v <= version);
+        self.migrations.insert(idx, (version, Box::new(migration)));
+    }
+
+    /// Renames the op of every node using `old_op`.
+    pub fn rename_op(&mut self, version: u32, old_op: &str, new_op: &str) {
+        let (old_op, new_op) = (old_op.to_owned(), new_op.to_owned());
+        self.register(version, move |data, _| {
+            data.graph = data.graph.map_ops(|node| NodeRewrite {
+                op_name: (node.op_name == old_op).then(|| new_op.clone()),
+                ..Default::default()
+            });
+            Ok(())
+        });
+    }
+
+    /// Renames an input of every node using `op_name`, along with the value
+    /// stored for it, if any.
+    pub fn rename_input(&mut self, version: u32, op_name: &str, old_name: &str, new_name: &str) {
+        let (op_name, old_name, new_name) =
+            (op_name.to_owned(), old_name.to_owned(), new_name.to_owned());
+        self.register(version, move |data, _| {
+            data.graph = data.graph.map_ops(|node| {
+                let mut rewrite = NodeRewrite::default();
+                if node.op_name == op_name {
+                    rewrite
+                        .input_names
+                        .insert(old_name.clone(), new_name.clone());
+                }
+                rewrite
+            });
+            for (node_id, node) in data.graph.nodes.iter() {
+                if node.op_name != op_name {
+                    continue;
+                }
+                if let Some(params) = &mut data.external_parameters {
+                    let old_param = ExternalParameter::new(node_id, old_name.clone());
+                    if let Some(value) = params.0.remove(&old_param) {
+                        params
+                            .0
+                            .insert(ExternalParameter::new(node_id, new_name.clone()), value);
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Renames an output of every node using `op_name`. Connections to the
+    /// output, and nodes returning it, are updated too.
+    pub fn rename_output(&mut self, version: u32, op_name: &str, old_name: &str, new_name: &str) {
+        let (op_name, old_name, new_name) =
+            (op_name.to_owned(), old_name.to_owned(), new_name.to_owned());
+        self.register(version, move |data, _| {
+            let renamed = data
+                .graph
+                .nodes
+                .iter_mut()
+                .filter(|(_, node)| node.op_name == op_name)
+                .map(|(node_id, node)| {
+                    for output in node.outputs.iter_mut().filter(|o| o.name == old_name) {
+                        output.name = new_name.clone();
+                    }
+                    if node.return_value.as_ref() == Some(&old_name) {
+                        node.return_value = Some(new_name.clone());
+                    }
+                    node_id
+                })
+                .collect::<HashSet<_>>();
+            for (_, node) in data.graph.nodes.iter_mut() {
+                for input in &mut node.inputs {
+                    if let DependencyKind::Connection {
+                        node, param_name, ..
+                    } = &mut input.kind
+                    {
+                        if renamed.contains(node) && *param_name == old_name {
+                            *param_name = new_name.clone();
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// The schema version graphs have after all the migrations are applied.
+    /// This is the version new graphs should be saved with. See
+    /// `SerializedBjkGraph::set_schema_version`.
+    pub fn latest_version(&self) -> u32 {
+        self.migrations.last().map(|(v, _)| *v).unwrap_or(0)
+    }
+
+    /// Applies the migrations for every version after `from_version`, in
+    /// order. Fails on the first migration returning an error.
+    pub fn migrate(
+        &self,
+        mut data: RuntimeData,
+        from_version: u32,
+        node_definitions: &NodeDefinitions,
+    ) -> Result<RuntimeData> {
+        for (version, migration) in self.migrations.iter().filter(|(v, _)| *v > from_version) {
+            migration(&mut data, node_definitions).map_err(|err| {
+                anyhow!("Could not migrate the graph to schema version {version}: {err}")
+            })?;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_interpreter::ExternalParameterValues;
+
+    #[test]
+    pub fn test_migration_order() {
+        let empty = || RuntimeData {
+            graph: BjkGraph::new(),
+            external_parameters: None,
+        };
+        let applied = Rc::new(RefCell::new(Vec::new()));
+        let mut migrations = GraphMigrations::new();
+        for (version, name) in [(2, "b"), (1, "a"), (2, "c")] {
+            let applied = applied.clone();
+            migrations.register(version, move |_, _| {
+                applied.borrow_mut().push(name);
+                Ok(())
+            });
+        }
+        assert_eq!(migrations.latest_version(), 2);
+
+        let node_definitions = NodeDefinitions::default();
+        migrations.migrate(empty(), 0, &node_definitions).unwrap();
+        assert_eq!(*applied.borrow(), vec!["a", "b", "c"]);
+        applied.borrow_mut().clear();
+        migrations.migrate(empty(), 1, &node_definitions).unwrap();
+        assert_eq!(*applied.borrow(), vec!["b", "c"]);
+        applied.borrow_mut().clear();
+        migrations.migrate(empty(), 2, &node_definitions).unwrap();
+        assert!(applied.borrow().is_empty());
+
+        migrations.register(3, |_, _| bail!("Broken"));
+        let err = migrations
+            .migrate(empty(), 2, &node_definitions)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("schema version 3"));
+    }
+
+    #[test]
+    pub fn test_rename_migrations() {
+        let mut graph = BjkGraph::new();
+        let a = graph.add_node("MakeBox", Some("out".into()));
+        graph.add_input(a, "size", DataType::Vector, None).unwrap();
+        graph.add_output(a, "out", DataType::Mesh).unwrap();
+        let b = graph.add_node("Bevel", Some("out_mesh".into()));
+        graph.add_input(b, "mesh", DataType::Mesh, None).unwrap();
+        graph.add_output(b, "out_mesh", DataType::Mesh).unwrap();
+        graph.add_connection(a, "out", b, "mesh").unwrap();
+        let params = ExternalParameterValues::builder()
+            .vec3(a, "size", Vec3::ONE)
+            .build();
+
+        let mut migrations = GraphMigrations::new();
+        migrations.rename_op(1, "MakeBox", "Box");
+        migrations.rename_input(1, "Box", "size", "extent");
+        migrations.rename_output(2, "Box", "out", "out_mesh");
+        let data = migrations
+            .migrate(
+                RuntimeData {
+                    graph,
+                    external_parameters: Some(params),
+                },
+                0,
+                &NodeDefinitions::default(),
+            )
+            .unwrap();
+
+        let box_node = &data.graph.nodes[a];
+        assert_eq!(box_node.op_name, "Box");
+        assert_eq!(box_node.inputs[0].name, "extent");
+        assert_eq!(box_node.outputs[0].name, "out_mesh");
+        assert_eq!(box_node.return_value.as_deref(), Some("out_mesh"));
+        assert_eq!(
+            data.graph.nodes[b].inputs[0].kind,
+            DependencyKind::Connection {
+                node: a,
+                param_name: "out_mesh".into(),
+                optional: false,
+            }
+        );
+        let params = data.external_parameters.unwrap();
+        assert_eq!(
+            params.0[&ExternalParameter::new(a, "extent".into())],
+            BlackjackValue::Vector(Vec3::ONE)
+        );
+        assert!(!params
+            .0
+            .contains_key(&ExternalParameter::new(a, "size".into())));
+    }
+
+    #[test]
+    pub fn test_map_ops() {
+        let mut graph = BjkGraph::new();
+        let a = graph.add_node("MakeBox", Some("out".into()));
+        graph
+            .add_input(a, "size", DataType::Vector, Some("box_size".into()))
+            .unwrap();
+        graph.add_output(a, "out", DataType::Mesh).unwrap();
+        let b = graph.add_node("Bevel", Some("out_mesh".into()));
+        graph.add_input(b, "mesh", DataType::Mesh, None).unwrap();
+        graph
+            .add_input(b, "amount", DataType::Scalar, None)
+            .unwrap();
+        graph.add_output(b, "out_mesh", DataType::Mesh).unwrap();
+        graph.add_connection(a, "out", b, "mesh").unwrap();
+        graph.set_connection_optional(b, "mesh", true).unwrap();
+        graph.default_node = Some(b);
+
+        let mut visited = Vec::new();
+        let new_graph = graph.map_ops(|node| {
+            visited.push(node.op_name.clone());
+            if node.op_name == "Bevel" {
+                NodeRewrite {
+                    op_name: Some("ChamferEdges".into()),
+                    input_names: [("mesh".to_owned(), "in_mesh".to_owned())].into(),
+                }
+            } else {
+                NodeRewrite::default()
+            }
+        });
+        visited.sort();
+        assert_eq!(visited, vec!["Bevel", "MakeBox"]);
+
+        assert_eq!(new_graph.default_node, Some(b));
+        assert_eq!(new_graph.nodes[a], graph.nodes[a]);
+        let delta = diff_graphs(&graph, &new_graph);
+        assert_eq!(delta.changed, vec![b]);
+        assert!(delta.added.is_empty() && delta.removed.is_empty());
+
+        let bevel = &new_graph.nodes[b];
+        assert_eq!(bevel.op_name, "ChamferEdges");
+        assert_eq!(bevel.inputs[0].name, "in_mesh");
+        assert_eq!(bevel.inputs[0].kind, graph.nodes[b].inputs[0].kind);
+        assert_eq!(bevel.inputs[1], graph.nodes[b].inputs[1]);
+        assert_eq!(bevel.outputs, graph.nodes[b].outputs);
+    }
+}
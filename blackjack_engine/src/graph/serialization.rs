@@ -7,7 +7,7 @@
 use std::{
     collections::HashMap,
     io::{BufRead, BufReader, BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, bail, Result};
@@ -17,12 +17,13 @@ use serde::{Deserialize, Serialize};
 use slotmap::{SecondaryMap, SlotMap};
 
 use crate::{
-    graph_interpreter::{ExternalParameter, ExternalParameterValues},
+    graph_interpreter::{BakedNode, ExternalParameter, ExternalParameterValues},
     prelude::selection::SelectionExpression,
 };
 
 use super::{
-    BjkGraph, BjkNode, BjkNodeId, BlackjackValue, DataType, DependencyKind, InputParameter, Output,
+    BjkGraph, BjkNode, BjkNodeId, BlackjackValue, DataType, DependencyKind, InputParameter,
+    NodeDefinitions, Output,
 };
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -89,8 +90,15 @@ impl SerializationVersion {
 
 #[derive(Serialize, Deserialize)]
 pub enum SerializedDependencyKind {
-    External { promoted: Option<String> },
-    Conection { node_idx: usize, param_name: String },
+    External {
+        promoted: Option<String>,
+    },
+    Conection {
+        node_idx: usize,
+        param_name: String,
+        #[serde(default)]
+        optional: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -112,6 +120,8 @@ pub struct SerializedBjkNode {
     pub return_value: Option<String>,
     pub inputs: Vec<SerializedInput>,
     pub outputs: Vec<SerializedOutput>,
+    #[serde(default)]
+    pub bypass: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -132,10 +142,95 @@ pub struct SerializedParamLocation {
 
 #[derive(Serialize, Deserialize)]
 pub enum SerializedBlackjackValue {
-    Vector(glam::Vec3),
-    Scalar(f32),
+    Vector(#[serde(serialize_with = "exact_floats::vec3")] glam::Vec3),
+    Scalar(#[serde(serialize_with = "exact_floats::scalar")] f32),
     String(String),
     Selection(String),
+    Color(#[serde(serialize_with = "exact_floats::vec4")] glam::Vec4),
+    Path(PathBuf),
+    List(Vec<SerializedBlackjackValue>),
+    None,
+    Int(i64),
+    Unset,
+}
+
+/// Floats in values are written with the shortest decimal representation that
+/// reads back as the same `f32`, independently of how the serializer formats
+/// floats. This way, loading and saving a file again is an identity, and the
+/// fingerprints of loaded graphs are the same on every machine. Vectors keep
+/// the same representation as `glam` uses.
+mod exact_floats {
+    use serde::ser::SerializeTupleStruct;
+    use serde::Serializer;
+
+    /// Returns the `f64` written as the shortest decimal representation of
+    /// `x`. Serializers write it back as that same representation.
+    fn shortest(x: f32) -> f64 {
+        if x.is_finite() {
+            // NOTE: Display for floats is the shortest representation that
+            // round-trips, and never uses exponents, so this can't fail.
+            x.to_string().parse().unwrap()
+        } else {
+            x as f64
+        }
+    }
+
+    pub fn scalar<S: Serializer>(x: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(shortest(*x))
+    }
+
+    fn floats<S: Serializer>(
+        name: &'static str,
+        xs: &[f32],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_tuple_struct(name, xs.len())?;
+        for x in xs {
+            state.serialize_field(&shortest(*x))?;
+        }
+        state.end()
+    }
+
+    pub fn vec3<S: Serializer>(v: &glam::Vec3, serializer: S) -> Result<S::Ok, S::Error> {
+        floats("Vec3", &v.to_array(), serializer)
+    }
+
+    pub fn vec4<S: Serializer>(v: &glam::Vec4, serializer: S) -> Result<S::Ok, S::Error> {
+        floats("Vec4", &v.to_array(), serializer)
+    }
+}
+
+/// An output captured by `bake_subgraph`. These are Lua values, so unlike
+/// `SerializedBlackjackValue`, they can be tables with any keys.
+#[derive(Serialize, Deserialize)]
+pub enum SerializedBakedValue {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    Vector(#[serde(serialize_with = "exact_floats::vec3")] glam::Vec3),
+    String(String),
+    /// A mesh, in the Wavefront OBJ format. Only the positions and faces of
+    /// the mesh are read back.
+    Mesh(String),
+    HeightMap {
+        width: usize,
+        height: usize,
+        heights: Vec<f32>,
+    },
+    Table(Vec<(SerializedBakedValue, SerializedBakedValue)>),
+}
+
+/// A node created with `bake_subgraph`. Baked nodes are not part of any node
+/// library, so they are stored in the files of the graphs using them.
+#[derive(Serialize, Deserialize)]
+pub struct SerializedBakedNode {
+    pub op_name: String,
+    pub label: String,
+    pub category: Option<String>,
+    pub outputs: Vec<SerializedOutput>,
+    pub returns: Option<String>,
+    pub values: Vec<(String, SerializedBakedValue)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -149,6 +244,14 @@ pub struct SerializedBjkGraph {
     pub default_node: Option<usize>,
     pub ui_data: Option<SerializedUiData>,
     pub external_parameters: Option<SerializedExternalParameters>,
+    /// The schema version of the node libraries this graph was saved with.
+    /// Files saved before schema versions were introduced have version 0.
+    /// See `GraphMigrations`.
+    #[serde(default)]
+    pub schema_version: u32,
+    /// The baked nodes used in this graph. See `register_baked_nodes`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub baked_nodes: Vec<SerializedBakedNode>,
 }
 
 /// Maps slotmap ids to serialized indices.
@@ -229,6 +332,8 @@ impl SerializedBjkGraph {
                     None
                 },
                 ui_data: None,
+                schema_version: 0,
+                baked_nodes: Vec::new(),
             },
             mappings,
         ))
@@ -237,6 +342,23 @@ impl SerializedBjkGraph {
     pub fn set_ui_data(&mut self, ui_data: SerializedUiData) {
         self.ui_data = Some(ui_data);
     }
+
+    /// Sets the schema version the graph is saved with. This should be the
+    /// `GraphMigrations::latest_version` of the loaded node libraries.
+    pub fn set_schema_version(&mut self, schema_version: u32) {
+        self.schema_version = schema_version;
+    }
+
+    /// Stores the given baked nodes along with the graph, so they can be
+    /// registered again when loading it. Fails if one of the baked values
+    /// can't be saved, like a string that is not UTF-8.
+    pub fn set_baked_nodes(&mut self, baked_nodes: &[BakedNode]) -> Result<()> {
+        self.baked_nodes = baked_nodes
+            .iter()
+            .map(BakedNode::to_serialized)
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
 }
 
 impl SerializedExternalParameters {
@@ -266,17 +388,92 @@ impl SerializedExternalParameters {
 }
 
 impl SerializedBlackjackValue {
+    /// Converts a runtime value. Returns `None` for `BlackjackValue::None`,
+    /// since those values are not stored in graph files.
     pub fn from_runtime(val: BlackjackValue) -> Option<Self> {
         match val {
-            BlackjackValue::Vector(v) => Some(Self::Vector(v)),
-            BlackjackValue::Scalar(s) => Some(Self::Scalar(s)),
-            BlackjackValue::String(s) => Some(Self::String(s)),
-            BlackjackValue::Selection(s, _) => Some(Self::Selection(s)),
             BlackjackValue::None => None,
+            val => Some((&val).into()),
+        }
+    }
+
+    pub fn into_runtime(self) -> BlackjackValue {
+        match self {
+            SerializedBlackjackValue::Vector(x) => BlackjackValue::Vector(x),
+            SerializedBlackjackValue::Scalar(x) => BlackjackValue::Scalar(x),
+            SerializedBlackjackValue::Int(x) => BlackjackValue::Int(x),
+            SerializedBlackjackValue::String(x) => BlackjackValue::String(x),
+            SerializedBlackjackValue::Selection(x) => {
+                let expr = SelectionExpression::parse(&x).ok();
+                BlackjackValue::Selection(x, expr)
+            }
+            SerializedBlackjackValue::Color(x) => BlackjackValue::Color(x),
+            SerializedBlackjackValue::Path(x) => BlackjackValue::Path(x),
+            SerializedBlackjackValue::List(x) => BlackjackValue::List(
+                x.into_iter()
+                    .map(SerializedBlackjackValue::into_runtime)
+                    .collect(),
+            ),
+            SerializedBlackjackValue::None => BlackjackValue::None,
+            SerializedBlackjackValue::Unset => BlackjackValue::Unset,
         }
     }
 }
 
+impl From<&BlackjackValue> for SerializedBlackjackValue {
+    fn from(val: &BlackjackValue) -> Self {
+        match val {
+            BlackjackValue::Vector(v) => Self::Vector(*v),
+            BlackjackValue::Scalar(s) => Self::Scalar(*s),
+            BlackjackValue::Int(i) => Self::Int(*i),
+            BlackjackValue::String(s) => Self::String(s.clone()),
+            BlackjackValue::Selection(s, _) => Self::Selection(s.clone()),
+            BlackjackValue::Color(c) => Self::Color(*c),
+            BlackjackValue::Path(p) => Self::Path(p.clone()),
+            BlackjackValue::List(items) => Self::List(items.iter().map(Self::from).collect()),
+            BlackjackValue::None => Self::None,
+            BlackjackValue::Unset => Self::Unset,
+        }
+    }
+}
+
+/// Values are serialized using the same representation as in graph files. For
+/// selections, only the selection string is stored, and the expression is
+/// parsed again when deserializing.
+impl Serialize for BlackjackValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedBlackjackValue::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlackjackValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(SerializedBlackjackValue::deserialize(deserializer)?.into_runtime())
+    }
+}
+
+/// External parameter values are serialized as a list of `(parameter, value)`
+/// pairs, sorted by parameter. Unlike a map, this can be represented in
+/// formats that only support string keys, like JSON.
+///
+/// Node ids are serialized as-is, so the values can only be restored for the
+/// same graph, with the same slotmap keys. Graph files use `IdMappings`
+/// instead.
+impl Serialize for ExternalParameterValues {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().sorted_by(|(a, _), (b, _)| {
+            (a.node_id, &a.param_name).cmp(&(b.node_id, &b.param_name))
+        }))
+    }
+}
+
+impl<'de> Deserialize<'de> for ExternalParameterValues {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<(ExternalParameter, BlackjackValue)>::deserialize(deserializer)?;
+        Ok(ExternalParameterValues(values.into_iter().collect()))
+    }
+}
+
 impl SerializedBjkNode {
     fn from_runtime_data(node: &BjkNode, mappings: &IdMappings) -> Result<Self> {
         let BjkNode {
@@ -284,6 +481,7 @@ impl SerializedBjkNode {
             return_value,
             inputs,
             outputs,
+            bypass,
         } = node;
 
         let inputs = inputs
@@ -300,18 +498,23 @@ impl SerializedBjkNode {
             return_value: return_value.clone(),
             inputs,
             outputs,
+            bypass: *bypass,
         })
     }
 }
 
-fn serialize_data_type(data_type: DataType) -> String {
+pub(crate) fn serialize_data_type(data_type: DataType) -> String {
     match data_type {
         super::DataType::Vector => "BJK_VECTOR",
         super::DataType::Scalar => "BJK_SCALAR",
+        super::DataType::Int => "BJK_INT",
         super::DataType::Selection => "BJK_SELECTION",
         super::DataType::Mesh => "BJK_MESH",
         super::DataType::String => "BJK_STRING",
         super::DataType::HeightMap => "BJK_HEIGHTMAP",
+        super::DataType::Color => "BJK_COLOR",
+        super::DataType::Path => "BJK_PATH",
+        super::DataType::List => "BJK_LIST",
     }
     .to_owned()
 }
@@ -349,9 +552,14 @@ impl SerializedDependencyKind {
             DependencyKind::External { promoted } => Ok(Self::External {
                 promoted: promoted.clone(),
             }),
-            DependencyKind::Connection { node, param_name } => Ok(Self::Conection {
+            DependencyKind::Connection {
+                node,
+                param_name,
+                optional,
+            } => Ok(Self::Conection {
                 node_idx: mappings.get_idx(*node)?,
                 param_name: param_name.clone(),
+                optional: *optional,
             }),
         }
     }
@@ -371,6 +579,24 @@ impl SerializedBjkGraph {
         Ok(ron::de::from_str(s)?)
     }
 
+    /// Registers the baked nodes stored in this graph in `node_definitions`,
+    /// and returns them. This needs to happen before running the graph, since
+    /// no node library defines them.
+    pub fn register_baked_nodes(
+        &self,
+        node_definitions: &NodeDefinitions,
+    ) -> Result<Vec<BakedNode>> {
+        let baked_nodes = self
+            .baked_nodes
+            .iter()
+            .map(BakedNode::from_serialized)
+            .collect::<Result<Vec<_>>>()?;
+        for baked in &baked_nodes {
+            baked.register(node_definitions);
+        }
+        Ok(baked_nodes)
+    }
+
     pub fn into_runtime(self) -> Result<(RuntimeData, Option<SerializedUiData>, IdMappings)> {
         let mut rt_nodes = SlotMap::<BjkNodeId, BjkNode>::with_key();
 
@@ -382,6 +608,7 @@ impl SerializedBjkGraph {
                 return_value: node.return_value.clone(),
                 inputs: vec![],
                 outputs: vec![],
+                bypass: node.bypass,
             });
 
             mappings.idx_to_id.push(node_id);
@@ -403,9 +630,11 @@ impl SerializedBjkGraph {
                             SerializedDependencyKind::Conection {
                                 node_idx,
                                 param_name,
+                                optional,
                             } => DependencyKind::Connection {
                                 node: mappings.idx_to_id[node_idx],
                                 param_name,
+                                optional,
                             },
                         },
                     })
@@ -444,14 +673,18 @@ impl SerializedBjkGraph {
     }
 }
 
-fn deserialize_data_type(data_type_str: &str) -> Option<DataType> {
+pub(crate) fn deserialize_data_type(data_type_str: &str) -> Option<DataType> {
     match data_type_str {
         "BJK_VECTOR" => Some(super::DataType::Vector),
         "BJK_SCALAR" => Some(super::DataType::Scalar),
+        "BJK_INT" => Some(super::DataType::Int),
         "BJK_SELECTION" => Some(super::DataType::Selection),
         "BJK_MESH" => Some(super::DataType::Mesh),
         "BJK_STRING" => Some(super::DataType::String),
         "BJK_HEIGHTMAP" => Some(super::DataType::HeightMap),
+        "BJK_COLOR" => Some(super::DataType::Color),
+        "BJK_PATH" => Some(super::DataType::Path),
+        "BJK_LIST" => Some(super::DataType::List),
         _ => None,
     }
     .to_owned()
@@ -468,15 +701,7 @@ impl SerializedExternalParameters {
                             node_id: mappings.get_id(param.node_idx)?,
                             param_name: param.param_name,
                         },
-                        match value {
-                            SerializedBlackjackValue::Vector(x) => BlackjackValue::Vector(x),
-                            SerializedBlackjackValue::Scalar(x) => BlackjackValue::Scalar(x),
-                            SerializedBlackjackValue::String(x) => BlackjackValue::String(x),
-                            SerializedBlackjackValue::Selection(x) => {
-                                let expr = SelectionExpression::parse(&x).ok();
-                                BlackjackValue::Selection(x, expr)
-                            }
-                        },
+                        value.into_runtime(),
                     ))
                 })
                 .collect::<Result<HashMap<_, _>>>()?,
@@ -519,4 +744,124 @@ mod tests {
         assert_eq!(version, new_version);
         assert_eq!(data, new_data);
     }
+
+    #[test]
+    pub fn test_schema_version() {
+        let (mut serialized, _) = SerializedBjkGraph::from_runtime(RuntimeData {
+            graph: BjkGraph::new(),
+            external_parameters: None,
+        })
+        .unwrap();
+        assert_eq!(serialized.schema_version, 0);
+        serialized.set_schema_version(3);
+        let restored =
+            SerializedBjkGraph::load_from_string(&ron::to_string(&serialized).unwrap()).unwrap();
+        assert_eq!(restored.schema_version, 3);
+
+        // Files saved before schema versions existed have version 0.
+        let old = "(nodes: [], default_node: None, ui_data: None, external_parameters: None)";
+        let restored = SerializedBjkGraph::load_from_string(old).unwrap();
+        assert_eq!(restored.schema_version, 0);
+    }
+
+    #[test]
+    pub fn test_float_roundtrip() {
+        let floats = [
+            0.1,
+            1.0 / 3.0,
+            -0.0,
+            16_777_216.0,
+            f32::MAX,
+            f32::MIN,
+            f32::MIN_POSITIVE,
+            f32::EPSILON,
+            // The smallest subnormal
+            f32::from_bits(1),
+        ];
+        for x in floats {
+            let values = [
+                BlackjackValue::Scalar(x),
+                BlackjackValue::Vector(glam::Vec3::new(x, 1.0, x)),
+                BlackjackValue::Color(glam::Vec4::splat(x)),
+            ];
+            for value in values {
+                let serialized = ron::to_string(&value).unwrap();
+                let restored: BlackjackValue = ron::from_str(&serialized).unwrap();
+                let bits = |value: &BlackjackValue| match value {
+                    BlackjackValue::Scalar(x) => vec![x.to_bits()],
+                    BlackjackValue::Vector(v) => v.to_array().map(f32::to_bits).to_vec(),
+                    BlackjackValue::Color(c) => c.to_array().map(f32::to_bits).to_vec(),
+                    _ => unreachable!(),
+                };
+                assert_eq!(bits(&value), bits(&restored), "{serialized}");
+                assert_eq!(ron::to_string(&restored).unwrap(), serialized);
+            }
+        }
+        assert_eq!(
+            ron::to_string(&BlackjackValue::Scalar(0.1)).unwrap(),
+            "Scalar(0.1)"
+        );
+    }
+
+    #[test]
+    pub fn test_external_parameter_values_roundtrip() {
+        let mut nodes = SlotMap::<BjkNodeId, ()>::with_key();
+        let a = nodes.insert(());
+        let b = nodes.insert(());
+
+        let mut values = ExternalParameterValues::default();
+        values.0.insert(
+            ExternalParameter::new(a, "size".into()),
+            BlackjackValue::Vector(glam::Vec3::new(1.0, 2.0, 3.0)),
+        );
+        values.0.insert(
+            ExternalParameter::new(a, "radius".into()),
+            BlackjackValue::Scalar(0.5),
+        );
+        // Not representable as an f32
+        values.0.insert(
+            ExternalParameter::new(a, "segments".into()),
+            BlackjackValue::Int(16_777_217),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "name".into()),
+            BlackjackValue::String("test".into()),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "faces".into()),
+            BlackjackValue::Selection("0..3".into(), SelectionExpression::parse("0..3").ok()),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "file".into()),
+            BlackjackValue::Path("assets/model.obj".into()),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "points".into()),
+            BlackjackValue::List(vec![
+                BlackjackValue::Vector(glam::Vec3::X),
+                BlackjackValue::Vector(glam::Vec3::Y),
+            ]),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "tint".into()),
+            BlackjackValue::Color(glam::Vec4::new(1.0, 0.5, 0.25, 1.0)),
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "mesh".into()),
+            BlackjackValue::None,
+        );
+        values.0.insert(
+            ExternalParameter::new(b, "offset".into()),
+            BlackjackValue::Unset,
+        );
+
+        let serialized = ron::to_string(&values).unwrap();
+        let restored: ExternalParameterValues = ron::from_str(&serialized).unwrap();
+        assert_eq!(restored.0.len(), values.0.len());
+        for (param, value) in &values.0 {
+            assert_eq!(format!("{value:?}"), format!("{:?}", restored.0[param]));
+        }
+        // The output does not depend on the map's iteration order.
+        assert_eq!(serialized, ron::to_string(&restored).unwrap());
+    }
 }
@@ -0,0 +1,115 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::BlackjackValue;
+
+/// A 64-bit FNV-1a hasher. Unlike the hashers in `std`, its output only
+/// depends on the bytes written to it, which are always written in the same
+/// way regardless of the platform.
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    pub(crate) fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub(crate) fn tag(&mut self, tag: u8) {
+        self.bytes(&[tag]);
+    }
+
+    pub(crate) fn u64(&mut self, x: u64) {
+        self.bytes(&x.to_le_bytes());
+    }
+
+    /// Strings are prefixed by their length, so consecutive strings can't be
+    /// confused with each other.
+    pub(crate) fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.bytes(s.as_bytes());
+    }
+
+    pub(crate) fn f32(&mut self, x: f32) {
+        let x = if x == 0.0 {
+            0.0
+        } else if x.is_nan() {
+            f32::NAN
+        } else {
+            x
+        };
+        self.bytes(&x.to_bits().to_le_bytes());
+    }
+
+    pub(crate) fn value(&mut self, value: &BlackjackValue) {
+        match value {
+            BlackjackValue::Vector(v) => {
+                self.tag(0);
+                for x in v.to_array() {
+                    self.f32(x);
+                }
+            }
+            BlackjackValue::Scalar(x) => {
+                self.tag(1);
+                self.f32(*x);
+            }
+            BlackjackValue::String(s) => {
+                self.tag(2);
+                self.str(s);
+            }
+            // The parsed expression is derived from the string.
+            BlackjackValue::Selection(s, _) => {
+                self.tag(3);
+                self.str(s);
+            }
+            BlackjackValue::Color(c) => {
+                self.tag(4);
+                for x in c.to_array() {
+                    self.f32(x);
+                }
+            }
+            BlackjackValue::Path(p) => {
+                self.tag(5);
+                self.str(&p.to_string_lossy());
+            }
+            BlackjackValue::List(items) => {
+                self.tag(6);
+                self.u64(items.len() as u64);
+                for item in items {
+                    self.value(item);
+                }
+            }
+            BlackjackValue::None => self.tag(7),
+            BlackjackValue::Int(i) => {
+                self.tag(8);
+                self.u64(*i as u64);
+            }
+            BlackjackValue::Unset => self.tag(9),
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_fnv1a() {
+        assert_eq!(StableHasher::new().finish(), 0xcbf29ce484222325);
+        let mut hasher = StableHasher::new();
+        hasher.bytes(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -4,34 +4,86 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use mlua::{Table, ToLua};
-use slotmap::SecondaryMap;
+use std::cell::{Cell, RefCell};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::gizmos::BlackjackGizmo;
-use crate::graph::{BjkGraph, BjkNodeId, BlackjackValue, NodeDefinitions};
-use crate::lua_engine::{ProgramResult, RenderableThing};
+use mlua::{FromLua, IntoLua, Table};
+use serde::{Deserialize, Serialize};
+use slotmap::{Key, SecondaryMap};
+
+use crate::gizmos::{BlackjackGizmo, GizmoId, GizmoKey};
+use crate::graph::{
+    BjkGraph, BjkNode, BjkNodeId, BlackjackValue, DataType, DependencyKind, InputDefinition,
+    NodeDefinition, NodeDefinitions, StableHasher,
+};
+use crate::lua_engine::{LazyRenderable, ProgramResult, RenderableRegistry, RenderableThing};
 use crate::prelude::*;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct ExternalParameter {
-    pub node_id: BjkNodeId,
-    pub param_name: String,
-}
+/// A persistent interpreter cache, to support incremental evaluation.
+mod cached_interpreter;
+pub use cached_interpreter::*;
 
-impl ExternalParameter {
-    pub fn new(node_id: BjkNodeId, param_name: String) -> Self {
-        Self {
-            node_id,
-            param_name,
-        }
-    }
-}
+/// Checks graphs for problems before running them.
+mod check_graph;
+pub use check_graph::*;
+
+/// Evaluating independent parts of a graph on several threads.
+mod parallel;
+pub use parallel::*;
+
+/// A common interface for graph evaluators, and a mock one for tests.
+mod evaluator;
+pub use evaluator::*;
+
+/// Replacing parts of a graph with nodes replaying their outputs.
+mod bake;
+pub use bake::*;
+
+/// Using whole graphs as nodes inside other graphs.
+mod subgraph;
+pub use subgraph::*;
+
+/// External parameters, and the values given to them by the host.
+mod params;
+pub use params::*;
+
+/// Optional settings for a graph run.
+mod options;
+pub use options::*;
 
-#[derive(Debug, Default, Clone)]
-pub struct ExternalParameterValues(pub HashMap<ExternalParameter, BlackjackValue>);
+/// Limits on the work a run can perform, and ways to stop it early.
+mod limits;
+pub use limits::*;
+
+/// The errors and warnings produced by a run.
+mod errors;
+pub use errors::*;
+
+/// Lua globals that are only set for the duration of a run.
+mod lua_globals;
+pub use lua_globals::*;
+
+/// Walking the dependencies of the nodes in a graph.
+mod traversal;
+pub use traversal::*;
+
+/// Evaluating a single node, once its dependencies have been evaluated.
+mod node_eval;
+use node_eval::*;
+
+/// Shared node library and graph builders for the tests of this module.
+#[cfg(test)]
+mod test_utils;
 
 pub struct InterpreterContext<'a, 'lua> {
-    outputs_cache: HashMap<BjkNodeId, mlua::Table<'lua>>,
+    /// The outputs of every node that has already been evaluated. Nodes
+    /// present in this map are not evaluated again.
+    outputs_cache: &'a mut HashMap<BjkNodeId, mlua::Table<'lua>>,
     /// The values for all the external parameters. Mutable reference because
     /// node gizmos may modify these values.
     external_param_values: &'a mut ExternalParameterValues,
@@ -40,301 +92,2058 @@ pub struct InterpreterContext<'a, 'lua> {
     gizmo_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
     /// Stores the gizmo outputs for each node. This is not filled if
     /// gizmo_state is None.
-    gizmo_outputs: &'a mut SecondaryMap<BjkNodeId, Vec<BlackjackGizmo>>,
+    gizmo_outputs: &'a mut SecondaryMap<BjkNodeId, Vec<(GizmoId, BlackjackGizmo)>>,
+    /// The node library tables for each op name, fetched on first use. The
+    /// cache only lives for a single run, so runs following a reload of the
+    /// node definitions will always see the new tables.
+    node_tables: HashMap<String, mlua::Table<'lua>>,
+    /// Whether missing external parameters should take their default value.
+    use_default_params: bool,
+    /// Whether missing external parameters that are not promoted should take
+    /// their default value.
+    default_internal_params: bool,
+    /// The time spent in the `op` function of each evaluated node. Only
+    /// present when profiling.
+    node_timings: Option<HashMap<BjkNodeId, Duration>>,
+    /// The external parameters whose value was modified by a gizmo.
+    changed_params: Vec<ExternalParameter>,
+    /// When present, node outputs are memoized by the contents of their
+    /// inputs. See `CachedInterpreter::set_memoize`.
+    content_cache: Option<&'a mut ContentCache<'lua>>,
+    /// See `EvaluationOptions::project_root`.
+    project_root: Option<&'a Path>,
+    /// The warnings emitted during the run. Only present when requested via
+    /// `RunDiagnostics::collect_warnings`.
+    warnings: Option<Vec<Warning>>,
+    /// See `RunLimits::cancel`.
+    cancel: Option<&'a CancelToken>,
+    /// See `EvaluationOptions::inject_node_id`.
+    inject_node_id: bool,
+    /// See `EvaluationOptions::non_finite_outputs`.
+    non_finite_outputs: Option<NonFinitePolicy>,
+    /// A hash of the options nodes can produce different outputs for:
+    /// `EvaluationOptions::context`, `seed` and `project_root`. Memoized
+    /// outputs are only reused for the same options.
+    options_hash: u64,
+    /// The keys of the output table of each evaluated node. Only present when
+    /// requested via `RunDiagnostics::record_output_keys`.
+    output_keys: Option<HashMap<BjkNodeId, Vec<String>>>,
+    /// See `EvaluationOptions::linked_params`.
+    linked_params: Option<&'a LinkedParams>,
+    /// The nodes that failed during the run, and were replaced by empty
+    /// placeholder outputs. Optional connections from these nodes take the
+    /// default value of their input.
+    failed_nodes: HashSet<BjkNodeId>,
+    /// The nodes whose outputs were taken from the content cache instead of
+    /// running their `op`.
+    memoized_nodes: HashSet<BjkNodeId>,
 }
 
+impl<'lua> InterpreterContext<'_, 'lua> {
+    /// Returns the node library's table for the node with `op_name`.
+    fn node_table(&mut self, lua: &'lua mlua::Lua, op_name: &str) -> Result<mlua::Table<'lua>> {
+        if let Some(table) = self.node_tables.get(op_name) {
+            return Ok(table.clone());
+        }
+        let table = lua
+            .load(format!("require('node_library'):getNode('{op_name}')"))
+            .eval::<mlua::Table>()?;
+        self.node_tables.insert(op_name.to_owned(), table.clone());
+        Ok(table)
+    }
+
+    /// Fails with `Cancelled` when the run was cancelled while evaluating
+    /// `node_id`. This is checked between the phases of a node's evaluation,
+    /// in addition to the checks made while Lua code is running.
+    fn check_cancelled(&self, node_id: BjkNodeId) -> Result<()> {
+        if self.cancel.map_or(false, |c| c.is_cancelled()) {
+            Err(Cancelled {
+                progress: RunProgress {
+                    node_id: Some(node_id),
+                    ..Default::default()
+                },
+            }
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the value of an external parameter. Linked parameters are
+    /// resolved through their shared slot first, see `LinkedParams`.
+    fn param_value(&self, param: &ExternalParameter) -> Result<Option<&BlackjackValue>> {
+        if let Some(linked) = self.linked_params {
+            if let Some(slot) = linked.links.get(param) {
+                let value = linked.shared.get(slot).ok_or_else(|| {
+                    anyhow!(
+                        "Parameter {}.{} is linked to the shared value '{slot}', which is not set",
+                        param.node_id.display_id(),
+                        param.param_name
+                    )
+                })?;
+                return Ok(Some(value));
+            }
+        }
+        Ok(self.external_param_values.0.get(param))
+    }
+
+    /// Sets a new value for an external parameter modified by a gizmo, and
+    /// records it as changed when it's different from the old one.
+    fn update_param(&mut self, param: &ExternalParameter, new_val: BlackjackValue) {
+        let old_val = self.external_param_values.0.get(param);
+        if old_val != Some(&new_val) {
+            self.external_param_values.0.insert(param.clone(), new_val);
+            if !self.changed_params.contains(param) {
+                self.changed_params.push(param.clone());
+            }
+        }
+    }
+
+    /// Emits a warning for `node_id`. The message is only built when warnings
+    /// are being collected.
+    fn warn(&mut self, node_id: BjkNodeId, message: impl FnOnce() -> String) {
+        if let Some(warnings) = &mut self.warnings {
+            warnings.push(Warning {
+                node_id,
+                message: message(),
+            });
+        }
+    }
+}
+
+/// Counts of the work done by a run. See `ProgramResult::stats`. Useful to
+/// check that incremental evaluation only runs the nodes affected by a
+/// change.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvaluationStats {
+    /// The nodes that were evaluated, including the ones that failed.
+    pub nodes_executed: usize,
+    /// The nodes whose outputs were needed, but were reused instead of
+    /// evaluating the node. This counts the outputs kept by a
+    /// `CachedInterpreter`, the ones memoized by their contents, and
+    /// overrides.
+    pub nodes_cached: usize,
+    /// The gizmos produced by the evaluated nodes.
+    pub gizmos_run: usize,
+}
+
+/// The gizmo state of a node, sent by the host to the interpreter.
+///
+/// Gizmos always run in the 'out' direction, producing the gizmos for the next
+/// frame. They only run in the 'in' direction, updating the node's parameters,
+/// when `gizmos_changed` is set and `active_gizmos` is present. When gizmos
+/// were marked as changed but no values are provided, the change is ignored,
+/// and a warning is emitted if warnings are being collected.
 #[derive(Clone, Debug, Default)]
 pub struct GizmoState {
+    /// The current value of each of the node's gizmos, in the same order as
+    /// they were returned by the previous run.
     pub active_gizmos: Option<Vec<BlackjackGizmo>>,
+    /// Whether the user interacted with the gizmos since the previous run.
     pub gizmos_changed: bool,
 }
 
-pub fn run_graph<'lua>(
+/// How far a run got before it was stopped by a `Cancelled` or
+/// `BudgetExceeded` error. Useful to find the nodes that are too slow, or to
+/// resume the run later.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunProgress {
+    /// The node that was being evaluated when the run stopped. `None` when
+    /// the run stopped between two nodes.
+    pub node_id: Option<BjkNodeId>,
+    /// The last node that was evaluated successfully, if any.
+    pub last_completed: Option<BjkNodeId>,
+    /// The number of nodes this run finished evaluating, including the ones
+    /// that failed under `ErrorPolicy::ContinueWithPlaceholder`. Nodes that
+    /// were already cached don't count.
+    pub nodes_completed: usize,
+    /// The wall-clock time since the run started.
+    pub elapsed: Duration,
+    /// The number of steps the Lua VM ran. Steps are only counted when the
+    /// run has a timeout, a step limit or a `CancelToken`, and are zero
+    /// otherwise.
+    pub steps: u64,
+}
+
+impl std::fmt::Display for RunProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.node_id {
+            Some(node_id) => write!(f, "while evaluating node {}", node_id.display_id())?,
+            None => write!(f, "between nodes")?,
+        }
+        write!(
+            f,
+            ", after {} nodes, {} steps and {:?}",
+            self.nodes_completed, self.steps, self.elapsed
+        )
+    }
+}
+
+/// Runs the graph, evaluating `target_node` and all of its dependencies, and
+/// returns the renderable produced by the target node's return value.
+///
+/// The target node is evaluated even when it has no return value, so there is
+/// no renderable: Executable nodes, like exporters, are run this way for their
+/// side effects.
+pub fn run_graph(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+) -> Result<ProgramResult> {
+    run_graph_with_options(
+        lua,
+        graph,
+        target_node,
+        external_param_values,
+        node_definitions,
+        gizmos_state,
+        RunGraphOptions::default(),
+    )
+}
+
+/// Like `run_graph`, but allows tweaking the run with some additional
+/// `options`.
+pub fn run_graph_with_options(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    options: RunGraphOptions<'_>,
+) -> Result<ProgramResult> {
+    let mut results = run_graph_impl(
+        lua,
+        graph,
+        &[target_node],
+        RunInputs {
+            external_param_values,
+            gizmos_state,
+        },
+        node_definitions,
+        options,
+        RunCaches {
+            outputs: &mut HashMap::new(),
+            content: None,
+            keep_outputs: false,
+        },
+    )?;
+    Ok(results.remove(0))
+}
+
+/// Like `run_graph_with_options`, but when the run fails, the outputs of the
+/// nodes evaluated so far are returned along with the error. Errors found
+/// before evaluating anything, like cycles, come with no partial outputs.
+pub fn run_graph_partial(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    options: RunGraphOptions<'_>,
+) -> std::result::Result<ProgramResult, PartialRunError> {
+    let renderables = options.renderables;
+    let mut outputs_cache = HashMap::new();
+    match run_graph_impl(
+        lua,
+        graph,
+        &[target_node],
+        RunInputs {
+            external_param_values,
+            gizmos_state,
+        },
+        node_definitions,
+        options,
+        RunCaches {
+            outputs: &mut outputs_cache,
+            content: None,
+            keep_outputs: false,
+        },
+    ) {
+        Ok(mut results) => Ok(results.remove(0)),
+        Err(error) => {
+            let evaluated = outputs_cache.keys().copied().collect();
+            Err(PartialRunError {
+                partial: collect_renderables(graph, &outputs_cache, &evaluated, renderables),
+                error,
+            })
+        }
+    }
+}
+
+/// Like `run_graph_with_options`, but the nodes in `overrides` are not
+/// evaluated. Instead, the given table is used as their outputs, so their
+/// dependencies are not evaluated either, unless other nodes need them. This
+/// can be used to test a node in isolation, or to freeze the result of an
+/// expensive part of the graph.
+///
+/// The override tables must have the same shape as the tables returned by the
+/// nodes' `op`, with a value for every output used by other nodes.
+#[allow(clippy::too_many_arguments)]
+pub fn run_graph_with_overrides<'lua>(
     lua: &'lua mlua::Lua,
     graph: &BjkGraph,
     target_node: BjkNodeId,
-    mut external_param_values: ExternalParameterValues,
+    external_param_values: ExternalParameterValues,
     node_definitions: &NodeDefinitions,
     gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    options: RunGraphOptions<'_>,
+    mut overrides: HashMap<BjkNodeId, mlua::Table<'lua>>,
 ) -> Result<ProgramResult> {
+    let mut results = run_graph_impl(
+        lua,
+        graph,
+        &[target_node],
+        RunInputs {
+            external_param_values,
+            gizmos_state,
+        },
+        node_definitions,
+        options,
+        RunCaches {
+            outputs: &mut overrides,
+            content: None,
+            // The override tables belong to the caller, so the renderable is
+            // copied out of them instead of taken.
+            keep_outputs: true,
+        },
+    )?;
+    Ok(results.remove(0))
+}
+
+/// Runs the graph for several target nodes at once, returning one result per
+/// target, in the same order. Nodes shared by more than one target are only
+/// evaluated once.
+///
+/// Each result only contains the gizmos and intermediate outputs of the nodes
+/// its target depends on. The updated external parameters are the same for
+/// all the results, since gizmos may modify parameters of any node.
+pub fn run_graph_multi(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    targets: &[BjkNodeId],
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    options: RunGraphOptions<'_>,
+) -> Result<Vec<ProgramResult>> {
+    run_graph_impl(
+        lua,
+        graph,
+        targets,
+        RunInputs {
+            external_param_values,
+            gizmos_state,
+        },
+        node_definitions,
+        options,
+        RunCaches {
+            outputs: &mut HashMap::new(),
+            content: None,
+            keep_outputs: false,
+        },
+    )
+}
+
+/// The values a graph is run with. See `run_graph_impl`.
+struct RunInputs {
+    external_param_values: ExternalParameterValues,
+    gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+}
+
+/// Where a run looks for the outputs of nodes evaluated before, and stores
+/// the outputs of the nodes it evaluates. See `run_graph_impl`.
+struct RunCaches<'c, 'lua> {
+    /// The outputs of each node. Nodes already present here are not evaluated
+    /// again.
+    outputs: &'c mut HashMap<BjkNodeId, mlua::Table<'lua>>,
+    /// The outputs of pure nodes, by the contents of their inputs. See
+    /// `CachedInterpreter::set_memoize`.
+    content: Option<&'c mut ContentCache<'lua>>,
+    /// When set, the renderables are copied out of the cached outputs instead
+    /// of being taken, so they remain valid for future runs.
+    keep_outputs: bool,
+}
+
+/// Shared implementation for all the graph running functions. Returns one
+/// result per target.
+fn run_graph_impl<'lua>(
+    lua: &'lua mlua::Lua,
+    graph: &BjkGraph,
+    targets: &[BjkNodeId],
+    inputs: RunInputs,
+    node_definitions: &NodeDefinitions,
+    mut options: RunGraphOptions<'_>,
+    caches: RunCaches<'_, 'lua>,
+) -> Result<Vec<ProgramResult>> {
+    let RunInputs {
+        mut external_param_values,
+        mut gizmos_state,
+    } = inputs;
+    let RunCaches {
+        outputs: outputs_cache,
+        content: content_cache,
+        keep_outputs,
+    } = caches;
+
+    // The nodes in a cycle can't be evaluated in any order, so we check for
+    // them before evaluating anything.
+    for target_node in targets {
+        if let Some(cycle) = find_cycle(graph, *target_node) {
+            return Err(InterpreterError::Cycle(cycle).into());
+        }
+    }
+
+    if let Some(max_depth) = options.limits.max_depth {
+        for target_node in targets {
+            let depth = dependency_depth(graph, *target_node);
+            if depth > max_depth {
+                return Err(InterpreterError::DepthExceeded {
+                    node_id: *target_node,
+                    depth,
+                    max_depth,
+                }
+                .into());
+            }
+        }
+    }
+
+    if options.evaluation.validate_params {
+        external_param_values.validate(graph, node_definitions)?;
+    }
+
+    if let (Some(gizmos_state), Some(gizmo_nodes)) =
+        (&mut gizmos_state, &options.evaluation.gizmo_nodes)
+    {
+        gizmos_state.retain(|node_id, _| gizmo_nodes.contains(&node_id));
+    }
+
     let gizmos_enabled = gizmos_state.is_some();
+    let memory_before = lua.used_memory();
+    let run_start = Instant::now();
+    let interrupt_guard = options
+        .limits
+        .budget
+        .install(lua, options.limits.cancel.clone());
+    let _context_global = options
+        .evaluation
+        .context
+        .map(|values| ContextGlobal::install(lua, values))
+        .transpose()?;
+    let options_hash = {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        options.evaluation.context.is_some().hash(&mut hasher);
+        if let Some(values) = options.evaluation.context {
+            for (key, value) in values.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+                key.hash(&mut hasher);
+                hash_value(value, &mut hasher);
+            }
+        }
+        options.evaluation.seed.hash(&mut hasher);
+        options.evaluation.project_root.hash(&mut hasher);
+        hasher.finish()
+    };
+    let print_capture = options
+        .diagnostics
+        .capture_print
+        .then(|| PrintCapture::install(lua))
+        .transpose()?;
 
-    let mut gizmo_outputs = Default::default();
+    let mut gizmo_outputs = SecondaryMap::new();
     let mut context = InterpreterContext {
-        outputs_cache: Default::default(),
+        outputs_cache: &mut *outputs_cache,
         external_param_values: &mut external_param_values,
         node_definitions,
         gizmo_state: gizmos_state,
         gizmo_outputs: &mut gizmo_outputs,
+        node_tables: Default::default(),
+        use_default_params: options.evaluation.use_default_params,
+        default_internal_params: options.evaluation.default_internal_params,
+        node_timings: options.diagnostics.profile.then(HashMap::new),
+        changed_params: Vec::new(),
+        content_cache,
+        project_root: options.evaluation.project_root.as_deref(),
+        warnings: options.diagnostics.collect_warnings.then(Vec::new),
+        cancel: options.limits.cancel.as_ref(),
+        inject_node_id: options.evaluation.inject_node_id,
+        non_finite_outputs: options.evaluation.non_finite_outputs,
+        options_hash,
+        output_keys: options.diagnostics.record_output_keys.then(HashMap::new),
+        linked_params: options.evaluation.linked_params,
+        failed_nodes: HashSet::new(),
+        memoized_nodes: HashSet::new(),
     };
 
-    // Ensure the outputs cache is populated.
-    run_node(lua, graph, &mut context, target_node)?;
-
-    let renderable = if let Some(return_value) = &graph.nodes[target_node].return_value {
-        let output = context
-            .outputs_cache
-            .get(&target_node)
-            .expect("Final node should be in the outputs cache");
-        Some(RenderableThing::from_lua_value(
-            output.get(return_value.as_str())?,
-        )?)
-    } else {
-        None
+    // Ensure the outputs cache is populated. The evaluation order is computed
+    // for all the targets upfront, so progress can be reported.
+    let mut order = Vec::new();
+    let mut scheduled = HashSet::new();
+    for target_node in targets {
+        if !context.outputs_cache.contains_key(target_node) {
+            let target_order = evaluation_order(graph, *target_node, |dep| {
+                context.outputs_cache.contains_key(&dep)
+            });
+            order.extend(
+                target_order
+                    .into_iter()
+                    .filter(|node_id| scheduled.insert(*node_id)),
+            );
+        }
+    }
+    // The cached nodes whose outputs are used in this run. These are the
+    // cached targets, and the cached dependencies of the evaluated nodes.
+    let mut cache_hits: HashSet<BjkNodeId> = targets
+        .iter()
+        .filter(|target| context.outputs_cache.contains_key(target))
+        .copied()
+        .collect();
+    for node_id in &order {
+        for input in &graph.nodes[*node_id].inputs {
+            if let DependencyKind::Connection { node: dep, .. } = &input.kind {
+                if !scheduled.contains(dep) {
+                    cache_hits.insert(*dep);
+                }
+            }
+        }
+    }
+    let is_cancelled = || {
+        options
+            .limits
+            .cancel
+            .as_ref()
+            .map_or(false, |c| c.is_cancelled())
     };
-
-    Ok(ProgramResult {
-        renderable,
-        updated_gizmos: if gizmos_enabled {
-            Some(gizmo_outputs)
+    let mut last_completed = None;
+    let progress = |node_id, last_completed, nodes_completed| RunProgress {
+        node_id,
+        last_completed,
+        nodes_completed,
+        elapsed: run_start.elapsed(),
+        steps: interrupt_guard
+            .as_ref()
+            .map_or(0, |guard| guard.state.steps.get()),
+    };
+    let mut failures = Vec::new();
+    for (i, node_id) in order.iter().enumerate() {
+        if is_cancelled() {
+            return Err(Cancelled {
+                progress: progress(None, last_completed, i),
+            }
+            .into());
+        }
+        if let Some(on_progress) = &mut options.diagnostics.on_progress {
+            on_progress(*node_id, i, order.len());
+        }
+        if let Some(print_capture) = &print_capture {
+            print_capture.current_node.set(Some(*node_id));
+        }
+        if let Some(seed) = options.evaluation.seed {
+            seed_random(lua, seed, *node_id)?;
+        }
+        if let Err(err) = eval_node(lua, graph, &mut context, *node_id) {
+            // The interrupt makes the Lua code fail, so the error is replaced
+            // to make the cancellation distinguishable.
+            if is_cancelled() {
+                return Err(Cancelled {
+                    progress: progress(Some(*node_id), last_completed, i),
+                }
+                .into());
+            }
+            let exceeded = interrupt_guard
+                .as_ref()
+                .and_then(|guard| guard.state.exceeded.get());
+            if let Some(limit) = exceeded {
+                return Err(BudgetExceeded {
+                    limit,
+                    progress: progress(Some(*node_id), last_completed, i),
+                }
+                .into());
+            }
+            // Failures of nodes only used through optional connections are
+            // never fatal, their dependents take default values instead.
+            let tolerated =
+                !targets.contains(node_id) && only_optional_dependents(graph, &order, *node_id);
+            if options.evaluation.error_policy == ErrorPolicy::Abort && !tolerated {
+                return Err(err);
+            }
+            if tolerated {
+                context.warn(*node_id, || {
+                    format!("Node failed, its dependents use default values: {err:#}")
+                });
+            }
+            failures.push(NodeFailure {
+                node_id: *node_id,
+                message: format!("{err:#}"),
+            });
+            context.failed_nodes.insert(*node_id);
+            context.outputs_cache.insert(*node_id, lua.create_table()?);
         } else {
-            None
-        },
-        updated_values: external_param_values,
-    })
+            last_completed = Some(*node_id);
+        }
+    }
+    let node_timings = context.node_timings;
+    let changed_params = context.changed_params;
+    let warnings = context.warnings;
+    let output_keys = context.output_keys;
+    let memoized_nodes = context.memoized_nodes;
+    let printed = print_capture.map(|capture| capture.lines.take());
+    let memory_usage = options.diagnostics.report_memory.then(|| MemoryUsage {
+        before: memory_before,
+        after: lua.used_memory(),
+    });
+
+    // The same renderable can't be taken out of the cache twice.
+    let keep_outputs = keep_outputs || targets.len() > 1;
+
+    let results = targets
+        .iter()
+        .map(|target_node| -> Result<ProgramResult> {
+            let dependencies = dependency_set(graph, *target_node);
+
+            // NOTE: This needs to happen before the target's renderable is
+            // taken out of the outputs cache below.
+            let all_outputs = options.diagnostics.collect_all_outputs.then(|| {
+                collect_renderables(graph, outputs_cache, &dependencies, options.renderables)
+            });
+
+            // NOTE: The target is always evaluated, with or without a return
+            // value, so its outputs should always be in the cache at this
+            // point. Still, a bug here shouldn't crash the host.
+            let output = outputs_cache.get(target_node).ok_or_else(|| {
+                anyhow!(
+                    "The outputs of the target node {target_node:?} are missing after running the graph"
+                )
+            })?;
+            let output_names = table_keys(output)?;
+
+            let target_failed = failures.iter().any(|f| f.node_id == *target_node);
+            let return_value = graph.nodes[*target_node].return_value.clone();
+            let (renderable, lazy_renderable) = match &return_value {
+                // The placeholder outputs of a failed node have no renderable.
+                Some(_) if target_failed => (None, None),
+                Some(return_value) if options.lazy_renderable => {
+                    let value = output.get(return_value.as_str())?;
+                    (None, Some(LazyRenderable::new(lua, value, keep_outputs)?))
+                }
+                Some(return_value) => {
+                    let value = output.get(return_value.as_str())?;
+                    let renderable =
+                        renderable_from_value(value, options.renderables, keep_outputs)?;
+                    (Some(renderable), None)
+                }
+                None => (None, None),
+            };
+
+            let (updated_gizmos, gizmo_ids) = if gizmos_enabled {
+                let mut updated_gizmos = SecondaryMap::new();
+                let mut gizmo_ids = SecondaryMap::new();
+                for (node_id, gizmos) in gizmo_outputs
+                    .iter()
+                    .filter(|(node_id, _)| dependencies.contains(node_id))
+                {
+                    let (ids, gizmos): (Vec<_>, Vec<_>) = gizmos.iter().cloned().unzip();
+                    updated_gizmos.insert(node_id, gizmos);
+                    gizmo_ids.insert(node_id, ids);
+                }
+                (Some(updated_gizmos), Some(gizmo_ids))
+            } else {
+                (None, None)
+            };
+
+            let node_timings = node_timings.as_ref().map(|timings| {
+                timings
+                    .iter()
+                    .filter(|(node_id, _)| dependencies.contains(node_id))
+                    .map(|(node_id, time)| (*node_id, *time))
+                    .collect()
+            });
+
+            let changed_params = changed_params
+                .iter()
+                .filter(|param| dependencies.contains(&param.node_id))
+                .cloned()
+                .collect();
+
+            let warnings = warnings.as_ref().map(|warnings| {
+                warnings
+                    .iter()
+                    .filter(|warning| dependencies.contains(&warning.node_id))
+                    .cloned()
+                    .collect()
+            });
+
+            let printed = printed.as_ref().map(|printed| {
+                printed
+                    .iter()
+                    .filter(|line| dependencies.contains(&line.node_id))
+                    .cloned()
+                    .collect()
+            });
+
+            let output_keys = output_keys.as_ref().map(|output_keys| {
+                output_keys
+                    .iter()
+                    .filter(|(node_id, _)| dependencies.contains(node_id))
+                    .map(|(node_id, keys)| (*node_id, keys.clone()))
+                    .collect()
+            });
+
+            let stats = EvaluationStats {
+                nodes_executed: order
+                    .iter()
+                    .filter(|node_id| {
+                        dependencies.contains(node_id) && !memoized_nodes.contains(node_id)
+                    })
+                    .count(),
+                nodes_cached: cache_hits
+                    .iter()
+                    .chain(&memoized_nodes)
+                    .filter(|node_id| dependencies.contains(node_id))
+                    .count(),
+                gizmos_run: gizmo_outputs
+                    .iter()
+                    .filter(|(node_id, _)| dependencies.contains(node_id))
+                    .map(|(_, gizmos)| gizmos.len())
+                    .sum(),
+            };
+
+            let failed_nodes = (options.evaluation.error_policy == ErrorPolicy::ContinueWithPlaceholder)
+                .then(|| {
+                    failures
+                        .iter()
+                        .filter(|failure| dependencies.contains(&failure.node_id))
+                        .cloned()
+                        .collect()
+                });
+
+            Ok(ProgramResult {
+                renderable,
+                lazy_renderable,
+                return_value,
+                output_names,
+                updated_gizmos,
+                gizmo_ids,
+                updated_values: external_param_values.clone(),
+                changed_params,
+                all_outputs,
+                node_timings,
+                warnings,
+                printed,
+                memory_usage,
+                failed_nodes,
+                output_keys,
+                stats,
+            })
+        })
+        .collect();
+
+    // The placeholders are not kept, so failed nodes are evaluated again on
+    // the next run of a `CachedInterpreter`.
+    for failure in &failures {
+        outputs_cache.remove(&failure.node_id);
+    }
+    results
+}
+
+/// Converts the cached outputs of the given `nodes` into renderables. For each
+/// node, its return value is used or, if it has none, its first output with a
+/// renderable data type. Nodes without a renderable output are skipped.
+fn collect_renderables(
+    graph: &BjkGraph,
+    outputs_cache: &HashMap<BjkNodeId, mlua::Table<'_>>,
+    nodes: &HashSet<BjkNodeId>,
+    renderables: Option<&RenderableRegistry>,
+) -> HashMap<BjkNodeId, RenderableThing> {
+    nodes
+        .iter()
+        .filter_map(|node_id| {
+            let outputs = outputs_cache.get(node_id)?;
+            let node = graph.nodes.get(*node_id)?;
+            let param_name = node.return_value.as_ref().or_else(|| {
+                node.outputs
+                    .iter()
+                    .find(|output| output.data_type.can_be_enabled())
+                    .map(|output| &output.name)
+            })?;
+            let value = outputs.get::<_, mlua::Value>(param_name.as_str()).ok()?;
+            let renderable = renderable_from_value(value, renderables, true).ok()?;
+            Some((*node_id, renderable))
+        })
+        .collect()
 }
 
+/// Converts a node output into a renderable. Custom renderables from the
+/// registry, if any, take precedence. When `cloned` is set, the value can
+/// still be used after the conversion.
+pub(crate) fn renderable_from_value(
+    value: mlua::Value<'_>,
+    renderables: Option<&RenderableRegistry>,
+    cloned: bool,
+) -> Result<RenderableThing> {
+    if let Some(custom) = renderables
+        .map(|registry| registry.convert(&value))
+        .transpose()?
+        .flatten()
+    {
+        Ok(custom)
+    } else if cloned {
+        RenderableThing::from_lua_value_cloned(&value)
+    } else {
+        RenderableThing::from_lua_value(value)
+    }
+}
+
+/// Evaluates the node with `node_id` and stores its outputs in the context's
+/// outputs cache. Any dependencies that are not already cached are evaluated
+/// first.
+///
+/// Dependencies are traversed using an explicit stack instead of recursion, so
+/// long chains of nodes can't overflow the native stack.
 pub fn run_node<'lua>(
     lua: &'lua mlua::Lua,
     graph: &BjkGraph,
     ctx: &mut InterpreterContext<'_, 'lua>,
     node_id: BjkNodeId,
 ) -> Result<()> {
-    let node = &graph.nodes[node_id];
-    let op_name = &node.op_name;
-    let node_def = ctx
-        .node_definitions
-        .node_def(op_name)
-        .ok_or_else(|| anyhow!("Node definition not found for {op_name}"))?;
-
-    // Stores the arguments that will be sent to this node's `op` fn
-    let mut input_map = lua.create_table()?;
-
-    // Used to allow the gizmo input function to update a node's parameters.
-    // This is None when gizmos don't run to optimize performance
-    let mut referenced_external_params = if ctx.gizmo_state.is_some() {
-        Some(Vec::<ExternalParameter>::new())
-    } else {
-        None
-    };
+    let order = evaluation_order(graph, node_id, |dep| ctx.outputs_cache.contains_key(&dep));
+    for node_id in order {
+        eval_node(lua, graph, ctx, node_id)?;
+    }
+    Ok(())
+}
 
-    // Compute the values for dependent nodes and populate the output cache.
-    for input in &node.inputs {
-        match &input.kind {
-            crate::graph::DependencyKind::Connection { node, param_name } => {
-                // Make sure the value is there by running the node.
-                let cached_output_map = if let Some(cached) = ctx.outputs_cache.get(node) {
-                    cached
-                } else {
-                    run_node(lua, graph, ctx, *node)?;
-                    ctx.outputs_cache
-                        .get(node)
-                        .expect("Cache should be populated after calling run_node.")
-                };
-
-                input_map.set(
-                    input.name.as_str(),
-                    cached_output_map.get::<_, mlua::Value>(param_name.as_str())?,
-                )?;
-            }
-            crate::graph::DependencyKind::External { promoted: _ } => {
-                let ext = ExternalParameter::new(node_id, input.name.clone());
-                let val = ctx.external_param_values.0.get(&ext).ok_or_else(|| {
-                    anyhow!(
-                        "Could not retrieve external parameter named '{}' from node {}",
-                        &input.name,
-                        node_id.display_id(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_memoization() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, mut params) = counter_chain(3);
+        let param = ExternalParameter::new(nodes[0], "x".into());
+        let mut interpreter = CachedInterpreter::new();
+        interpreter.set_memoize(true);
+        macro_rules! run {
+            () => {
+                interpreter
+                    .run(
+                        &lua,
+                        &graph,
+                        nodes[2],
+                        params.clone(),
+                        &node_definitions,
+                        None,
                     )
-                })?;
-                input_map.set(input.name.as_str(), val.clone().to_lua(lua)?)?;
-                if let Some(m) = &mut referenced_external_params {
-                    m.push(ext);
-                }
-            }
+                    .unwrap()
+            };
         }
+
+        run!();
+        assert_eq!(runs(&lua), 3);
+
+        // The inputs didn't change, so the memoized outputs are used.
+        interpreter.mark_node_dirty(nodes[0]);
+        run!();
+        assert_eq!(runs(&lua), 3);
+
+        params.0.insert(param.clone(), BlackjackValue::Scalar(2.0));
+        interpreter.mark_param_dirty(&param);
+        run!();
+        assert_eq!(runs(&lua), 6);
+
+        // A node whose inputs are unaffected by a change can reuse its outputs,
+        // even when the host marks it as dirty.
+        interpreter.mark_node_dirty(nodes[1]);
+        run!();
+        assert_eq!(runs(&lua), 6);
+
+        // Outputs memoized with other options are not reused.
+        let mut run_with = |seed: Option<u64>, project_root: Option<&str>| {
+            interpreter.mark_node_dirty(nodes[0]);
+            interpreter
+                .run_with_options(
+                    &lua,
+                    &graph,
+                    nodes[2],
+                    params.clone(),
+                    &node_definitions,
+                    None,
+                    RunGraphOptions {
+                        evaluation: EvaluationOptions {
+                            seed,
+                            project_root: project_root.map(PathBuf::from),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            runs(&lua)
+        };
+        assert_eq!(run_with(Some(1), None), 9);
+        assert_eq!(run_with(Some(1), None), 9);
+        assert_eq!(run_with(Some(2), None), 12);
+        assert_eq!(run_with(Some(2), Some("project")), 15);
+        assert_eq!(run_with(Some(2), Some("project")), 15);
     }
 
-    // This special value is injected into the inputs to signal nodes that the
-    // gizmos are being processed. This is useful to let nodes optimize out
-    // parts of the computation when they're running on a game engine.
-    if ctx.gizmo_state.is_some() {
-        input_map.set("__gizmos_enabled", true)?;
-    }
-
-    let node_table = lua
-        .load(&(format!("require('node_library'):getNode('{op_name}')")))
-        .eval::<mlua::Table>()?;
-
-    struct GizmoFns<'lua> {
-        update_params_fn: mlua::Function<'lua>,
-        update_gizmos_fn: mlua::Function<'lua>,
-        affected_params_fn: mlua::Function<'lua>,
-    }
-
-    struct GizmoDescriptor<'lua> {
-        gizmos_changed: bool,
-        data: Option<BlackjackGizmo>,
-        fns: GizmoFns<'lua>,
-    }
-
-    // The data for each of the input gizmos. If this is the empty vec, then gizmos are disabled.
-    let mut gizmo_descriptors: Vec<GizmoDescriptor> = (|| -> Result<_> {
-        if node_def.has_gizmo {
-            if let Some(gizmos_state) = &mut ctx.gizmo_state {
-                // NOTE: We remove the input slotmap because each node only
-                // needs the data from its own gizmos and all nodes are run
-                // exactly once.
-                if let Some(gizmo_data) = gizmos_state.remove(node_id) {
-                    let gizmos_table: mlua::Table = node_table
-                        .get("gizmos")
-                        .map_err(|err| anyhow!("Expected node to have gizmos table. {err}"))?;
-
-                    let mut gizmo_descriptors = Vec::<GizmoDescriptor>::new();
-                    for (i, gizmo_descr) in
-                        gizmos_table.sequence_values::<mlua::Table>().enumerate()
-                    {
-                        let gizmo_descr = gizmo_descr?;
-                        macro_rules! get_fn {
-                            ($name:expr) => {
-                                gizmo_descr.get($name).map_err(|err| {
-                                    anyhow!("Missing '{}' in gizmos table. {err}", $name)
-                                })?
-                            };
-                        }
-
-                        gizmo_descriptors.push(GizmoDescriptor {
-                            data: gizmo_data.active_gizmos.as_ref().map(|v| v[i].clone()),
-                            gizmos_changed: gizmo_data.gizmos_changed,
-                            fns: GizmoFns {
-                                update_params_fn: get_fn!("update_params"),
-                                update_gizmos_fn: get_fn!("update_gizmos"),
-                                affected_params_fn: get_fn!("affected_params"),
-                            },
-                        });
-                    }
-                    return Ok(gizmo_descriptors);
-                }
-            }
+    #[test]
+    pub fn test_impure_nodes() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            RUNS = 0
+            require('node_library'):addNodes({
+                Clock = {
+                    label = "Clock",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = { { name = "out", type = "scalar" } },
+                    pure = false,
+                    op = function(inputs)
+                        RUNS = RUNS + 1
+                        return { out = os.clock() }
+                    end,
+                },
+            })
+            "#,
+        );
+        assert!(!node_definitions.node_def("Clock").unwrap().pure);
+        let mut graph = BjkGraph::new();
+        let clock = graph.add_node("Clock", None);
+        graph.add_input(clock, "x", DataType::Scalar, None).unwrap();
+        graph.add_output(clock, "out", DataType::Scalar).unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(clock, "x", 0.0)
+            .build();
+
+        let mut interpreter = CachedInterpreter::new();
+        interpreter.set_memoize(true);
+        for expected_runs in 1..=2 {
+            interpreter.mark_node_dirty(clock);
+            interpreter
+                .run(&lua, &graph, clock, params.clone(), &node_definitions, None)
+                .unwrap();
+            // The inputs didn't change, but the node runs again anyway.
+            assert_eq!(runs(&lua), expected_runs);
         }
-        Ok(Vec::new())
-    })()?;
+    }
 
-    // When a gizmo affects parameters, the gizmo will be disabled if all the
-    // parameters are connected. Nodes can return nil from the `affected_params`
-    // function to disable this behavior.
-    let enabled_gizmos = gizmo_descriptors
-        .iter()
-        .map(|descr| {
-            if let Some(affected_params) = descr
-                .fns
-                .affected_params_fn
-                .call::<_, Option<Vec<String>>>(())?
-            {
-                for input in node.inputs.iter() {
-                    if affected_params.contains(&input.name) {
-                        match &input.kind {
-                            crate::graph::DependencyKind::External { .. } => return Ok(true),
-                            crate::graph::DependencyKind::Connection { .. } => {}
-                        }
-                    }
-                }
-                Ok(false)
+    #[test]
+    pub fn test_collect_all_outputs() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, params) = counter_chain(1);
+        let cube = graph.add_node("Cube", None);
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            cube,
+            params.clone(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                diagnostics: RunDiagnostics {
+                    collect_all_outputs: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(result.renderable.is_none());
+        let all_outputs = result.all_outputs.unwrap();
+        assert!(matches!(
+            all_outputs.get(&cube),
+            Some(RenderableThing::HalfEdgeMesh(_))
+        ));
+        // Scalar outputs can't be rendered, and nodes that didn't run have no
+        // outputs at all.
+        assert!(!all_outputs.contains_key(&nodes[0]));
+
+        let result = run_graph(&lua, &graph, nodes[0], params, &node_definitions, None).unwrap();
+        assert!(result.all_outputs.is_none());
+    }
+
+    #[test]
+    pub fn test_partial_outputs() {
+        let broken = r#"
+            require('node_library'):addNodes({
+                Broken = {
+                    label = "Broken",
+                    inputs = { { name = "mesh", type = "mesh" } },
+                    outputs = { { name = "out_mesh", type = "mesh" } },
+                    returns = "out_mesh",
+                    op = function(inputs)
+                        error("Broken on purpose")
+                    end,
+                },
+            })
+        "#;
+        let (lua, node_definitions) = test_lua(&format!("{TEST_NODES}\n{broken}"));
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let broken = graph.add_node("Broken", Some("out_mesh".into()));
+        graph
+            .add_input(broken, "mesh", DataType::Mesh, None)
+            .unwrap();
+        graph
+            .add_output(broken, "out_mesh", DataType::Mesh)
+            .unwrap();
+        graph
+            .add_connection(cube, "out_mesh", broken, "mesh")
+            .unwrap();
+
+        let err = run_graph_partial(
+            &lua,
+            &graph,
+            broken,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("Broken on purpose"));
+        assert_eq!(err.partial.len(), 1);
+        assert!(matches!(
+            err.partial.get(&cube),
+            Some(RenderableThing::HalfEdgeMesh(_))
+        ));
+
+        let result = run_graph_partial(
+            &lua,
+            &graph,
+            cube,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions::default(),
+        )
+        .unwrap();
+        assert!(result.renderable.is_some());
+    }
+
+    #[test]
+    pub fn test_overrides() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, mut params) = counter_chain(2);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[1], "out", add, "a").unwrap();
+        params.0.insert(
+            ExternalParameter::new(add, "b".into()),
+            BlackjackValue::Scalar(0.0),
+        );
+
+        let pinned = lua.create_table().unwrap();
+        pinned.set("out", 10.0).unwrap();
+        run_graph_with_overrides(
+            &lua,
+            &graph,
+            add,
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions::default(),
+            HashMap::from([(nodes[1], pinned)]),
+        )
+        .unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 10.0);
+        // Neither the overridden node nor its dependencies ran.
+        assert_eq!(runs(&lua), 0);
+    }
+
+    #[test]
+    pub fn test_error_policy() {
+        let broken = r#"
+            require('node_library'):addNodes({
+                Broken = {
+                    label = "Broken",
+                    inputs = { { name = "mesh", type = "mesh" } },
+                    outputs = { { name = "out_mesh", type = "mesh" } },
+                    returns = "out_mesh",
+                    op = function(inputs)
+                        error("Broken on purpose")
+                    end,
+                },
+            })
+        "#;
+        let (lua, node_definitions) = test_lua(&format!("{TEST_NODES}\n{broken}"));
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let broken = graph.add_node("Broken", Some("out_mesh".into()));
+        graph
+            .add_input(broken, "mesh", DataType::Mesh, None)
+            .unwrap();
+        graph
+            .add_output(broken, "out_mesh", DataType::Mesh)
+            .unwrap();
+        graph
+            .add_connection(cube, "out_mesh", broken, "mesh")
+            .unwrap();
+        let reroute = graph.add_node("Reroute", Some("mesh".into()));
+        graph
+            .add_input(reroute, "mesh", DataType::Mesh, None)
+            .unwrap();
+        graph.add_output(reroute, "mesh", DataType::Mesh).unwrap();
+        graph
+            .add_connection(broken, "out_mesh", reroute, "mesh")
+            .unwrap();
+        let run = |error_policy| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                reroute,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    diagnostics: RunDiagnostics {
+                        collect_all_outputs: true,
+                        ..Default::default()
+                    },
+                    evaluation: EvaluationOptions {
+                        error_policy,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(run(ErrorPolicy::Abort).is_err());
+
+        let result = run(ErrorPolicy::ContinueWithPlaceholder).unwrap();
+        assert!(result.renderable.is_none());
+        let failed_nodes = result.failed_nodes.unwrap();
+        assert_eq!(
+            failed_nodes.iter().map(|f| f.node_id).collect_vec(),
+            vec![broken, reroute]
+        );
+        assert!(failed_nodes[0].message.contains("Broken on purpose"));
+        // The parts of the graph that didn't fail are still available.
+        assert!(result.all_outputs.unwrap().contains_key(&cube));
+    }
+
+    #[test]
+    pub fn test_run_graph_multi() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+        let results = run_graph_multi(
+            &lua,
+            &graph,
+            &[nodes[2], nodes[1]],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        // Shared nodes only run once
+        assert_eq!(runs(&lua), 3);
+    }
+
+    #[test]
+    pub fn test_gizmo_nodes() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let mut params = ExternalParameterValues::default();
+        let mut nodes = Vec::<BjkNodeId>::new();
+        for _ in 0..3 {
+            let node = graph.add_node("Gizmo", None);
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+            if let Some(prev) = nodes.last() {
+                graph.add_connection(*prev, "out", node, "x").unwrap();
             } else {
-                Ok(true)
+                params.0.insert(
+                    ExternalParameter::new(node, "x".into()),
+                    BlackjackValue::Scalar(1.0),
+                );
             }
-        })
-        .collect::<Result<Vec<bool>>>()?;
-
-    // Run pre-gizmo
-    for it in gizmo_descriptors.iter().zip(&enabled_gizmos) {
-        if let (
-            GizmoDescriptor {
-                gizmos_changed: true,
-                data: Some(gizmo_in),
-                fns: GizmoFns {
-                    update_params_fn, ..
+            nodes.push(node);
+        }
+
+        let mut gizmos_state = SecondaryMap::new();
+        for node in &nodes {
+            gizmos_state.insert(*node, GizmoState::default());
+        }
+
+        // Without a filter, every node in the gizmo state gets its gizmos.
+        let result = run_graph(
+            &lua,
+            &graph,
+            nodes[2],
+            params.clone(),
+            &node_definitions,
+            Some(gizmos_state.clone()),
+        )
+        .unwrap();
+        assert_eq!(result.updated_gizmos.unwrap().len(), 3);
+
+        // The first node is in the middle of the graph, not the target.
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[2],
+            params,
+            &node_definitions,
+            Some(gizmos_state),
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    gizmo_nodes: Some([nodes[0], nodes[1]].into_iter().collect()),
+                    ..Default::default()
                 },
+                ..Default::default()
             },
-            true,
-        ) = it
-        {
-            // Update params
-            // Patch the input map, running the gizmo function
-            let input_gizmo = gizmo_in.clone().to_lua(lua)?;
-            let new_input_map = update_params_fn
-                .call::<_, Table>((input_map, input_gizmo))
-                .map_err(|err| {
-                    anyhow!(
-                        "A node's update_params gizmo callback should return an updated parameter list as a table. {err}"
-                    )
-                })?;
-            input_map = new_input_map;
+        )
+        .unwrap();
+        let updated_gizmos = result.updated_gizmos.unwrap();
+        assert_eq!(updated_gizmos.len(), 2);
+        assert!(updated_gizmos.contains_key(nodes[0]));
+        assert!(updated_gizmos.contains_key(nodes[1]));
+        assert!(!updated_gizmos.contains_key(nodes[2]));
+    }
 
-            // Write the inputs that were returned to lua back to the
-            // external_parameter_values in the context. This will then be sent
-            // as part of the program output, to communicate to the integration
-            // that parameters for a node have changed.
-            let referenced_external_params = referenced_external_params
-                .as_ref()
-                .expect("When gizmos run, this should be defined");
-            for param in referenced_external_params.iter() {
-                let new_val = input_map
-                    .get::<_, BlackjackValue>(param.param_name.clone())
-                    .map_err(|err| {
-                        anyhow!(
-                        "The gizmos input function modified a parameter in an illegal way: {err}"
-                    )
-                    })?;
-                *ctx.external_param_values
-                    .0
-                    .get_mut(param)
-                    .expect("Should be there") = new_val;
+    #[test]
+    pub fn test_gizmo_out_only() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Gizmo", None);
+        graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        let param = ExternalParameter::new(node, "x".into());
+        let mut params = ExternalParameterValues::default();
+        params.0.insert(param.clone(), BlackjackValue::Scalar(1.0));
+
+        let run = |gizmos_changed: bool, active_gizmos: Option<Vec<BlackjackGizmo>>| {
+            let mut gizmos_state = SecondaryMap::new();
+            gizmos_state.insert(
+                node,
+                GizmoState {
+                    active_gizmos,
+                    gizmos_changed,
+                },
+            );
+            run_graph(
+                &lua,
+                &graph,
+                node,
+                params.clone(),
+                &node_definitions,
+                Some(gizmos_state),
+            )
+            .unwrap()
+        };
+
+        // The first run produces the gizmo from the node's parameters.
+        let result = run(false, None);
+        let mut gizmos = result.updated_gizmos.unwrap()[node].clone();
+        match &mut gizmos[0] {
+            BlackjackGizmo::Transform(gizmo) => {
+                assert_eq!(gizmo.translation.x, 1.0);
+                gizmo.translation.x = 5.0;
             }
+            BlackjackGizmo::None => panic!("Expected a transform gizmo"),
         }
+
+        // An unchanged gizmo must not write back into the parameters.
+        let result = run(false, Some(gizmos.clone()));
+        assert!(matches!(
+            result.updated_values.0[&param],
+            BlackjackValue::Scalar(x) if x == 1.0
+        ));
+        assert!(result.changed_params.is_empty());
+
+        // A changed gizmo does.
+        let result = run(true, Some(gizmos));
+        assert!(matches!(
+            result.updated_values.0[&param],
+            BlackjackValue::Scalar(x) if x == 5.0
+        ));
+        assert_eq!(result.changed_params, vec![param.clone()]);
     }
 
-    // Run node 'op'
-    let op_fn: mlua::Function = node_table
-        .get("op")
-        .map_err(|err| anyhow!("Node should always have an 'op'. {err}"))?;
-    let outputs = match op_fn.call(input_map.clone())? {
-        mlua::Value::Table(t) => t,
-        other => {
-            bail!("A node's `op` function should always return a table, got {other:?}");
+    #[test]
+    pub fn test_update_gizmos_write_back() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            local snap_gizmo = function(param_name)
+                return {
+                    update_gizmos = function(inputs, gizmo, outputs)
+                        local gizmo = TransformGizmo.new(
+                            vector(inputs.x, 0, 0), vector(0, 0, 0), vector(1, 1, 1)
+                        )
+                        return gizmo, { [param_name] = math.floor(inputs.x) }
+                    end,
+                }
+            end
+            require('node_library'):addNodes({
+                Snap = {
+                    label = "Snap",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = { { name = "out", type = "scalar" } },
+                    op = function(inputs)
+                        return { out = inputs.x }
+                    end,
+                    gizmos = { snap_gizmo("x") },
+                },
+                BadSnap = {
+                    label = "BadSnap",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = { { name = "out", type = "scalar" } },
+                    op = function(inputs)
+                        return { out = inputs.x }
+                    end,
+                    gizmos = { snap_gizmo("y") },
+                },
+            })
+            "#,
+        );
+        let run = |op_name: &str| {
+            let mut graph = BjkGraph::new();
+            let node = graph.add_node(op_name, None);
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+            let params = ExternalParameterValues::builder()
+                .scalar(node, "x", 2.5)
+                .build();
+            let mut gizmos_state = SecondaryMap::new();
+            gizmos_state.insert(
+                node,
+                GizmoState {
+                    active_gizmos: None,
+                    gizmos_changed: false,
+                },
+            );
+            let result = run_graph(
+                &lua,
+                &graph,
+                node,
+                params,
+                &node_definitions,
+                Some(gizmos_state),
+            );
+            (node, result)
+        };
+
+        let (node, result) = run("Snap");
+        let result = result.unwrap();
+        let param = ExternalParameter::new(node, "x".into());
+        assert_eq!(result.updated_values.0[&param], BlackjackValue::Scalar(2.0));
+        assert_eq!(result.changed_params, vec![param]);
+        assert!(matches!(
+            result.updated_gizmos.unwrap()[node][..],
+            [BlackjackGizmo::Transform(_)]
+        ));
+
+        let (_, result) = run("BadSnap");
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("not an external parameter"));
+    }
+
+    #[test]
+    pub fn test_gizmo_state_warnings() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("TwoGizmos", None);
+        graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(node, "x", 1.0)
+            .build();
+
+        let run = |gizmos_changed: bool, active_gizmos: Option<Vec<BlackjackGizmo>>| {
+            let mut gizmos_state = SecondaryMap::new();
+            gizmos_state.insert(
+                node,
+                GizmoState {
+                    active_gizmos,
+                    gizmos_changed,
+                },
+            );
+            run_graph_with_options(
+                &lua,
+                &graph,
+                node,
+                params.clone(),
+                &node_definitions,
+                Some(gizmos_state),
+                RunGraphOptions {
+                    diagnostics: RunDiagnostics {
+                        collect_warnings: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .warnings
+            .unwrap()
+        };
+
+        assert!(run(false, None).is_empty());
+        let warnings = run(true, None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .message
+            .contains("no gizmo values were provided"));
+        // Too few gizmos are not an error, the missing ones are ignored.
+        let warnings = run(true, Some(vec![BlackjackGizmo::None]));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .message
+            .contains("has 2 gizmos, but 1 gizmo values"));
+    }
+
+    #[test]
+    pub fn test_gizmo_ids() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            local function gizmo(name)
+                return {
+                    name = name,
+                    update_gizmos = function(inputs, gizmo, outputs)
+                        return TransformGizmo.default()
+                    end,
+                }
+            end
+            require('node_library'):addNodes({
+                Named = {
+                    label = "Named",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs) return {} end,
+                    gizmos = { gizmo("start"), gizmo(nil), gizmo("end") },
+                },
+                Duplicated = {
+                    label = "Duplicated",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs) return {} end,
+                    gizmos = { gizmo("start"), gizmo("start") },
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let named = graph.add_node("Named", None);
+        let duplicated = graph.add_node("Duplicated", None);
+        let run = |node| {
+            let mut gizmos_state = SecondaryMap::new();
+            gizmos_state.insert(node, GizmoState::default());
+            run_graph(
+                &lua,
+                &graph,
+                node,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                Some(gizmos_state),
+            )
+        };
+
+        let result = run(named).unwrap();
+        let id = |key| GizmoId {
+            node_id: named,
+            key,
+        };
+        assert_eq!(
+            result.gizmo_ids.unwrap()[named],
+            vec![
+                id(GizmoKey::Name("start".into())),
+                id(GizmoKey::Index(1)),
+                id(GizmoKey::Name("end".into())),
+            ]
+        );
+        assert_eq!(result.updated_gizmos.unwrap()[named].len(), 3);
+        assert!(run(duplicated).is_err());
+    }
+
+    #[test]
+    pub fn test_partial_gizmos() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                OutOnly = {
+                    label = "OutOnly",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = {},
+                    op = function(inputs) return {} end,
+                    gizmos = {
+                        {
+                            update_gizmos = function(inputs, gizmo, outputs)
+                                return TransformGizmo.new(
+                                    vector(inputs.x, 0, 0), vector(0, 0, 0), vector(1, 1, 1)
+                                )
+                            end,
+                        },
+                    },
+                },
+                InOnly = {
+                    label = "InOnly",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = {},
+                    op = function(inputs) return {} end,
+                    gizmos = {
+                        {
+                            update_params = function(inputs, gizmo)
+                                inputs.x = gizmo:translation().x
+                                return inputs
+                            end,
+                        },
+                    },
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let out_only = graph.add_node("OutOnly", None);
+        let in_only = graph.add_node("InOnly", None);
+        for node in [out_only, in_only] {
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
         }
-    };
+        let params = ExternalParameterValues::builder()
+            .scalar(out_only, "x", 1.0)
+            .scalar(in_only, "x", 1.0)
+            .build();
+        let moved: BlackjackGizmo = lua
+            .load("return TransformGizmo.new(vector(5, 0, 0), vector(0, 0, 0), vector(1, 1, 1))")
+            .eval()
+            .unwrap();
+        let run = |node, gizmos_changed| {
+            let mut gizmos_state = SecondaryMap::new();
+            gizmos_state.insert(
+                node,
+                GizmoState {
+                    active_gizmos: Some(vec![moved.clone()]),
+                    gizmos_changed,
+                },
+            );
+            run_graph(
+                &lua,
+                &graph,
+                node,
+                params.clone(),
+                &node_definitions,
+                Some(gizmos_state),
+            )
+        };
 
-    ctx.outputs_cache.insert(node_id, outputs.clone());
-
-    // Run post-gizmo
-    for (gz_descr, enabled) in gizmo_descriptors.iter_mut().zip(&enabled_gizmos) {
-        let updated_gizmo = enabled
-            .then(|| -> anyhow::Result<_> {
-                let gizmo = gz_descr
-                    .data
-                    .as_mut()
-                    .map(|gz| gz.clone().to_lua(lua))
-                    .transpose()?
-                    .unwrap_or(mlua::Value::Nil);
-
-                gz_descr
-                    .fns
-                    .update_gizmos_fn
-                    .call::<_, BlackjackGizmo>((input_map.clone(), gizmo, outputs.clone()))
-                    .map_err(|err| {
-                        anyhow!("A node's gizmo outputs function should return a new gizmo. {err}")
-                    })
+        // Out-only gizmos work as long as they are not changed.
+        let result = run(out_only, false).unwrap();
+        assert!(matches!(
+            result.updated_gizmos.unwrap()[out_only][..],
+            [BlackjackGizmo::Transform(_)]
+        ));
+        let err = run(out_only, true).err().unwrap();
+        assert!(err.to_string().contains("no 'update_params' function"));
+
+        // In-only gizmos update the parameters, but produce no gizmo.
+        let result = run(in_only, true).unwrap();
+        assert_eq!(
+            result.updated_values.0[&ExternalParameter::new(in_only, "x".into())],
+            BlackjackValue::Scalar(5.0)
+        );
+        assert!(matches!(
+            result.updated_gizmos.unwrap()[in_only][..],
+            [BlackjackGizmo::None]
+        ));
+    }
+
+    #[test]
+    pub fn test_profiling() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+
+        let result = run_graph(
+            &lua,
+            &graph,
+            nodes[2],
+            params.clone(),
+            &node_definitions,
+            None,
+        )
+        .unwrap();
+        assert!(result.node_timings.is_none());
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[2],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                diagnostics: RunDiagnostics {
+                    profile: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let node_timings = result.node_timings.unwrap();
+        assert_eq!(node_timings.len(), 3);
+        assert!(nodes.iter().all(|node| node_timings.contains_key(node)));
+    }
+
+    #[test]
+    pub fn test_progress_callback() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+
+        let mut progress = Vec::new();
+        let mut on_progress = |node_id: BjkNodeId, evaluated: usize, total: usize| {
+            progress.push((node_id, evaluated, total));
+        };
+        run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[2],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                diagnostics: RunDiagnostics {
+                    on_progress: Some(&mut on_progress),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            progress,
+            vec![(nodes[0], 0, 3), (nodes[1], 1, 3), (nodes[2], 2, 3)]
+        );
+    }
+
+    #[test]
+    pub fn test_gizmos_per_node() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let a = graph.add_node("Gizmo", None);
+        let b = graph.add_node("TwoGizmos", None);
+        for node in [a, b] {
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+        }
+        graph.add_connection(a, "out", b, "x").unwrap();
+        let mut params = ExternalParameterValues::default();
+        params.0.insert(
+            ExternalParameter::new(a, "x".into()),
+            BlackjackValue::Scalar(1.0),
+        );
+
+        let mut gizmos_state = SecondaryMap::new();
+        gizmos_state.insert(a, GizmoState::default());
+        gizmos_state.insert(b, GizmoState::default());
+        let result = run_graph(
+            &lua,
+            &graph,
+            b,
+            params,
+            &node_definitions,
+            Some(gizmos_state),
+        )
+        .unwrap();
+
+        // Each node gets its own gizmos, in the order they were declared.
+        let updated_gizmos = result.updated_gizmos.unwrap();
+        assert_eq!(updated_gizmos[a].len(), 1);
+        assert!(matches!(updated_gizmos[a][0], BlackjackGizmo::Transform(_)));
+        // The input of `b` is connected, so its gizmos are disabled.
+        assert_eq!(updated_gizmos[b].len(), 2);
+        assert!(updated_gizmos[b]
+            .iter()
+            .all(|gizmo| matches!(gizmo, BlackjackGizmo::None)));
+    }
+
+    #[test]
+    pub fn test_layered_node_definitions() {
+        use crate::graph::NativeOp;
+
+        let (lua, base) = test_lua(TEST_NODES);
+        let extension = NodeDefinitions::default();
+        let mut counter_def = base.node_def("Counter").unwrap().clone();
+        counter_def.label = "Native Counter".into();
+        extension.register_native(
+            counter_def,
+            NativeOp::new(|lua, inputs| {
+                let x = f32::from_lua(inputs["x"].clone(), lua)?;
+                lua.globals().set("NATIVE", x)?;
+                Ok(HashMap::from([("out".into(), (x + 1.0).into_lua(lua)?)]))
+            }),
+        );
+        let node_definitions = NodeDefinitions::layered(&[&base, &extension]);
+
+        // The extension takes precedence, and the rest comes from the base.
+        assert_eq!(
+            node_definitions.node_def("Counter").unwrap().label,
+            "Native Counter"
+        );
+        assert!(node_definitions.node_def("Cube").is_some());
+        assert_eq!(node_definitions.node_names(), base.node_names());
+        let all = node_definitions.all();
+        assert_eq!(
+            all.iter()
+                .map(|node_def| node_def.op_name.clone())
+                .collect_vec(),
+            node_definitions.node_names()
+        );
+        let find = |op_name: &str| all.iter().find(|node_def| node_def.op_name == op_name);
+        assert_eq!(find("Counter").unwrap().label, "Native Counter");
+        assert!(find("Gizmo").unwrap().has_gizmo);
+        assert!(!find("Cube").unwrap().has_gizmo);
+        assert_eq!(
+            find("Cube").unwrap().category.as_deref(),
+            Some("Primitives")
+        );
+        assert_eq!(find("Counter").unwrap().category, None);
+        let reversed = NodeDefinitions::layered(&[&extension, &base]);
+        assert_eq!(reversed.node_def("Counter").unwrap().label, "Counter");
+
+        let (graph, nodes, params) = counter_chain(1);
+        run_graph(&lua, &graph, nodes[0], params, &node_definitions, None).unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("NATIVE").unwrap(), 1.0);
+        assert_eq!(runs(&lua), 0);
+    }
+
+    #[test]
+    pub fn test_gizmos_must_be_array() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                MapGizmos = {
+                    label = "MapGizmos",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        return {}
+                    end,
+                    gizmos = { first = {}, second = {} },
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("MapGizmos", None);
+        let mut gizmos_state = SecondaryMap::new();
+        gizmos_state.insert(node, GizmoState::default());
+        let err = run_graph(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            Some(gizmos_state),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("must be an array"));
+    }
+
+    #[test]
+    pub fn test_output_names() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, params) = counter_chain(1);
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+
+        let result =
+            run_graph(&lua, &graph, cube, params.clone(), &node_definitions, None).unwrap();
+        assert_eq!(result.return_value.as_deref(), Some("out_mesh"));
+        assert_eq!(result.output_names, vec!["out_mesh"]);
+
+        let result = run_graph(&lua, &graph, nodes[0], params, &node_definitions, None).unwrap();
+        assert_eq!(result.return_value, None);
+        assert_eq!(result.output_names, vec!["out"]);
+    }
+
+    #[test]
+    pub fn test_output_keys() {
+        // The node returns an output that is not in its definition.
+        let dynamic = r#"
+            require('node_library'):addNodes({
+                Dynamic = {
+                    label = "Dynamic",
+                    inputs = { { name = "mesh", type = "mesh" } },
+                    outputs = { { name = "out", type = "mesh" } },
+                    op = function(inputs)
+                        return { out = inputs.mesh, extra = 1 }
+                    end,
+                    returns = "out",
+                },
             })
-            .transpose()?;
-        ctx.gizmo_outputs
-            .entry(node_id)
+        "#;
+        let (lua, node_definitions) = test_lua(&format!("{TEST_NODES}\n{dynamic}"));
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", None);
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let node = graph.add_node("Dynamic", Some("out".into()));
+        graph.add_input(node, "mesh", DataType::Mesh, None).unwrap();
+        graph.add_output(node, "out", DataType::Mesh).unwrap();
+        graph
+            .add_connection(cube, "out_mesh", node, "mesh")
+            .unwrap();
+
+        let run = |record_output_keys| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                node,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    diagnostics: RunDiagnostics {
+                        record_output_keys,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
             .unwrap()
-            .or_default()
-            // When gizmos are disabled, we push a `None` value. This will be
-            // converted into `nil` when that gizmo is enabled again and its
-            // value is pushed to Lua.
-            .push(updated_gizmo.unwrap_or(BlackjackGizmo::None));
+        };
+        let output_keys = run(true).output_keys.unwrap();
+        assert_eq!(output_keys.len(), 2);
+        assert_eq!(output_keys[&cube], vec!["out_mesh"]);
+        assert_eq!(output_keys[&node], vec!["extra", "out"]);
+        assert!(run(false).output_keys.is_none());
     }
 
-    Ok(())
+    #[test]
+    pub fn test_warnings() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, _) = counter_chain(2);
+        let run = |collect_warnings| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                nodes[1],
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    diagnostics: RunDiagnostics {
+                        collect_warnings,
+                        ..Default::default()
+                    },
+                    evaluation: EvaluationOptions {
+                        use_default_params: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        assert!(run(false).warnings.is_none());
+        let warnings = run(true).warnings.unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].node_id, nodes[0]);
+        assert!(warnings[0].message.contains("'x' is missing"));
+    }
+
+    #[test]
+    pub fn test_lazy_renderable() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            cube,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                lazy_renderable: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(result.renderable.is_none());
+        let lazy = result.lazy_renderable.unwrap();
+        assert_eq!(lazy.vertex_count(&lua).unwrap(), 8);
+        assert_eq!(lazy.face_count(&lua).unwrap(), 6);
+        let renderable = lazy.into_renderable(&lua, None).unwrap();
+        assert_eq!(renderable.vertex_count(), 8);
+        assert_eq!(renderable.face_count(), 6);
+    }
+
+    #[test]
+    pub fn test_clone_program_result() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let run = |lazy_renderable| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                cube,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    lazy_renderable,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        // Cloned meshes don't share their channels.
+        let result = run(false);
+        let mut copy = result.clone();
+        match &mut copy.renderable {
+            Some(RenderableThing::HalfEdgeMesh(mesh)) => {
+                halfedge::edit_ops::add_vertex(mesh, Vec3::ZERO).unwrap();
+            }
+            _ => panic!("Expected a mesh"),
+        }
+        assert_eq!(result.renderable.unwrap().vertex_count(), 8);
+        assert_eq!(copy.renderable.unwrap().vertex_count(), 9);
+
+        // Every clone of a lazy renderable can be converted.
+        let result = run(true);
+        let copy = result.clone();
+        let a = result.lazy_renderable.unwrap().into_renderable(&lua, None);
+        let b = copy.lazy_renderable.unwrap().into_renderable(&lua, None);
+        assert_eq!(a.unwrap().vertex_count(), 8);
+        assert_eq!(b.unwrap().vertex_count(), 8);
+    }
+
+    #[test]
+    pub fn test_custom_renderables() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Volume = {
+                    label = "Volume",
+                    inputs = {},
+                    outputs = { { name = "out", type = "mesh" } },
+                    op = function(inputs)
+                        return { out = { __renderable = "volume", resolution = 16 } }
+                    end,
+                    returns = "out",
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let volume = graph.add_node("Volume", Some("out".into()));
+        graph.add_output(volume, "out", DataType::Mesh).unwrap();
+
+        struct Volume(u32);
+        let mut registry = RenderableRegistry::new();
+        registry.register("volume", |table| {
+            Ok(Box::new(Volume(table.get("resolution")?)))
+        });
+        let run = |renderables: Option<&RenderableRegistry>| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                volume,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    renderables,
+                    ..Default::default()
+                },
+            )
+        };
+
+        match run(Some(&registry)).unwrap().renderable {
+            Some(RenderableThing::Custom(custom)) => {
+                assert_eq!(custom.kind, "volume");
+                assert_eq!(custom.downcast_ref::<Volume>().unwrap().0, 16);
+            }
+            _ => panic!("Expected a custom renderable"),
+        }
+        assert!(run(None).is_err());
+        assert!(run(Some(&RenderableRegistry::new())).is_err());
+    }
+
+    #[test]
+    pub fn test_run_sweep() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, params) = counter_chain(2);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[1], "out", add, "a").unwrap();
+        let swept = ExternalParameter::new(add, "b".into());
+        let values = [1.0, 2.0, 3.0].map(BlackjackValue::Scalar);
+
+        let results = run_sweep(
+            &lua,
+            &graph,
+            add,
+            &params,
+            &node_definitions,
+            &swept,
+            &values,
+        );
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 6.0);
+        // The counters don't depend on the swept parameter, so they only run
+        // once.
+        assert_eq!(runs(&lua), 2);
+    }
+
+    #[test]
+    pub fn test_run_animation() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, _) = counter_chain(2);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[1], "out", add, "a").unwrap();
+        let frame = |x, b| {
+            ExternalParameterValues::builder()
+                .scalar(nodes[0], "x", x)
+                .scalar(add, "b", b)
+                .build()
+        };
+        let frames = [frame(1.0, 0.0), frame(1.0, 1.0), frame(2.0, 1.0)];
+
+        let results = run_animation(&lua, &graph, add, &node_definitions, &frames);
+        let executed = results
+            .into_iter()
+            .map(|result| result.unwrap().stats.nodes_executed)
+            .collect_vec();
+        // Only the nodes affected by the parameters that changed since the
+        // previous frame are evaluated again.
+        assert_eq!(executed, vec![3, 1, 3]);
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 5.0);
+    }
+
+    #[test]
+    pub fn test_node_definition_outputs() {
+        let (_, node_definitions) = test_lua(TEST_NODES);
+        let outputs = node_definitions.outputs("Cube").unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].name, "out_mesh");
+        assert_eq!(outputs[0].data_type, DataType::Mesh);
+        drop(outputs);
+        assert!(node_definitions.outputs("NotInstalled").is_none());
+    }
 }
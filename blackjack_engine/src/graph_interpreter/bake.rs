@@ -0,0 +1,439 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::parallel::{PortableMesh, PortableValue};
+use super::*;
+use crate::graph::serialization::{
+    deserialize_data_type, serialize_data_type, SerializedBakedNode, SerializedBakedValue,
+    SerializedOutput,
+};
+use crate::graph::{NativeOp, NodeDefinition, Output, OutputDefinition};
+use crate::mesh::heightmap::HeightMap;
+
+/// A node replaying the outputs of a subgraph, as returned by
+/// `bake_subgraph`. The baked node has no inputs, and its `op` returns a copy
+/// of the outputs captured when baking, without evaluating anything.
+pub struct BakedNode {
+    /// The definition of the baked node. It has the outputs of the node that
+    /// was baked, and no inputs.
+    pub node_def: NodeDefinition,
+    outputs: Rc<Vec<(String, PortableValue)>>,
+}
+
+impl BakedNode {
+    /// Registers the baked node in `node_definitions`, as a native node. See
+    /// `NodeDefinitions::register_native`.
+    pub fn register(&self, node_definitions: &NodeDefinitions) {
+        let outputs = self.outputs.clone();
+        let op = NativeOp::new(move |lua, _inputs| {
+            outputs
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), value.clone().into_lua(lua)?)))
+                .collect()
+        });
+        node_definitions.register_native(self.node_def.clone(), op);
+    }
+
+    /// Converts the baked node to the form stored in graph files. See
+    /// `SerializedBjkGraph::set_baked_nodes`.
+    ///
+    /// Meshes are stored in the Wavefront OBJ format, so only their positions
+    /// and faces are kept. Fails for strings that are not valid UTF-8.
+    pub fn to_serialized(&self) -> Result<SerializedBakedNode> {
+        Ok(SerializedBakedNode {
+            op_name: self.node_def.op_name.clone(),
+            label: self.node_def.label.clone(),
+            category: self.node_def.category.clone(),
+            outputs: self
+                .node_def
+                .outputs
+                .iter()
+                .map(|output| SerializedOutput {
+                    name: output.name.clone(),
+                    data_type: serialize_data_type(output.data_type),
+                })
+                .collect(),
+            returns: self.node_def.returns.clone(),
+            values: self
+                .outputs
+                .iter()
+                .map(|(name, value)| {
+                    let value = serialize_value(value)
+                        .with_context(|| format!("Could not save the baked '{name}' output"))?;
+                    Ok((name.clone(), value))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Restores a baked node saved with `to_serialized`. The node still needs
+    /// to be registered before it can be used.
+    pub fn from_serialized(serialized: &SerializedBakedNode) -> Result<BakedNode> {
+        let outputs = serialized
+            .outputs
+            .iter()
+            .map(|output| {
+                Ok(OutputDefinition {
+                    name: output.name.clone(),
+                    data_type: deserialize_data_type(&output.data_type)
+                        .ok_or_else(|| anyhow!("Unknown data type: {}", output.data_type))?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let values = serialized
+            .values
+            .iter()
+            .map(|(name, value)| {
+                let value = deserialize_value(value)
+                    .with_context(|| format!("Could not load the baked '{name}' output"))?;
+                Ok((name.clone(), value))
+            })
+            .collect::<Result<_>>()?;
+        Ok(BakedNode {
+            node_def: baked_node_def(
+                serialized.op_name.clone(),
+                serialized.label.clone(),
+                serialized.category.clone(),
+                outputs,
+                serialized.returns.clone(),
+            ),
+            outputs: Rc::new(values),
+        })
+    }
+
+    /// Replaces `node` with a new node of the baked type, and returns its id.
+    /// The connections from the outputs of `node` are moved to the new node.
+    /// Then, `node` and the nodes it depends on are removed from the graph,
+    /// unless some other node still uses them.
+    ///
+    /// The values of the external parameters of the removed nodes are no
+    /// longer needed, but they are not removed from any
+    /// `ExternalParameterValues`.
+    pub fn replace(&self, graph: &mut BjkGraph, node: BjkNodeId) -> BjkNodeId {
+        let subtree = dependency_set(graph, node);
+        let outputs = graph.nodes[node]
+            .outputs
+            .iter()
+            .map(|output| Output {
+                name: output.name.clone(),
+                data_type: output.data_type,
+            })
+            .collect();
+        let baked = graph.add_node(
+            &self.node_def.op_name,
+            graph.nodes[node].return_value.clone(),
+        );
+        graph.nodes[baked].outputs = outputs;
+        for (_, other) in graph.nodes.iter_mut() {
+            for input in &mut other.inputs {
+                if let DependencyKind::Connection { node: src, .. } = &mut input.kind {
+                    if *src == node {
+                        *src = baked;
+                    }
+                }
+            }
+        }
+        if graph.default_node == Some(node) {
+            graph.default_node = Some(baked);
+        }
+
+        let roots = graph
+            .nodes
+            .keys()
+            .filter(|node_id| !subtree.contains(node_id))
+            .collect_vec();
+        for node_id in unused_nodes(graph, &roots) {
+            if subtree.contains(&node_id) {
+                graph.nodes.remove(node_id);
+            }
+        }
+        baked
+    }
+}
+
+/// Evaluates `node`, and returns a node replaying its outputs, named
+/// `op_name`. Replacing `node` with the baked node, see `BakedNode::replace`,
+/// gives the same results downstream without evaluating `node` or its
+/// dependencies again. This can be used to freeze an expensive part of a
+/// graph.
+///
+/// The outputs are captured as plain values, vectors, meshes, height maps and
+/// tables of those. Baking fails if the node outputs anything else. Every run
+/// of the baked node returns a fresh copy of the outputs, so nodes modifying
+/// their inputs in place can't change the baked values. Baked nodes can be
+/// saved along with a graph, see `SerializedBjkGraph::set_baked_nodes`.
+pub fn bake_subgraph(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    node: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    op_name: impl ToString,
+) -> Result<BakedNode> {
+    let mut outputs_cache = HashMap::new();
+    run_graph_impl(
+        lua,
+        graph,
+        &[node],
+        RunInputs {
+            external_param_values,
+            gizmos_state: None,
+        },
+        node_definitions,
+        RunGraphOptions::default(),
+        RunCaches {
+            outputs: &mut outputs_cache,
+            content: None,
+            keep_outputs: true,
+        },
+    )?;
+    let outputs = outputs_cache[&node]
+        .clone()
+        .pairs::<String, mlua::Value>()
+        .map(|pair| {
+            let (name, value) = pair?;
+            let value = PortableValue::from_lua(value)
+                .with_context(|| format!("Could not bake the '{name}' output"))?;
+            Ok((name, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let original = graph.nodes[node].op_name.as_str();
+    let original_def = node_definitions
+        .node_def(original)
+        .ok_or_else(|| anyhow!("Node definition not found for {original}"))?;
+    let node_def = baked_node_def(
+        op_name.to_string(),
+        format!("{} (baked)", original_def.label),
+        original_def.category.clone(),
+        original_def.outputs.clone(),
+        graph.nodes[node].return_value.clone(),
+    );
+    Ok(BakedNode {
+        node_def,
+        outputs: Rc::new(outputs),
+    })
+}
+
+/// The definition of a baked node, which has no inputs and always returns the
+/// same outputs.
+fn baked_node_def(
+    op_name: String,
+    label: String,
+    category: Option<String>,
+    outputs: Vec<OutputDefinition>,
+    returns: Option<String>,
+) -> NodeDefinition {
+    NodeDefinition {
+        op_name,
+        label,
+        category,
+        inputs: Vec::new(),
+        outputs,
+        returns,
+        executable: false,
+        has_gizmo: false,
+        native_op: None,
+        subgraph: None,
+        passthrough: false,
+        pure: true,
+    }
+}
+
+fn serialize_value(value: &PortableValue) -> Result<SerializedBakedValue> {
+    Ok(match value {
+        PortableValue::Nil => SerializedBakedValue::Nil,
+        PortableValue::Boolean(b) => SerializedBakedValue::Boolean(*b),
+        PortableValue::Integer(i) => SerializedBakedValue::Integer((*i).into()),
+        PortableValue::Number(x) => SerializedBakedValue::Number(*x),
+        PortableValue::Vector(v) => SerializedBakedValue::Vector(*v),
+        PortableValue::String(s) => SerializedBakedValue::String(
+            String::from_utf8(s.clone()).map_err(|_| anyhow!("Only UTF-8 strings can be saved"))?,
+        ),
+        PortableValue::Mesh(mesh) => {
+            let mut obj = Vec::new();
+            mesh.mesh().write_wavefront_obj(&mut obj)?;
+            SerializedBakedValue::Mesh(String::from_utf8(obj)?)
+        }
+        PortableValue::HeightMap(heightmap) => {
+            let (width, height) = heightmap.size();
+            SerializedBakedValue::HeightMap {
+                width,
+                height,
+                heights: heightmap.heights(),
+            }
+        }
+        PortableValue::Table(pairs) => SerializedBakedValue::Table(
+            pairs
+                .iter()
+                .map(|(k, v)| Ok((serialize_value(k)?, serialize_value(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+fn deserialize_value(value: &SerializedBakedValue) -> Result<PortableValue> {
+    Ok(match value {
+        SerializedBakedValue::Nil => PortableValue::Nil,
+        SerializedBakedValue::Boolean(b) => PortableValue::Boolean(*b),
+        SerializedBakedValue::Integer(i) => PortableValue::Integer(
+            (*i).try_into()
+                .map_err(|_| anyhow!("The integer {i} is out of range"))?,
+        ),
+        SerializedBakedValue::Number(x) => PortableValue::Number(*x),
+        SerializedBakedValue::Vector(v) => PortableValue::Vector(*v),
+        SerializedBakedValue::String(s) => PortableValue::String(s.as_bytes().to_vec()),
+        SerializedBakedValue::Mesh(obj) => PortableValue::Mesh(Box::new(PortableMesh::new(
+            &HalfEdgeMesh::read_wavefront_obj(obj.as_bytes())?,
+        ))),
+        SerializedBakedValue::HeightMap {
+            width,
+            height,
+            heights,
+        } => PortableValue::HeightMap(HeightMap::from_heights(*width, *height, heights.clone())?),
+        SerializedBakedValue::Table(pairs) => PortableValue::Table(
+            pairs
+                .iter()
+                .map(|(k, v)| Ok((deserialize_value(k)?, deserialize_value(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::serialization::{RuntimeData, SerializedBjkGraph};
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_bake_subgraph() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // Counter (1 + 1) -> Counter (2 + 1) -> Counter (3 + 1) -> Add (4 + 0)
+        let (mut graph, nodes, _) = counter_chain(3);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[2], "out", add, "a").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .scalar(add, "b", 0.0)
+            .build();
+        let run = |graph: &BjkGraph| {
+            lua.globals().set("RUNS", 0).unwrap();
+            run_graph(&lua, graph, add, params.clone(), &node_definitions, None).unwrap();
+            (lua.globals().get::<_, f32>("SUM").unwrap(), runs(&lua))
+        };
+        assert_eq!(run(&graph), (4.0, 3));
+
+        let baked_counter = bake_subgraph(
+            &lua,
+            &graph,
+            nodes[1],
+            params.clone(),
+            &node_definitions,
+            "BakedCounter",
+        )
+        .unwrap();
+        assert!(baked_counter.node_def.inputs.is_empty());
+        baked_counter.register(&node_definitions);
+        let baked_id = baked_counter.replace(&mut graph, nodes[1]);
+        assert!(!graph.nodes.contains_key(nodes[0]));
+        assert!(!graph.nodes.contains_key(nodes[1]));
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(
+            graph.nodes[nodes[2]].inputs[0].kind,
+            DependencyKind::Connection {
+                node: baked_id,
+                param_name: "out".into(),
+                optional: false,
+            }
+        );
+        // Only the last counter runs, with the same result.
+        assert_eq!(run(&graph), (4.0, 1));
+
+        // Every run gets its own copy of a baked mesh.
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let baked_cube = bake_subgraph(
+            &lua,
+            &graph,
+            cube,
+            params.clone(),
+            &node_definitions,
+            "BakedCube",
+        )
+        .unwrap();
+        baked_cube.register(&node_definitions);
+        let baked_cube_id = baked_cube.replace(&mut graph, cube);
+        for _ in 0..2 {
+            let result = run_graph(
+                &lua,
+                &graph,
+                baked_cube_id,
+                params.clone(),
+                &node_definitions,
+                None,
+            )
+            .unwrap();
+            assert_eq!(result.renderable.unwrap().vertex_count(), 8);
+        }
+
+        // Baked nodes are saved with the graph, and registered again when
+        // loading it, without the original nodes.
+        let mut params = params;
+        params
+            .0
+            .retain(|param, _| graph.nodes.contains_key(param.node_id));
+        let (mut serialized, mappings) = SerializedBjkGraph::from_runtime(RuntimeData {
+            graph,
+            external_parameters: Some(params),
+        })
+        .unwrap();
+        serialized
+            .set_baked_nodes(&[baked_counter, baked_cube])
+            .unwrap();
+        let saved = ron::ser::to_string(&serialized).unwrap();
+        let loaded = SerializedBjkGraph::load_from_string(&saved).unwrap();
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let restored = loaded.register_baked_nodes(&node_definitions).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(
+            node_definitions.node_def("BakedCounter").unwrap().label,
+            "Counter (baked)"
+        );
+        let (data, _, loaded_mappings) = loaded.into_runtime().unwrap();
+        let id = |node| {
+            loaded_mappings
+                .get_id(mappings.get_idx(node).unwrap())
+                .unwrap()
+        };
+        let params = data.external_parameters.unwrap();
+        lua.globals().set("RUNS", 0).unwrap();
+        run_graph(
+            &lua,
+            &data.graph,
+            id(add),
+            params.clone(),
+            &node_definitions,
+            None,
+        )
+        .unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 4.0);
+        assert_eq!(runs(&lua), 1);
+        let result = run_graph(
+            &lua,
+            &data.graph,
+            id(baked_cube_id),
+            params,
+            &node_definitions,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.renderable.unwrap().vertex_count(), 8);
+    }
+}
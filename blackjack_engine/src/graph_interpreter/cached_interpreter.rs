@@ -0,0 +1,502 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::graph::GraphDelta;
+
+/// An interpreter that keeps the outputs of every evaluated node alive between
+/// runs, so editing a graph only needs to re-evaluate the affected nodes.
+///
+/// The cache is not aware of changes on its own: Whenever a node, or one of
+/// its external parameters, changes, the host must mark it as dirty. Dirty
+/// nodes are evaluated again on the next run, and so are all of the nodes that
+/// depend on them, transitively.
+///
+/// Nodes with gizmo state are always evaluated, so their gizmos can be updated.
+///
+/// Optionally, outputs can also be memoized by the contents of their inputs.
+/// See `set_memoize`.
+///
+/// The state of the cache can be saved and restored, for undo support. See
+/// `snapshot`.
+#[derive(Default)]
+pub struct CachedInterpreter<'lua> {
+    outputs_cache: HashMap<BjkNodeId, mlua::Table<'lua>>,
+    dirty: HashSet<BjkNodeId>,
+    content_cache: Option<ContentCache<'lua>>,
+    /// The external parameter values after the last run.
+    values: ExternalParameterValues,
+}
+
+/// A saved state of a `CachedInterpreter`, as returned by
+/// `CachedInterpreter::snapshot`.
+#[derive(Clone)]
+pub struct CacheSnapshot<'lua> {
+    outputs_cache: HashMap<BjkNodeId, mlua::Table<'lua>>,
+    dirty: HashSet<BjkNodeId>,
+    values: ExternalParameterValues,
+}
+
+impl CacheSnapshot<'_> {
+    /// The external parameter values after the last run before the snapshot
+    /// was taken. Hosts can use these to restore their parameters on undo.
+    pub fn values(&self) -> &ExternalParameterValues {
+        &self.values
+    }
+}
+
+/// Memoizes node outputs by a hash of their op name and the contents of their
+/// inputs. For inputs connected to other nodes, the key of the connected node
+/// is used as its contents.
+///
+/// Outputs that were not used during the previous run are discarded, so the
+/// cache doesn't grow with every value a parameter goes through.
+#[derive(Default)]
+pub(super) struct ContentCache<'lua> {
+    /// The outputs used during the current run.
+    current: HashMap<u64, mlua::Table<'lua>>,
+    /// The outputs used during the previous run.
+    previous: HashMap<u64, mlua::Table<'lua>>,
+    /// The key for the last evaluation of each node.
+    node_keys: HashMap<BjkNodeId, u64>,
+}
+
+impl<'lua> ContentCache<'lua> {
+    /// Returns the memoized outputs for `key`, if any.
+    pub(super) fn get(&mut self, key: u64) -> Option<mlua::Table<'lua>> {
+        if let Some(outputs) = self.current.get(&key) {
+            return Some(outputs.clone());
+        }
+        let outputs = self.previous.remove(&key)?;
+        self.current.insert(key, outputs.clone());
+        Some(outputs)
+    }
+
+    /// Stores the outputs of an evaluation of `node_id`. When no key could be
+    /// computed for the node, the outputs are not memoized, and neither are
+    /// those of the nodes depending on it.
+    pub(super) fn insert(
+        &mut self,
+        node_id: BjkNodeId,
+        key: Option<u64>,
+        outputs: &mlua::Table<'lua>,
+    ) {
+        if let Some(key) = key {
+            self.current.insert(key, outputs.clone());
+            self.node_keys.insert(node_id, key);
+        } else {
+            self.node_keys.remove(&node_id);
+        }
+    }
+
+    pub(super) fn node_key(&self, node_id: BjkNodeId) -> Option<u64> {
+        self.node_keys.get(&node_id).copied()
+    }
+
+    /// Discards the outputs that were not used in the last run.
+    fn start_run(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+
+impl<'lua> CachedInterpreter<'lua> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables or disables memoizing node outputs by the contents of their
+    /// inputs. When enabled, dirty nodes whose inputs are the same as in a
+    /// recent run reuse their previous outputs instead of being evaluated.
+    ///
+    /// Memoization assumes nodes always produce the same outputs for the same
+    /// inputs, so it should only be used with node libraries where this holds.
+    pub fn set_memoize(&mut self, memoize: bool) {
+        if memoize != self.content_cache.is_some() {
+            self.content_cache = memoize.then(ContentCache::default);
+        }
+    }
+
+    /// Marks a node as dirty. Its cached outputs, and those of its dependent
+    /// nodes, will be discarded on the next run.
+    pub fn mark_node_dirty(&mut self, node_id: BjkNodeId) {
+        self.dirty.insert(node_id);
+    }
+
+    /// Marks the node reading the given external parameter as dirty.
+    pub fn mark_param_dirty(&mut self, param: &ExternalParameter) {
+        self.mark_node_dirty(param.node_id);
+    }
+
+    /// Marks the nodes affected by a change in the graph as dirty. See
+    /// `diff_graphs`.
+    pub fn mark_delta_dirty(&mut self, delta: &GraphDelta) {
+        self.dirty.extend(delta.dirty_nodes());
+    }
+
+    /// Discards all the cached outputs. This should be called when the node
+    /// definitions are reloaded, or the graph changes in ways that are not
+    /// tracked by marking nodes dirty.
+    pub fn invalidate_all(&mut self) {
+        self.outputs_cache.clear();
+        self.dirty.clear();
+        if let Some(content_cache) = &mut self.content_cache {
+            *content_cache = ContentCache::default();
+        }
+    }
+
+    /// Returns the output `name` of `node_id` from the last run in which the
+    /// node was evaluated, if it is a number.
+    pub fn get_output_scalar(&self, node_id: BjkNodeId, name: &str) -> Option<f64> {
+        match self.cached_output(node_id, name)? {
+            mlua::Value::Number(x) => Some(x),
+            mlua::Value::Integer(i) => Some(i as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns the output `name` of `node_id` from the last run in which the
+    /// node was evaluated, if it is a vector.
+    pub fn get_output_vec3(&self, node_id: BjkNodeId, name: &str) -> Option<Vec3> {
+        match self.cached_output(node_id, name)? {
+            mlua::Value::Vector(v) => Some(Vec3::new(v.x(), v.y(), v.z())),
+            _ => None,
+        }
+    }
+
+    fn cached_output(&self, node_id: BjkNodeId, name: &str) -> Option<mlua::Value<'lua>> {
+        self.outputs_cache.get(&node_id)?.get(name).ok()
+    }
+
+    /// Saves the current state of the cache. Outputs are not copied: The
+    /// snapshot shares the Lua values with the cache, so taking one is cheap.
+    pub fn snapshot(&self) -> CacheSnapshot<'lua> {
+        CacheSnapshot {
+            outputs_cache: self.outputs_cache.clone(),
+            dirty: self.dirty.clone(),
+            values: self.values.clone(),
+        }
+    }
+
+    /// Restores a state saved with `snapshot`. The next run only evaluates
+    /// the nodes that were not cached when the snapshot was taken, as long as
+    /// the graph is also restored to the same version. Otherwise, the changes
+    /// need to be marked dirty, e.g. using `mark_delta_dirty`.
+    pub fn restore(&mut self, snapshot: CacheSnapshot<'lua>) {
+        self.outputs_cache = snapshot.outputs_cache;
+        self.dirty = snapshot.dirty;
+        self.values = snapshot.values;
+    }
+
+    /// Runs the graph, like `run_graph` does, but only evaluating the nodes
+    /// that are not cached from previous runs.
+    pub fn run(
+        &mut self,
+        lua: &'lua mlua::Lua,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        node_definitions: &NodeDefinitions,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    ) -> Result<ProgramResult> {
+        self.run_with_options(
+            lua,
+            graph,
+            target_node,
+            external_param_values,
+            node_definitions,
+            gizmos_state,
+            RunGraphOptions::default(),
+        )
+    }
+
+    /// Like `run`, with additional options. See `RunGraphOptions`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_with_options(
+        &mut self,
+        lua: &'lua mlua::Lua,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        node_definitions: &NodeDefinitions,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+        options: RunGraphOptions<'_>,
+    ) -> Result<ProgramResult> {
+        if let Some(gizmos_state) = &gizmos_state {
+            self.dirty.extend(gizmos_state.keys());
+        }
+        self.invalidate_dirty(graph);
+        if let Some(content_cache) = &mut self.content_cache {
+            content_cache.start_run();
+        }
+
+        let mut results = run_graph_impl(
+            lua,
+            graph,
+            &[target_node],
+            RunInputs {
+                external_param_values,
+                gizmos_state,
+            },
+            node_definitions,
+            options,
+            RunCaches {
+                outputs: &mut self.outputs_cache,
+                content: self.content_cache.as_mut(),
+                keep_outputs: true,
+            },
+        )?;
+        let result = results.remove(0);
+        self.values = result.updated_values.clone();
+        Ok(result)
+    }
+
+    /// Discards the cached outputs for all the dirty nodes, following
+    /// `Connection` edges forward to their dependents.
+    fn invalidate_dirty(&mut self, graph: &BjkGraph) {
+        // Nodes that were removed from the graph will never be used again.
+        self.outputs_cache
+            .retain(|node_id, _| graph.nodes.contains_key(*node_id));
+
+        let dependents = dependents_map(graph);
+        let mut visited = HashSet::new();
+        let mut pending = self.dirty.drain().collect_vec();
+        while let Some(node_id) = pending.pop() {
+            if visited.insert(node_id) {
+                self.outputs_cache.remove(&node_id);
+                if let Some(deps) = dependents.get(&node_id) {
+                    pending.extend(deps.iter().copied());
+                }
+            }
+        }
+    }
+}
+
+/// Runs the graph once for each of the `values` of the `swept` parameter,
+/// with the rest of the parameters taken from `base_params`. The nodes that
+/// don't depend on the swept parameter are only evaluated once.
+///
+/// A failed run doesn't stop the sweep: The result for each value is returned
+/// separately, in the same order as `values`.
+pub fn run_sweep(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    base_params: &ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    swept: &ExternalParameter,
+    values: &[BlackjackValue],
+) -> Vec<Result<ProgramResult>> {
+    let mut interpreter = CachedInterpreter::new();
+    let mut params = base_params.clone();
+    values
+        .iter()
+        .map(|value| {
+            params.0.insert(swept.clone(), value.clone());
+            interpreter.mark_param_dirty(swept);
+            interpreter.run(
+                lua,
+                graph,
+                target_node,
+                params.clone(),
+                node_definitions,
+                None,
+            )
+        })
+        .collect()
+}
+
+/// Runs the graph once for each of the `frames`, like the frames of an
+/// animation, where each frame has its own external parameter values. Only
+/// the nodes with a parameter that changed since the previous frame are
+/// evaluated again, along with their dependents. Gizmos are not run.
+///
+/// This is the same as `run_sweep`, but any number of parameters can change
+/// between frames. A failed frame doesn't stop the animation: The result for
+/// each frame is returned separately, in the same order as `frames`.
+pub fn run_animation(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    node_definitions: &NodeDefinitions,
+    frames: &[ExternalParameterValues],
+) -> Vec<Result<ProgramResult>> {
+    let mut interpreter = CachedInterpreter::new();
+    let mut previous: Option<&ExternalParameterValues> = None;
+    frames
+        .iter()
+        .map(|params| {
+            if let Some(previous) = previous {
+                let changed = params
+                    .0
+                    .keys()
+                    .chain(previous.0.keys())
+                    .filter(|param| params.0.get(param) != previous.0.get(param));
+                for param in changed {
+                    interpreter.mark_param_dirty(param);
+                }
+            }
+            previous = Some(params);
+            interpreter.run(
+                lua,
+                graph,
+                target_node,
+                params.clone(),
+                node_definitions,
+                None,
+            )
+        })
+        .collect()
+}
+
+/// Returns, for each node, the list of nodes that take one of its outputs as
+/// an input.
+fn dependents_map(graph: &BjkGraph) -> HashMap<BjkNodeId, Vec<BjkNodeId>> {
+    let mut dependents = HashMap::<BjkNodeId, Vec<BjkNodeId>>::new();
+    for (node_id, node) in &graph.nodes {
+        for input in &node.inputs {
+            if let DependencyKind::Connection { node: dep, .. } = &input.kind {
+                dependents.entry(*dep).or_default().push(node_id);
+            }
+        }
+    }
+    dependents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_cached_interpreter() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+        let mut interpreter = CachedInterpreter::new();
+        macro_rules! run {
+            () => {
+                interpreter
+                    .run(
+                        &lua,
+                        &graph,
+                        nodes[2],
+                        params.clone(),
+                        &node_definitions,
+                        None,
+                    )
+                    .unwrap()
+            };
+        }
+
+        run!();
+        assert_eq!(runs(&lua), 3);
+
+        // Nothing changed, the outputs are all cached.
+        run!();
+        assert_eq!(runs(&lua), 3);
+
+        // Only the dirty node and its dependents need to run again.
+        interpreter.mark_node_dirty(nodes[1]);
+        run!();
+        assert_eq!(runs(&lua), 5);
+
+        interpreter.mark_param_dirty(&ExternalParameter::new(nodes[0], "x".into()));
+        run!();
+        assert_eq!(runs(&lua), 8);
+    }
+
+    #[test]
+    pub fn test_evaluation_stats() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // Counter -> Counter -> Counter -> Add
+        let (mut graph, nodes, _) = counter_chain(3);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[2], "out", add, "a").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .scalar(add, "b", 0.0)
+            .build();
+        let mut interpreter = CachedInterpreter::new();
+        macro_rules! run {
+            () => {{
+                let result = interpreter
+                    .run(&lua, &graph, add, params.clone(), &node_definitions, None)
+                    .unwrap();
+                assert_eq!(result.stats.gizmos_run, 0);
+                (result.stats.nodes_executed, result.stats.nodes_cached)
+            }};
+        }
+
+        assert_eq!(run!(), (4, 0));
+        // Only the target's outputs are needed.
+        assert_eq!(run!(), (0, 1));
+        // Editing a parameter of the target only runs the target, using the
+        // cached outputs of the node before it.
+        interpreter.mark_param_dirty(&ExternalParameter::new(add, "b".into()));
+        assert_eq!(run!(), (1, 1));
+        interpreter.mark_node_dirty(nodes[1]);
+        assert_eq!(run!(), (3, 1));
+        interpreter.mark_param_dirty(&ExternalParameter::new(nodes[0], "x".into()));
+        assert_eq!(run!(), (4, 0));
+    }
+
+    #[test]
+    pub fn test_cache_snapshot() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, mut params) = counter_chain(3);
+        let param = ExternalParameter::new(nodes[0], "x".into());
+        let mut interpreter = CachedInterpreter::new();
+        macro_rules! run {
+            () => {
+                interpreter
+                    .run(
+                        &lua,
+                        &graph,
+                        nodes[2],
+                        params.clone(),
+                        &node_definitions,
+                        None,
+                    )
+                    .unwrap()
+            };
+        }
+
+        run!();
+        let snapshot = interpreter.snapshot();
+        assert_eq!(runs(&lua), 3);
+
+        params.0.insert(param.clone(), BlackjackValue::Scalar(2.0));
+        interpreter.mark_param_dirty(&param);
+        run!();
+        assert_eq!(runs(&lua), 6);
+
+        // Undo the change. Restoring the snapshot doesn't evaluate anything.
+        interpreter.restore(snapshot);
+        params = interpreter.snapshot().values().clone();
+        assert_eq!(params.0[&param], BlackjackValue::Scalar(1.0));
+        run!();
+        assert_eq!(runs(&lua), 6);
+    }
+
+    #[test]
+    pub fn test_cached_output_accessors() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(2);
+        let mut interpreter = CachedInterpreter::new();
+        assert_eq!(interpreter.get_output_scalar(nodes[1], "out"), None);
+        interpreter
+            .run(&lua, &graph, nodes[1], params, &node_definitions, None)
+            .unwrap();
+
+        assert_eq!(interpreter.get_output_scalar(nodes[0], "out"), Some(2.0));
+        assert_eq!(interpreter.get_output_scalar(nodes[1], "out"), Some(3.0));
+        assert_eq!(interpreter.get_output_scalar(nodes[1], "missing"), None);
+        // Type mismatches are not an error.
+        assert_eq!(interpreter.get_output_vec3(nodes[1], "out"), None);
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// A problem found in a graph by `check_graph`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The node the problem was found in, if it is specific to a node.
+    pub node_id: Option<BjkNodeId>,
+    /// The input parameter the problem was found in, if any.
+    pub param_name: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.node_id, &self.param_name) {
+            (Some(node_id), Some(param_name)) => {
+                write!(f, "{}.{param_name}: ", node_id.display_id())?
+            }
+            (Some(node_id), None) => write!(f, "{}: ", node_id.display_id())?,
+            _ => {}
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Checks that a graph can be run, without evaluating any of its nodes. The
+/// following is checked:
+/// - Every node has a node definition.
+/// - Every input is either connected to an existing output of the same type,
+///   or has a valid value in `external_params`. Inputs without a value are
+///   fine when `options` makes them take their default, as long as their node
+///   definition declares them.
+/// - The types of connected inputs and outputs also match in the node
+///   definitions of both nodes.
+/// - The graph's default node exists and has a return value.
+/// - The graph doesn't contain cycles.
+///
+/// All the problems found are returned, instead of stopping at the first one.
+/// An empty list means the graph is ready to run.
+pub fn check_graph(
+    graph: &BjkGraph,
+    node_definitions: &NodeDefinitions,
+    external_params: &ExternalParameterValues,
+    options: &EvaluationOptions,
+) -> Result<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    // Traversing the graph is not possible when connections are broken.
+    let mut has_broken_connections = false;
+    let mut report = |node_id: Option<BjkNodeId>, param_name: Option<&str>, message: String| {
+        diagnostics.push(Diagnostic {
+            node_id,
+            param_name: param_name.map(|p| p.to_owned()),
+            message,
+        })
+    };
+
+    for (node_id, node) in &graph.nodes {
+        if node_definitions.node_def(&node.op_name).is_none() {
+            report(
+                Some(node_id),
+                None,
+                format!("Node definition not found for {}", node.op_name),
+            );
+        }
+
+        for input in &node.inputs {
+            let param_name = Some(input.name.as_str());
+            match &input.kind {
+                DependencyKind::External { promoted } => {
+                    let param = ExternalParameter::new(node_id, input.name.clone());
+                    match external_params.0.get(&param) {
+                        Some(BlackjackValue::Unset) => {}
+                        Some(value) if input.data_type.coerce_value(value.clone()).is_none() => {
+                            report(
+                                Some(node_id),
+                                param_name,
+                                format!(
+                                    "Expected a value of type {:?}, got {value:?}",
+                                    input.data_type
+                                ),
+                            )
+                        }
+                        Some(_) => {}
+                        None if options.use_default_params
+                            || (options.default_internal_params && promoted.is_none()) =>
+                        {
+                            let declared = node_definitions
+                                .node_def(&node.op_name)
+                                .map(|def| def.inputs.iter().any(|i| i.name == input.name));
+                            if declared == Some(false) {
+                                report(
+                                    Some(node_id),
+                                    param_name,
+                                    "Input has no external value, and no default in its node \
+                                     definition"
+                                        .into(),
+                                )
+                            }
+                        }
+                        None => report(
+                            Some(node_id),
+                            param_name,
+                            "Input is not connected, and has no external value".into(),
+                        ),
+                    }
+                }
+                DependencyKind::Connection {
+                    node: src_node,
+                    param_name: src_param,
+                    ..
+                } => {
+                    let src_output = graph
+                        .nodes
+                        .get(*src_node)
+                        .map(|src| src.outputs.iter().find(|o| &o.name == src_param));
+                    match src_output {
+                        None => {
+                            has_broken_connections = true;
+                            report(
+                                Some(node_id),
+                                param_name,
+                                format!(
+                                    "Connected to node {}, which is not in the graph",
+                                    src_node.display_id()
+                                ),
+                            )
+                        }
+                        Some(None) => report(
+                            Some(node_id),
+                            param_name,
+                            format!(
+                                "Connected to output '{src_param}' of node {}, which does not exist",
+                                src_node.display_id()
+                            ),
+                        ),
+                        Some(Some(output)) if output.data_type != input.data_type => report(
+                            Some(node_id),
+                            param_name,
+                            format!(
+                                "Input is {:?}, but its corresponding output is {:?}",
+                                input.data_type, output.data_type
+                            ),
+                        ),
+                        Some(Some(_)) => {}
+                    }
+
+                    // The graph may have been saved with different versions
+                    // of the nodes, so the definitions are checked too.
+                    let declared_input = node_definitions.input_type(&node.op_name, &input.name);
+                    let declared_output = graph
+                        .nodes
+                        .get(*src_node)
+                        .and_then(|src| node_definitions.output_type(&src.op_name, src_param));
+                    if let (Some(expected), Some(found)) = (declared_input, declared_output) {
+                        if expected != found {
+                            report(
+                                Some(node_id),
+                                param_name,
+                                format!(
+                                    "Expected {expected:?} from output '{src_param}' of node {}, \
+                                     but its definition declares it as {found:?}",
+                                    src_node.display_id()
+                                ),
+                            )
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    match graph.default_node {
+        Some(target) if graph.nodes.contains_key(target) => {
+            if graph.nodes[target].return_value.is_none() {
+                report(
+                    Some(target),
+                    None,
+                    "The target node has no return value".into(),
+                );
+            }
+            if let Some(cycle) = (!has_broken_connections)
+                .then(|| find_cycle(graph, target))
+                .flatten()
+            {
+                report(
+                    Some(target),
+                    None,
+                    InterpreterError::Cycle(cycle).to_string(),
+                );
+            }
+        }
+        Some(target) => report(
+            None,
+            None,
+            format!(
+                "The default node {} is not in the graph",
+                target.display_id()
+            ),
+        ),
+        None => report(None, None, "The graph has no default node".into()),
+    }
+
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_check_graph() {
+        let (_lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, params) = counter_chain(2);
+        let messages = |graph: &BjkGraph, params: &ExternalParameterValues| {
+            check_graph(
+                graph,
+                &node_definitions,
+                params,
+                &EvaluationOptions::default(),
+            )
+            .unwrap()
+            .iter()
+            .map(|d| d.to_string())
+            .collect_vec()
+        };
+
+        assert_eq!(
+            messages(&graph, &params),
+            vec!["The graph has no default node"]
+        );
+
+        graph.default_node = Some(nodes[1]);
+        graph.nodes[nodes[1]].return_value = Some("out".into());
+        assert!(messages(&graph, &params).is_empty());
+
+        // All the problems are reported at once.
+        graph.nodes[nodes[1]].return_value = None;
+        graph.add_node("NotInstalled", None);
+        graph.nodes[nodes[1]].inputs[0].kind = DependencyKind::Connection {
+            node: nodes[0],
+            param_name: "missing".into(),
+            optional: false,
+        };
+        let diagnostics = messages(&graph, &ExternalParameterValues::default());
+        assert_eq!(diagnostics.len(), 4, "{diagnostics:?}");
+
+        // Connections are checked against the types in the node definitions,
+        // even when the types in the graph match.
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph
+            .add_output(cube, "out_mesh", DataType::Scalar)
+            .unwrap();
+        let counter = graph.add_node("Counter", None);
+        graph
+            .add_input(counter, "x", DataType::Scalar, None)
+            .unwrap();
+        graph
+            .add_connection(cube, "out_mesh", counter, "x")
+            .unwrap();
+        graph.default_node = Some(cube);
+        assert_eq!(
+            messages(&graph, &ExternalParameterValues::default()),
+            vec![format!(
+                "{}.x: Expected Scalar from output 'out_mesh' of node {}, but its definition \
+                 declares it as Mesh",
+                counter.display_id(),
+                cube.display_id()
+            )]
+        );
+
+        // Inputs without a value are fine when they take their default.
+        let (mut graph, nodes, _) = counter_chain(1);
+        graph.default_node = Some(nodes[0]);
+        graph.nodes[nodes[0]].return_value = Some("out".into());
+        let params = ExternalParameterValues::default();
+        let check = |graph: &BjkGraph, options: &EvaluationOptions| {
+            check_graph(graph, &node_definitions, &params, options)
+                .unwrap()
+                .iter()
+                .map(|d| d.to_string())
+                .collect_vec()
+        };
+        assert_eq!(
+            check(&graph, &EvaluationOptions::default()),
+            vec![format!(
+                "{}.x: Input is not connected, and has no external value",
+                nodes[0].display_id()
+            )]
+        );
+        let use_defaults = EvaluationOptions {
+            use_default_params: true,
+            ..Default::default()
+        };
+        assert!(check(&graph, &use_defaults).is_empty());
+        // Unless the node definition doesn't declare the input.
+        graph
+            .add_input(nodes[0], "undeclared", DataType::Scalar, None)
+            .unwrap();
+        assert_eq!(
+            check(&graph, &use_defaults),
+            vec![format!(
+                "{}.undeclared: Input has no external value, and no default in its node \
+                 definition",
+                nodes[0].display_id()
+            )]
+        );
+    }
+}
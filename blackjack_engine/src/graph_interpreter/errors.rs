@@ -0,0 +1,255 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// A node that failed to evaluate during a run using
+/// `ErrorPolicy::ContinueWithPlaceholder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeFailure {
+    pub node_id: BjkNodeId,
+    /// The error, including its full chain of causes.
+    pub message: String,
+}
+
+/// A problem found while running a graph that didn't prevent the run from
+/// completing, like a parameter falling back to its default value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub node_id: BjkNodeId,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.node_id.display_id(), self.message)
+    }
+}
+
+/// The errors a graph run can fail with, other than errors raised by the Lua
+/// code of the nodes. Runs return `anyhow::Error`, which can be downcast to
+/// this type to recover from specific failures.
+#[derive(Debug)]
+pub enum InterpreterError {
+    /// The graph uses a node that is not in the node definitions.
+    MissingNodeDef { op_name: String },
+    /// No value was provided for a node's external parameter.
+    MissingExternalParam {
+        node_id: BjkNodeId,
+        param_name: String,
+    },
+    /// The value of an external parameter is not one of the values allowed
+    /// by its `Enum` input.
+    InvalidEnumValue {
+        node_id: BjkNodeId,
+        param_name: String,
+        value: BlackjackValue,
+        allowed: Vec<String>,
+    },
+    /// A node's `op` function returned something other than a table. `got`
+    /// is the Lua type name of the returned value.
+    BadOpReturn {
+        node_id: BjkNodeId,
+        op_name: String,
+        got: &'static str,
+    },
+    /// A node's `update_gizmos` function returned something other than a
+    /// gizmo. `got` is the Lua type name of the returned value.
+    BadGizmoReturn {
+        node_id: BjkNodeId,
+        op_name: String,
+        got: &'static str,
+    },
+    /// The graph contains a cycle, as returned by `find_cycle`.
+    Cycle(Vec<BjkNodeId>),
+    /// The longest chain of connections leading to `node_id` is `depth`
+    /// connections long, which exceeds `RunLimits::max_depth`.
+    DepthExceeded {
+        node_id: BjkNodeId,
+        depth: usize,
+        max_depth: usize,
+    },
+    /// The `param_name` input of `node_id` is connected to the `src_param`
+    /// output of `src_node`, but evaluating `src_node` didn't produce a value
+    /// for it.
+    MissingOutput {
+        node_id: BjkNodeId,
+        param_name: String,
+        src_node: BjkNodeId,
+        src_param: String,
+    },
+    /// The `output` of `node_id` is NaN or infinite, or is a vector with such
+    /// a component. Only checked when requested via
+    /// `EvaluationOptions::non_finite_outputs`.
+    NonFiniteOutput {
+        node_id: BjkNodeId,
+        op_name: String,
+        output: String,
+    },
+    /// `node_id` is bypassed, but there's no single input to forward to its
+    /// `output`. `candidates` are the inputs with the output's data type,
+    /// which is empty when there are none.
+    AmbiguousBypass {
+        node_id: BjkNodeId,
+        op_name: String,
+        output: String,
+        candidates: Vec<String>,
+    },
+    /// The `param_name` input of `node_id` is an `Int`, but it received a
+    /// number with a fractional part, or a `Scalar`. Numbers read from Lua
+    /// are only scalars when they have a fractional part, which may have been
+    /// rounded away in `value`.
+    FractionalInt {
+        node_id: BjkNodeId,
+        param_name: String,
+        value: f64,
+    },
+}
+
+impl std::fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpreterError::MissingNodeDef { op_name } => {
+                write!(f, "Node definition not found for {op_name}")
+            }
+            InterpreterError::MissingExternalParam {
+                node_id,
+                param_name,
+            } => write!(
+                f,
+                "Could not retrieve external parameter named '{param_name}' from node {}",
+                node_id.display_id()
+            ),
+            InterpreterError::InvalidEnumValue {
+                node_id,
+                param_name,
+                value,
+                allowed,
+            } => write!(
+                f,
+                "Invalid value {value:?} for parameter '{param_name}' of node {}. Allowed values are: {}",
+                node_id.display_id(),
+                allowed.join(", ")
+            ),
+            InterpreterError::BadOpReturn {
+                node_id,
+                op_name,
+                got,
+            } => write!(
+                f,
+                "The `op` function of node '{op_name}' ({}) should always return a table, got {got}",
+                node_id.display_id()
+            ),
+            InterpreterError::BadGizmoReturn {
+                node_id,
+                op_name,
+                got,
+            } => write!(
+                f,
+                "The `update_gizmos` function of node '{op_name}' ({}) should return a gizmo, got {got}",
+                node_id.display_id()
+            ),
+            InterpreterError::Cycle(cycle) => write!(
+                f,
+                "The graph contains a cycle: {}",
+                cycle.iter().map(|n| n.display_id()).join(" -> ")
+            ),
+            InterpreterError::DepthExceeded {
+                node_id,
+                depth,
+                max_depth,
+            } => write!(
+                f,
+                "Node {} depends on a chain of {depth} connections, but the maximum is {max_depth}",
+                node_id.display_id()
+            ),
+            InterpreterError::MissingOutput {
+                node_id,
+                param_name,
+                src_node,
+                src_param,
+            } => write!(
+                f,
+                "Input '{param_name}' of node {} is connected to output '{src_param}' of node {}, \
+                 but that node produced no value for it",
+                node_id.display_id(),
+                src_node.display_id()
+            ),
+            InterpreterError::NonFiniteOutput {
+                node_id,
+                op_name,
+                output,
+            } => write!(
+                f,
+                "Output '{output}' of node '{op_name}' ({}) is not a finite number",
+                node_id.display_id()
+            ),
+            InterpreterError::AmbiguousBypass {
+                node_id,
+                op_name,
+                output,
+                candidates,
+            } if candidates.is_empty() => write!(
+                f,
+                "Node '{op_name}' ({}) is bypassed, but has no input to forward to its \
+                 output '{output}'",
+                node_id.display_id()
+            ),
+            InterpreterError::AmbiguousBypass {
+                node_id,
+                op_name,
+                output,
+                candidates,
+            } => write!(
+                f,
+                "Node '{op_name}' ({}) is bypassed, but any of its inputs {} could be \
+                 forwarded to its output '{output}'",
+                node_id.display_id(),
+                candidates.join(", ")
+            ),
+            InterpreterError::FractionalInt {
+                node_id,
+                param_name,
+                value,
+            } => write!(
+                f,
+                "Parameter '{param_name}' of node {} is an integer, but got {value}",
+                node_id.display_id()
+            ),
+        }
+    }
+}
+impl std::error::Error for InterpreterError {}
+
+/// The error returned by `run_graph_partial`. Along with the `error` itself,
+/// it contains the renderables of the nodes that were evaluated before the
+/// failure, so hosts can keep showing the parts of the graph that still work.
+pub struct PartialRunError {
+    /// The renderable output of every node that was evaluated successfully,
+    /// as in `ProgramResult::all_outputs`.
+    pub partial: HashMap<BjkNodeId, RenderableThing>,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Debug for PartialRunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PartialRunError")
+            .field("partial", &self.partial.keys().collect_vec())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for PartialRunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+impl std::error::Error for PartialRunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
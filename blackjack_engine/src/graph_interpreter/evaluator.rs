@@ -0,0 +1,134 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::lua_engine::LuaRuntime;
+
+/// Something that can run a graph, like `run_graph` does. Host code written
+/// against this trait, instead of calling `run_graph` directly, can be tested
+/// with a `MockEvaluator`, without loading a Lua node library.
+pub trait GraphEvaluator {
+    fn run_graph(
+        &mut self,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    ) -> Result<ProgramResult>;
+}
+
+impl GraphEvaluator for LuaRuntime {
+    fn run_graph(
+        &mut self,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    ) -> Result<ProgramResult> {
+        self.run(graph, target_node, external_param_values, gizmos_state)
+    }
+}
+
+/// A function producing the canned result of a `MockEvaluator` for a target.
+pub type MockResultFn =
+    Box<dyn FnMut(&BjkGraph, &ExternalParameterValues) -> Result<ProgramResult>>;
+
+/// A `GraphEvaluator` returning canned results, for tests. Nothing is
+/// evaluated: Running a target calls the function registered for it with
+/// `on_target`, and running any other target fails.
+///
+/// Every run is recorded in `calls`, so tests can check what the host asked
+/// for.
+#[derive(Default)]
+pub struct MockEvaluator {
+    results: HashMap<BjkNodeId, MockResultFn>,
+    pub calls: Vec<MockCall>,
+}
+
+/// A run requested from a `MockEvaluator`.
+#[derive(Debug, Clone)]
+pub struct MockCall {
+    pub target_node: BjkNodeId,
+    pub external_param_values: ExternalParameterValues,
+    pub gizmos_enabled: bool,
+}
+
+impl MockEvaluator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the function producing the result for runs of `target_node`.
+    /// Replaces any function set before for the same target.
+    pub fn on_target(
+        &mut self,
+        target_node: BjkNodeId,
+        result: impl FnMut(&BjkGraph, &ExternalParameterValues) -> Result<ProgramResult> + 'static,
+    ) -> &mut Self {
+        self.results.insert(target_node, Box::new(result));
+        self
+    }
+}
+
+impl GraphEvaluator for MockEvaluator {
+    fn run_graph(
+        &mut self,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    ) -> Result<ProgramResult> {
+        self.calls.push(MockCall {
+            target_node,
+            external_param_values: external_param_values.clone(),
+            gizmos_enabled: gizmos_state.is_some(),
+        });
+        let result = self.results.get_mut(&target_node).ok_or_else(|| {
+            anyhow!(
+                "The mock evaluator has no result for node {}",
+                target_node.display_id()
+            )
+        })?;
+        result(graph, &external_param_values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_mock_evaluator() {
+        // Host code only depending on the evaluator trait.
+        fn preview(evaluator: &mut impl GraphEvaluator, graph: &BjkGraph) -> Result<usize> {
+            let target = graph
+                .default_node
+                .ok_or_else(|| anyhow!("No default node"))?;
+            let result = evaluator.run_graph(graph, target, Default::default(), None)?;
+            Ok(match result.renderable {
+                Some(RenderableThing::HalfEdgeMesh(mesh)) => mesh.read_connectivity().num_faces(),
+                _ => 0,
+            })
+        }
+
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", Some("out_mesh".into()));
+        graph.default_node = Some(cube);
+
+        let mut evaluator = MockEvaluator::new();
+        assert!(preview(&mut evaluator, &graph).is_err());
+        evaluator.on_target(cube, |_, params| {
+            let mesh = halfedge::primitives::Box::build(Vec3::ZERO, Vec3::ONE);
+            Ok(ProgramResult::new(
+                Some(RenderableThing::HalfEdgeMesh(mesh)),
+                params.clone(),
+            ))
+        });
+        assert_eq!(preview(&mut evaluator, &graph).unwrap(), 6);
+        assert_eq!(evaluator.calls.len(), 2);
+        assert!(evaluator.calls.iter().all(|call| call.target_node == cube));
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// A flag that can be used to cancel a graph run from another thread. The
+/// token is checked before each node is evaluated, and periodically while a
+/// node's Lua code is running.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation of any runs using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error returned by a graph run that was cancelled through its
+/// `CancelToken`. Use `anyhow::Error::is` to tell it apart from other errors.
+#[derive(Debug)]
+pub struct Cancelled {
+    pub progress: RunProgress,
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Graph evaluation was cancelled {}", self.progress)
+    }
+}
+impl std::error::Error for Cancelled {}
+
+/// One of the limits of an `ExecutionBudget`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetLimit {
+    Timeout(Duration),
+    Steps(u64),
+    Memory(usize),
+}
+
+/// The error returned by a graph run that exceeded one of the limits of its
+/// `ExecutionBudget`.
+#[derive(Debug)]
+pub struct BudgetExceeded {
+    pub limit: BudgetLimit,
+    pub progress: RunProgress,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.limit {
+            BudgetLimit::Timeout(timeout) => write!(
+                f,
+                "Graph evaluation exceeded its time budget of {timeout:?}"
+            )?,
+            BudgetLimit::Steps(max_steps) => write!(
+                f,
+                "Graph evaluation exceeded its budget of {max_steps} steps"
+            )?,
+            BudgetLimit::Memory(max_memory) => write!(
+                f,
+                "Graph evaluation exceeded its memory budget of {max_memory} bytes"
+            )?,
+        }
+        write!(f, " {}", self.progress)
+    }
+}
+impl std::error::Error for BudgetExceeded {}
+
+/// Limits the amount of work a graph run can perform. When any of the limits
+/// is exceeded, the run is aborted with a `BudgetExceeded` error. This
+/// protects hosts against node definitions that loop forever.
+///
+/// Limits are enforced from inside the Lua VM, so time spent in Rust code
+/// called by the nodes is only checked once control returns to Lua.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecutionBudget {
+    /// The maximum wall-clock time the run can take.
+    pub timeout: Option<Duration>,
+    /// The maximum number of steps the Lua VM can run. Luau counts a step at
+    /// every function call and loop iteration.
+    pub max_steps: Option<u64>,
+    /// The maximum memory the Lua state can use during the run, in bytes.
+    /// This includes the memory that was already in use before the run, as
+    /// reported by `MemoryUsage::before`. Luau can't limit allocations, so
+    /// this is checked at the same points as `max_steps`, and the run can go
+    /// past the limit by what it allocates between two steps.
+    pub max_memory: Option<usize>,
+}
+
+impl ExecutionBudget {
+    pub fn is_unlimited(&self) -> bool {
+        self.timeout.is_none() && self.max_steps.is_none() && self.max_memory.is_none()
+    }
+
+    /// Installs an interrupt in the given Lua state enforcing this budget, and
+    /// aborting when the `cancel` token is set. The interrupt is removed when
+    /// the returned guard is dropped.
+    pub(super) fn install(
+        self,
+        lua: &mlua::Lua,
+        cancel: Option<CancelToken>,
+    ) -> Option<InterruptGuard<'_>> {
+        if self.is_unlimited() && cancel.is_none() {
+            return None;
+        }
+        let start = Instant::now();
+        let state = Rc::new(InterruptState::default());
+        lua.set_interrupt({
+            let state = state.clone();
+            move |lua| {
+                if cancel.as_ref().map_or(false, |c| c.is_cancelled()) {
+                    return Err(mlua::Error::external(Cancelled {
+                        progress: RunProgress::default(),
+                    }));
+                }
+                state.steps.set(state.steps.get() + 1);
+                if let Some(max_steps) = self.max_steps {
+                    if state.steps.get() > max_steps {
+                        state.exceeded.set(Some(BudgetLimit::Steps(max_steps)));
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "Graph evaluation exceeded its budget of {max_steps} steps"
+                        )));
+                    }
+                }
+                if let Some(timeout) = self.timeout {
+                    if start.elapsed() > timeout {
+                        state.exceeded.set(Some(BudgetLimit::Timeout(timeout)));
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "Graph evaluation exceeded its time budget of {timeout:?}"
+                        )));
+                    }
+                }
+                if let Some(max_memory) = self.max_memory {
+                    if lua.used_memory() > max_memory {
+                        state.exceeded.set(Some(BudgetLimit::Memory(max_memory)));
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "Graph evaluation exceeded its memory budget of {max_memory} bytes"
+                        )));
+                    }
+                }
+                Ok(mlua::VmState::Continue)
+            }
+        });
+        Some(InterruptGuard { lua, state })
+    }
+}
+
+/// What the interrupt installed by `ExecutionBudget::install` has seen so
+/// far. Shared with the interrupt, so the run can report it.
+#[derive(Default)]
+pub(super) struct InterruptState {
+    pub(super) steps: Cell<u64>,
+    /// The limit that made the interrupt fail, if any.
+    pub(super) exceeded: Cell<Option<BudgetLimit>>,
+}
+
+/// Removes the interrupt from a Lua state when dropped.
+pub(super) struct InterruptGuard<'lua> {
+    lua: &'lua mlua::Lua,
+    pub(super) state: Rc<InterruptState>,
+}
+
+impl Drop for InterruptGuard<'_> {
+    fn drop(&mut self) {
+        self.lua.remove_interrupt();
+    }
+}
+
+/// The memory used by the Lua state, in bytes, before and after evaluating
+/// the graph. See `RunDiagnostics::report_memory`.
+///
+/// The peak usage during the run is not available, but it can be bounded
+/// with `ExecutionBudget::max_memory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub before: usize,
+    pub after: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_execution_budget() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", None);
+        let spin = graph.add_node("Spin", None);
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        graph.add_input(spin, "mesh", DataType::Mesh, None).unwrap();
+        graph
+            .add_connection(cube, "out_mesh", spin, "mesh")
+            .unwrap();
+
+        for budget in [
+            ExecutionBudget {
+                max_steps: Some(1000),
+                ..Default::default()
+            },
+            ExecutionBudget {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        ] {
+            let err = run_graph_with_options(
+                &lua,
+                &graph,
+                spin,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    limits: RunLimits {
+                        budget,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .err()
+            .unwrap();
+            let exceeded = err.downcast_ref::<BudgetExceeded>().unwrap();
+            assert_eq!(exceeded.progress.node_id, Some(spin));
+            assert_eq!(exceeded.progress.last_completed, Some(cube));
+            assert_eq!(exceeded.progress.nodes_completed, 1);
+            assert!(exceeded.progress.steps > 0);
+            match (exceeded.limit, budget) {
+                (BudgetLimit::Steps(steps), ExecutionBudget { max_steps, .. }) => {
+                    assert_eq!(Some(steps), max_steps);
+                }
+                (
+                    BudgetLimit::Timeout(timeout),
+                    ExecutionBudget {
+                        timeout: expected, ..
+                    },
+                ) => {
+                    assert_eq!(Some(timeout), expected);
+                    assert!(exceeded.progress.elapsed >= timeout);
+                }
+                (BudgetLimit::Memory(_), _) => unreachable!(),
+            }
+        }
+
+        // A budget without any steps stops the run at the first node.
+        let err = run_graph_with_options(
+            &lua,
+            &graph,
+            spin,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                limits: RunLimits {
+                    budget: ExecutionBudget {
+                        max_steps: Some(0),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        let exceeded = err.downcast_ref::<BudgetExceeded>().unwrap();
+        assert_eq!(exceeded.limit, BudgetLimit::Steps(0));
+        assert_eq!(exceeded.progress.node_id, Some(cube));
+        assert_eq!(exceeded.progress.nodes_completed, 0);
+    }
+
+    #[test]
+    pub fn test_memory_budget() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Hog = {
+                    label = "Hog",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        HOARD = {}
+                        for i = 1, 1000000 do
+                            HOARD[i] = i
+                        end
+                        return {}
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let hog = graph.add_node("Hog", None);
+        let run = |max_memory| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                hog,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    limits: RunLimits {
+                        budget: ExecutionBudget {
+                            max_memory,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    diagnostics: RunDiagnostics {
+                        report_memory: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        let usage = run(None).unwrap().memory_usage.unwrap();
+        assert!(usage.after > usage.before);
+
+        lua.globals().set("HOARD", mlua::Value::Nil).unwrap();
+        lua.gc_collect().unwrap();
+        let max_memory = lua.used_memory() + 64 * 1024;
+        let err = run(Some(max_memory)).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<BudgetExceeded>().unwrap().limit,
+            BudgetLimit::Memory(max_memory)
+        );
+        // The limit only applies to the run.
+        assert!(run(None).is_ok());
+    }
+
+    #[test]
+    pub fn test_gizmo_budget() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                SpinGizmo = {
+                    label = "SpinGizmo",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        return {}
+                    end,
+                    gizmos = {
+                        {
+                            update_params = function(inputs, gizmo)
+                                return inputs
+                            end,
+                            update_gizmos = function(inputs, gizmo, outputs)
+                                while true do end
+                            end,
+                            affected_params = function()
+                                return nil
+                            end,
+                        },
+                    },
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("SpinGizmo", None);
+        let mut gizmos_state = SecondaryMap::new();
+        gizmos_state.insert(node, GizmoState::default());
+
+        let err = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            Some(gizmos_state),
+            RunGraphOptions {
+                limits: RunLimits {
+                    budget: ExecutionBudget {
+                        max_steps: Some(1000),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        let exceeded = err.downcast_ref::<BudgetExceeded>().unwrap();
+        assert_eq!(exceeded.limit, BudgetLimit::Steps(1000));
+        assert_eq!(exceeded.progress.node_id, Some(node));
+    }
+
+    #[test]
+    pub fn test_cancel_token() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let err = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[2],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                limits: RunLimits {
+                    cancel: Some(cancel),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        let cancelled = err.downcast_ref::<Cancelled>().unwrap();
+        assert_eq!(cancelled.progress.node_id, None);
+        assert_eq!(cancelled.progress.last_completed, None);
+        assert_eq!(runs(&lua), 0);
+
+        // Cancelling from another thread interrupts a node that never ends.
+        let mut graph = BjkGraph::new();
+        let spin = graph.add_node("Spin", None);
+        let cancel = CancelToken::new();
+        let handle = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.cancel();
+            }
+        });
+        let err = run_graph_with_options(
+            &lua,
+            &graph,
+            spin,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                limits: RunLimits {
+                    cancel: Some(cancel),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        let cancelled = err.downcast_ref::<Cancelled>().unwrap();
+        assert_eq!(cancelled.progress.node_id, Some(spin));
+        assert!(cancelled.progress.steps > 0);
+        handle.join().unwrap();
+
+        // Nodes catching errors can't swallow the cancellation, since the
+        // interrupt keeps failing until the node returns.
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                CatchSpin = {
+                    label = "CatchSpin",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        while true do
+                            pcall(function()
+                                while true do end
+                            end)
+                        end
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("CatchSpin", None);
+        let cancel = CancelToken::new();
+        let handle = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.cancel();
+            }
+        });
+        let err = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                limits: RunLimits {
+                    cancel: Some(cancel),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.downcast_ref::<Cancelled>().unwrap().progress.node_id,
+            Some(node)
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_max_depth() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, params) = counter_chain(3);
+        assert_eq!(dependency_depth(&graph, nodes[2]), 2);
+
+        let run = |max_depth| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                nodes[2],
+                params.clone(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    limits: RunLimits {
+                        max_depth: Some(max_depth),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(run(2).is_ok());
+        let err = run(1).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::DepthExceeded {
+                depth: 2,
+                max_depth: 1,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// A line printed by the Lua code of a node. See
+/// `RunDiagnostics::capture_print`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintedLine {
+    /// The node that was being evaluated when the line was printed.
+    pub node_id: BjkNodeId,
+    pub text: String,
+}
+
+/// Replaces Lua's `print` function to capture its output. The original
+/// function is restored when dropped.
+pub(super) struct PrintCapture<'lua> {
+    lua: &'lua mlua::Lua,
+    original: mlua::Value<'lua>,
+    pub(super) lines: Rc<RefCell<Vec<PrintedLine>>>,
+    /// The node being evaluated, which printed lines are attributed to.
+    pub(super) current_node: Rc<Cell<Option<BjkNodeId>>>,
+}
+
+impl<'lua> PrintCapture<'lua> {
+    pub(super) fn install(lua: &'lua mlua::Lua) -> Result<Self> {
+        let original = lua.globals().get("print")?;
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let current_node = Rc::new(Cell::new(None));
+        let print = {
+            let (lines, current_node) = (lines.clone(), current_node.clone());
+            lua.create_function(move |lua, args: mlua::Variadic<mlua::Value>| {
+                // Arguments are formatted like the builtin `print` does.
+                let tostring: mlua::Function = lua.globals().get("tostring")?;
+                let text = args
+                    .into_iter()
+                    .map(|arg| tostring.call::<_, String>(arg))
+                    .collect::<mlua::Result<Vec<_>>>()?
+                    .join("\t");
+                if let Some(node_id) = current_node.get() {
+                    lines.borrow_mut().push(PrintedLine { node_id, text });
+                }
+                Ok(())
+            })?
+        };
+        lua.globals().set("print", print)?;
+        Ok(Self {
+            lua,
+            original,
+            lines,
+            current_node,
+        })
+    }
+}
+
+impl Drop for PrintCapture<'_> {
+    fn drop(&mut self) {
+        // NOTE: Errors are ignored, there's no way to report them here.
+        let _ = self.lua.globals().set("print", self.original.clone());
+    }
+}
+
+/// Sets the `bjk.context` global for the duration of a run. See
+/// `EvaluationOptions::context`. The previous value is restored when dropped.
+pub(super) struct ContextGlobal<'lua> {
+    bjk: Table<'lua>,
+    previous: mlua::Value<'lua>,
+}
+
+impl<'lua> ContextGlobal<'lua> {
+    pub(super) fn install(
+        lua: &'lua mlua::Lua,
+        values: &HashMap<String, BlackjackValue>,
+    ) -> Result<Self> {
+        let bjk = match lua.globals().get::<_, Option<Table>>("bjk")? {
+            Some(bjk) => bjk,
+            None => {
+                let bjk = lua.create_table()?;
+                lua.globals().set("bjk", bjk.clone())?;
+                bjk
+            }
+        };
+        let context = lua.create_table()?;
+        for (key, value) in values {
+            context.set(key.as_str(), value.clone().into_lua(lua)?)?;
+        }
+        let freeze: mlua::Function = lua.globals().get::<_, Table>("table")?.get("freeze")?;
+        freeze.call::<_, ()>(context.clone())?;
+        let previous = bjk.get("context")?;
+        bjk.set("context", context)?;
+        Ok(Self { bjk, previous })
+    }
+}
+
+impl Drop for ContextGlobal<'_> {
+    fn drop(&mut self) {
+        // NOTE: Errors are ignored, there's no way to report them here.
+        let _ = self.bjk.set("context", self.previous.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_context_global() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Scaled = {
+                    label = "Scaled",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        SCALE = bjk.context.unit_scale
+                        WRITABLE = pcall(function()
+                            bjk.context.unit_scale = 0
+                        end)
+                        return {}
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Scaled", None);
+        let context = HashMap::from([("unit_scale".to_owned(), BlackjackValue::Scalar(2.5))]);
+        run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    context: Some(&context),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SCALE").unwrap(), 2.5);
+        assert!(!lua.globals().get::<_, bool>("WRITABLE").unwrap());
+        // The context is only available during the run.
+        assert!(lua
+            .load("return bjk.context")
+            .eval::<Option<Table>>()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    pub fn test_capture_print() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Print = {
+                    label = "Print",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = { { name = "out", type = "scalar" } },
+                    op = function(inputs)
+                        print("x is", inputs.x)
+                        return { out = inputs.x }
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let mut nodes = Vec::new();
+        for _ in 0..2 {
+            let node = graph.add_node("Print", None);
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+            if let Some(prev) = nodes.last() {
+                graph.add_connection(*prev, "out", node, "x").unwrap();
+            }
+            nodes.push(node);
+        }
+        let params = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 2.0)
+            .build();
+
+        let original_print = lua.globals().get::<_, mlua::Function>("print").unwrap();
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[1],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                diagnostics: RunDiagnostics {
+                    capture_print: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let printed = result.printed.unwrap();
+        assert_eq!(printed.len(), 2);
+        assert_eq!(printed[0].node_id, nodes[0]);
+        assert_eq!(printed[0].text, "x is\t2");
+        assert_eq!(printed[1].node_id, nodes[1]);
+
+        // The original print function is restored after the run.
+        assert!(original_print == lua.globals().get::<_, mlua::Function>("print").unwrap());
+    }
+}
@@ -0,0 +1,1774 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// Returns the type of `value` as Lua code would see it. mlua reads numbers
+/// without a fractional part as integers, but Lua has a single number type.
+pub(super) fn lua_type_name(value: &mlua::Value) -> &'static str {
+    match value {
+        mlua::Value::Integer(_) => "number",
+        other => other.type_name(),
+    }
+}
+
+/// Wraps an error raised while calling one of a node's Lua functions, adding
+/// the node and function names to the message. The message also includes the
+/// Lua traceback when mlua provides one. The original `mlua::Error` is kept as
+/// the error's source.
+pub(super) fn node_lua_error(
+    err: mlua::Error,
+    op_name: &str,
+    node_id: BjkNodeId,
+    fn_name: &str,
+) -> anyhow::Error {
+    let description = describe_lua_error(&err);
+    anyhow::Error::new(err).context(format!(
+        "Error in '{fn_name}' of node '{op_name}' ({}): {description}",
+        node_id.display_id()
+    ))
+}
+
+pub(super) fn describe_lua_error(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::CallbackError { traceback, cause } => {
+            format!("{}\n{traceback}", describe_lua_error(cause))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Computes the key used to memoize the outputs of a node, from its op name and
+/// the contents of its inputs. Returns `None` when memoization is disabled, the
+/// node is not pure, or the key can't be computed because a dependency has no
+/// key. This means nodes depending on impure nodes are not memoized either.
+pub(super) fn content_key(
+    graph: &BjkGraph,
+    ctx: &InterpreterContext<'_, '_>,
+    node_id: BjkNodeId,
+) -> Option<u64> {
+    let content_cache = ctx.content_cache.as_ref()?;
+    let node = &graph.nodes[node_id];
+    if !ctx.node_definitions.node_def(&node.op_name)?.pure {
+        return None;
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    node.op_name.hash(&mut hasher);
+    // Nodes can behave differently when gizmos are enabled.
+    ctx.gizmo_state.is_some().hash(&mut hasher);
+    // Nodes that know their id may produce different outputs for the same
+    // inputs.
+    if ctx.inject_node_id {
+        node_id.hash(&mut hasher);
+    }
+    ctx.options_hash.hash(&mut hasher);
+    for input in &node.inputs {
+        input.name.hash(&mut hasher);
+        match &input.kind {
+            DependencyKind::Connection {
+                node, param_name, ..
+            } => {
+                content_cache.node_key(*node)?.hash(&mut hasher);
+                param_name.hash(&mut hasher);
+            }
+            DependencyKind::External { .. } => {
+                let param = ExternalParameter::new(node_id, input.name.clone());
+                hash_value(ctx.param_value(&param).ok()??, &mut hasher);
+            }
+        }
+    }
+    Some(hasher.finish())
+}
+
+/// Seeds Lua's random number generator for the evaluation of `node_id`. See
+/// `EvaluationOptions::seed`.
+pub(super) fn seed_random(lua: &mlua::Lua, seed: u64, node_id: BjkNodeId) -> Result<()> {
+    // The std hashers may change between Rust releases, which would change
+    // the numbers generated for the same seed.
+    let mut hasher = StableHasher::new();
+    hasher.u64(seed);
+    hasher.u64(node_id.data().as_ffi());
+    let randomseed: mlua::Function = lua
+        .globals()
+        .get::<_, mlua::Table>("math")?
+        .get("randomseed")?;
+    // NOTE: Luau takes the seed as a 32-bit integer.
+    randomseed.call::<_, ()>(hasher.finish() as i32)?;
+    Ok(())
+}
+
+pub(super) fn hash_value(value: &BlackjackValue, hasher: &mut impl Hasher) {
+    std::mem::discriminant(value).hash(hasher);
+    match value {
+        BlackjackValue::Vector(v) => v.to_array().map(f32::to_bits).hash(hasher),
+        BlackjackValue::Scalar(x) => x.to_bits().hash(hasher),
+        BlackjackValue::Int(i) => i.hash(hasher),
+        BlackjackValue::String(s) => s.hash(hasher),
+        BlackjackValue::Selection(s, _) => s.hash(hasher),
+        BlackjackValue::Color(c) => c.to_array().map(f32::to_bits).hash(hasher),
+        BlackjackValue::Path(p) => p.hash(hasher),
+        BlackjackValue::List(items) => {
+            items.len().hash(hasher);
+            for item in items {
+                hash_value(item, hasher);
+            }
+        }
+        BlackjackValue::None | BlackjackValue::Unset => {}
+    }
+}
+
+/// Evaluates a single node, running its `op` and gizmo functions. All the
+/// node's dependencies must already be in the outputs cache.
+pub(super) fn eval_node<'lua>(
+    lua: &'lua mlua::Lua,
+    graph: &BjkGraph,
+    ctx: &mut InterpreterContext<'_, 'lua>,
+    node_id: BjkNodeId,
+) -> Result<()> {
+    let node = &graph.nodes[node_id];
+    let op_name = &node.op_name;
+    let node_definitions = ctx.node_definitions;
+    let node_def =
+        node_definitions
+            .node_def(op_name)
+            .ok_or_else(|| InterpreterError::MissingNodeDef {
+                op_name: op_name.clone(),
+            })?;
+
+    // Stores the arguments that will be sent to this node's `op` fn
+    let mut input_map = lua.create_table()?;
+
+    // Used to allow the gizmo input function to update a node's parameters.
+    // This is None when gizmos don't run to optimize performance
+    let mut referenced_external_params = if ctx.gizmo_state.is_some() {
+        Some(Vec::<ExternalParameter>::new())
+    } else {
+        None
+    };
+
+    // Fill in the inputs, from the outputs of the dependencies or the external
+    // parameters.
+    for input in &node.inputs {
+        match &input.kind {
+            crate::graph::DependencyKind::Connection {
+                node,
+                param_name,
+                optional,
+            } => {
+                let upstream_bypassed = graph.nodes.get(*node).map_or(false, |n| n.bypass);
+                if *optional && (ctx.failed_nodes.contains(node) || upstream_bypassed) {
+                    let default = input_default(&node_def, &input.name)?;
+                    ctx.warn(node_id, || {
+                        format!(
+                            "Input '{}' is connected to node {}, which failed or is \
+                             bypassed, using its default value",
+                            input.name,
+                            node.display_id()
+                        )
+                    });
+                    input_map.set(input.name.as_str(), default.into_lua(lua)?)?;
+                    continue;
+                }
+                // Dependencies are evaluated before their dependents, so this
+                // only fails if the evaluation order is wrong.
+                let cached_output_map = ctx.outputs_cache.get(node).ok_or_else(|| {
+                    anyhow!(
+                        "Node {node_id:?} ({op_name}) was evaluated before its dependency {node:?}"
+                    )
+                })?;
+
+                let value = cached_output_map.get::<_, mlua::Value>(param_name.as_str())?;
+                match value {
+                    mlua::Value::Nil => {
+                        return Err(InterpreterError::MissingOutput {
+                            node_id,
+                            param_name: input.name.clone(),
+                            src_node: *node,
+                            src_param: param_name.clone(),
+                        }
+                        .into());
+                    }
+                    mlua::Value::Number(x)
+                        if input.data_type == DataType::Int && x.fract() != 0.0 =>
+                    {
+                        return Err(InterpreterError::FractionalInt {
+                            node_id,
+                            param_name: input.name.clone(),
+                            value: x,
+                        }
+                        .into());
+                    }
+                    _ => {}
+                }
+                input_map.set(input.name.as_str(), value)?;
+            }
+            crate::graph::DependencyKind::External { promoted } => {
+                let ext = ExternalParameter::new(node_id, input.name.clone());
+                let val = match ctx.param_value(&ext)? {
+                    // Unset parameters are not replaced by their default in
+                    // the values reported back, so they stay unset.
+                    Some(BlackjackValue::Unset) => input_default(&node_def, &input.name)?,
+                    Some(val) => val.clone(),
+                    None if ctx.use_default_params
+                        || (ctx.default_internal_params && promoted.is_none()) =>
+                    {
+                        let default = input_default(&node_def, &input.name)?;
+                        ctx.warn(node_id, || {
+                            format!(
+                                "Parameter '{}' is missing, using its default value",
+                                input.name
+                            )
+                        });
+                        // Store the default, so it is reported back to the
+                        // caller and gizmos can update it.
+                        ctx.external_param_values
+                            .0
+                            .insert(ext.clone(), default.clone());
+                        default
+                    }
+                    None => {
+                        return Err(InterpreterError::MissingExternalParam {
+                            node_id,
+                            param_name: input.name.clone(),
+                        }
+                        .into())
+                    }
+                };
+                let input_def = node_def.inputs.iter().find(|i| i.name == input.name);
+                let val = match input_def {
+                    Some(input_def) => coerce_input(node_id, input_def, val)?,
+                    None => val,
+                };
+                if let Some(input_def) = input_def {
+                    if !input_def.allows_value(&val) {
+                        return Err(InterpreterError::InvalidEnumValue {
+                            node_id,
+                            param_name: input.name.clone(),
+                            value: val,
+                            allowed: input_def.enum_values().unwrap_or_default().to_vec(),
+                        }
+                        .into());
+                    }
+                }
+                let val = match (val, ctx.project_root) {
+                    (BlackjackValue::Path(path), Some(root)) if path.is_relative() => {
+                        BlackjackValue::Path(root.join(path))
+                    }
+                    (val, _) => val,
+                };
+                input_map.set(input.name.as_str(), val.into_lua(lua)?)?;
+                if let Some(m) = &mut referenced_external_params {
+                    m.push(ext);
+                }
+            }
+        }
+    }
+
+    // This special value is injected into the inputs to signal nodes that the
+    // gizmos are being processed. This is useful to let nodes optimize out
+    // parts of the computation when they're running on a game engine.
+    if ctx.gizmo_state.is_some() {
+        input_map.set("__gizmos_enabled", true)?;
+    }
+    if ctx.inject_node_id {
+        input_map.set("__node_id", node_id.display_id())?;
+    }
+
+    // Bypassed nodes don't run their `op` or gizmos.
+    if node.bypass {
+        let outputs = bypass_outputs(lua, node, node_id, &input_map)?;
+        if let Some(output_keys) = &mut ctx.output_keys {
+            output_keys.insert(node_id, table_keys(&outputs)?);
+        }
+        ctx.outputs_cache.insert(node_id, outputs);
+        return Ok(());
+    }
+
+    // Native, sub-graph and passthrough nodes don't have an `op` in the Lua
+    // node library.
+    let node_table =
+        if node_def.native_op.is_some() || node_def.subgraph.is_some() || node_def.passthrough {
+            None
+        } else {
+            Some(ctx.node_table(lua, op_name)?)
+        };
+
+    // Gizmo functions are only required for the directions the gizmo runs in:
+    // `update_params` when the gizmo was changed by the user, and
+    // `update_gizmos` to produce the gizmo for the next frame. Gizmos without
+    // an `update_gizmos` function produce no gizmo. A missing `affected_params`
+    // works the same as returning nil.
+    //
+    // Besides the gizmo, `update_gizmos` can return a table of new values for
+    // the node's external parameters, keyed by input name. These are written
+    // back like the ones from `update_params`, but since `op` already ran,
+    // they only take effect on the next run.
+    struct GizmoFns<'lua> {
+        update_params_fn: Option<mlua::Function<'lua>>,
+        update_gizmos_fn: Option<mlua::Function<'lua>>,
+        affected_params_fn: Option<mlua::Function<'lua>>,
+    }
+
+    struct GizmoDescriptor<'lua> {
+        id: GizmoId,
+        gizmos_changed: bool,
+        data: Option<BlackjackGizmo>,
+        fns: GizmoFns<'lua>,
+    }
+
+    // The data for each of the input gizmos. If this is the empty vec, then gizmos are disabled.
+    let gizmo_descriptors: Vec<GizmoDescriptor> = (|| -> Result<_> {
+        if let (true, Some(node_table)) = (node_def.has_gizmo, &node_table) {
+            if let Some(gizmos_state) = &mut ctx.gizmo_state {
+                // NOTE: We remove the input slotmap because each node only
+                // needs the data from its own gizmos and all nodes are run
+                // exactly once.
+                if let Some(gizmo_data) = gizmos_state.remove(node_id) {
+                    let gizmos_table: mlua::Table = node_table
+                        .get("gizmos")
+                        .map_err(|err| anyhow!("Expected node to have gizmos table. {err}"))?;
+                    // Gizmos are matched with the host's handles by their
+                    // position, so they must be declared as an array to have a
+                    // stable order.
+                    let num_gizmos = gizmos_table.raw_len();
+                    if num_gizmos == 0 {
+                        ctx.warn(node_id, || {
+                            format!("Node {op_name} has gizmos enabled, but declares no gizmos")
+                        });
+                    }
+                    if gizmos_table
+                        .clone()
+                        .pairs::<mlua::Value, mlua::Value>()
+                        .count()
+                        != num_gizmos
+                    {
+                        bail!(
+                            "The 'gizmos' table of node {} ({op_name}) must be an array",
+                            node_id.display_id()
+                        );
+                    }
+
+                    match &gizmo_data.active_gizmos {
+                        None if gizmo_data.gizmos_changed => ctx.warn(node_id, || {
+                            "Gizmos were marked as changed, but no gizmo values were \
+                             provided. The node's parameters are not updated."
+                                .into()
+                        }),
+                        Some(active) if active.len() != num_gizmos => ctx.warn(node_id, || {
+                            format!(
+                                "Node {op_name} has {num_gizmos} gizmos, but {} gizmo \
+                                 values were provided. Missing values are ignored.",
+                                active.len()
+                            )
+                        }),
+                        _ => {}
+                    }
+
+                    let mut gizmo_descriptors = Vec::<GizmoDescriptor>::new();
+                    for (i, gizmo_descr) in
+                        gizmos_table.sequence_values::<mlua::Table>().enumerate()
+                    {
+                        let gizmo_descr = gizmo_descr?;
+                        let key = match gizmo_descr.get::<_, Option<String>>("name")? {
+                            Some(name) => GizmoKey::Name(name),
+                            None => GizmoKey::Index(i),
+                        };
+                        if gizmo_descriptors.iter().any(|descr| descr.id.key == key) {
+                            bail!(
+                                "Node {} ({op_name}) has two gizmos with the same name: {key:?}",
+                                node_id.display_id()
+                            );
+                        }
+                        macro_rules! get_fn {
+                            ($name:expr) => {
+                                gizmo_descr.get($name).map_err(|err| {
+                                    anyhow!("Invalid '{}' in gizmos table. {err}", $name)
+                                })?
+                            };
+                        }
+
+                        gizmo_descriptors.push(GizmoDescriptor {
+                            id: GizmoId { node_id, key },
+                            // Uninitialized gizmos count as missing, so they
+                            // don't update the parameters.
+                            data: gizmo_data
+                                .active_gizmos
+                                .as_ref()
+                                .and_then(|v| v.get(i).cloned())
+                                .filter(|gizmo| !matches!(gizmo, BlackjackGizmo::None)),
+                            gizmos_changed: gizmo_data.gizmos_changed,
+                            fns: GizmoFns {
+                                update_params_fn: get_fn!("update_params"),
+                                update_gizmos_fn: get_fn!("update_gizmos"),
+                                affected_params_fn: get_fn!("affected_params"),
+                            },
+                        });
+                    }
+                    return Ok(gizmo_descriptors);
+                }
+            }
+        }
+        Ok(Vec::new())
+    })()?;
+
+    // When a gizmo affects parameters, the gizmo will be disabled if all the
+    // parameters are connected. Nodes can return nil from the `affected_params`
+    // function to disable this behavior.
+    let enabled_gizmos = gizmo_descriptors
+        .iter()
+        .map(|descr| {
+            let affected_params = descr
+                .fns
+                .affected_params_fn
+                .as_ref()
+                .map(|affected_params_fn| {
+                    affected_params_fn
+                        .call::<_, Option<Vec<String>>>(())
+                        .map_err(|err| node_lua_error(err, op_name, node_id, "affected_params"))
+                })
+                .transpose()?
+                .flatten();
+            if let Some(affected_params) = affected_params {
+                for input in node.inputs.iter() {
+                    if affected_params.contains(&input.name) {
+                        match &input.kind {
+                            crate::graph::DependencyKind::External { .. } => return Ok(true),
+                            crate::graph::DependencyKind::Connection { .. } => {}
+                        }
+                    }
+                }
+                Ok(false)
+            } else {
+                Ok(true)
+            }
+        })
+        .collect::<Result<Vec<bool>>>()?;
+
+    // Run pre-gizmo. This only happens for gizmos that were changed by the
+    // user since the last run. Otherwise, the gizmo only runs in the 'out'
+    // direction: `update_params` is skipped, and `update_gizmos` is called
+    // after `op` to produce the gizmo for the next frame.
+    for (i, it) in gizmo_descriptors.iter().zip(&enabled_gizmos).enumerate() {
+        if let (
+            GizmoDescriptor {
+                gizmos_changed: true,
+                data: Some(gizmo_in),
+                fns: GizmoFns {
+                    update_params_fn, ..
+                },
+                ..
+            },
+            true,
+        ) = it
+        {
+            let update_params_fn = update_params_fn.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "Gizmo {i} of node {} ({op_name}) was changed, but it has no \
+                     'update_params' function",
+                    node_id.display_id()
+                )
+            })?;
+
+            // Update params
+            // Patch the input map, running the gizmo function
+            let input_gizmo = gizmo_in.clone().into_lua(lua)?;
+            let new_input_map = update_params_fn
+                .call::<_, Table>((input_map, input_gizmo))
+                .map_err(|err| node_lua_error(err, op_name, node_id, "update_params"))?;
+            input_map = new_input_map;
+
+            // Write the inputs that were returned to lua back to the
+            // external_parameter_values in the context. This will then be sent
+            // as part of the program output, to communicate to the integration
+            // that parameters for a node have changed.
+            // When gizmos run, the referenced parameters are always recorded.
+            for param in referenced_external_params.iter().flatten() {
+                let new_val = input_map
+                    .get::<_, BlackjackValue>(param.param_name.clone())
+                    .map_err(|err| {
+                        anyhow!(
+                        "The gizmos input function modified a parameter in an illegal way: {err}"
+                    )
+                    })?;
+                if !ctx.external_param_values.0.contains_key(param) {
+                    bail!("Parameter {param:?} is missing after running gizmos");
+                }
+                let new_val = coerce_gizmo_param(node_id, &node_def, param, new_val)?;
+                ctx.update_param(param, new_val);
+            }
+        }
+    }
+
+    // Run node 'op'
+    ctx.check_cancelled(node_id)?;
+    let op_fn: Option<mlua::Function> = node_table
+        .as_ref()
+        .map(|node_table| {
+            node_table
+                .get("op")
+                .map_err(|err| anyhow!("Node should always have an 'op'. {err}"))
+        })
+        .transpose()?;
+    // NOTE: The key is computed after the gizmos had a chance to update the
+    // external parameters.
+    let content_key = content_key(graph, ctx, node_id);
+    let memoized = content_key.and_then(|key| ctx.content_cache.as_mut()?.get(key));
+    let outputs = if let Some(outputs) = memoized {
+        ctx.memoized_nodes.insert(node_id);
+        outputs
+    } else {
+        let op_start = Instant::now();
+        let op_result =
+            match (&node_def.native_op, &node_def.subgraph, &op_fn) {
+                _ if node_def.passthrough => passthrough_outputs(lua, node, node_id, &input_map),
+                (Some(native_op), _, _) => native_op.call(lua, &input_map).map_err(|err| {
+                    anyhow!(
+                        "Error running native op for node {} ({op_name}): {err}",
+                        node_id.display_id()
+                    )
+                }),
+                (None, Some(subgraph), _) => subgraph
+                    .eval(lua, ctx, node_id, &input_map)
+                    .with_context(|| {
+                        format!(
+                            "Error evaluating the graph inside node {} ({op_name})",
+                            node_id.display_id()
+                        )
+                    }),
+                (None, None, Some(op_fn)) => {
+                    lua_op_result(op_fn.call(input_map.clone()), op_name, node_id)
+                }
+                (None, None, None) => unreachable!("Lua nodes always have an 'op'"),
+            };
+        if let Some(node_timings) = &mut ctx.node_timings {
+            node_timings.insert(node_id, op_start.elapsed());
+        }
+        let outputs = op_result?;
+        if let Some(policy) = ctx.non_finite_outputs {
+            check_finite_outputs(&outputs, policy, op_name, node_id)?;
+        }
+        outputs
+    };
+    if let Some(content_cache) = &mut ctx.content_cache {
+        content_cache.insert(node_id, content_key, &outputs);
+    }
+    if let Some(output_keys) = &mut ctx.output_keys {
+        output_keys.insert(node_id, table_keys(&outputs)?);
+    }
+
+    ctx.outputs_cache.insert(node_id, outputs.clone());
+
+    // Run post-gizmo
+    ctx.check_cancelled(node_id)?;
+    for (gz_descr, enabled) in gizmo_descriptors.iter().zip(&enabled_gizmos) {
+        let update_gizmos_fn = match (&gz_descr.fns.update_gizmos_fn, enabled) {
+            (Some(update_gizmos_fn), true) => Some(update_gizmos_fn),
+            _ => None,
+        };
+        let updated_gizmo = update_gizmos_fn
+            .map(|update_gizmos_fn| -> anyhow::Result<_> {
+                let gizmo = gz_descr
+                    .data
+                    .as_ref()
+                    .map(|gz| gz.clone().into_lua(lua))
+                    .transpose()?
+                    .unwrap_or(mlua::Value::Nil);
+
+                let mut returned = update_gizmos_fn
+                    .call::<_, mlua::MultiValue>((input_map.clone(), gizmo, outputs.clone()))
+                    .map_err(|err| node_lua_error(err, op_name, node_id, "update_gizmos"))?
+                    .into_iter();
+                let gizmo = returned.next().unwrap_or(mlua::Value::Nil);
+                let got = lua_type_name(&gizmo);
+                let gizmo = BlackjackGizmo::from_lua(gizmo, lua).map_err(|_| {
+                    InterpreterError::BadGizmoReturn {
+                        node_id,
+                        op_name: op_name.clone(),
+                        got,
+                    }
+                })?;
+                Ok((gizmo, returned.next()))
+            })
+            .transpose()?;
+        let updated_gizmo = match updated_gizmo {
+            Some((gizmo, Some(mlua::Value::Table(new_params)))) => {
+                for pair in new_params.pairs::<String, mlua::Value>() {
+                    let (param_name, value) = pair?;
+                    let param = referenced_external_params
+                        .iter()
+                        .flatten()
+                        .find(|param| param.param_name == param_name)
+                        .cloned()
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "The 'update_gizmos' function of node {} ({op_name}) returned a \
+                                 value for '{param_name}', which is not an external parameter \
+                                 of the node",
+                                node_id.display_id()
+                            )
+                        })?;
+                    let new_val = BlackjackValue::from_lua(value, lua).map_err(|err| {
+                        anyhow!(
+                            "The 'update_gizmos' function of node {} ({op_name}) returned an \
+                             invalid value for '{param_name}': {err}",
+                            node_id.display_id()
+                        )
+                    })?;
+                    let new_val = coerce_gizmo_param(node_id, &node_def, &param, new_val)?;
+                    ctx.update_param(&param, new_val);
+                }
+                Some(gizmo)
+            }
+            Some((gizmo, None | Some(mlua::Value::Nil))) => Some(gizmo),
+            Some((_, Some(other))) => bail!(
+                "The 'update_gizmos' function of node {} ({op_name}) should return a table of \
+                 parameters as its second value, got {}",
+                node_id.display_id(),
+                lua_type_name(&other)
+            ),
+            None => None,
+        };
+        ctx.gizmo_outputs
+            .entry(node_id)
+            .unwrap()
+            .or_default()
+            // When gizmos are disabled, or have no `update_gizmos` function,
+            // we push a `None` value. This will be converted into `nil` when
+            // that gizmo is enabled again and its value is pushed to Lua.
+            .push((
+                gz_descr.id.clone(),
+                updated_gizmo.unwrap_or(BlackjackGizmo::None),
+            ));
+    }
+
+    Ok(())
+}
+
+/// Values that went through Lua may not have the type of their input. Converts
+/// them with `DataType::coerce_value`, failing when an `Int` input gets a
+/// number with a fractional part. Values that can't be converted are returned
+/// as they are.
+pub(super) fn coerce_input(
+    node_id: BjkNodeId,
+    input_def: &InputDefinition,
+    value: BlackjackValue,
+) -> Result<BlackjackValue> {
+    if input_def.data_type.is_valid_value(&value) {
+        return Ok(value);
+    }
+    match (input_def.data_type, value) {
+        (DataType::Int, BlackjackValue::Scalar(x)) => Err(InterpreterError::FractionalInt {
+            node_id,
+            param_name: input_def.name.clone(),
+            value: x as f64,
+        }
+        .into()),
+        (data_type, value) => Ok(data_type.coerce_value(value.clone()).unwrap_or(value)),
+    }
+}
+
+/// Gizmos write parameters back from Lua, so they need the same conversions
+/// as the values of connections.
+pub(super) fn coerce_gizmo_param(
+    node_id: BjkNodeId,
+    node_def: &NodeDefinition,
+    param: &ExternalParameter,
+    value: BlackjackValue,
+) -> Result<BlackjackValue> {
+    match node_def.inputs.iter().find(|i| i.name == param.param_name) {
+        Some(input_def) => coerce_input(node_id, input_def, value),
+        None => Ok(value),
+    }
+}
+
+/// Returns the default value of the input named `input_name`, as declared in
+/// the node definition.
+pub(super) fn input_default(node_def: &NodeDefinition, input_name: &str) -> Result<BlackjackValue> {
+    node_def
+        .inputs
+        .iter()
+        .find(|input_def| input_def.name == input_name)
+        .map(|input_def| input_def.default_value())
+        .ok_or_else(|| {
+            anyhow!(
+                "Node definition for {} has no input named '{input_name}'",
+                node_def.op_name
+            )
+        })
+}
+
+/// Computes the outputs of a passthrough node, taking the value for each
+/// output from the input with the same name.
+pub(super) fn passthrough_outputs<'lua>(
+    lua: &'lua mlua::Lua,
+    node: &BjkNode,
+    node_id: BjkNodeId,
+    input_map: &Table<'lua>,
+) -> Result<Table<'lua>> {
+    let outputs = lua.create_table()?;
+    for output in &node.outputs {
+        if !node.inputs.iter().any(|input| input.name == output.name) {
+            bail!(
+                "Passthrough node {} ({}) has no input for its output '{}'",
+                node_id.display_id(),
+                node.op_name,
+                output.name
+            );
+        }
+        outputs.set(
+            output.name.as_str(),
+            input_map.get::<_, mlua::Value>(output.name.as_str())?,
+        )?;
+    }
+    Ok(outputs)
+}
+
+/// Computes the outputs of a bypassed node. Each output takes the value of the
+/// input with the same name or, failing that, of the only input with the same
+/// data type. See `BjkNode::bypass`.
+pub(super) fn bypass_outputs<'lua>(
+    lua: &'lua mlua::Lua,
+    node: &BjkNode,
+    node_id: BjkNodeId,
+    input_map: &Table<'lua>,
+) -> Result<Table<'lua>> {
+    let outputs = lua.create_table()?;
+    for output in &node.outputs {
+        let input_name = match node.inputs.iter().find(|input| input.name == output.name) {
+            Some(input) => &input.name,
+            None => {
+                let candidates = node
+                    .inputs
+                    .iter()
+                    .filter(|input| input.data_type == output.data_type)
+                    .map(|input| &input.name)
+                    .collect_vec();
+                match candidates.as_slice() {
+                    [input_name] => *input_name,
+                    _ => {
+                        return Err(InterpreterError::AmbiguousBypass {
+                            node_id,
+                            op_name: node.op_name.clone(),
+                            output: output.name.clone(),
+                            candidates: candidates.into_iter().cloned().collect(),
+                        }
+                        .into())
+                    }
+                }
+            }
+        };
+        outputs.set(
+            output.name.as_str(),
+            input_map.get::<_, mlua::Value>(input_name.as_str())?,
+        )?;
+    }
+    Ok(outputs)
+}
+
+/// Checks the value returned by a Lua node's `op` function is a table.
+pub(super) fn lua_op_result<'lua>(
+    op_result: mlua::Result<mlua::Value<'lua>>,
+    op_name: &str,
+    node_id: BjkNodeId,
+) -> Result<mlua::Table<'lua>> {
+    match op_result.map_err(|err| node_lua_error(err, op_name, node_id, "op"))? {
+        mlua::Value::Table(t) => Ok(t),
+        other => Err(InterpreterError::BadOpReturn {
+            node_id,
+            op_name: op_name.to_owned(),
+            got: lua_type_name(&other),
+        }
+        .into()),
+    }
+}
+
+/// Returns the keys of a node's output table, sorted.
+pub(super) fn table_keys(outputs: &Table) -> Result<Vec<String>> {
+    Ok(outputs
+        .clone()
+        .pairs::<String, mlua::Value>()
+        .map(|pair| Ok(pair?.0))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .sorted()
+        .collect_vec())
+}
+
+/// Applies the `policy` to the NaN or infinite numbers and vectors among the
+/// `outputs` of a node.
+pub(super) fn check_finite_outputs(
+    outputs: &Table,
+    policy: NonFinitePolicy,
+    op_name: &str,
+    node_id: BjkNodeId,
+) -> Result<()> {
+    let zero_non_finite = |x: f32| if x.is_finite() { x } else { 0.0 };
+    let mut replaced = Vec::new();
+    for pair in outputs.clone().pairs::<mlua::Value, mlua::Value>() {
+        let (key, value) = pair?;
+        let replacement = match value {
+            mlua::Value::Number(x) if !x.is_finite() => mlua::Value::Number(0.0),
+            mlua::Value::Vector(v)
+                if !(v.x().is_finite() && v.y().is_finite() && v.z().is_finite()) =>
+            {
+                mlua::Value::Vector(mlua::Vector::new(
+                    zero_non_finite(v.x()),
+                    zero_non_finite(v.y()),
+                    zero_non_finite(v.z()),
+                ))
+            }
+            _ => continue,
+        };
+        match policy {
+            NonFinitePolicy::Error => {
+                let output = match &key {
+                    mlua::Value::String(s) => s.to_string_lossy().into_owned(),
+                    other => format!("{other:?}"),
+                };
+                return Err(InterpreterError::NonFiniteOutput {
+                    node_id,
+                    op_name: op_name.to_owned(),
+                    output,
+                }
+                .into());
+            }
+            NonFinitePolicy::Zero => replaced.push((key, replacement)),
+        }
+    }
+    // The table can't be modified while iterating over it.
+    for (key, value) in replaced {
+        outputs.set(key, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{DataType, NodeDefinition};
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_optional_connections() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let fail = graph.add_node("Fail", None);
+        graph.add_output(fail, "out", DataType::Scalar).unwrap();
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(fail, "out", add, "a").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(add, "b", 2.0)
+            .build();
+        let run = |graph: &BjkGraph| {
+            run_graph_with_options(
+                &lua,
+                graph,
+                add,
+                params.clone(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    diagnostics: RunDiagnostics {
+                        collect_warnings: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Mandatory connections propagate the failure.
+        assert!(run(&graph).is_err());
+
+        // Optional connections take the input's default value instead.
+        graph.set_connection_optional(add, "a", true).unwrap();
+        let result = run(&graph).unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 2.0);
+        let warnings = result.warnings.unwrap();
+        assert!(warnings.iter().any(|w| w.node_id == fail));
+        assert!(warnings.iter().any(|w| w.node_id == add));
+
+        // The failure is still fatal when another node needs the output.
+        let other = graph.add_node("Add", None);
+        graph.add_input(other, "a", DataType::Scalar, None).unwrap();
+        graph.add_connection(fail, "out", other, "a").unwrap();
+        graph.add_input(add, "c", DataType::Scalar, None).unwrap();
+        graph.add_output(other, "out", DataType::Scalar).unwrap();
+        graph.add_connection(other, "out", add, "c").unwrap();
+        assert!(run(&graph).is_err());
+
+        assert!(graph.set_connection_optional(add, "b", true).is_err());
+    }
+
+    #[test]
+    pub fn test_non_finite_outputs() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Explode = {
+                    label = "Explode",
+                    inputs = {},
+                    outputs = {
+                        { name = "x", type = "scalar" },
+                        { name = "v", type = "vec3" },
+                    },
+                    op = function(inputs)
+                        return { x = 1 / 0, v = vector(0 / 0, 1, 2) }
+                    end,
+                },
+                Probe = {
+                    label = "Probe",
+                    inputs = {
+                        { name = "x", type = "scalar", default = 0 },
+                        { name = "v", type = "vec3", default = vector(0, 0, 0) },
+                    },
+                    outputs = {},
+                    op = function(inputs)
+                        X, V = inputs.x, inputs.v
+                        return {}
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let explode = graph.add_node("Explode", None);
+        graph.add_output(explode, "x", DataType::Scalar).unwrap();
+        graph.add_output(explode, "v", DataType::Vector).unwrap();
+        let probe = graph.add_node("Probe", None);
+        graph.add_input(probe, "x", DataType::Scalar, None).unwrap();
+        graph.add_input(probe, "v", DataType::Vector, None).unwrap();
+        graph.add_connection(explode, "x", probe, "x").unwrap();
+        graph.add_connection(explode, "v", probe, "v").unwrap();
+        let run = |non_finite_outputs| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                probe,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    evaluation: EvaluationOptions {
+                        non_finite_outputs,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Unchecked by default.
+        run(None).unwrap();
+        assert!(lua.globals().get::<_, f32>("X").unwrap().is_infinite());
+
+        let err = run(Some(NonFinitePolicy::Error)).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::NonFiniteOutput { node_id, .. }) if *node_id == explode
+        ));
+
+        run(Some(NonFinitePolicy::Zero)).unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("X").unwrap(), 0.0);
+        assert!(matches!(
+            lua.globals().get::<_, mlua::Value>("V").unwrap(),
+            mlua::Value::Vector(v) if v == mlua::Vector::new(0.0, 1.0, 2.0)
+        ));
+    }
+
+    #[test]
+    pub fn test_lua_error_context() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let fail = graph.add_node("Fail", None);
+        let err = run_graph(
+            &lua,
+            &graph,
+            fail,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .err()
+        .unwrap();
+        let message = err.to_string();
+        assert!(message.contains("'op' of node 'Fail'"));
+        assert!(message.contains(&fail.display_id()));
+        assert!(message.contains("Something went wrong"));
+        assert!(err.downcast_ref::<mlua::Error>().is_some());
+    }
+
+    #[test]
+    pub fn test_default_params() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, _) = counter_chain(2);
+        let params = ExternalParameterValues::default();
+
+        let result = run_graph(
+            &lua,
+            &graph,
+            nodes[1],
+            params.clone(),
+            &node_definitions,
+            None,
+        );
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::MissingExternalParam { node_id, param_name })
+                if *node_id == nodes[0] && param_name == "x"
+        ));
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[1],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    use_default_params: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            result.updated_values.0[&ExternalParameter::new(nodes[0], "x".into())],
+            BlackjackValue::Scalar(x) if x == 0.0
+        ));
+
+        // Unset parameters take their default without any option, and stay
+        // unset.
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Repeat", None);
+        graph.add_input(node, "count", DataType::Int, None).unwrap();
+        let params = ExternalParameterValues::builder()
+            .unset(node, "count")
+            .build();
+        params.validate(&graph, &node_definitions).unwrap();
+        let result = run_graph(&lua, &graph, node, params, &node_definitions, None).unwrap();
+        assert_eq!(lua.globals().get::<_, i64>("COUNT").unwrap(), 3);
+        assert_eq!(
+            result.updated_values.0[&ExternalParameter::new(node, "count".into())],
+            BlackjackValue::Unset
+        );
+    }
+
+    #[test]
+    pub fn test_validate_params() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (graph, nodes, mut params) = counter_chain(2);
+        params.validate(&graph, &node_definitions).unwrap();
+
+        params.0.insert(
+            ExternalParameter::new(nodes[0], "x".into()),
+            BlackjackValue::String("one".into()),
+        );
+        let err = params.validate(&graph, &node_definitions).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(&nodes[0].display_id()));
+        assert!(message.contains("Scalar"));
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            nodes[1],
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    validate_params: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(runs(&lua), 0);
+    }
+
+    #[test]
+    pub fn test_missing_node_def() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("NotInstalled", None);
+        let err = run_graph(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::MissingNodeDef { op_name }) if op_name == "NotInstalled"
+        ));
+    }
+
+    #[test]
+    pub fn test_color_values() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Tint", None);
+        graph
+            .add_input(node, "tint", DataType::Color, None)
+            .unwrap();
+        let param = ExternalParameter::new(node, "tint".into());
+        let tint = || lua.globals().get::<_, BlackjackValue>("TINT").unwrap();
+
+        // The default comes from the node definition, with alpha defaulting to 1.
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    use_default_params: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let default = BlackjackValue::Color(Vec4::new(1.0, 0.5, 0.25, 1.0));
+        assert_eq!(result.updated_values.0[&param], default);
+        assert_eq!(tint(), default);
+
+        let mut params = ExternalParameterValues::default();
+        let color = BlackjackValue::Color(Vec4::new(0.1, 0.2, 0.3, 0.4));
+        params.0.insert(param, color.clone());
+        params.validate(&graph, &node_definitions).unwrap();
+        run_graph(&lua, &graph, node, params, &node_definitions, None).unwrap();
+        assert_eq!(tint(), color);
+    }
+
+    #[test]
+    pub fn test_int_values() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Repeat", None);
+        graph.add_input(node, "count", DataType::Int, None).unwrap();
+        let param = ExternalParameter::new(node, "count".into());
+        let count = || lua.globals().get::<_, i64>("COUNT").unwrap();
+        let run = |value: BlackjackValue| {
+            let mut params = ExternalParameterValues::default();
+            params.0.insert(param.clone(), value);
+            run_graph(&lua, &graph, node, params, &node_definitions, None)
+        };
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    use_default_params: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(result.updated_values.0[&param], BlackjackValue::Int(3));
+        assert_eq!(count(), 3);
+
+        // Not representable as an f32
+        run(BlackjackValue::Int(16_777_217)).unwrap();
+        assert_eq!(count(), 16_777_217);
+
+        // Numbers coming from Lua are integers when they have no fractional
+        // part. The check happens before they are narrowed to a scalar.
+        let lua_value = |code: &str| lua.load(code).eval::<BlackjackValue>().unwrap();
+        assert_eq!(lua_value("return 4"), BlackjackValue::Int(4));
+        assert_eq!(lua_value("return 3e9"), BlackjackValue::Int(3_000_000_000));
+        assert!(matches!(
+            lua_value("return 1e8 + 0.5"),
+            BlackjackValue::Scalar(_)
+        ));
+        run(lua_value("return 3e9")).unwrap();
+        assert_eq!(count(), 3_000_000_000);
+
+        for value in [BlackjackValue::Scalar(2.5), lua_value("return 1e8 + 0.5")] {
+            let err = run(value).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<InterpreterError>(),
+                Some(InterpreterError::FractionalInt { .. })
+            ));
+        }
+
+        let mut params = ExternalParameterValues::default();
+        params.0.insert(param.clone(), BlackjackValue::Scalar(2.0));
+        assert!(params.validate(&graph, &node_definitions).is_err());
+        params.0.insert(param, BlackjackValue::Int(2));
+        params.validate(&graph, &node_definitions).unwrap();
+    }
+
+    #[test]
+    pub fn test_lua_value_conversions() {
+        let lua = mlua::Lua::new();
+        let roundtrip = |value: BlackjackValue, data_type: DataType| {
+            let lua_value = value.into_lua(&lua).unwrap();
+            data_type.coerce_value(BlackjackValue::from_lua(lua_value, &lua).unwrap())
+        };
+        let value = BlackjackValue::Int(1 << 40);
+        assert_eq!(roundtrip(value.clone(), DataType::Int), Some(value));
+        let value = BlackjackValue::Path("models/cube.obj".into());
+        assert_eq!(roundtrip(value.clone(), DataType::Path), Some(value));
+        // Integers are accepted for scalars, but not the other way around.
+        assert_eq!(
+            roundtrip(BlackjackValue::Scalar(2.0), DataType::Scalar),
+            Some(BlackjackValue::Scalar(2.0))
+        );
+        assert_eq!(roundtrip(BlackjackValue::Scalar(2.5), DataType::Int), None);
+
+        let eval = |code: &str| lua.load(code).eval::<BlackjackValue>();
+        assert_eq!(
+            eval("return {1, 2.5}").unwrap(),
+            BlackjackValue::List(vec![
+                BlackjackValue::Scalar(1.0),
+                BlackjackValue::Scalar(2.5)
+            ])
+        );
+        assert_eq!(
+            eval("return {1, 2}").unwrap(),
+            BlackjackValue::List(vec![BlackjackValue::Int(1), BlackjackValue::Int(2)])
+        );
+        assert!(eval("return {x = 1}").is_err());
+        assert!(eval("return {1, 2, x = 3}").is_err());
+        assert!(eval("return {1, 'a'}").is_err());
+    }
+
+    #[test]
+    pub fn test_list_values() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Total", None);
+        graph
+            .add_input(node, "values", DataType::List, None)
+            .unwrap();
+        let total = || lua.globals().get::<_, f32>("TOTAL").unwrap();
+        let scalars = |xs: &[f32]| xs.iter().map(|x| BlackjackValue::Scalar(*x)).collect_vec();
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    use_default_params: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            result.updated_values.0[&ExternalParameter::new(node, "values".into())],
+            BlackjackValue::List(scalars(&[1.5, 2.0]))
+        );
+        assert_eq!(total(), 3.5);
+
+        let params = ExternalParameterValues::builder()
+            .list(node, "values", scalars(&[1.0, 2.0, 3.0, 4.0]))
+            .build();
+        params.validate(&graph, &node_definitions).unwrap();
+        run_graph(&lua, &graph, node, params, &node_definitions, None).unwrap();
+        assert_eq!(total(), 10.0);
+
+        // Lists round-trip through Lua, and must have a single element type.
+        let list = BlackjackValue::List(vec![
+            BlackjackValue::Vector(Vec3::X),
+            BlackjackValue::Vector(Vec3::Y),
+        ]);
+        let roundtrip: BlackjackValue = lua.load("return ...").call(list.clone()).unwrap();
+        assert_eq!(roundtrip, list);
+        assert!(lua
+            .load("return { 1, 'two' }")
+            .eval::<BlackjackValue>()
+            .is_err());
+        let params = ExternalParameterValues::builder()
+            .list(
+                node,
+                "values",
+                vec![
+                    BlackjackValue::Scalar(1.0),
+                    BlackjackValue::String("two".into()),
+                ],
+            )
+            .build();
+        assert!(params.validate(&graph, &node_definitions).is_err());
+    }
+
+    #[test]
+    pub fn test_enum_values() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Axis", None);
+        graph
+            .add_input(node, "axis", DataType::String, None)
+            .unwrap();
+        let param = ExternalParameter::new(node, "axis".into());
+        let run = |value: &str| {
+            let mut params = ExternalParameterValues::default();
+            params
+                .0
+                .insert(param.clone(), BlackjackValue::String(value.into()));
+            let validation = params.validate(&graph, &node_definitions);
+            let result = run_graph(&lua, &graph, node, params, &node_definitions, None);
+            (validation, result)
+        };
+
+        let (validation, result) = run("Y");
+        assert!(validation.is_ok());
+        assert!(result.is_ok());
+
+        let (validation, result) = run("W");
+        assert!(validation.is_err());
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::InvalidEnumValue { allowed, .. }) if allowed.len() == 3
+        ));
+    }
+
+    #[test]
+    pub fn test_path_values() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Load", None);
+        graph.add_input(node, "file", DataType::Path, None).unwrap();
+        let param = ExternalParameter::new(node, "file".into());
+
+        let root = PathBuf::from("/projects/test");
+        let mut params = ExternalParameterValues::default();
+        params.0.insert(
+            param.clone(),
+            BlackjackValue::Path(root.join("meshes/cube.obj")),
+        );
+        params.make_paths_relative(&root);
+        assert_eq!(
+            params.0[&param],
+            BlackjackValue::Path("meshes/cube.obj".into())
+        );
+        params.validate(&graph, &node_definitions).unwrap();
+
+        let result = run_graph_with_options(
+            &lua,
+            &graph,
+            node,
+            params,
+            &node_definitions,
+            None,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    project_root: Some(root.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let file: String = lua.globals().get("FILE").unwrap();
+        assert_eq!(PathBuf::from(file), root.join("meshes/cube.obj"));
+        // The stored value is not modified.
+        assert_eq!(
+            result.updated_values.0[&param],
+            BlackjackValue::Path("meshes/cube.obj".into())
+        );
+    }
+
+    #[test]
+    pub fn test_native_nodes() {
+        use crate::graph::NativeOp;
+        use std::rc::Rc;
+
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let native_def = |op_name: &str| NodeDefinition {
+            op_name: op_name.into(),
+            label: op_name.into(),
+            category: None,
+            inputs: vec![],
+            outputs: vec![],
+            returns: None,
+            executable: false,
+            has_gizmo: false,
+            native_op: None,
+            subgraph: None,
+            passthrough: false,
+            pure: true,
+        };
+        node_definitions.register_native(
+            native_def("Double"),
+            NativeOp::new(|lua, inputs| {
+                let x = f32::from_lua(inputs["x"].clone(), lua)?;
+                Ok(HashMap::from([("out".into(), (2.0 * x).into_lua(lua)?)]))
+            }),
+        );
+        let recorded = Rc::new(Cell::new(0.0));
+        let recorded_ = recorded.clone();
+        node_definitions.register_native(
+            native_def("Record"),
+            NativeOp::new(move |lua, inputs| {
+                recorded_.set(f32::from_lua(inputs["x"].clone(), lua)?);
+                Ok(HashMap::new())
+            }),
+        );
+
+        // Counter (1 + 1) -> Double (2 * 2) -> Record
+        let (mut graph, nodes, params) = counter_chain(1);
+        let double = graph.add_node("Double", None);
+        graph
+            .add_input(double, "x", DataType::Scalar, None)
+            .unwrap();
+        graph.add_output(double, "out", DataType::Scalar).unwrap();
+        graph.add_connection(nodes[0], "out", double, "x").unwrap();
+        let record = graph.add_node("Record", None);
+        graph
+            .add_input(record, "x", DataType::Scalar, None)
+            .unwrap();
+        graph.add_connection(double, "out", record, "x").unwrap();
+
+        run_graph(&lua, &graph, record, params, &node_definitions, None).unwrap();
+        assert_eq!(recorded.get(), 4.0);
+
+        // Native nodes survive reloading the Lua node definitions.
+        node_definitions.update(Default::default());
+        assert!(node_definitions.node_def("Double").is_some());
+        assert!(node_definitions.node_def("Counter").is_none());
+    }
+
+    #[test]
+    pub fn test_bad_returns() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                BadOp = {
+                    label = "BadOp",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        return 42
+                    end,
+                },
+                BadGizmo = {
+                    label = "BadGizmo",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        return {}
+                    end,
+                    gizmos = {
+                        {
+                            update_params = function(inputs, gizmo)
+                                return inputs
+                            end,
+                            update_gizmos = function(inputs, gizmo, outputs)
+                                return "gizmo"
+                            end,
+                            affected_params = function()
+                                return nil
+                            end,
+                        },
+                    },
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let bad_op = graph.add_node("BadOp", None);
+        let bad_gizmo = graph.add_node("BadGizmo", None);
+        let mut gizmos_state = SecondaryMap::new();
+        gizmos_state.insert(bad_gizmo, GizmoState::default());
+
+        let err = run_graph(
+            &lua,
+            &graph,
+            bad_op,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().ends_with("got number"));
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::BadOpReturn { got: "number", .. })
+        ));
+
+        let err = run_graph(
+            &lua,
+            &graph,
+            bad_gizmo,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            Some(gizmos_state),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::BadGizmoReturn { got: "string", .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_default_internal_params() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, _) = counter_chain(2);
+        let run = |graph: &BjkGraph| {
+            run_graph_with_options(
+                &lua,
+                graph,
+                nodes[1],
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    evaluation: EvaluationOptions {
+                        default_internal_params: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        let result = run(&graph).unwrap();
+        assert!(matches!(
+            result.updated_values.0[&ExternalParameter::new(nodes[0], "x".into())],
+            BlackjackValue::Scalar(x) if x == 0.0
+        ));
+
+        graph.nodes[nodes[0]].inputs[0].kind = DependencyKind::External {
+            promoted: Some("Start".into()),
+        };
+        assert!(matches!(
+            run(&graph).err().unwrap().downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::MissingExternalParam { node_id, .. }) if *node_id == nodes[0]
+        ));
+    }
+
+    #[test]
+    pub fn test_passthrough_nodes() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let cube = graph.add_node("Cube", None);
+        graph.add_output(cube, "out_mesh", DataType::Mesh).unwrap();
+        let reroute = graph.add_node("Reroute", Some("mesh".into()));
+        graph
+            .add_input(reroute, "mesh", DataType::Mesh, None)
+            .unwrap();
+        graph.add_output(reroute, "mesh", DataType::Mesh).unwrap();
+        graph
+            .add_connection(cube, "out_mesh", reroute, "mesh")
+            .unwrap();
+
+        let result = run_graph(
+            &lua,
+            &graph,
+            reroute,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .unwrap();
+        match result.renderable {
+            Some(RenderableThing::HalfEdgeMesh(mesh)) => {
+                assert_eq!(mesh.read_connectivity().num_vertices(), 8)
+            }
+            _ => panic!("Expected the rerouted cube mesh"),
+        }
+
+        // Every output needs an input to take its value from.
+        graph.add_output(reroute, "other", DataType::Mesh).unwrap();
+        let err = run_graph(
+            &lua,
+            &graph,
+            reroute,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("no input for its output 'other'"));
+    }
+
+    #[test]
+    pub fn test_bypass_nodes() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // Counter (1 + 1) -> Counter (bypassed) -> Counter (2 + 1) -> Add (3 + 0)
+        let (mut graph, nodes, _) = counter_chain(3);
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(nodes[2], "out", add, "a").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .scalar(nodes[1], "y", 0.0)
+            .scalar(add, "b", 0.0)
+            .build();
+        let run = |graph: &BjkGraph| -> Result<(f32, u32)> {
+            lua.globals().set("RUNS", 0).unwrap();
+            run_graph(&lua, graph, add, params.clone(), &node_definitions, None)?;
+            Ok((lua.globals().get::<_, f32>("SUM").unwrap(), runs(&lua)))
+        };
+
+        graph.nodes[nodes[1]].bypass = true;
+        assert_eq!(run(&graph).unwrap(), (3.0, 2));
+
+        // Outputs without an input of the same name take the only input with
+        // the same data type, and fail when there is more than one.
+        graph
+            .add_input(nodes[1], "y", DataType::Scalar, None)
+            .unwrap();
+        let err = run(&graph).err().unwrap();
+        match err.downcast_ref::<InterpreterError>() {
+            Some(InterpreterError::AmbiguousBypass {
+                node_id,
+                output,
+                candidates,
+                ..
+            }) => {
+                assert_eq!(*node_id, nodes[1]);
+                assert_eq!(output, "out");
+                assert_eq!(candidates, &["x", "y"]);
+            }
+            _ => panic!("Expected an ambiguous bypass error, got {err}"),
+        }
+
+        // Optional connections from bypassed nodes take their default value.
+        graph.nodes[nodes[1]].inputs.pop();
+        graph.set_connection_optional(nodes[2], "x", true).unwrap();
+        assert_eq!(run(&graph).unwrap(), (1.0, 2));
+    }
+
+    #[test]
+    pub fn test_seed() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Random = {
+                    label = "Random",
+                    inputs = {},
+                    outputs = { { name = "out", type = "scalar" } },
+                    op = function(inputs)
+                        VALUE = math.random()
+                        return { out = VALUE }
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Random", None);
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        let run = |seed| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                node,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    evaluation: EvaluationOptions {
+                        seed: Some(seed),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            lua.globals().get::<_, f64>("VALUE").unwrap()
+        };
+
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    pub fn test_inject_node_id() {
+        let (lua, node_definitions) = test_lua(
+            r#"
+            require('node_library'):addNodes({
+                Identify = {
+                    label = "Identify",
+                    inputs = {},
+                    outputs = {},
+                    op = function(inputs)
+                        NODE_ID = inputs.__node_id
+                        return {}
+                    end,
+                },
+            })
+            "#,
+        );
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Identify", None);
+        let run = |inject_node_id| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                node,
+                ExternalParameterValues::default(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    evaluation: EvaluationOptions {
+                        inject_node_id,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            lua.globals().get::<_, Option<String>>("NODE_ID").unwrap()
+        };
+
+        assert_eq!(run(false), None);
+        assert_eq!(run(true), Some(node.display_id()));
+    }
+
+    #[test]
+    pub fn test_missing_output() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, params) = counter_chain(2);
+        graph.nodes[nodes[1]].inputs[0].kind = DependencyKind::Connection {
+            node: nodes[0],
+            param_name: "not_an_output".into(),
+            optional: false,
+        };
+        let err = run_graph(&lua, &graph, nodes[1], params, &node_definitions, None)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::MissingOutput { node_id, param_name, src_node, src_param })
+                if *node_id == nodes[1]
+                    && param_name == "x"
+                    && *src_node == nodes[0]
+                    && src_param == "not_an_output"
+        ));
+    }
+}
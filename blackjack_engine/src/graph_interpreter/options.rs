@@ -0,0 +1,158 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// What to do when a node fails to evaluate. See
+/// `EvaluationOptions::error_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop the run, and return the error. Failures of nodes that are only
+    /// used through optional connections don't stop the run: Their dependents
+    /// take the default value of the connected inputs, and a warning is
+    /// emitted instead.
+    #[default]
+    Abort,
+    /// Record the error, and carry on as if the node produced no outputs. The
+    /// nodes using those outputs fail in turn, but independent parts of the
+    /// graph are still evaluated. The errors are returned in
+    /// `ProgramResult::failed_nodes`.
+    ContinueWithPlaceholder,
+}
+
+/// Optional settings for a graph run. The default value corresponds to a
+/// regular run, as performed by `run_graph`. Most settings are grouped by
+/// what they affect: How long the run can take, what it reports back, and how
+/// the nodes are evaluated.
+#[derive(Default)]
+pub struct RunGraphOptions<'a> {
+    /// Limits on the work the run can perform, and ways to stop it early.
+    pub limits: RunLimits,
+    /// Additional information collected during the run, and returned in the
+    /// `ProgramResult`.
+    pub diagnostics: RunDiagnostics<'a>,
+    /// Settings changing how the nodes are evaluated, and so the results of
+    /// the run.
+    pub evaluation: EvaluationOptions<'a>,
+    /// The converters used for renderables that are not meshes or height
+    /// maps. See `RenderableRegistry`.
+    pub renderables: Option<&'a RenderableRegistry>,
+    /// When set, the target's renderable is returned unconverted, in
+    /// `ProgramResult::lazy_renderable`, instead of being copied out of Lua
+    /// right away.
+    pub lazy_renderable: bool,
+}
+
+/// Limits on the work a graph run can perform. See `RunGraphOptions::limits`.
+#[derive(Default)]
+pub struct RunLimits {
+    /// Limits on the amount of work the run can perform.
+    pub budget: ExecutionBudget,
+    /// Allows cancelling the run from another thread. A cancelled run returns
+    /// a `Cancelled` error.
+    pub cancel: Option<CancelToken>,
+    /// When set, runs fail with `InterpreterError::DepthExceeded` if a target
+    /// node is at the end of a chain of more than this many connections. This
+    /// is checked before anything is evaluated.
+    pub max_depth: Option<usize>,
+}
+
+/// The additional information collected during a graph run. See
+/// `RunGraphOptions::diagnostics`.
+#[derive(Default)]
+pub struct RunDiagnostics<'a> {
+    /// When set, the renderables for all the evaluated nodes are returned in
+    /// `ProgramResult::all_outputs`, not just the one for the target node.
+    /// This is useful to preview intermediate results when debugging a graph.
+    pub collect_all_outputs: bool,
+    /// When set, the time spent in each node's `op` function is measured, and
+    /// returned in `ProgramResult::node_timings`.
+    pub profile: bool,
+    /// Called before each node is evaluated, with the node's id, the number
+    /// of nodes evaluated so far, and the total number of nodes this run will
+    /// evaluate. Nodes whose outputs are already cached don't count towards
+    /// the total.
+    pub on_progress: Option<&'a mut dyn FnMut(BjkNodeId, usize, usize)>,
+    /// When set, recoverable problems found during the run are returned in
+    /// `ProgramResult::warnings`.
+    pub collect_warnings: bool,
+    /// When set, the output of Lua's `print` function is captured during the
+    /// run, and returned in `ProgramResult::printed` instead of being written
+    /// to stdout.
+    pub capture_print: bool,
+    /// When set, the memory used by the Lua state before and after the run is
+    /// returned in `ProgramResult::memory_usage`.
+    pub report_memory: bool,
+    /// When set, the keys of the output table produced by each node are
+    /// returned in `ProgramResult::output_keys`. Useful for nodes whose
+    /// outputs depend on their inputs, which may not match the outputs
+    /// declared in their definition.
+    pub record_output_keys: bool,
+}
+
+/// Settings changing how the nodes of a graph are evaluated. See
+/// `RunGraphOptions::evaluation`.
+#[derive(Default)]
+pub struct EvaluationOptions<'a> {
+    /// When set, only the nodes in this set get their gizmos evaluated. Any
+    /// other entries in the gizmo state are ignored. When `None`, gizmos are
+    /// evaluated for every node that has an entry in the gizmo state.
+    pub gizmo_nodes: Option<HashSet<BjkNodeId>>,
+    /// When set, external parameters that are missing from the provided
+    /// values take the default declared in their node definition, instead of
+    /// making the run fail. The defaults are returned as part of
+    /// `ProgramResult::updated_values`.
+    pub use_default_params: bool,
+    /// Like `use_default_params`, but only for parameters that are not
+    /// promoted. Promoted parameters are the ones meant to be set by the user
+    /// of the graph, so a missing value for them is still an error.
+    pub default_internal_params: bool,
+    /// When set, the external parameter values are checked against the node
+    /// definitions, using `ExternalParameterValues::validate`, before running.
+    pub validate_params: bool,
+    /// The directory relative `BlackjackValue::Path` values are resolved
+    /// against. Nodes always receive absolute paths when this is set.
+    pub project_root: Option<PathBuf>,
+    /// When set, Lua's random number generator is seeded before evaluating
+    /// each node, from this seed and the node's id. This makes runs using
+    /// `math.random` reproducible, and the numbers a node gets don't depend
+    /// on which other nodes were evaluated before it, or on the platform.
+    pub seed: Option<u64>,
+    /// When set, each node receives its own id in the `__node_id` field of
+    /// its inputs, as returned by `BjkNodeId::display_id`. Ids are stable
+    /// for as long as the node is in the graph, so nodes can use them for
+    /// things like per-node labels or seeds.
+    pub inject_node_id: bool,
+    /// When set, the numbers and vectors output by each node are checked for
+    /// NaN or infinite values, which are handled according to the policy.
+    /// Values nested inside tables or meshes are not checked. When `None`,
+    /// outputs are not checked at all.
+    pub non_finite_outputs: Option<NonFinitePolicy>,
+    /// What to do when a node fails. Errors cancelling the run, or found
+    /// before evaluating any node, like cycles, always abort the run.
+    pub error_policy: ErrorPolicy,
+    /// Values shared by all the nodes, like a unit scale or a resolution
+    /// setting. Nodes can read them from the `bjk.context` global, without
+    /// having them wired as inputs. The table is read-only, and its previous
+    /// value is restored after the run.
+    pub context: Option<&'a HashMap<String, BlackjackValue>>,
+    /// When set, the parameters linked to a shared slot take the slot's value
+    /// instead of their own. Linking a parameter to a slot that has no value
+    /// is an error. Gizmos can't modify linked parameters: The values they
+    /// write are reported in `ProgramResult::updated_values`, but the node
+    /// keeps reading the shared value.
+    pub linked_params: Option<&'a LinkedParams>,
+}
+
+/// What to do with NaN or infinite numbers in the outputs of a node. See
+/// `EvaluationOptions::non_finite_outputs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// Fail with `InterpreterError::NonFiniteOutput`.
+    Error,
+    /// Replace the non-finite numbers, and vector components, with zero.
+    Zero,
+}
@@ -0,0 +1,334 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::{mesh::heightmap::HeightMap, prelude::halfedge::HalfEdgeMesh};
+
+/// Runs the graph like `run_graph`, but evaluating the independent branches
+/// of the target node in parallel, on up to `threads` threads.
+///
+/// A Lua state can't be shared between threads, so each thread initializes
+/// its own state by calling `init_worker`, which should load the same node
+/// library as `lua`. The dependencies of the target node are split into
+/// groups of nodes that don't share any connections, and each group is
+/// evaluated on one of the threads. The outputs used by the target node are
+/// then copied into `lua`, where the target node is evaluated.
+///
+/// Outputs can only be copied between Lua states when they contain plain
+/// values, vectors, meshes, height maps, or tables of those. Gizmos are not
+/// supported when running in parallel.
+pub fn run_graph_parallel(
+    lua: &mlua::Lua,
+    graph: &BjkGraph,
+    target_node: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    init_worker: impl Fn() -> Result<(mlua::Lua, NodeDefinitions)> + Sync,
+    threads: usize,
+) -> Result<ProgramResult> {
+    if let Some(cycle) = find_cycle(graph, target_node) {
+        return Err(InterpreterError::Cycle(cycle).into());
+    }
+
+    let target_inputs: HashSet<BjkNodeId> = graph.nodes[target_node]
+        .inputs
+        .iter()
+        .filter_map(|input| match &input.kind {
+            DependencyKind::Connection { node, .. } => Some(*node),
+            DependencyKind::External { .. } => None,
+        })
+        .collect();
+
+    // Worker buckets are balanced by the number of nodes they evaluate.
+    let mut buckets = vec![Vec::<BjkNodeId>::new(); threads.max(1)];
+    for branch in independent_branches(graph, target_node)
+        .into_iter()
+        .sorted_by_key(|branch| std::cmp::Reverse(branch.len()))
+    {
+        let bucket = buckets
+            .iter_mut()
+            .min_by_key(|bucket| bucket.len())
+            .expect("There is at least one bucket");
+        bucket.extend(branch);
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.max(1))
+        .build()?;
+    let results = std::sync::Mutex::new(Vec::new());
+    pool.scope(|s| {
+        for bucket in buckets.iter().filter(|bucket| !bucket.is_empty()) {
+            let (init_worker, results) = (&init_worker, &results);
+            let (target_inputs, external_param_values) = (&target_inputs, &external_param_values);
+            s.spawn(move |_| {
+                let result = eval_bucket(
+                    init_worker,
+                    graph,
+                    bucket,
+                    target_inputs,
+                    external_param_values,
+                );
+                results.lock().unwrap().push(result);
+            });
+        }
+    });
+
+    let mut outputs_cache = HashMap::new();
+    for result in results.into_inner().unwrap() {
+        for (node_id, outputs) in result? {
+            let outputs = match outputs.into_lua(lua)? {
+                mlua::Value::Table(t) => t,
+                _ => unreachable!("Node outputs are always tables"),
+            };
+            outputs_cache.insert(node_id, outputs);
+        }
+    }
+
+    let mut results = run_graph_impl(
+        lua,
+        graph,
+        &[target_node],
+        RunInputs {
+            external_param_values,
+            gizmos_state: None,
+        },
+        node_definitions,
+        RunGraphOptions::default(),
+        RunCaches {
+            outputs: &mut outputs_cache,
+            content: None,
+            keep_outputs: false,
+        },
+    )?;
+    Ok(results.remove(0))
+}
+
+/// Splits the dependencies of `target`, excluding the target itself, into
+/// groups that are not connected to each other. Each group is returned in
+/// evaluation order.
+pub(super) fn independent_branches(graph: &BjkGraph, target: BjkNodeId) -> Vec<Vec<BjkNodeId>> {
+    let mut order = reachable_nodes(graph, target);
+    order.pop();
+
+    // A union-find over the nodes, joining each node with its dependencies.
+    let mut parents: HashMap<BjkNodeId, BjkNodeId> = order.iter().map(|n| (*n, *n)).collect();
+    fn root(parents: &HashMap<BjkNodeId, BjkNodeId>, mut node: BjkNodeId) -> BjkNodeId {
+        while parents[&node] != node {
+            node = parents[&node];
+        }
+        node
+    }
+    for node_id in &order {
+        for input in &graph.nodes[*node_id].inputs {
+            if let DependencyKind::Connection { node: dep, .. } = &input.kind {
+                let (a, b) = (root(&parents, *node_id), root(&parents, *dep));
+                parents.insert(a, b);
+            }
+        }
+    }
+
+    let mut branches = HashMap::<BjkNodeId, Vec<BjkNodeId>>::new();
+    let mut branch_order = Vec::new();
+    for node_id in order {
+        let branch_root = root(&parents, node_id);
+        if !branches.contains_key(&branch_root) {
+            branch_order.push(branch_root);
+        }
+        branches.entry(branch_root).or_default().push(node_id);
+    }
+    branch_order
+        .into_iter()
+        .map(|root| branches.remove(&root).unwrap())
+        .collect()
+}
+
+/// Evaluates `nodes`, in order, in a new Lua state. Returns the outputs of the
+/// nodes in `needed`.
+fn eval_bucket(
+    init_worker: &(impl Fn() -> Result<(mlua::Lua, NodeDefinitions)> + Sync),
+    graph: &BjkGraph,
+    nodes: &[BjkNodeId],
+    needed: &HashSet<BjkNodeId>,
+    external_param_values: &ExternalParameterValues,
+) -> Result<Vec<(BjkNodeId, PortableValue)>> {
+    let (lua, node_definitions) = init_worker()?;
+    let mut outputs_cache = HashMap::new();
+    let mut external_param_values = external_param_values.clone();
+    let mut gizmo_outputs = SecondaryMap::new();
+    let mut ctx = InterpreterContext {
+        outputs_cache: &mut outputs_cache,
+        external_param_values: &mut external_param_values,
+        node_definitions: &node_definitions,
+        gizmo_state: None,
+        gizmo_outputs: &mut gizmo_outputs,
+        node_tables: Default::default(),
+        use_default_params: false,
+        default_internal_params: false,
+        node_timings: None,
+        changed_params: Vec::new(),
+        content_cache: None,
+        project_root: None,
+        warnings: None,
+        cancel: None,
+        inject_node_id: false,
+        non_finite_outputs: None,
+        options_hash: 0,
+        output_keys: None,
+        linked_params: None,
+        failed_nodes: HashSet::new(),
+        memoized_nodes: HashSet::new(),
+    };
+    for node_id in nodes {
+        eval_node(&lua, graph, &mut ctx, *node_id)?;
+    }
+
+    nodes
+        .iter()
+        .filter(|node_id| needed.contains(node_id))
+        .map(|node_id| {
+            let outputs = mlua::Value::Table(outputs_cache[node_id].clone());
+            Ok((*node_id, PortableValue::from_lua(outputs)?))
+        })
+        .collect()
+}
+
+/// A copy of a Lua value that can be sent to another thread, and converted
+/// back into a value of a different Lua state.
+#[derive(Clone)]
+pub(super) enum PortableValue {
+    Nil,
+    Boolean(bool),
+    Integer(mlua::Integer),
+    Number(f64),
+    Vector(Vec3),
+    String(Vec<u8>),
+    Mesh(Box<PortableMesh>),
+    HeightMap(HeightMap),
+    Table(Vec<(PortableValue, PortableValue)>),
+}
+
+/// A mesh that can be sent to another thread.
+#[derive(Clone)]
+pub(super) struct PortableMesh(HalfEdgeMesh);
+
+// SAFETY: Meshes are not `Send` because their channels are stored behind
+// `Rc`s. Portable meshes are only created from a clone of a mesh, which
+// duplicates the channels, and cloning a portable mesh clones the mesh again.
+// This means no other mesh shares the `Rc`s, so moving the whole mesh to a
+// different thread is sound.
+unsafe impl Send for PortableMesh {}
+
+impl PortableMesh {
+    pub(super) fn new(mesh: &HalfEdgeMesh) -> Self {
+        PortableMesh(mesh.clone())
+    }
+
+    pub(super) fn mesh(&self) -> &HalfEdgeMesh {
+        &self.0
+    }
+}
+
+impl PortableValue {
+    pub(super) fn from_lua(value: mlua::Value) -> Result<Self> {
+        Ok(match value {
+            mlua::Value::Nil => PortableValue::Nil,
+            mlua::Value::Boolean(b) => PortableValue::Boolean(b),
+            mlua::Value::Integer(i) => PortableValue::Integer(i),
+            mlua::Value::Number(x) => PortableValue::Number(x),
+            mlua::Value::Vector(v) => PortableValue::Vector(Vec3::new(v.x(), v.y(), v.z())),
+            mlua::Value::String(s) => PortableValue::String(s.as_bytes().to_vec()),
+            mlua::Value::UserData(u) if u.is::<HalfEdgeMesh>() => {
+                PortableValue::Mesh(Box::new(PortableMesh::new(&*u.borrow::<HalfEdgeMesh>()?)))
+            }
+            mlua::Value::UserData(u) if u.is::<HeightMap>() => {
+                PortableValue::HeightMap(u.borrow::<HeightMap>()?.clone())
+            }
+            mlua::Value::Table(t) => PortableValue::Table(
+                t.pairs::<mlua::Value, mlua::Value>()
+                    .map(|pair| {
+                        let (k, v) = pair?;
+                        Ok((Self::from_lua(k)?, Self::from_lua(v)?))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            other => bail!(
+                "Values of type {} can't be copied between Lua states",
+                other.type_name()
+            ),
+        })
+    }
+
+    pub(super) fn into_lua(self, lua: &mlua::Lua) -> Result<mlua::Value<'_>> {
+        Ok(match self {
+            PortableValue::Nil => mlua::Value::Nil,
+            PortableValue::Boolean(b) => mlua::Value::Boolean(b),
+            PortableValue::Integer(i) => mlua::Value::Integer(i),
+            PortableValue::Number(x) => mlua::Value::Number(x),
+            PortableValue::Vector(v) => mlua::Value::Vector(mlua::Vector::new(v.x, v.y, v.z)),
+            PortableValue::String(s) => mlua::Value::String(lua.create_string(&s)?),
+            PortableValue::Mesh(mesh) => mlua::Value::UserData(lua.create_userdata(mesh.0)?),
+            PortableValue::HeightMap(h) => mlua::Value::UserData(lua.create_userdata(h)?),
+            PortableValue::Table(pairs) => {
+                let table = lua.create_table()?;
+                for (k, v) in pairs {
+                    table.set(k.into_lua(lua)?, v.into_lua(lua)?)?;
+                }
+                mlua::Value::Table(table)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_run_graph_parallel() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // Two independent chains of counters, feeding the inputs of `Add`.
+        let (mut graph, a, mut params) = counter_chain(3);
+        let mut b = Vec::new();
+        for _ in 0..2 {
+            let node = graph.add_node("Counter", None);
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+            if let Some(prev) = b.last() {
+                graph.add_connection(*prev, "out", node, "x").unwrap();
+            }
+            b.push(node);
+        }
+        params.0.insert(
+            ExternalParameter::new(b[0], "x".into()),
+            BlackjackValue::Scalar(1.0),
+        );
+        let add = graph.add_node("Add", None);
+        for input in ["a", "b"] {
+            graph.add_input(add, input, DataType::Scalar, None).unwrap();
+        }
+        graph.add_connection(a[2], "out", add, "a").unwrap();
+        graph.add_connection(b[1], "out", add, "b").unwrap();
+
+        let branches = independent_branches(&graph, add);
+        assert_eq!(branches, vec![a.clone(), b.clone()]);
+
+        run_graph_parallel(
+            &lua,
+            &graph,
+            add,
+            params,
+            &node_definitions,
+            || Ok(test_lua(TEST_NODES)),
+            2,
+        )
+        .unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 7.0);
+        // All the counters ran on worker threads.
+        assert_eq!(runs(&lua), 0);
+    }
+}
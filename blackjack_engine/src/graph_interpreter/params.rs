@@ -0,0 +1,362 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct ExternalParameter {
+    pub node_id: BjkNodeId,
+    pub param_name: String,
+}
+
+impl ExternalParameter {
+    pub fn new(node_id: BjkNodeId, param_name: String) -> Self {
+        Self {
+            node_id,
+            param_name,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ExternalParameterValues(pub HashMap<ExternalParameter, BlackjackValue>);
+
+impl ExternalParameterValues {
+    /// Returns a builder to construct a set of values, one parameter at a
+    /// time.
+    pub fn builder() -> ExternalParameterValuesBuilder {
+        ExternalParameterValuesBuilder::default()
+    }
+
+    /// Adds all the values in `other` to this set. When both sets have a value
+    /// for the same parameter, the one in `other` wins.
+    pub fn merge(&mut self, other: &ExternalParameterValues) {
+        self.0
+            .extend(other.0.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /// Like `merge`, but returns the merged values as a new set, leaving this
+    /// one unchanged.
+    pub fn with_overrides(&self, overrides: &ExternalParameterValues) -> ExternalParameterValues {
+        let mut merged = self.clone();
+        merged.merge(overrides);
+        merged
+    }
+
+    /// Replaces the `Path` values inside `root` by paths relative to it. This
+    /// can be used before storing the values, so they remain valid when the
+    /// project is moved. Run the graph with `EvaluationOptions::project_root`
+    /// to resolve them again.
+    pub fn make_paths_relative(&mut self, root: &Path) {
+        for value in self.0.values_mut() {
+            if let BlackjackValue::Path(path) = value {
+                if let Ok(relative) = path.strip_prefix(root) {
+                    *path = relative.to_owned();
+                }
+            }
+        }
+    }
+
+    /// Checks that every value is of the type declared for its input in the
+    /// node definitions. All the mismatches are reported in a single error,
+    /// identifying each parameter by its node id and name. `Unset` values are
+    /// valid for any input.
+    pub fn validate(&self, graph: &BjkGraph, node_definitions: &NodeDefinitions) -> Result<()> {
+        let mut errors = Vec::new();
+        for (param, value) in &self.0 {
+            let node_id = param.node_id.display_id();
+            let param_name = &param.param_name;
+            let node = match graph.nodes.get(param.node_id) {
+                Some(node) => node,
+                None => {
+                    errors.push(format!("{node_id}.{param_name}: Node is not in the graph"));
+                    continue;
+                }
+            };
+            let node_def = match node_definitions.node_def(&node.op_name) {
+                Some(node_def) => node_def,
+                None => {
+                    errors.push(format!(
+                        "{node_id}.{param_name}: No node definition for {}",
+                        node.op_name
+                    ));
+                    continue;
+                }
+            };
+            match node_def.inputs.iter().find(|i| &i.name == param_name) {
+                Some(_) if *value == BlackjackValue::Unset => {}
+                Some(input_def) if input_def.data_type.coerce_value(value.clone()).is_none() => {
+                    errors.push(format!(
+                        "{node_id}.{param_name}: Expected a value of type {:?}, got {value:?}",
+                        input_def.data_type
+                    ));
+                }
+                Some(input_def) if !input_def.allows_value(value) => {
+                    errors.push(format!(
+                        "{node_id}.{param_name}: {value:?} is not one of the allowed values {:?}",
+                        input_def.enum_values().unwrap_or_default()
+                    ));
+                }
+                Some(_) => {}
+                None => errors.push(format!(
+                    "{node_id}.{param_name}: Node {} has no input with this name",
+                    node.op_name
+                )),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort();
+            bail!("Invalid external parameters:\n{}", errors.join("\n"))
+        }
+    }
+}
+
+/// Builds `ExternalParameterValues`, wrapping each value in the right
+/// `BlackjackValue` variant.
+///
+/// Setting the same parameter twice is a bug in the calling code, so the
+/// setters panic when that happens.
+#[derive(Default)]
+pub struct ExternalParameterValuesBuilder {
+    values: ExternalParameterValues,
+}
+
+impl ExternalParameterValuesBuilder {
+    /// Sets the value for the `param_name` parameter of `node_id`.
+    pub fn value(
+        mut self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        value: BlackjackValue,
+    ) -> Self {
+        let param = ExternalParameter::new(node_id, param_name.to_string());
+        if self.values.0.contains_key(&param) {
+            panic!(
+                "Parameter {}.{} was set twice",
+                node_id.display_id(),
+                param.param_name,
+            );
+        }
+        self.values.0.insert(param, value);
+        self
+    }
+
+    pub fn scalar(self, node_id: BjkNodeId, param_name: impl ToString, value: f32) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Scalar(value))
+    }
+
+    pub fn int(self, node_id: BjkNodeId, param_name: impl ToString, value: i64) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Int(value))
+    }
+
+    /// Marks the parameter as unset, so it takes the default value of its
+    /// input. See `BlackjackValue::Unset`.
+    pub fn unset(self, node_id: BjkNodeId, param_name: impl ToString) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Unset)
+    }
+
+    pub fn vec3(
+        self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        value: impl Into<Vec3>,
+    ) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Vector(value.into()))
+    }
+
+    pub fn string(
+        self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        value: impl ToString,
+    ) -> Self {
+        self.value(
+            node_id,
+            param_name,
+            BlackjackValue::String(value.to_string()),
+        )
+    }
+
+    pub fn color(
+        self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        value: impl Into<Vec4>,
+    ) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Color(value.into()))
+    }
+
+    pub fn path(
+        self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        value: impl Into<PathBuf>,
+    ) -> Self {
+        self.value(node_id, param_name, BlackjackValue::Path(value.into()))
+    }
+
+    pub fn list(
+        self,
+        node_id: BjkNodeId,
+        param_name: impl ToString,
+        items: Vec<BlackjackValue>,
+    ) -> Self {
+        self.value(node_id, param_name, BlackjackValue::List(items))
+    }
+
+    pub fn build(self) -> ExternalParameterValues {
+        self.values
+    }
+}
+
+/// Shared values that several external parameters can be linked to, like a
+/// global level of detail. A linked parameter takes the value of its shared
+/// slot, instead of its own value in `ExternalParameterValues`, so setting one
+/// shared value updates all the parameters linked to it. See
+/// `EvaluationOptions::linked_params`.
+#[derive(Debug, Default, Clone)]
+pub struct LinkedParams {
+    /// The name of the shared slot each linked parameter reads from.
+    pub links: HashMap<ExternalParameter, String>,
+    /// The value of each shared slot, by name.
+    pub shared: HashMap<String, BlackjackValue>,
+}
+
+impl LinkedParams {
+    /// Links the `param_name` parameter of `node_id` to the shared `slot`.
+    /// Replaces any previous link of the parameter.
+    pub fn link(&mut self, node_id: BjkNodeId, param_name: impl ToString, slot: impl ToString) {
+        self.links.insert(
+            ExternalParameter::new(node_id, param_name.to_string()),
+            slot.to_string(),
+        );
+    }
+
+    /// Sets the value of the shared `slot`, for all the parameters linked to
+    /// it.
+    pub fn set(&mut self, slot: impl ToString, value: BlackjackValue) {
+        self.shared.insert(slot.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_linked_params() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        let mut graph = BjkGraph::new();
+        let counters = [(); 2].map(|_| {
+            let node = graph.add_node("Counter", None);
+            graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+            node
+        });
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(counters[0], "out", add, "a").unwrap();
+        graph.add_connection(counters[1], "out", add, "b").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(counters[0], "x", 1.0)
+            .scalar(counters[1], "x", 1.0)
+            .build();
+
+        let mut linked = LinkedParams::default();
+        linked.link(counters[0], "x", "detail");
+        linked.link(counters[1], "x", "detail");
+        let run = |linked: &LinkedParams| {
+            run_graph_with_options(
+                &lua,
+                &graph,
+                add,
+                params.clone(),
+                &node_definitions,
+                None,
+                RunGraphOptions {
+                    evaluation: EvaluationOptions {
+                        linked_params: Some(linked),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .map(|_| lua.globals().get::<_, f32>("SUM").unwrap())
+        };
+        let err = run(&linked).unwrap_err();
+        assert!(format!("{err:#}").contains("'detail', which is not set"));
+        linked.set("detail", BlackjackValue::Scalar(5.0));
+        assert_eq!(run(&linked).unwrap(), 12.0);
+        linked.set("detail", BlackjackValue::Scalar(2.0));
+        assert_eq!(run(&linked).unwrap(), 6.0);
+        // Unlinked parameters keep their own value.
+        linked
+            .links
+            .remove(&ExternalParameter::new(counters[1], "x".into()));
+        assert_eq!(run(&linked).unwrap(), 5.0);
+    }
+
+    #[test]
+    pub fn test_external_parameter_values_builder() {
+        let (_, nodes, _) = counter_chain(2);
+        let values = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .vec3(nodes[0], "pos", [0.0, 1.0, 2.0])
+            .string(nodes[1], "name", "cube")
+            .build();
+        assert_eq!(values.0.len(), 3);
+        assert_eq!(
+            values.0[&ExternalParameter::new(nodes[0], "pos".into())],
+            BlackjackValue::Vector(Vec3::new(0.0, 1.0, 2.0))
+        );
+        assert_eq!(
+            values.0[&ExternalParameter::new(nodes[1], "name".into())],
+            BlackjackValue::String("cube".into())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "was set twice")]
+    pub fn test_external_parameter_values_builder_duplicate() {
+        let (_, nodes, _) = counter_chain(1);
+        ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .scalar(nodes[0], "x", 2.0);
+    }
+
+    #[test]
+    pub fn test_merge_external_parameter_values() {
+        let (_, nodes, _) = counter_chain(2);
+        let defaults = ExternalParameterValues::builder()
+            .scalar(nodes[0], "x", 1.0)
+            .scalar(nodes[1], "x", 2.0)
+            .build();
+        let preset = ExternalParameterValues::builder()
+            .scalar(nodes[1], "x", 3.0)
+            .string(nodes[1], "name", "preset")
+            .build();
+
+        let merged = defaults.with_overrides(&preset);
+        assert_eq!(merged.0.len(), 3);
+        let x = |values: &ExternalParameterValues, node_id| {
+            values.0[&ExternalParameter::new(node_id, "x".into())].clone()
+        };
+        assert_eq!(x(&merged, nodes[0]), BlackjackValue::Scalar(1.0));
+        assert_eq!(x(&merged, nodes[1]), BlackjackValue::Scalar(3.0));
+        // The original values are not modified.
+        assert_eq!(x(&defaults, nodes[1]), BlackjackValue::Scalar(2.0));
+
+        let mut values = preset.clone();
+        values.merge(&defaults);
+        assert_eq!(x(&values, nodes[1]), BlackjackValue::Scalar(2.0));
+    }
+}
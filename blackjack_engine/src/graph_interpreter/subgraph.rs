@@ -0,0 +1,306 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::graph::OutputDefinition;
+
+/// A graph used as the `op` of a node, as returned by `wrap_graph`. Cloning
+/// it is cheap, since the graph is shared.
+#[derive(Clone)]
+pub struct SubGraph(Rc<SubGraphInner>);
+
+struct SubGraphInner {
+    graph: BjkGraph,
+    target: BjkNodeId,
+    /// The values of the parameters of the graph that are not promoted.
+    external_param_values: ExternalParameterValues,
+    /// The parameter of the graph behind each input of the node, by input
+    /// name.
+    inputs: Vec<(String, ExternalParameter)>,
+    /// Set while the graph is being evaluated, to catch graphs containing a
+    /// node that wraps themselves.
+    running: Cell<bool>,
+}
+
+impl std::fmt::Debug for SubGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SubGraph")
+    }
+}
+
+/// Clears the `running` flag of a sub-graph when dropped.
+struct RunningGuard<'a>(&'a Cell<bool>);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+impl SubGraph {
+    /// Evaluates the graph, with the values in `input_map` for its promoted
+    /// parameters, and returns the outputs of its target node.
+    ///
+    /// The graph is evaluated in the same Lua state, so the budget and the
+    /// cancel token of the outer run still apply. Gizmos, warnings and
+    /// statistics of the inner run are not reported.
+    pub(crate) fn eval<'lua>(
+        &self,
+        lua: &'lua mlua::Lua,
+        ctx: &InterpreterContext<'_, 'lua>,
+        node_id: BjkNodeId,
+        input_map: &Table<'lua>,
+    ) -> Result<Table<'lua>> {
+        let inner = &self.0;
+        if inner.running.replace(true) {
+            bail!(
+                "Node {} is part of the graph it wraps",
+                node_id.display_id()
+            );
+        }
+        let _running = RunningGuard(&inner.running);
+
+        let mut external_param_values = inner.external_param_values.clone();
+        for (input_name, param) in &inner.inputs {
+            let value = input_map.get::<_, BlackjackValue>(input_name.as_str())?;
+            external_param_values.0.insert(param.clone(), value);
+        }
+        let mut outputs_cache = HashMap::new();
+        run_graph_impl(
+            lua,
+            &inner.graph,
+            &[inner.target],
+            RunInputs {
+                external_param_values,
+                gizmos_state: None,
+            },
+            ctx.node_definitions,
+            RunGraphOptions {
+                evaluation: EvaluationOptions {
+                    use_default_params: ctx.use_default_params,
+                    default_internal_params: ctx.default_internal_params,
+                    project_root: ctx.project_root.map(Path::to_owned),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            RunCaches {
+outputs: &mut outputs_cache,
+content: None,
+keep_outputs: // The outputs are returned as they are, including the renderable.
+            true,
+},
+        )?;
+        outputs_cache
+            .remove(&inner.target)
+            .ok_or_else(|| anyhow!("The target of the wrapped graph was not evaluated"))
+    }
+}
+
+/// A node evaluating a whole graph, as returned by `wrap_graph`.
+pub struct SubGraphNode {
+    /// The definition of the node. Its inputs are the promoted parameters of
+    /// the graph, and its outputs are the outputs of the graph's target.
+    pub node_def: NodeDefinition,
+    subgraph: SubGraph,
+}
+
+impl SubGraphNode {
+    /// Registers the node in `node_definitions`. See
+    /// `NodeDefinitions::register_subgraph`.
+    pub fn register(&self, node_definitions: &NodeDefinitions) {
+        node_definitions.register_subgraph(self.node_def.clone(), self.subgraph.clone());
+    }
+}
+
+/// Wraps `graph` as a node named `op_name`, so it can be reused inside other
+/// graphs. When the node is evaluated, `target` is evaluated in `graph`, and
+/// its outputs become the outputs of the node.
+///
+/// The promoted parameters of the nodes `target` depends on become the
+/// inputs of the node, named after their promoted name. Their definitions,
+/// including defaults, are taken from the definitions of the inner nodes.
+/// The rest of the parameters take their value from `external_param_values`.
+/// Since parameters are identified by the inner node ids, they can't clash
+/// with the parameters of the graphs using the node.
+///
+/// Only external parameters can be promoted, so the node can't take meshes
+/// as inputs. The node is pure when all the inner nodes are, so it can be
+/// memoized like any other node.
+pub fn wrap_graph(
+    graph: BjkGraph,
+    target: BjkNodeId,
+    external_param_values: ExternalParameterValues,
+    node_definitions: &NodeDefinitions,
+    op_name: impl ToString,
+) -> Result<SubGraphNode> {
+    let target_node = graph
+        .nodes
+        .get(target)
+        .ok_or_else(|| anyhow!("The target node is not in the graph"))?;
+    let dependencies = dependency_set(&graph, target);
+
+    let mut inputs = Vec::new();
+    let mut input_defs: Vec<InputDefinition> = Vec::new();
+    let mut pure = true;
+    for (node_id, node) in &graph.nodes {
+        if !dependencies.contains(&node_id) {
+            continue;
+        }
+        let node_def = node_definitions.node_def(&node.op_name).ok_or_else(|| {
+            InterpreterError::MissingNodeDef {
+                op_name: node.op_name.clone(),
+            }
+        })?;
+        pure &= node_def.pure;
+        for input in &node.inputs {
+            if let DependencyKind::External {
+                promoted: Some(promoted),
+            } = &input.kind
+            {
+                if input_defs
+                    .iter()
+                    .any(|input_def| &input_def.name == promoted)
+                {
+                    bail!("More than one parameter is promoted as '{promoted}'");
+                }
+                let mut input_def = node_def
+                    .inputs
+                    .iter()
+                    .find(|input_def| input_def.name == input.name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Node definition for {} has no input named '{}'",
+                            node.op_name,
+                            input.name
+                        )
+                    })?;
+                input_def.name = promoted.clone();
+                input_defs.push(input_def);
+                inputs.push((
+                    promoted.clone(),
+                    ExternalParameter::new(node_id, input.name.clone()),
+                ));
+            }
+        }
+    }
+
+    let op_name = op_name.to_string();
+    let node_def = NodeDefinition {
+        label: op_name.clone(),
+        op_name,
+        category: None,
+        inputs: input_defs,
+        outputs: target_node
+            .outputs
+            .iter()
+            .map(|output| OutputDefinition {
+                name: output.name.clone(),
+                data_type: output.data_type,
+            })
+            .collect(),
+        returns: target_node.return_value.clone(),
+        executable: false,
+        has_gizmo: false,
+        native_op: None,
+        subgraph: None,
+        passthrough: false,
+        pure,
+    };
+    let subgraph = SubGraph(Rc::new(SubGraphInner {
+        graph,
+        target,
+        external_param_values,
+        inputs,
+        running: Cell::new(false),
+    }));
+    Ok(SubGraphNode { node_def, subgraph })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_subgraph_nodes() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // Two counters, adding two to the promoted `start` parameter.
+        let (mut inner, inner_nodes, _) = counter_chain(2);
+        inner.nodes[inner_nodes[0]].inputs[0].kind = DependencyKind::External {
+            promoted: Some("start".into()),
+        };
+        let wrapped = wrap_graph(
+            inner,
+            inner_nodes[1],
+            ExternalParameterValues::default(),
+            &node_definitions,
+            "AddTwo",
+        )
+        .unwrap();
+        let input_names = wrapped.node_def.inputs.iter().map(|i| i.name.as_str());
+        assert_eq!(input_names.collect_vec(), vec!["start"]);
+        assert_eq!(wrapped.node_def.outputs[0].name, "out");
+        wrapped.register(&node_definitions);
+
+        // The same node can be used more than once, with its own parameters.
+        let mut graph = BjkGraph::new();
+        let first = graph.add_node("AddTwo", None);
+        let second = graph.add_node("AddTwo", None);
+        for node in [first, second] {
+            graph
+                .add_input(node, "start", DataType::Scalar, None)
+                .unwrap();
+            graph.add_output(node, "out", DataType::Scalar).unwrap();
+        }
+        graph.add_connection(first, "out", second, "start").unwrap();
+        let add = graph.add_node("Add", None);
+        graph.add_input(add, "a", DataType::Scalar, None).unwrap();
+        graph.add_input(add, "b", DataType::Scalar, None).unwrap();
+        graph.add_connection(second, "out", add, "a").unwrap();
+        let params = ExternalParameterValues::builder()
+            .scalar(first, "start", 1.0)
+            .scalar(add, "b", 0.0)
+            .build();
+        lua.globals().set("RUNS", 0).unwrap();
+        run_graph(&lua, &graph, add, params, &node_definitions, None).unwrap();
+        assert_eq!(lua.globals().get::<_, f32>("SUM").unwrap(), 5.0);
+        assert_eq!(runs(&lua), 4);
+
+        // A graph can't contain the node wrapping it.
+        let (inner, inner_nodes, params) = counter_chain(1);
+        wrap_graph(inner, inner_nodes[0], params, &node_definitions, "Nested")
+            .unwrap()
+            .register(&node_definitions);
+        let mut inner = BjkGraph::new();
+        let nested = inner.add_node("Nested", None);
+        inner.add_output(nested, "out", DataType::Scalar).unwrap();
+        wrap_graph(
+            inner,
+            nested,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            "Nested",
+        )
+        .unwrap()
+        .register(&node_definitions);
+        let mut graph = BjkGraph::new();
+        let nested = graph.add_node("Nested", None);
+        let err = run_graph(
+            &lua,
+            &graph,
+            nested,
+            ExternalParameterValues::default(),
+            &node_definitions,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{err:#}").contains("is part of the graph it wraps"));
+    }
+}
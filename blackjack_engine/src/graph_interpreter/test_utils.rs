@@ -0,0 +1,221 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::lua_engine::lua_stdlib::{load_lua_bindings, StdLuaFileIo};
+
+/// A node library for tests. The `Counter` node adds one to its input, and
+/// counts how many times it ran in the `RUNS` global. The `Cube` node
+/// returns a cube mesh. The `Spin` node loops forever, and the `Fail` node
+/// always raises an error. The `Gizmo` and `TwoGizmos` nodes pass their
+/// input through, and have one and two gizmos tweaking it, respectively.
+/// The `Tint` node stores its color input in the `TINT` global, and the
+/// `Load` node stores its path in `FILE`. The `Repeat` node stores its
+/// integer input in `COUNT`. The `Add` node stores the sum of
+/// its inputs in `SUM`. The `Reroute` node forwards a mesh unchanged.
+pub(super) const TEST_NODES: &str = r#"
+    RUNS = 0
+    local P = require("params")
+    local point_gizmo = {
+        update_params = function(inputs, gizmo)
+            inputs.x = gizmo:translation().x
+            return inputs
+        end,
+        update_gizmos = function(inputs, gizmo, outputs)
+            return TransformGizmo.new(
+                vector(inputs.x, 0, 0), vector(0, 0, 0), vector(1, 1, 1)
+            )
+        end,
+        affected_params = function()
+            return { "x" }
+        end,
+    }
+    require('node_library'):addNodes({
+        Counter = {
+            label = "Counter",
+            inputs = { { name = "x", type = "scalar", default = 0 } },
+            outputs = { { name = "out", type = "scalar" } },
+            op = function(inputs)
+                RUNS = RUNS + 1
+                return { out = inputs.x + 1 }
+            end,
+        },
+        Cube = {
+            label = "Cube",
+            category = "Primitives",
+            inputs = {},
+            outputs = { { name = "out_mesh", type = "mesh" } },
+            op = function(inputs)
+                return { out_mesh = Primitives.cube(vector(0, 0, 0), vector(1, 1, 1)) }
+            end,
+            returns = "out_mesh",
+        },
+        Spin = {
+            label = "Spin",
+            inputs = {},
+            outputs = {},
+            op = function(inputs)
+                while true do end
+            end,
+        },
+        Gizmo = {
+            label = "Gizmo",
+            inputs = { { name = "x", type = "scalar", default = 0 } },
+            outputs = { { name = "out", type = "scalar" } },
+            op = function(inputs)
+                return { out = inputs.x }
+            end,
+            gizmos = { point_gizmo },
+        },
+        TwoGizmos = {
+            label = "TwoGizmos",
+            inputs = { { name = "x", type = "scalar", default = 0 } },
+            outputs = { { name = "out", type = "scalar" } },
+            op = function(inputs)
+                return { out = inputs.x }
+            end,
+            gizmos = { point_gizmo, point_gizmo },
+        },
+        Axis = {
+            label = "Axis",
+            inputs = { P.enum("axis", { "X", "Y", "Z" }, 0) },
+            outputs = {},
+            op = function(inputs)
+                return {}
+            end,
+        },
+        Load = {
+            label = "Load",
+            inputs = { P.path("file") },
+            outputs = {},
+            op = function(inputs)
+                FILE = inputs.file
+                return {}
+            end,
+        },
+        Repeat = {
+            label = "Repeat",
+            inputs = { P.int("count", { default = 3, min = 0 }) },
+            outputs = {},
+            op = function(inputs)
+                COUNT = inputs.count
+                return {}
+            end,
+        },
+        Tint = {
+            label = "Tint",
+            inputs = { P.color("tint", { r = 1, g = 0.5, b = 0.25 }) },
+            outputs = {},
+            op = function(inputs)
+                TINT = inputs.tint
+                return {}
+            end,
+        },
+        Total = {
+            label = "Total",
+            inputs = { P.list("values", { 1.5, 2 }) },
+            outputs = {},
+            op = function(inputs)
+                TOTAL = 0
+                for _, x in ipairs(inputs.values) do
+                    TOTAL = TOTAL + x
+                end
+                return {}
+            end,
+        },
+        Reroute = {
+            label = "Reroute",
+            inputs = { { name = "mesh", type = "mesh" } },
+            outputs = { { name = "mesh", type = "mesh" } },
+            passthrough = true,
+            returns = "mesh",
+        },
+        Add = {
+            label = "Add",
+            inputs = {
+                { name = "a", type = "scalar", default = 0 },
+                { name = "b", type = "scalar", default = 0 },
+            },
+            outputs = {},
+            op = function(inputs)
+                SUM = inputs.a + inputs.b
+                return {}
+            end,
+        },
+        Fail = {
+            label = "Fail",
+            inputs = {},
+            outputs = {},
+            op = function(inputs)
+                error("Something went wrong")
+            end,
+        },
+    })
+"#;
+
+/// Initializes a Lua state with the blackjack bindings, and loads the
+/// given node library source code into it.
+pub(super) fn test_lua(node_library_src: &str) -> (mlua::Lua, NodeDefinitions) {
+    let lua = mlua::Lua::new();
+    let lua_io = Arc::new(StdLuaFileIo {
+        base_folder: ".".into(),
+    });
+    load_lua_bindings(&lua, lua_io).unwrap();
+    lua.load(node_library_src).exec().unwrap();
+    let nodes = lua
+        .load("require('node_library')")
+        .eval::<mlua::Table>()
+        .unwrap()
+        .get::<_, mlua::Table>("nodes")
+        .unwrap();
+    let node_definitions =
+        NodeDefinitions::new(NodeDefinition::load_nodes_from_table(nodes).unwrap());
+    (lua, node_definitions)
+}
+
+/// Builds a chain of `n` `Counter` nodes, each one taking its input from
+/// the previous one. Returns the graph, its nodes, and the external
+/// parameter value for the first node.
+pub(super) fn counter_chain(n: usize) -> (BjkGraph, Vec<BjkNodeId>, ExternalParameterValues) {
+    let mut graph = BjkGraph::new();
+    let mut nodes = Vec::<BjkNodeId>::new();
+    for _ in 0..n {
+        let node = graph.add_node("Counter", None);
+        graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        if let Some(prev) = nodes.last() {
+            graph.add_connection(*prev, "out", node, "x").unwrap();
+        }
+        nodes.push(node);
+    }
+    let mut params = ExternalParameterValues::default();
+    params.0.insert(
+        ExternalParameter::new(nodes[0], "x".into()),
+        BlackjackValue::Scalar(1.0),
+    );
+    (graph, nodes, params)
+}
+
+pub(super) fn runs(lua: &mlua::Lua) -> u32 {
+    lua.globals().get("RUNS").unwrap()
+}
+
+/// Builds a graph with two nodes, `a` and `b`, where `b` takes its input
+/// from `a`. Optionally, `a` also takes its input from `b`, closing a cycle.
+pub(super) fn two_node_graph(with_cycle: bool) -> (BjkGraph, BjkNodeId, BjkNodeId) {
+    let mut graph = BjkGraph::new();
+    let a = graph.add_node("Test", Some("out_mesh".into()));
+    let b = graph.add_node("Test", Some("out_mesh".into()));
+    for node in [a, b] {
+        graph.add_input(node, "mesh", DataType::Mesh, None).unwrap();
+        graph.add_output(node, "out_mesh", DataType::Mesh).unwrap();
+    }
+    graph.add_connection(a, "out_mesh", b, "mesh").unwrap();
+    if with_cycle {
+        graph.add_connection(b, "out_mesh", a, "mesh").unwrap();
+    }
+    (graph, a, b)
+}
@@ -0,0 +1,361 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// Returns the nodes `target` depends on, directly or transitively, following
+/// `Connection` edges. External parameters are not followed. The nodes are in
+/// topological order: Every node comes after all of its dependencies, so the
+/// `target` itself is the last node.
+///
+/// The graph must not have cycles. See `find_cycle`.
+pub fn reachable_nodes(graph: &BjkGraph, target: BjkNodeId) -> Vec<BjkNodeId> {
+    evaluation_order(graph, target, |_| false)
+}
+
+/// Like `reachable_nodes`, but fails with `InterpreterError::Cycle` when the
+/// nodes `target` depends on form a cycle, instead of returning an order that
+/// can't be evaluated.
+///
+/// This is the order nodes are evaluated in when nothing is cached. It only
+/// depends on the order of each node's inputs, so the same graph always
+/// gives the same order.
+pub fn topological_order(graph: &BjkGraph, target: BjkNodeId) -> Result<Vec<BjkNodeId>> {
+    if let Some(cycle) = find_cycle(graph, target) {
+        return Err(InterpreterError::Cycle(cycle).into());
+    }
+    Ok(reachable_nodes(graph, target))
+}
+
+/// Returns the nodes that none of the `targets` depend on, in the order they
+/// are stored in the graph. Evaluating the targets never runs these nodes, so
+/// they can be removed without changing the result. This is the complement of
+/// the `reachable_nodes` of every target.
+///
+/// Targets that are not in the graph are ignored. Cycles are allowed.
+pub fn unused_nodes(graph: &BjkGraph, targets: &[BjkNodeId]) -> Vec<BjkNodeId> {
+    let used: HashSet<BjkNodeId> = targets
+        .iter()
+        .filter(|target| graph.nodes.contains_key(**target))
+        .flat_map(|target| reachable_nodes(graph, *target))
+        .collect();
+    graph
+        .nodes
+        .keys()
+        .filter(|node_id| !used.contains(node_id))
+        .collect()
+}
+
+/// An external parameter read when evaluating a graph. See
+/// `required_external_params`.
+#[derive(Clone, Debug)]
+pub struct RequiredParameter {
+    pub param: ExternalParameter,
+    /// The definition of the input, from the node library.
+    pub input_def: InputDefinition,
+    /// The name the parameter is promoted as, if any. Promoted parameters are
+    /// meant to be shown to the users of the graph.
+    pub promoted: Option<String>,
+}
+
+/// Returns every external parameter read when evaluating `target`, along with
+/// the definition of its input. The parameters are in evaluation order.
+pub fn required_external_params(
+    graph: &BjkGraph,
+    target: BjkNodeId,
+    node_definitions: &NodeDefinitions,
+) -> Result<Vec<RequiredParameter>> {
+    if let Some(cycle) = find_cycle(graph, target) {
+        return Err(InterpreterError::Cycle(cycle).into());
+    }
+
+    let mut required = Vec::new();
+    for node_id in reachable_nodes(graph, target) {
+        let node = &graph.nodes[node_id];
+        let node_def = node_definitions.node_def(&node.op_name).ok_or_else(|| {
+            InterpreterError::MissingNodeDef {
+                op_name: node.op_name.clone(),
+            }
+        })?;
+        for input in &node.inputs {
+            if let DependencyKind::External { promoted } = &input.kind {
+                let input_def = node_def
+                    .inputs
+                    .iter()
+                    .find(|i| i.name == input.name)
+                    .ok_or_else(|| {
+                        anyhow!("Node {} has no input named {}", node.op_name, input.name)
+                    })?;
+                required.push(RequiredParameter {
+                    param: ExternalParameter::new(node_id, input.name.clone()),
+                    input_def: input_def.clone(),
+                    promoted: promoted.clone(),
+                });
+            }
+        }
+    }
+    Ok(required)
+}
+
+/// Returns the number of connections in the longest chain of dependencies
+/// leading to `target`. External parameters don't count.
+///
+/// The graph must not have cycles. See `find_cycle`.
+pub(super) fn dependency_depth(graph: &BjkGraph, target: BjkNodeId) -> usize {
+    let mut depths = HashMap::<BjkNodeId, usize>::new();
+    // Dependencies come before their dependents, so their depth is known.
+    for node_id in reachable_nodes(graph, target) {
+        let depth = graph.nodes[node_id]
+            .inputs
+            .iter()
+            .filter_map(|input| match &input.kind {
+                DependencyKind::Connection { node, .. } => Some(depths[node] + 1),
+                DependencyKind::External { .. } => None,
+            })
+            .max()
+            .unwrap_or(0);
+        depths.insert(node_id, depth);
+    }
+    depths[&target]
+}
+
+/// Returns whether all the nodes in `order` using the outputs of `node_id` do
+/// so through optional connections.
+pub(super) fn only_optional_dependents(
+    graph: &BjkGraph,
+    order: &[BjkNodeId],
+    node_id: BjkNodeId,
+) -> bool {
+    order.iter().all(|dependent| {
+        graph.nodes[*dependent]
+            .inputs
+            .iter()
+            .all(|input| match &input.kind {
+                DependencyKind::Connection { node, optional, .. } => *node != node_id || *optional,
+                DependencyKind::External { .. } => true,
+            })
+    })
+}
+
+/// Returns the set of nodes `target` depends on, following `Connection`
+/// edges. The set includes `target` itself.
+pub(super) fn dependency_set(graph: &BjkGraph, target: BjkNodeId) -> HashSet<BjkNodeId> {
+    reachable_nodes(graph, target).into_iter().collect()
+}
+
+/// Looks for a cycle among the nodes `target` depends on, following
+/// `Connection` edges. When a cycle is found, returns the path of nodes forming
+/// it, where each node depends on the next and the first and last node are the
+/// same.
+pub fn find_cycle(graph: &BjkGraph, target: BjkNodeId) -> Option<Vec<BjkNodeId>> {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Visit {
+        InProgress,
+        Done,
+    }
+
+    let mut visited = SecondaryMap::<BjkNodeId, Visit>::new();
+    // Iterative DFS. Each entry stores a node and the index of the next input
+    // to explore. The stack is also the path from `target` to the current node.
+    let mut stack = vec![(target, 0)];
+    visited.insert(target, Visit::InProgress);
+
+    while let Some(&(node_id, input_idx)) = stack.last() {
+        if let Some(input) = graph.nodes[node_id].inputs.get(input_idx) {
+            stack.last_mut().unwrap().1 += 1;
+            if let DependencyKind::Connection { node: dep, .. } = &input.kind {
+                match visited.get(*dep) {
+                    Some(Visit::InProgress) => {
+                        let start = stack
+                            .iter()
+                            .position(|(n, _)| n == dep)
+                            .expect("In progress nodes should be in the stack");
+                        let mut cycle = stack[start..].iter().map(|(n, _)| *n).collect_vec();
+                        cycle.push(*dep);
+                        return Some(cycle);
+                    }
+                    Some(Visit::Done) => {}
+                    None => {
+                        visited.insert(*dep, Visit::InProgress);
+                        stack.push((*dep, 0));
+                    }
+                }
+            }
+        } else {
+            visited.insert(node_id, Visit::Done);
+            stack.pop();
+        }
+    }
+
+    None
+}
+
+/// Returns the nodes that need to be evaluated to compute `target`, ordered so
+/// that every node comes after all of its dependencies. Dependencies for which
+/// `is_cached` returns true are skipped, along with their own dependencies.
+/// The `target` is always evaluated, and is the last node in the order.
+pub(super) fn evaluation_order(
+    graph: &BjkGraph,
+    target: BjkNodeId,
+    is_cached: impl Fn(BjkNodeId) -> bool,
+) -> Vec<BjkNodeId> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    // Iterative post-order DFS. Each entry stores a node and the index of the
+    // next input to explore.
+    let mut stack = vec![(target, 0)];
+    visited.insert(target);
+
+    while let Some(&(node_id, input_idx)) = stack.last() {
+        if let Some(input) = graph.nodes[node_id].inputs.get(input_idx) {
+            stack.last_mut().unwrap().1 += 1;
+            if let DependencyKind::Connection { node: dep, .. } = &input.kind {
+                if !is_cached(*dep) && visited.insert(*dep) {
+                    stack.push((*dep, 0));
+                }
+            }
+        } else {
+            order.push(node_id);
+            stack.pop();
+        }
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::DataType;
+    use crate::graph_interpreter::test_utils::*;
+
+    #[test]
+    pub fn test_find_cycle() {
+        let (graph, _, b) = two_node_graph(false);
+        assert_eq!(find_cycle(&graph, b), None);
+
+        let (graph, a, b) = two_node_graph(true);
+        assert_eq!(find_cycle(&graph, b), Some(vec![b, a, b]));
+        assert_eq!(find_cycle(&graph, a), Some(vec![a, b, a]));
+    }
+
+    #[test]
+    pub fn test_run_graph_reports_cycle() {
+        let lua = mlua::Lua::new();
+        let (graph, a, _) = two_node_graph(true);
+        let err = run_graph(
+            &lua,
+            &graph,
+            a,
+            ExternalParameterValues::default(),
+            &NodeDefinitions::default(),
+            None,
+        )
+        .err()
+        .expect("Running a graph with cycles should fail");
+        assert!(err.to_string().contains(&a.display_id()));
+        assert!(matches!(
+            err.downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_deep_graph() {
+        let (lua, node_definitions) = test_lua(TEST_NODES);
+        // The outputs of every node are kept until the end of the run, and
+        // mlua can only hold around 8000 Lua references at a time.
+        let (graph, nodes, params) = counter_chain(5_000);
+        run_graph(
+            &lua,
+            &graph,
+            *nodes.last().unwrap(),
+            params,
+            &node_definitions,
+            None,
+        )
+        .unwrap();
+        assert_eq!(runs(&lua), 5_000);
+    }
+
+    #[test]
+    pub fn test_reachable_nodes() {
+        let (mut graph, nodes, _) = counter_chain(3);
+        assert_eq!(reachable_nodes(&graph, nodes[2]), nodes);
+        assert_eq!(reachable_nodes(&graph, nodes[0]), vec![nodes[0]]);
+
+        // A diamond: `d` depends on `b` and `c`, which both depend on `a`.
+        let (a, b, c) = (nodes[0], nodes[1], nodes[2]);
+        graph.add_connection(a, "out", c, "x").unwrap();
+        let d = graph.add_node("Counter", None);
+        graph.add_input(d, "x", DataType::Scalar, None).unwrap();
+        graph.add_input(d, "y", DataType::Scalar, None).unwrap();
+        graph.add_connection(b, "out", d, "x").unwrap();
+        graph.add_connection(c, "out", d, "y").unwrap();
+        // Not connected to `d`.
+        let unrelated = graph.add_node("Counter", None);
+
+        let reachable = reachable_nodes(&graph, d);
+        assert_eq!(reachable.len(), 4);
+        assert!(!reachable.contains(&unrelated));
+        let pos = |n| reachable.iter().position(|x| *x == n).unwrap();
+        assert!(pos(a) < pos(b) && pos(a) < pos(c));
+        assert!(pos(b) < pos(d) && pos(c) < pos(d));
+    }
+
+    #[test]
+    pub fn test_topological_order() {
+        let (graph, nodes, _) = counter_chain(3);
+        let order = topological_order(&graph, nodes[2]).unwrap();
+        assert_eq!(order, nodes);
+        assert_eq!(order, reachable_nodes(&graph, nodes[2]));
+        assert_eq!(topological_order(&graph, nodes[2]).unwrap(), order);
+
+        let (graph, a, _) = two_node_graph(true);
+        assert!(matches!(
+            topological_order(&graph, a)
+                .err()
+                .unwrap()
+                .downcast_ref::<InterpreterError>(),
+            Some(InterpreterError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_unused_nodes() {
+        let (mut graph, nodes, _) = counter_chain(3);
+        let orphan = graph.add_node("Counter", None);
+        assert_eq!(unused_nodes(&graph, &[nodes[2]]), vec![orphan]);
+        assert_eq!(unused_nodes(&graph, &[nodes[1]]), vec![nodes[2], orphan]);
+        assert!(unused_nodes(&graph, &[nodes[2], orphan]).is_empty());
+        assert_eq!(unused_nodes(&graph, &[]).len(), 4);
+
+        let (graph, a, _) = two_node_graph(true);
+        assert!(unused_nodes(&graph, &[a]).is_empty());
+    }
+
+    #[test]
+    pub fn test_required_external_params() {
+        let (_, node_definitions) = test_lua(TEST_NODES);
+        let (mut graph, nodes, _) = counter_chain(2);
+        let unused = graph.add_node("Counter", None);
+        graph
+            .add_input(unused, "x", DataType::Scalar, Some("unused".into()))
+            .unwrap();
+        graph.nodes[nodes[0]].inputs[0].kind = DependencyKind::External {
+            promoted: Some("Start".into()),
+        };
+
+        let required = required_external_params(&graph, nodes[1], &node_definitions).unwrap();
+        assert_eq!(required.len(), 1);
+        assert_eq!(
+            required[0].param,
+            ExternalParameter::new(nodes[0], "x".into())
+        );
+        assert_eq!(required[0].input_def.data_type, DataType::Scalar);
+        assert_eq!(required[0].promoted.as_deref(), Some("Start"));
+    }
+}
@@ -5,6 +5,8 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::{
+    any::Any,
+    rc::Rc,
     sync::{
         mpsc::{self, Receiver},
         Arc,
@@ -13,17 +15,21 @@ use std::{
 };
 
 use crate::{
-    gizmos::BlackjackGizmo,
-    graph::{BjkNodeId, NodeDefinitions},
-    graph_interpreter::ExternalParameterValues,
-    mesh::heightmap::HeightMap,
+    gizmos::{BlackjackGizmo, GizmoId},
+    graph::{BjkGraph, BjkNodeId, NodeDefinitions},
+    graph_interpreter::{
+        renderable_from_value, run_graph_with_options, EvaluationStats, ExternalParameter,
+        ExternalParameterValues, GizmoState, MemoryUsage, NodeFailure, PrintedLine,
+        RunGraphOptions, Warning,
+    },
+    mesh::{halfedge::ply::PlyFormat, heightmap::HeightMap},
     prelude::*,
 };
 use mlua::Lua;
 use notify::{DebouncedEvent, Watcher};
 use slotmap::SecondaryMap;
 
-use self::lua_stdlib::{load_node_definitions, LuaFileIo, StdLuaFileIo};
+use self::lua_stdlib::{load_node_definitions, LuaFileIo, LuaSandbox, StdLuaFileIo};
 
 pub mod lua_stdlib;
 
@@ -43,10 +49,129 @@ impl<T> ToLuaError<T> for Result<T, TraversalError> {
     }
 }
 
+/// Cloning a renderable makes a deep copy of its geometry, which may be
+/// expensive for large meshes. The data of custom renderables is shared
+/// instead.
 #[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
 pub enum RenderableThing {
     HalfEdgeMesh(HalfEdgeMesh),
     HeightMap(HeightMap),
+    /// A renderable of a type unknown to blackjack, produced by one of the
+    /// converters in a `RenderableRegistry`.
+    Custom(CustomRenderable),
+}
+
+/// The kind of geometry in a `RenderableThing`, for hosts that need to pick
+/// how to draw or export a renderable without matching on its contents. More
+/// kinds may be added in the future.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RenderableKind {
+    /// A mesh with at least one face.
+    Mesh,
+    /// A mesh with edges, but no faces, e.g. a polyline.
+    Curve,
+    /// A mesh with only vertices.
+    PointCloud,
+    HeightMap,
+    Custom,
+}
+
+/// The triangles of a mesh, as plain arrays ready to be uploaded to the GPU.
+/// See `RenderableThing::to_gpu_buffers`.
+#[derive(Clone, Debug, Default)]
+pub struct GpuMesh {
+    /// Vertex positions, one per vertex.
+    pub positions: Vec<[f32; 3]>,
+    /// Vertex normals, one per vertex.
+    pub normals: Vec<[f32; 3]>,
+    /// Indices: 3*N where N is the number of triangles. Indices point to
+    /// elements of `positions` and `normals`.
+    pub indices: Vec<u32>,
+}
+
+impl From<halfedge::VertexIndexBuffers> for GpuMesh {
+    fn from(buffers: halfedge::VertexIndexBuffers) -> Self {
+        GpuMesh {
+            positions: buffers.positions.iter().map(|p| p.to_array()).collect(),
+            normals: buffers.normals.iter().map(|n| n.to_array()).collect(),
+            indices: buffers.indices,
+        }
+    }
+}
+
+/// A renderable produced by a host-provided converter. The host is
+/// responsible for drawing it.
+#[derive(Clone)]
+pub struct CustomRenderable {
+    /// The tag the renderable was registered with.
+    pub kind: String,
+    /// The data produced by the converter. Clones of the renderable share it.
+    pub data: Rc<dyn Any>,
+}
+
+impl CustomRenderable {
+    /// Returns the data of this renderable, if it is of type `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.data.downcast_ref()
+    }
+}
+
+/// The field Lua tables use to identify the kind of custom renderable they
+/// describe.
+pub const RENDERABLE_TAG_FIELD: &str = "__renderable";
+
+/// A converter from a Lua table to the data of a custom renderable.
+pub type RenderableConverter = dyn Fn(&mlua::Table<'_>) -> Result<Box<dyn Any>>;
+
+/// Allows hosts to support renderable types other than meshes and height
+/// maps. Nodes produce custom renderables by returning a table with a
+/// `__renderable` field, and the converter registered for the field's value
+/// turns it into a `RenderableThing::Custom`.
+#[derive(Default)]
+pub struct RenderableRegistry {
+    converters: HashMap<String, Box<RenderableConverter>>,
+}
+
+impl RenderableRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the converter for tables tagged as `kind`. Any previous
+    /// converter for the same kind is replaced.
+    pub fn register(
+        &mut self,
+        kind: impl ToString,
+        converter: impl Fn(&mlua::Table<'_>) -> Result<Box<dyn Any>> + 'static,
+    ) {
+        self.converters
+            .insert(kind.to_string(), Box::new(converter));
+    }
+
+    /// Converts `value` into a custom renderable. Returns `None` when the
+    /// value is not a tagged table, so it can be converted as a regular
+    /// renderable instead. Tables tagged with an unregistered kind are an
+    /// error.
+    pub fn convert(&self, value: &mlua::Value<'_>) -> Result<Option<RenderableThing>> {
+        let table = match value {
+            mlua::Value::Table(table) => table,
+            _ => return Ok(None),
+        };
+        let kind = match table.get::<_, Option<String>>(RENDERABLE_TAG_FIELD)? {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        let converter = self
+            .converters
+            .get(&kind)
+            .ok_or_else(|| anyhow!("No converter registered for renderables of kind '{kind}'"))?;
+        Ok(Some(RenderableThing::Custom(CustomRenderable {
+            data: Rc::from(converter(table)?),
+            kind,
+        })))
+    }
 }
 
 impl RenderableThing {
@@ -63,19 +188,338 @@ impl RenderableThing {
             }
         }
     }
+
+    /// Like `from_lua_value`, but the renderable is copied instead of taken out
+    /// of the Lua value, so the value can still be used afterwards.
+    pub fn from_lua_value_cloned(renderable: &mlua::Value<'_>) -> Result<Self> {
+        match renderable {
+            mlua::Value::UserData(renderable) if renderable.is::<HalfEdgeMesh>() => Ok(
+                RenderableThing::HalfEdgeMesh(renderable.borrow::<HalfEdgeMesh>()?.clone()),
+            ),
+            mlua::Value::UserData(renderable) if renderable.is::<HeightMap>() => Ok(
+                RenderableThing::HeightMap(renderable.borrow::<HeightMap>()?.clone()),
+            ),
+            _ => {
+                bail!("Object {renderable:?} is not a thing we can render.")
+            }
+        }
+    }
+
+    /// Returns the kind of geometry in this renderable. Empty meshes are point
+    /// clouds.
+    pub fn kind(&self) -> RenderableKind {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) => {
+                let conn = mesh.read_connectivity();
+                if conn.num_faces() > 0 {
+                    RenderableKind::Mesh
+                } else if conn.num_halfedges() > 0 {
+                    RenderableKind::Curve
+                } else {
+                    RenderableKind::PointCloud
+                }
+            }
+            RenderableThing::HeightMap(_) => RenderableKind::HeightMap,
+            RenderableThing::Custom(_) => RenderableKind::Custom,
+        }
+    }
+
+    /// Returns the number of vertices of this renderable. For height maps, this
+    /// is the number of vertices of the mesh they are rendered as.
+    pub fn vertex_count(&self) -> usize {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) => mesh.read_connectivity().num_vertices(),
+            RenderableThing::HeightMap(height_map) => height_map.rendered_vertex_count(),
+            RenderableThing::Custom(_) => 0,
+        }
+    }
+
+    /// Returns the number of faces of this renderable, which is always 0 for
+    /// things other than meshes.
+    pub fn face_count(&self) -> usize {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) => mesh.read_connectivity().num_faces(),
+            RenderableThing::HeightMap(_) | RenderableThing::Custom(_) => 0,
+        }
+    }
+
+    /// Returns the min and max corners of the axis-aligned box containing this
+    /// renderable, or `None` when it is empty.
+    pub fn bounding_box(&self) -> Option<(Vec3, Vec3)> {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) => mesh
+                .read_connectivity()
+                .iter_vertices_with_channel(&mesh.read_positions())
+                .map(|(_, _, pos)| (pos, pos))
+                .reduce(|(min, max), (pos, _)| (min.min(pos), max.max(pos))),
+            RenderableThing::HeightMap(height_map) => height_map.bounding_box(),
+            RenderableThing::Custom(_) => None,
+        }
+    }
+
+    /// Triangulates the faces of this renderable, which must be a mesh, into
+    /// buffers for the GPU. With `smooth`, vertices are shared between faces
+    /// and normals are averaged. Otherwise, each triangle gets its own
+    /// vertices, with the normal of its face. Normals stored in the mesh are
+    /// used when present, and generated otherwise. Faces with more than three
+    /// vertices are split using `triangulation`.
+    pub fn to_gpu_buffers(
+        &self,
+        smooth: bool,
+        triangulation: TriangulationStrategy,
+    ) -> Result<GpuMesh> {
+        let buffers = match self {
+            RenderableThing::HalfEdgeMesh(mesh) if smooth => {
+                mesh.generate_triangle_buffers_smooth(false, triangulation)?
+            }
+            RenderableThing::HalfEdgeMesh(mesh) => {
+                mesh.generate_triangle_buffers_flat(false, triangulation)?
+            }
+            RenderableThing::HeightMap(_) => {
+                bail!("Only meshes can be converted to GPU buffers, but this is a height map.")
+            }
+            RenderableThing::Custom(custom) => {
+                bail!(
+                    "Only meshes can be converted to GPU buffers, but this is a custom \
+                     renderable ({}).",
+                    custom.kind
+                )
+            }
+        };
+        Ok(buffers.into())
+    }
+
+    /// Writes this renderable in the Wavefront OBJ format. Only meshes can be
+    /// exported. Faces are written as polygons, without triangulating them.
+    /// See `HalfEdgeMesh::write_wavefront_obj` for details.
+    pub fn export_obj(&self, writer: impl std::io::Write) -> Result<()> {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) => mesh.write_wavefront_obj(writer),
+            RenderableThing::HeightMap(_) => {
+                bail!("Only meshes can be exported to OBJ, but this is a height map.")
+            }
+            RenderableThing::Custom(custom) => {
+                bail!("Custom renderables ({}) can't be exported.", custom.kind)
+            }
+        }
+    }
+
+    /// Writes this renderable as a binary glTF (`.glb`) file. Faces are
+    /// triangulated using `triangulation`, and exported with positions and
+    /// normals.
+    pub fn export_gltf(
+        &self,
+        writer: impl std::io::Write,
+        triangulation: TriangulationStrategy,
+    ) -> Result<()> {
+        let buffers = match self {
+            RenderableThing::HalfEdgeMesh(mesh) if mesh.gen_config.smooth_normals => {
+                mesh.generate_triangle_buffers_smooth(false, triangulation)?
+            }
+            RenderableThing::HalfEdgeMesh(mesh) => {
+                mesh.generate_triangle_buffers_flat(false, triangulation)?
+            }
+            RenderableThing::HeightMap(height_map) => height_map.generate_triangle_buffers(),
+            RenderableThing::Custom(custom) => {
+                bail!("Custom renderables ({}) can't be exported.", custom.kind)
+            }
+        };
+        buffers.write_glb(writer)
+    }
+
+    /// Writes this renderable as a PLY point cloud. Only meshes without faces,
+    /// i.e. point clouds, can be exported. See `HalfEdgeMesh::write_ply` for
+    /// details.
+    pub fn export_ply(&self, writer: impl std::io::Write, format: PlyFormat) -> Result<()> {
+        match self {
+            RenderableThing::HalfEdgeMesh(mesh) if mesh.read_connectivity().num_faces() > 0 => {
+                bail!(
+                    "PLY export only supports point clouds, but this mesh has faces. \
+                     Use `export_obj` or `export_gltf` instead."
+                )
+            }
+            RenderableThing::HalfEdgeMesh(mesh) => mesh.write_ply(writer, format),
+            RenderableThing::HeightMap(_) => {
+                bail!(
+                    "PLY export only supports point clouds, but this is a height map. \
+                     Use `export_gltf` instead."
+                )
+            }
+            RenderableThing::Custom(custom) => {
+                bail!("Custom renderables ({}) can't be exported.", custom.kind)
+            }
+        }
+    }
+}
+
+/// A renderable that has not been converted yet, returned instead of
+/// `ProgramResult::renderable` when requested with
+/// `RunGraphOptions::lazy_renderable`.
+///
+/// The handle keeps the target node's output alive in the Lua state, so
+/// nothing is copied out of Lua until `into_renderable` is called. Some
+/// metadata can be read without converting the renderable at all.
+///
+/// Clones share the same handle. When more than one of them is alive, the
+/// conversion copies the output, so every clone can be converted.
+#[derive(Clone)]
+pub struct LazyRenderable {
+    value: Rc<mlua::RegistryKey>,
+    /// Whether the output is still cached by the interpreter, in which case
+    /// it is copied instead of taken out of Lua.
+    cloned: bool,
+}
+
+impl LazyRenderable {
+    pub(crate) fn new(lua: &Lua, value: mlua::Value<'_>, cloned: bool) -> Result<Self> {
+        Ok(Self {
+            value: Rc::new(lua.create_registry_value(value)?),
+            cloned,
+        })
+    }
+
+    /// Converts the renderable, like `ProgramResult::renderable` would have
+    /// been. `lua` must be the state the graph was run on.
+    pub fn into_renderable(
+        self,
+        lua: &Lua,
+        renderables: Option<&RenderableRegistry>,
+    ) -> Result<RenderableThing> {
+        let value = lua.registry_value::<mlua::Value>(&self.value)?;
+        match Rc::try_unwrap(self.value) {
+            Ok(key) => {
+                let renderable = renderable_from_value(value, renderables, self.cloned);
+                lua.remove_registry_value(key)?;
+                renderable
+            }
+            // Other clones still need the value.
+            Err(_) => renderable_from_value(value, renderables, true),
+        }
+    }
+
+    /// Returns the number of vertices of the renderable, without converting
+    /// it. See `RenderableThing::vertex_count`.
+    pub fn vertex_count(&self, lua: &Lua) -> Result<usize> {
+        match lua.registry_value::<mlua::Value>(&self.value)? {
+            mlua::Value::UserData(u) if u.is::<HalfEdgeMesh>() => Ok(u
+                .borrow::<HalfEdgeMesh>()?
+                .read_connectivity()
+                .num_vertices()),
+            mlua::Value::UserData(u) if u.is::<HeightMap>() => {
+                Ok(u.borrow::<HeightMap>()?.rendered_vertex_count())
+            }
+            mlua::Value::Table(t) if t.contains_key(RENDERABLE_TAG_FIELD)? => Ok(0),
+            other => bail!("Object {other:?} is not a thing we can render."),
+        }
+    }
+
+    /// Returns the number of faces of the renderable, without converting it.
+    /// See `RenderableThing::face_count`.
+    pub fn face_count(&self, lua: &Lua) -> Result<usize> {
+        match lua.registry_value::<mlua::Value>(&self.value)? {
+            mlua::Value::UserData(u) if u.is::<HalfEdgeMesh>() => {
+                Ok(u.borrow::<HalfEdgeMesh>()?.read_connectivity().num_faces())
+            }
+            mlua::Value::UserData(u) if u.is::<HeightMap>() => Ok(0),
+            mlua::Value::Table(t) if t.contains_key(RENDERABLE_TAG_FIELD)? => Ok(0),
+            other => bail!("Object {other:?} is not a thing we can render."),
+        }
+    }
 }
 
 /// The result of an invocation to a lua program.
+///
+/// Cloning a result makes a deep copy of its renderables. See
+/// `RenderableThing`.
+#[derive(Clone)]
 pub struct ProgramResult {
     /// The renderable thing produced by this program to be shown on-screen.
+    /// This is `None` when `lazy_renderable` is returned instead.
     pub renderable: Option<RenderableThing>,
+    /// The unconverted renderable, when requested via
+    /// `RunGraphOptions::lazy_renderable`.
+    pub lazy_renderable: Option<LazyRenderable>,
+    /// The output of the target node `renderable` was taken from, if any.
+    pub return_value: Option<String>,
+    /// The names of all the outputs produced by the target node, sorted. Any
+    /// of these can be set as the node's return value to preview it instead.
+    pub output_names: Vec<String>,
     /// The gizmos requested by graph nodes after an execution of this program.
     /// If you are implementing an integration, you can ignore this field. This
     /// field will be returned as None will be none when gizmos aren't run.
+    ///
+    /// Gizmos are grouped by the node that produced them, in the same order
+    /// as the node definition's `gizmos` table, so each one can be routed back
+    /// to its node. The `gizmos` table must be an array, so this order is the
+    /// same on every run.
     pub updated_gizmos: Option<SecondaryMap<BjkNodeId, Vec<BlackjackGizmo>>>,
+    /// The identity of each of the gizmos in `updated_gizmos`, in the same
+    /// order. Hosts can use these to match the gizmos of this run with the
+    /// ones of the previous run. See `GizmoId`.
+    pub gizmo_ids: Option<SecondaryMap<BjkNodeId, Vec<GizmoId>>>,
     /// The updated external parameters. Any node may modify its own parameters
     /// when running its gizmo function.
     pub updated_values: ExternalParameterValues,
+    /// The external parameters in `updated_values` that were modified by a
+    /// gizmo during this run, so integrations can apply only the changes.
+    pub changed_params: Vec<ExternalParameter>,
+    /// The renderables for every node that was evaluated, when requested via
+    /// `RunDiagnostics::collect_all_outputs`. Nodes without any renderable
+    /// output are not present in the map.
+    pub all_outputs: Option<HashMap<BjkNodeId, RenderableThing>>,
+    /// The time spent in the `op` function of each evaluated node, when
+    /// requested via `RunDiagnostics::profile`. Timings are exclusive: The
+    /// time spent evaluating a node's dependencies is not included.
+    pub node_timings: Option<HashMap<BjkNodeId, Duration>>,
+    /// The recoverable problems found while running the graph, when requested
+    /// via `RunDiagnostics::collect_warnings`.
+    pub warnings: Option<Vec<Warning>>,
+    /// The lines printed by the nodes during the run, when requested via
+    /// `RunDiagnostics::capture_print`.
+    pub printed: Option<Vec<PrintedLine>>,
+    /// The memory used by the Lua state before and after the run, when
+    /// requested via `RunDiagnostics::report_memory`.
+    pub memory_usage: Option<MemoryUsage>,
+    /// The nodes that failed during the run, when using
+    /// `ErrorPolicy::ContinueWithPlaceholder`.
+    pub failed_nodes: Option<Vec<NodeFailure>>,
+    /// The keys of the output table produced by each evaluated node, sorted,
+    /// when requested via `RunDiagnostics::record_output_keys`. Unlike the
+    /// outputs in the node definitions, these are the outputs each node
+    /// actually returned in this run.
+    pub output_keys: Option<HashMap<BjkNodeId, Vec<String>>>,
+    /// How many of the nodes the target depends on were evaluated in this
+    /// run, and how many were served from a cache.
+    pub stats: EvaluationStats,
+}
+
+impl ProgramResult {
+    /// A result with the given `renderable` and nothing else, as if the graph
+    /// ran without any of the optional outputs. Useful to build the canned
+    /// results of a `MockEvaluator`.
+    pub fn new(
+        renderable: Option<RenderableThing>,
+        updated_values: ExternalParameterValues,
+    ) -> Self {
+        ProgramResult {
+            renderable,
+            lazy_renderable: None,
+            return_value: None,
+            output_names: Vec::new(),
+            updated_gizmos: None,
+            gizmo_ids: None,
+            updated_values,
+            changed_params: Vec::new(),
+            all_outputs: None,
+            node_timings: None,
+            warnings: None,
+            printed: None,
+            memory_usage: None,
+            failed_nodes: None,
+            output_keys: None,
+            stats: EvaluationStats::default(),
+        }
+    }
 }
 
 pub struct LuaFileWatcher {
@@ -83,6 +527,15 @@ pub struct LuaFileWatcher {
     pub watcher_channel: Receiver<notify::DebouncedEvent>,
 }
 
+/// A Lua state with the node library loaded, and the node definitions read
+/// from it.
+///
+/// Loading the node library runs all of its Lua files, so hosts evaluating
+/// many graphs, like a server rendering user submissions, should initialize a
+/// runtime once and evaluate every graph with `run`. Nothing from a run is
+/// kept in the runtime, other than what the node library itself stores in Lua
+/// globals, so runs don't affect each other. A runtime is not `Send`. Hosts
+/// running graphs on several threads need one runtime per thread.
 pub struct LuaRuntime {
     pub lua: Lua,
     pub node_definitions: NodeDefinitions,
@@ -114,6 +567,66 @@ impl LuaRuntime {
         })
     }
 
+    /// Like `initialize_custom`, but the node libraries run inside the given
+    /// sandbox. Note that with the default sandbox, node libraries can only
+    /// require the builtin libraries. Any modules they depend on must be added
+    /// to `LuaSandbox::allowed_modules`.
+    pub fn initialize_sandboxed(
+        lua_io: impl LuaFileIo + 'static,
+        sandbox: &LuaSandbox,
+    ) -> anyhow::Result<LuaRuntime> {
+        let lua = Lua::new();
+        let lua_io = Arc::new(lua_io);
+        lua_stdlib::load_lua_bindings(&lua, lua_io.clone())?;
+        lua_stdlib::install_sandbox(&lua, sandbox)?;
+        let node_definitions = NodeDefinitions::new(load_node_definitions(&lua, lua_io.as_ref())?);
+
+        Ok(LuaRuntime {
+            lua,
+            node_definitions,
+            file_watcher: None,
+            lua_io,
+        })
+    }
+
+    /// Runs the graph in this runtime, like `run_graph`. See `run_graph` for
+    /// the meaning of the arguments.
+    pub fn run(
+        &self,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+    ) -> Result<ProgramResult> {
+        self.run_with_options(
+            graph,
+            target_node,
+            external_param_values,
+            gizmos_state,
+            RunGraphOptions::default(),
+        )
+    }
+
+    /// Like `run`, with additional options. See `RunGraphOptions`.
+    pub fn run_with_options(
+        &self,
+        graph: &BjkGraph,
+        target_node: BjkNodeId,
+        external_param_values: ExternalParameterValues,
+        gizmos_state: Option<SecondaryMap<BjkNodeId, GizmoState>>,
+        options: RunGraphOptions<'_>,
+    ) -> Result<ProgramResult> {
+        run_graph_with_options(
+            &self.lua,
+            graph,
+            target_node,
+            external_param_values,
+            &self.node_definitions,
+            gizmos_state,
+            options,
+        )
+    }
+
     pub fn start_file_watcher(&mut self) -> Result<()> {
         let (tx, rx) = mpsc::channel();
         let mut watcher = notify::watcher(tx, Duration::from_secs(1))?;
@@ -125,6 +638,29 @@ impl LuaRuntime {
         Ok(())
     }
 
+    /// Runs the Lua source files again, picking up any changes in the node
+    /// library, without creating a new Lua state. The `NodeDefinitions` are
+    /// updated in place, so every place sharing them sees the new ones.
+    ///
+    /// Graph runs after this will use the new `op` implementations. Outputs
+    /// cached from previous runs, e.g. in a `CachedInterpreter`, should be
+    /// discarded by the caller.
+    pub fn reload_node_definitions(&mut self) -> anyhow::Result<()> {
+        // Reset the _LOADED table to clear any required libraries from the
+        // cache. This will trigger reloading of libraries when the hot
+        // reloaded code first requires them, effectively picking up changes in
+        // transitively required libraries as well.
+        self.lua
+            .globals()
+            .set("_LOADED", self.lua.create_table()?)?;
+
+        // By calling this, all code under $BLACKJACK_LUA/run will be executed
+        // and the node definitions will be reloaded.
+        self.node_definitions
+            .update(load_node_definitions(&self.lua, self.lua_io.as_ref())?);
+        Ok(())
+    }
+
     /// Watches the lua source folders for changes. Returns true when a change
     /// was detected and the `NodeDefinitions` were successfully updated.
     pub fn watch_for_changes(&mut self) -> anyhow::Result<bool> {
@@ -139,19 +675,7 @@ impl LuaRuntime {
                 | DebouncedEvent::Remove(_)
                 | DebouncedEvent::Rename(_, _) => {
                     println!("Reloading Lua scripts...");
-                    // Reset the _LOADED table to clear any required libraries
-                    // from the cache. This will trigger reloading of libraries
-                    // when the hot reloaded code first requires them,
-                    // effectively picking up changes in transitively required
-                    // libraries as well.
-                    self.lua
-                        .globals()
-                        .set("_LOADED", self.lua.create_table()?)?;
-
-                    // By calling this, all code under $BLACKJACK_LUA/run will
-                    // be executed and the node definitions will be reloaded.
-                    self.node_definitions
-                        .update(load_node_definitions(&self.lua, self.lua_io.as_ref())?);
+                    self.reload_node_definitions()?;
                 }
                 _ => {}
             }
@@ -161,3 +685,240 @@ impl LuaRuntime {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        graph::{BjkGraph, DataType},
+        graph_interpreter::run_graph,
+        lua_engine::lua_stdlib::LuaSourceFile,
+    };
+
+    /// A `LuaFileIo` with a single run file, whose contents can be changed.
+    struct MemoryLuaFileIo(Rc<RefCell<String>>);
+
+    impl LuaFileIo for MemoryLuaFileIo {
+        fn base_folder(&self) -> &str {
+            ""
+        }
+
+        fn find_run_files(&self) -> Box<dyn Iterator<Item = String>> {
+            Box::new(std::iter::once("run.lua".to_string()))
+        }
+
+        fn load_file_absolute(&self, path: &str) -> anyhow::Result<LuaSourceFile> {
+            Ok(LuaSourceFile {
+                contents: self.0.borrow().clone(),
+                name: path.into(),
+            })
+        }
+
+        fn load_file_require(&self, path: &str) -> anyhow::Result<LuaSourceFile> {
+            bail!("No file at {path}")
+        }
+    }
+
+    fn node_library(version: u32) -> String {
+        format!(
+            r#"
+            require('node_library'):addNodes({{
+                Version = {{
+                    label = "Version {version}",
+                    inputs = {{}},
+                    outputs = {{ {{ name = "out", type = "scalar" }} }},
+                    op = function(inputs)
+                        VERSION = {version}
+                        return {{ out = {version} }}
+                    end,
+                }},
+            }})
+            "#
+        )
+    }
+
+    #[test]
+    pub fn test_export() {
+        let cube =
+            RenderableThing::HalfEdgeMesh(halfedge::primitives::Box::build(Vec3::ZERO, Vec3::ONE));
+        let mut obj = Vec::new();
+        cube.export_obj(&mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        let count = |prefix: &str| obj.lines().filter(|l| l.starts_with(prefix)).count();
+        assert_eq!(count("v "), 8);
+        assert_eq!(count("f "), 6);
+
+        let mut glb = Vec::new();
+        cube.export_gltf(&mut glb, TriangulationStrategy::default())
+            .unwrap();
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(
+            u32::from_le_bytes(glb[8..12].try_into().unwrap()),
+            glb.len() as u32
+        );
+
+        let err = cube.export_ply(Vec::new(), PlyFormat::Ascii).unwrap_err();
+        assert!(err.to_string().contains("export_obj"));
+        let mut points = HalfEdgeMesh::new();
+        halfedge::edit_ops::add_vertex(&mut points, Vec3::ZERO).unwrap();
+        halfedge::edit_ops::add_vertex(&mut points, Vec3::X).unwrap();
+        let points = RenderableThing::HalfEdgeMesh(points);
+        let mut ply = Vec::new();
+        points.export_ply(&mut ply, PlyFormat::Ascii).unwrap();
+        let ply = String::from_utf8(ply).unwrap();
+        assert!(ply.contains("element vertex 2"));
+        assert!(ply.ends_with("end_header\n0 0 0\n1 0 0\n"));
+        let mut ply = Vec::new();
+        points
+            .export_ply(&mut ply, PlyFormat::BinaryLittleEndian)
+            .unwrap();
+        assert!(ply.ends_with(&[0, 0, 0x80, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0]));
+
+        let height_map =
+            RenderableThing::HeightMap(HeightMap::from_perlin(4, 4, 1.0, Vec2::ZERO, 1.0));
+        let err = height_map.export_obj(Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("Only meshes"));
+    }
+
+    #[test]
+    pub fn test_renderable_metadata() {
+        let cube =
+            RenderableThing::HalfEdgeMesh(halfedge::primitives::Box::build(Vec3::ZERO, Vec3::ONE));
+        assert_eq!(cube.vertex_count(), 8);
+        assert_eq!(cube.face_count(), 6);
+        assert_eq!(
+            cube.bounding_box(),
+            Some((Vec3::splat(-0.5), Vec3::splat(0.5)))
+        );
+        assert_eq!(
+            RenderableThing::HalfEdgeMesh(HalfEdgeMesh::new()).bounding_box(),
+            None
+        );
+
+        let height_map =
+            RenderableThing::HeightMap(HeightMap::from_perlin(6, 6, 1.0, Vec2::ZERO, 1.0));
+        assert_eq!(height_map.vertex_count(), 16);
+        assert_eq!(height_map.face_count(), 0);
+        let (min, max) = height_map.bounding_box().unwrap();
+        assert_eq!((min.x, max.x), (0.05, 0.2));
+    }
+
+    #[test]
+    pub fn test_renderable_kind() {
+        let cube =
+            RenderableThing::HalfEdgeMesh(halfedge::primitives::Box::build(Vec3::ZERO, Vec3::ONE));
+        assert_eq!(cube.kind(), RenderableKind::Mesh);
+
+        let line = HalfEdgeMesh::new();
+        halfedge::edit_ops::add_edge(&line, Vec3::ZERO, Vec3::X).unwrap();
+        assert_eq!(
+            RenderableThing::HalfEdgeMesh(line).kind(),
+            RenderableKind::Curve
+        );
+
+        let mut points = HalfEdgeMesh::new();
+        halfedge::edit_ops::add_vertex(&mut points, Vec3::ZERO).unwrap();
+        assert_eq!(
+            RenderableThing::HalfEdgeMesh(points).kind(),
+            RenderableKind::PointCloud
+        );
+
+        let height_map =
+            RenderableThing::HeightMap(HeightMap::from_perlin(4, 4, 1.0, Vec2::ZERO, 1.0));
+        assert_eq!(height_map.kind(), RenderableKind::HeightMap);
+    }
+
+    #[test]
+    pub fn test_gpu_buffers() {
+        let cube =
+            RenderableThing::HalfEdgeMesh(halfedge::primitives::Box::build(Vec3::ZERO, Vec3::ONE));
+        let flat = cube
+            .to_gpu_buffers(false, TriangulationStrategy::Fan)
+            .unwrap();
+        // 6 quads, split in 2 triangles each.
+        assert_eq!(flat.indices.len(), 36);
+        assert_eq!(flat.positions.len(), 36);
+        assert_eq!(flat.normals.len(), 36);
+        let smooth = cube
+            .to_gpu_buffers(true, TriangulationStrategy::EarClipping)
+            .unwrap();
+        assert_eq!(smooth.indices.len(), 36);
+        assert_eq!(smooth.positions.len(), 8);
+        assert!(smooth.indices.iter().all(|i| (*i as usize) < 8));
+
+        let height_map =
+            RenderableThing::HeightMap(HeightMap::from_perlin(4, 4, 1.0, Vec2::ZERO, 1.0));
+        assert!(height_map
+            .to_gpu_buffers(false, TriangulationStrategy::default())
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_runtime_run() {
+        // The library counts how many times it was loaded.
+        let source = Rc::new(RefCell::new(
+            r#"
+            LOADS = (LOADS or 0) + 1
+            require('node_library'):addNodes({
+                Double = {
+                    label = "Double",
+                    inputs = { { name = "x", type = "scalar", default = 0 } },
+                    outputs = { { name = "out", type = "scalar" } },
+                    op = function(inputs)
+                        DOUBLED = inputs.x * 2
+                        return { out = DOUBLED }
+                    end,
+                },
+            })
+            "#
+            .to_string(),
+        ));
+        let runtime = LuaRuntime::initialize_custom(MemoryLuaFileIo(source)).unwrap();
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Double", None);
+        graph.add_input(node, "x", DataType::Scalar, None).unwrap();
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        for i in 0..10 {
+            let params = ExternalParameterValues::builder()
+                .scalar(node, "x", i as f32)
+                .build();
+            let result = runtime.run(&graph, node, params, None).unwrap();
+            assert!(result.renderable.is_none());
+            assert_eq!(result.output_names, vec!["out".to_string()]);
+            let globals = runtime.lua.globals();
+            assert_eq!(globals.get::<_, f32>("DOUBLED").unwrap(), i as f32 * 2.0);
+            assert_eq!(globals.get::<_, u32>("LOADS").unwrap(), 1);
+        }
+    }
+
+    #[test]
+    pub fn test_reload_node_definitions() {
+        let source = Rc::new(RefCell::new(node_library(1)));
+        let mut runtime = LuaRuntime::initialize_custom(MemoryLuaFileIo(source.clone())).unwrap();
+        let shared_defs = runtime.node_definitions.share();
+
+        let mut graph = BjkGraph::new();
+        let node = graph.add_node("Version", None);
+        graph.add_output(node, "out", DataType::Scalar).unwrap();
+        let run = |runtime: &LuaRuntime| {
+            run_graph(
+                &runtime.lua,
+                &graph,
+                node,
+                ExternalParameterValues::default(),
+                &runtime.node_definitions,
+                None,
+            )
+            .unwrap();
+            runtime.lua.globals().get::<_, u32>("VERSION").unwrap()
+        };
+
+        assert_eq!(run(&runtime), 1);
+        *source.borrow_mut() = node_library(2);
+        runtime.reload_node_definitions().unwrap();
+        assert_eq!(run(&runtime), 2);
+        assert_eq!(shared_defs.node_def("Version").unwrap().label, "Version 2");
+    }
+}
@@ -6,12 +6,13 @@
 
 use std::sync::Arc;
 
-use mlua::{FromLua, Lua, Table, ToLua, UserData};
+use mlua::{FromLua, IntoLua, Lua, Table, UserData};
 
 use crate::{
     lua_engine::ToLuaError,
     prelude::halfedge::{
         id_types::{FaceId, HalfEdgeId, VertexId},
+        selection::SelectionExpression,
         ChannelKeyType, ChannelValueType,
     },
 };
@@ -24,6 +25,9 @@ pub use lua_require_io::*;
 
 mod lua_core_library;
 
+mod lua_sandbox;
+pub use lua_sandbox::*;
+
 pub mod lua_documentation;
 
 /// A function pointer to register global lua functions. Stored globally using
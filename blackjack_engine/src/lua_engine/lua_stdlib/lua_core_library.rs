@@ -62,7 +62,7 @@ pub fn load(lua: &Lua, lua_io: Arc<dyn LuaFileIo + 'static>) -> anyhow::Result<(
                 Ok(v) => Ok(mlua::MultiValue::from_vec(vec![v])),
                 Err(err) => Ok(mlua::MultiValue::from_vec(vec![
                     mlua::Nil,
-                    format!("{err}").to_lua(lua)?,
+                    format!("{err}").into_lua(lua)?,
                 ])),
             }
         })?,
@@ -4,17 +4,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{borrow::Cow, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use crate::graph::{NodeDefinition, NodeDefinitionsInner};
+use crate::graph::{NodeDefinition, NodeDefinitions, NodeDefinitionsInner};
 
 pub struct LuaSourceFile {
     pub contents: String,
     pub name: String,
 }
 
-impl<'lua> mlua::AsChunk<'lua> for LuaSourceFile {
-    fn source(&self) -> std::result::Result<Cow<'_, [u8]>, std::io::Error> {
+impl<'lua, 'a> mlua::AsChunk<'lua, 'a> for &'a LuaSourceFile {
+    fn source(self) -> std::result::Result<Cow<'a, [u8]>, std::io::Error> {
         Ok(Cow::Borrowed(self.contents.as_bytes()))
     }
 
@@ -103,18 +107,288 @@ impl LuaFileIo for StdLuaFileIo {
 
 /// Scans and runs all files inside $BLACKJACK_LUA/run. Then, parses every
 /// registered node and returns a `NodeDefinitions` object with the nodes.
+///
+/// Two files registering a node with the same name is an error, since only
+/// one of them could be used.
 pub fn load_node_definitions(
     lua: &mlua::Lua,
     lua_io: &dyn LuaFileIo,
 ) -> anyhow::Result<NodeDefinitionsInner> {
+    let table = lua
+        .load("require('node_library')")
+        .eval::<mlua::Table>()?
+        .get::<_, mlua::Table>("nodes")?;
+    let mut tracker = NodeSources::new(&table)?;
+
     for path in lua_io.find_run_files() {
         let file = lua_io.load_file_absolute(&path)?;
         lua.load(&file).exec()?;
+        for node in tracker.registered_by(&table, Path::new(&path))? {
+            if let Some(previous) = node.replaced {
+                anyhow::bail!(
+                    "Node {} is defined both in {} and {path}",
+                    node.op_name,
+                    previous.display()
+                );
+            }
+        }
     }
 
-    let table = lua
-        .load("require('node_library')")
-        .eval::<mlua::Table>()?
-        .get::<_, mlua::Table>("nodes")?;
     NodeDefinition::load_nodes_from_table(table)
 }
+
+/// A node registered by a file. See `NodeSources::registered_by`.
+struct RegisteredNode<'lua> {
+    op_name: String,
+    table: mlua::Table<'lua>,
+    /// When this node replaced one registered by a different file, the path
+    /// of that file.
+    replaced: Option<PathBuf>,
+}
+
+/// Keeps track of the file each node in the node library was registered by.
+struct NodeSources<'lua> {
+    /// The table of each node, to find out which nodes were added or replaced
+    /// by each file.
+    tables: HashMap<String, mlua::Table<'lua>>,
+    sources: HashMap<String, PathBuf>,
+}
+
+impl<'lua> NodeSources<'lua> {
+    /// Nodes that are already in the node library are not attributed to any
+    /// file.
+    fn new(node_library: &mlua::Table<'lua>) -> mlua::Result<Self> {
+        Ok(Self {
+            tables: node_library
+                .clone()
+                .pairs::<String, mlua::Table>()
+                .collect::<mlua::Result<_>>()?,
+            sources: HashMap::new(),
+        })
+    }
+
+    /// Returns the nodes added or replaced after running the file at `path`.
+    fn registered_by(
+        &mut self,
+        node_library: &mlua::Table<'lua>,
+        path: &Path,
+    ) -> mlua::Result<Vec<RegisteredNode<'lua>>> {
+        let mut registered = Vec::new();
+        for pair in node_library.clone().pairs::<String, mlua::Table>() {
+            let (op_name, table) = pair?;
+            if self.tables.get(&op_name) == Some(&table) {
+                continue;
+            }
+            self.tables.insert(op_name.clone(), table.clone());
+            let replaced = self
+                .sources
+                .insert(op_name.clone(), path.to_owned())
+                .filter(|previous| previous != path);
+            registered.push(RegisteredNode {
+                op_name,
+                table,
+                replaced,
+            });
+        }
+        Ok(registered)
+    }
+}
+
+/// A problem found by `NodeDefinitions::load_from_dir` in one of the files.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    /// The file that failed to load.
+    pub path: PathBuf,
+    /// The node that could not be registered, if the file itself ran fine.
+    pub op_name: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(op_name) = &self.op_name {
+            write!(f, " ({op_name})")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl NodeDefinitions {
+    /// Runs every `.lua` file inside `path` and its subfolders, in
+    /// alphabetical order, and returns the definitions for the nodes they
+    /// register. The Lua bindings must already be loaded in `lua`.
+    ///
+    /// Files that fail to run, nodes that can't be parsed and nodes defined by
+    /// more than one file are reported as `LoadError`s, and the remaining
+    /// nodes are still loaded. Only
+    /// failing to read the node library itself aborts the load.
+    pub fn load_from_dir(
+        path: impl AsRef<Path>,
+        lua: &mlua::Lua,
+    ) -> anyhow::Result<(NodeDefinitions, Vec<LoadError>)> {
+        let mut files = walkdir::WalkDir::new(path)
+            .follow_links(true)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_type().is_file() && e.path().extension().map_or(false, |ext| ext == "lua")
+            })
+            .map(|e| e.into_path())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let node_library = lua
+            .load("require('node_library')")
+            .eval::<mlua::Table>()?
+            .get::<_, mlua::Table>("nodes")?;
+
+        let mut definitions = NodeDefinitionsInner::default();
+        let mut errors = Vec::new();
+        let mut tracker = NodeSources::new(&node_library)?;
+        for file in files {
+            let result = std::fs::read_to_string(&file)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| {
+                    lua.load(&LuaSourceFile {
+                        contents,
+                        name: file.display().to_string(),
+                    })
+                    .exec()
+                    .map_err(anyhow::Error::from)
+                });
+            if let Err(err) = result {
+                errors.push(LoadError {
+                    path: file.clone(),
+                    op_name: None,
+                    message: err.to_string(),
+                });
+            }
+
+            // NOTE: A file that fails halfway may have registered some nodes.
+            for RegisteredNode {
+                op_name,
+                table,
+                replaced,
+            } in tracker.registered_by(&node_library, &file)?
+            {
+                // The node library keeps the last definition, so that's the
+                // one that is loaded.
+                if let Some(previous) = replaced {
+                    errors.push(LoadError {
+                        path: file.clone(),
+                        op_name: Some(op_name.clone()),
+                        message: format!(
+                            "This node is also defined in {}, that definition is replaced",
+                            previous.display()
+                        ),
+                    });
+                }
+                match NodeDefinition::from_lua(op_name.clone(), table) {
+                    Ok(node_def) => {
+                        definitions.0.insert(op_name, node_def);
+                    }
+                    Err(err) => {
+                        definitions.0.remove(&op_name);
+                        errors.push(LoadError {
+                            path: file.clone(),
+                            op_name: Some(op_name),
+                            message: err.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok((NodeDefinitions::new(definitions), errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::lua_engine::lua_stdlib::load_lua_bindings;
+
+    #[test]
+    pub fn test_load_from_dir() {
+        let dir = std::env::temp_dir().join(format!("bjk_load_from_dir_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("pack")).unwrap();
+        let node = |name: &str| {
+            format!(
+                "require('node_library'):addNodes({{ {name} = {{
+                    label = '{name}', inputs = {{}}, outputs = {{}},
+                    op = function(inputs) return {{}} end,
+                }} }})"
+            )
+        };
+        std::fs::write(dir.join("a.lua"), node("Good")).unwrap();
+        std::fs::write(dir.join("pack/b.lua"), node("AlsoGood")).unwrap();
+        std::fs::write(dir.join("broken.lua"), "this is not lua").unwrap();
+        std::fs::write(
+            dir.join("c.lua"),
+            "require('node_library'):addNodes({ NoOutputs = { label = 'NoOutputs', inputs = {} } })",
+        )
+        .unwrap();
+
+        let lua = mlua::Lua::new();
+        let lua_io = Arc::new(StdLuaFileIo {
+            base_folder: ".".into(),
+        });
+        load_lua_bindings(&lua, lua_io).unwrap();
+        let result = NodeDefinitions::load_from_dir(&dir, &lua);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (node_definitions, errors) = result.unwrap();
+
+        assert_eq!(
+            node_definitions
+                .node_names()
+                .into_iter()
+                .sorted()
+                .collect_vec(),
+            vec!["AlsoGood", "Good"]
+        );
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors[0].path.ends_with("broken.lua"));
+        assert_eq!(errors[1].op_name.as_deref(), Some("NoOutputs"));
+    }
+
+    #[test]
+    pub fn test_duplicate_nodes() {
+        let dir = std::env::temp_dir().join(format!("bjk_duplicate_nodes_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("run")).unwrap();
+        let node = |label: &str| {
+            format!(
+                "require('node_library'):addNodes({{ Node = {{
+                    label = '{label}', inputs = {{}}, outputs = {{}},
+                    op = function(inputs) return {{}} end,
+                }} }})"
+            )
+        };
+        std::fs::write(dir.join("run/a.lua"), node("First")).unwrap();
+        std::fs::write(dir.join("run/b.lua"), node("Second")).unwrap();
+
+        let lua = mlua::Lua::new();
+        let lua_io = Arc::new(StdLuaFileIo {
+            base_folder: dir.display().to_string(),
+        });
+        load_lua_bindings(&lua, lua_io.clone()).unwrap();
+        let err = load_node_definitions(&lua, lua_io.as_ref()).err();
+
+        let lua = mlua::Lua::new();
+        load_lua_bindings(&lua, lua_io).unwrap();
+        let result = NodeDefinitions::load_from_dir(dir.join("run"), &lua);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let err = err.unwrap().to_string();
+        assert!(err.contains("a.lua") && err.contains("b.lua"), "{err}");
+        let (node_definitions, errors) = result.unwrap();
+        assert_eq!(node_definitions.node_def("Node").unwrap().label, "Second");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].path.ends_with("b.lua"));
+        assert!(errors[0].message.contains("a.lua"));
+    }
+}
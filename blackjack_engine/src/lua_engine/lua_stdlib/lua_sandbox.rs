@@ -0,0 +1,174 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashSet;
+
+use mlua::{Function, Lua};
+
+/// The libraries that are baked into the blackjack binary. These can always be
+/// required, even in a sandbox.
+pub const BUILTIN_LIBRARIES: &[&str] = &["params", "node_library", "utils"];
+
+/// Describes the restrictions of a sandboxed Lua state. Sandboxing is needed
+/// to safely run node libraries from untrusted sources, since node code could
+/// otherwise access the host's filesystem.
+#[derive(Debug, Clone)]
+pub struct LuaSandbox {
+    /// The names of the globals that are removed from the Lua state.
+    pub removed_globals: Vec<String>,
+    /// The functions that are removed from global tables, as `Table.field`
+    /// entries, like `"HalfEdgeMesh.to_wavefront_obj"`.
+    pub removed_functions: Vec<String>,
+    /// The modules Lua code can `require`, in addition to the
+    /// `BUILTIN_LIBRARIES`. When `None`, any module can be required.
+    pub allowed_modules: Option<HashSet<String>>,
+}
+
+impl Default for LuaSandbox {
+    /// Removes every global and function giving access to the filesystem, or
+    /// to loading arbitrary code, and only allows requiring the builtin
+    /// libraries.
+    fn default() -> Self {
+        Self {
+            removed_globals: [
+                "Io",
+                "io",
+                "os",
+                "package",
+                "loadstring",
+                "dofile",
+                "loadfile",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            removed_functions: [
+                "HalfEdgeMesh.to_wavefront_obj",
+                "HalfEdgeMesh.from_wavefront_obj",
+                "bjk.import_obj",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            allowed_modules: Some(HashSet::new()),
+        }
+    }
+}
+
+/// Installs the given sandbox in a Lua state where the blackjack bindings have
+/// already been loaded. The sandbox applies to all code running afterwards.
+pub fn install_sandbox(lua: &Lua, sandbox: &LuaSandbox) -> anyhow::Result<()> {
+    let globals = lua.globals();
+    for global in &sandbox.removed_globals {
+        globals.set(global.as_str(), mlua::Value::Nil)?;
+    }
+    for function in &sandbox.removed_functions {
+        let (table, field) = function.split_once('.').ok_or_else(|| {
+            anyhow::anyhow!("Removed functions must be 'Table.field' entries, got '{function}'")
+        })?;
+        // The table may have been removed already, or never loaded.
+        if let Some(table) = globals.get::<_, Option<mlua::Table>>(table)? {
+            table.set(field, mlua::Value::Nil)?;
+        }
+    }
+
+    if let Some(allowed_modules) = sandbox.allowed_modules.clone() {
+        let require = lua.create_registry_value(globals.get::<_, Function>("require")?)?;
+        globals.set(
+            "require",
+            lua.create_function(move |lua, module: String| {
+                if !BUILTIN_LIBRARIES.contains(&module.as_str())
+                    && !allowed_modules.contains(&module)
+                {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "Requiring module '{module}' is not allowed in this sandbox"
+                    )));
+                }
+                lua.registry_value::<Function>(&require)?
+                    .call::<_, mlua::Value>(module)
+            })?,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::lua_engine::lua_stdlib::{load_lua_bindings, StdLuaFileIo};
+
+    fn sandboxed_lua(sandbox: &LuaSandbox) -> Lua {
+        let lua = Lua::new();
+        let lua_io = Arc::new(StdLuaFileIo {
+            base_folder: ".".into(),
+        });
+        load_lua_bindings(&lua, lua_io).unwrap();
+        install_sandbox(&lua, sandbox).unwrap();
+        lua
+    }
+
+    #[test]
+    pub fn test_default_sandbox() {
+        let lua = sandboxed_lua(&LuaSandbox::default());
+        assert!(lua
+            .load("return Io == nil and os == nil")
+            .eval::<bool>()
+            .unwrap());
+        assert!(lua.load("require('node_library')").exec().is_ok());
+        let err = lua.load("require('vector_math')").exec().unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+        // Scripts can catch the rejection like any other error.
+        let (ok, message) = lua
+            .load(
+                "local ok, err = pcall(require, 'vector_math')
+                return ok, tostring(err)",
+            )
+            .eval::<(bool, String)>()
+            .unwrap();
+        assert!(!ok);
+        assert!(message.contains("not allowed"));
+    }
+
+    #[test]
+    pub fn test_default_sandbox_functions() {
+        let lua = sandboxed_lua(&LuaSandbox::default());
+        assert!(lua
+            .load(
+                "return HalfEdgeMesh.to_wavefront_obj == nil
+                    and HalfEdgeMesh.from_wavefront_obj == nil
+                    and bjk.import_obj == nil"
+            )
+            .eval::<bool>()
+            .unwrap());
+        // Other functions in the same tables are kept.
+        assert!(lua
+            .load("return HalfEdgeMesh.new ~= nil")
+            .eval::<bool>()
+            .unwrap());
+    }
+
+    #[test]
+    pub fn test_custom_sandbox() {
+        let lua = sandboxed_lua(&LuaSandbox {
+            removed_globals: vec!["loadstring".into()],
+            removed_functions: vec!["bjk.import_obj".into()],
+            allowed_modules: None,
+        });
+        assert!(lua.load("return loadstring == nil").eval::<bool>().unwrap());
+        assert!(lua.load("return Io ~= nil").eval::<bool>().unwrap());
+        assert!(lua
+            .load("return bjk.import_obj == nil and HalfEdgeMesh.from_wavefront_obj ~= nil")
+            .eval::<bool>()
+            .unwrap());
+        // Any module can be required. This one doesn't exist, so loading
+        // fails, but not because of the sandbox.
+        let err = lua.load("require('does_not_exist')").exec().unwrap_err();
+        assert!(!err.to_string().contains("not allowed"));
+    }
+}
@@ -12,15 +12,17 @@ use super::*;
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct LVec3(pub glam::Vec3);
-impl<'lua> ToLua<'lua> for LVec3 {
-    fn to_lua(self, _lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
-        Ok(mlua::Value::Vector(self.0.x, self.0.y, self.0.z))
+impl<'lua> IntoLua<'lua> for LVec3 {
+    fn into_lua(self, _lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+        Ok(mlua::Value::Vector(mlua::Vector::new(
+            self.0.x, self.0.y, self.0.z,
+        )))
     }
 }
 impl<'lua> FromLua<'lua> for LVec3 {
     fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
         match lua_value {
-            mlua::Value::Vector(x, y, z) => Ok(LVec3(glam::Vec3::new(x, y, z))),
+            mlua::Value::Vector(v) => Ok(LVec3(glam::Vec3::new(v.x(), v.y(), v.z()))),
             _ => Err(mlua::Error::FromLuaConversionError {
                 from: lua_value.type_name(),
                 to: "Vec3",
@@ -53,8 +55,8 @@ impl LVec3 {
 /// isn't.
 macro_rules! ids_from_to_lua {
     ($id_ty:ty) => {
-        impl<'lua> ToLua<'lua> for $id_ty {
-            fn to_lua(self, _lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+        impl<'lua> IntoLua<'lua> for $id_ty {
+            fn into_lua(self, _lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
                 use slotmap::Key;
                 Ok(mlua::Value::LightUserData(keydata_to_lightdata(
                     self.data(),
@@ -91,6 +93,28 @@ fn ligthdata_to_keydata(d: mlua::LightUserData) -> slotmap::KeyData {
 impl UserData for ChannelKeyType {}
 impl UserData for ChannelValueType {}
 
+/// Copies userdata values out of Lua, so functions exposed to Lua can take
+/// them as arguments by value.
+macro_rules! userdata_from_lua {
+    ($ty:ty) => {
+        impl<'lua> FromLua<'lua> for $ty {
+            fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+                match lua_value {
+                    mlua::Value::UserData(ud) => Ok(ud.borrow::<$ty>()?.clone()),
+                    _ => Err(mlua::Error::FromLuaConversionError {
+                        from: lua_value.type_name(),
+                        to: stringify!($ty),
+                        message: None,
+                    }),
+                }
+            }
+        }
+    };
+}
+userdata_from_lua!(ChannelKeyType);
+userdata_from_lua!(ChannelValueType);
+userdata_from_lua!(SelectionExpression);
+
 pub struct PerlinNoise(pub noise::Perlin);
 
 #[blackjack_macros::blackjack_lua_module]
@@ -8,10 +8,13 @@ local NodeLibrary = {
     nodes = {}
 }
 
-function NodeLibrary:addNodes(nodes)
+--- Adds the `nodes` to the library. When given, `category` is used for the
+--- nodes that don't declare their own.
+function NodeLibrary:addNodes(nodes, category)
     assert(type(nodes) == "table")
 
     for k, v in pairs(nodes) do
+        v.category = v.category or category
         if self.nodes[k] then
             print("[Engine] Redefinition for node "..k)
         else
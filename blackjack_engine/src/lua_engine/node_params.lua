@@ -30,6 +30,21 @@ Params.scalar_int = function(name, config)
     return s
 end
 
+--- An integer parameter, with given `default`, `min` and `max` value. Unlike
+--- `scalar_int`, values are stored as integers, so they don't lose precision,
+--- and fractional values are rejected.
+Params.int = function(name, config)
+    config = config or {}
+    assert(type(config) == 'table', "config should be table")
+    return {
+        name = name,
+        default = config.default or 0,
+        min = config.min,
+        max = config.max,
+        type = "int",
+    }
+end
+
 --- A vector parameter, with given `default` value
 Params.v3 = function(name, default)
     return { name = name, default = default, type = "vec3" }
@@ -81,6 +96,25 @@ Params.file = function(name, mode)
     return { name = name, type = "file", mode = mode }
 end
 
+--- A color parameter, with a given `default` value. Colors are tables with `r`,
+--- `g`, `b` and `a` fields, in the [0, 1] range.
+Params.color = function(name, default)
+    return { name = name, default = default, type = "color" }
+end
+
+--- A path parameter. Works like `Params.file`, but values are stored as paths,
+--- which can be relative to the project root. Nodes always receive absolute
+--- paths.
+Params.path = function(name, mode)
+    return { name = name, type = "path", mode = mode or "open" }
+end
+
+--- A list parameter, with a given `default` list of values. All the values in
+--- a list must have the same type.
+Params.list = function(name, default)
+    return { name = name, default = default or {}, type = "list" }
+end
+
 --- A heightmap mesh parameter. Like a regular mesh, it can't be set by the user
 --- so it has no widget.
 Params.heightmap = function(name)
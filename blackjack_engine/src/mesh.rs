@@ -10,3 +10,6 @@ pub mod halfedge;
 /// A heightmap data structure. A different mesh representation based on
 /// heightmaps. Supports different operations.
 pub mod heightmap;
+
+/// Exporting meshes in the binary glTF format.
+pub mod gltf_export;
@@ -0,0 +1,180 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{borrow::Cow, collections::HashMap, io::Write};
+
+use gltf::json::{self, validation::Checked::Valid};
+
+use crate::prelude::*;
+
+/// Appends `data` to the binary buffer `bin`, and returns the view for the
+/// bytes that were added.
+fn push_view(
+    bin: &mut Vec<u8>,
+    data: impl Iterator<Item = [u8; 4]>,
+    target: json::buffer::Target,
+) -> json::buffer::View {
+    let byte_offset = bin.len() as u32;
+    for bytes in data {
+        bin.extend_from_slice(&bytes);
+    }
+    json::buffer::View {
+        buffer: json::Index::new(0),
+        byte_length: bin.len() as u32 - byte_offset,
+        byte_offset: Some(byte_offset),
+        byte_stride: None,
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: None,
+        target: Some(Valid(target)),
+    }
+}
+
+fn accessor(
+    view: u32,
+    count: usize,
+    component_type: json::accessor::ComponentType,
+    type_: json::accessor::Type,
+) -> json::Accessor {
+    json::Accessor {
+        buffer_view: Some(json::Index::new(view)),
+        byte_offset: 0,
+        count: count as u32,
+        component_type: Valid(json::accessor::GenericComponentType(component_type)),
+        extensions: Default::default(),
+        extras: Default::default(),
+        type_: Valid(type_),
+        min: None,
+        max: None,
+        name: None,
+        normalized: false,
+        sparse: None,
+    }
+}
+
+fn vec3_bytes(vectors: &[Vec3]) -> impl Iterator<Item = [u8; 4]> + '_ {
+    vectors
+        .iter()
+        .flat_map(|v| v.to_array())
+        .map(|x| x.to_le_bytes())
+}
+
+impl VertexIndexBuffers {
+    /// Writes these buffers as a binary glTF (`.glb`) file, with a single
+    /// triangle mesh with positions and normals.
+    pub fn write_glb(&self, writer: impl Write) -> Result<()> {
+        if self.indices.is_empty() {
+            bail!("Cannot export an empty mesh to glTF.")
+        }
+
+        use json::accessor::{ComponentType, Type};
+        use json::buffer::Target;
+
+        let mut bin = Vec::<u8>::new();
+        let buffer_views = vec![
+            push_view(&mut bin, vec3_bytes(&self.positions), Target::ArrayBuffer),
+            push_view(&mut bin, vec3_bytes(&self.normals), Target::ArrayBuffer),
+            push_view(
+                &mut bin,
+                self.indices.iter().map(|i| i.to_le_bytes()),
+                Target::ElementArrayBuffer,
+            ),
+        ];
+
+        // The glTF spec requires bounds for the position accessor.
+        let (min, max) = self.positions.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), pos| (min.min(*pos), max.max(*pos)),
+        );
+        let mut positions = accessor(0, self.positions.len(), ComponentType::F32, Type::Vec3);
+        positions.min = Some(json::Value::from(min.to_array().to_vec()));
+        positions.max = Some(json::Value::from(max.to_array().to_vec()));
+        let normals = accessor(1, self.normals.len(), ComponentType::F32, Type::Vec3);
+        let indices = accessor(2, self.indices.len(), ComponentType::U32, Type::Scalar);
+
+        let primitive = json::mesh::Primitive {
+            attributes: HashMap::from([
+                (Valid(json::mesh::Semantic::Positions), json::Index::new(0)),
+                (Valid(json::mesh::Semantic::Normals), json::Index::new(1)),
+            ]),
+            extensions: Default::default(),
+            extras: Default::default(),
+            indices: Some(json::Index::new(2)),
+            material: None,
+            mode: Valid(json::mesh::Mode::Triangles),
+            targets: None,
+        };
+
+        let root = json::Root {
+            asset: json::Asset {
+                generator: Some("Blackjack".into()),
+                ..Default::default()
+            },
+            accessors: vec![positions, normals, indices],
+            buffers: vec![json::Buffer {
+                byte_length: bin.len() as u32,
+                extensions: Default::default(),
+                extras: Default::default(),
+                name: None,
+                uri: None,
+            }],
+            buffer_views,
+            meshes: vec![json::Mesh {
+                extensions: Default::default(),
+                extras: Default::default(),
+                name: None,
+                primitives: vec![primitive],
+                weights: None,
+            }],
+            nodes: vec![json::Node {
+                camera: None,
+                children: None,
+                extensions: Default::default(),
+                extras: Default::default(),
+                matrix: None,
+                mesh: Some(json::Index::new(0)),
+                name: None,
+                rotation: None,
+                scale: None,
+                translation: None,
+                skin: None,
+                weights: None,
+            }],
+            scenes: vec![json::Scene {
+                extensions: Default::default(),
+                extras: Default::default(),
+                name: None,
+                nodes: vec![json::Index::new(0)],
+            }],
+            scene: Some(json::Index::new(0)),
+            ..Default::default()
+        };
+
+        // GLB chunks must be aligned to 4 bytes. The JSON chunk is padded
+        // with spaces, and the binary chunk with zeros.
+        let mut json = json::serialize::to_vec(&root)?;
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+        while bin.len() % 4 != 0 {
+            bin.push(0);
+        }
+
+        // Header, plus the header of each of the two chunks.
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let glb = gltf::binary::Glb {
+            header: gltf::binary::Header {
+                magic: *b"glTF",
+                version: 2,
+                length: length as u32,
+            },
+            json: Cow::Owned(json),
+            bin: Some(Cow::Owned(bin)),
+        };
+        glb.to_writer(writer)?;
+        Ok(())
+    }
+}
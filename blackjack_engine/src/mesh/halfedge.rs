@@ -38,6 +38,9 @@ pub mod edit_ops;
 /// Import / Export of HalfEdgeMesh data structure to Wavefront OBJ files
 pub mod wavefront_obj;
 
+/// Export of HalfEdgeMesh point clouds to PLY files
+pub mod ply;
+
 /// A compact halfedge graph specifically optimized for some operations
 pub mod compact_mesh;
 
@@ -48,6 +51,10 @@ pub mod selection;
 pub mod gpu_buffer_generation;
 pub use gpu_buffer_generation::*;
 
+/// Splitting polygons into triangles, for rendering and export
+pub mod triangulation;
+pub use triangulation::*;
+
 pub mod halfedge_lua_api;
 
 pub mod channels;
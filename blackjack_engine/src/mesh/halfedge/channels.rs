@@ -16,7 +16,7 @@ use std::{
 
 use crate::lua_engine::lua_stdlib;
 use glam::Vec3;
-use mlua::{FromLua, Lua, ToLua};
+use mlua::{FromLua, IntoLua, Lua};
 
 use super::*;
 
@@ -98,7 +98,7 @@ impl_channel_value!(Vec3);
 impl_channel_value!(f32);
 impl_channel_value!(bool);
 
-/// The `FromLua` and `ToLua` traits have a lifetime parameter which is
+/// The `FromLua` and `IntoLua` traits have a lifetime parameter which is
 /// unnecessary for the channel keys and values. We introduce this new trait
 /// instead which makes things simpler when implementing dynamic channels.
 pub trait FromToLua {
@@ -112,7 +112,7 @@ macro_rules! impl_from_to_lua {
     (wrapped $t:ident $wrapper:ident) => {
         impl FromToLua for $t {
             fn cast_to_lua<'lua>(self, lua: &'lua Lua) -> mlua::Value {
-                lua_stdlib::$wrapper(self).to_lua(lua).unwrap()
+                lua_stdlib::$wrapper(self).into_lua(lua).unwrap()
             }
 
             fn cast_from_lua(value: mlua::Value, lua: &Lua) -> Result<Self> {
@@ -124,7 +124,7 @@ macro_rules! impl_from_to_lua {
     (flat $t:ident) => {
         impl FromToLua for $t {
             fn cast_to_lua<'lua>(self, lua: &'lua Lua) -> mlua::Value {
-                self.to_lua(lua).unwrap()
+                self.into_lua(lua).unwrap()
             }
 
             fn cast_from_lua(value: mlua::Value, lua: &Lua) -> Result<Self> {
@@ -48,9 +48,13 @@ pub struct FaceOverlayBuffers {
 
 impl HalfEdgeMesh {
     /// Generates the [`TriangleBuffers`] for this mesh. Suitable to be uploaded
-    /// to the GPU.
+    /// to the GPU. Faces are split into triangles using `triangulation`.
     #[profiling::function]
-    pub fn generate_triangle_buffers_flat(&self, force_gen: bool) -> Result<VertexIndexBuffers> {
+    pub fn generate_triangle_buffers_flat(
+        &self,
+        force_gen: bool,
+        triangulation: TriangulationStrategy,
+    ) -> Result<VertexIndexBuffers> {
         let positions_ch = self.read_positions();
         let conn = self.read_connectivity();
 
@@ -76,17 +80,17 @@ impl HalfEdgeMesh {
             // rendering even if we have slightly malformed meshes.
             let normal = normal_ch[face_id];
 
-            let vertices = conn.face_vertices(face_id);
-
-            let v1 = vertices[0];
+            let vertices = conn
+                .face_vertices(face_id)
+                .iter()
+                .map(|v| positions_ch[*v])
+                .collect_vec();
 
-            for (&v2, &v3) in vertices[1..].iter().tuple_windows() {
-                positions.push(positions_ch[v1]);
-                positions.push(positions_ch[v2]);
-                positions.push(positions_ch[v3]);
-                normals.push(normal);
-                normals.push(normal);
-                normals.push(normal);
+            for triangle in triangulate_polygon(&vertices, triangulation) {
+                for i in triangle {
+                    positions.push(vertices[i]);
+                    normals.push(normal);
+                }
             }
         }
 
@@ -99,8 +103,12 @@ impl HalfEdgeMesh {
 
     /// If `force_gen` is true, ignores any existing vertex normals channel in
     /// the mesh and generates one from scratch instead. This is used in some
-    /// viewport modes.
-    pub fn generate_triangle_buffers_smooth(&self, force_gen: bool) -> Result<VertexIndexBuffers> {
+    /// viewport modes. Faces are split into triangles using `triangulation`.
+    pub fn generate_triangle_buffers_smooth(
+        &self,
+        force_gen: bool,
+        triangulation: TriangulationStrategy,
+    ) -> Result<VertexIndexBuffers> {
         let positions_ch = self.read_positions();
         let conn = self.read_connectivity();
 
@@ -136,11 +144,11 @@ impl HalfEdgeMesh {
         let mut indices = vec![];
         for (face_id, _face) in conn.faces.iter() {
             let vertices = conn.face_vertices(face_id);
-            let v1 = vertices[0];
-            for (&v2, &v3) in vertices[1..].iter().tuple_windows() {
-                indices.push(v_id_to_idx[v1]);
-                indices.push(v_id_to_idx[v2]);
-                indices.push(v_id_to_idx[v3]);
+            let face_positions = vertices.iter().map(|v| positions_ch[*v]).collect_vec();
+            for triangle in triangulate_polygon(&face_positions, triangulation) {
+                for i in triangle {
+                    indices.push(v_id_to_idx[vertices[i]]);
+                }
             }
         }
 
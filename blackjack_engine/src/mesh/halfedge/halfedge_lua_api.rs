@@ -6,7 +6,7 @@
 
 use super::*;
 use crate::lua_engine::{lua_stdlib::LVec3, ToLuaError};
-use mlua::{Function, Lua, ToLua, Value};
+use mlua::{Function, IntoLua, Lua, Value};
 
 #[blackjack_macros::blackjack_lua_module]
 #[allow(non_upper_case_globals)]
@@ -215,7 +215,7 @@ mod lua_api {
             let mut i = 0;
             lua.create_function_mut(move |lua, ()| {
                 let val = if i < vertices.len() {
-                    vertices[i].to_lua(lua)?
+                    vertices[i].into_lua(lua)?
                 } else {
                     mlua::Value::Nil
                 };
@@ -237,7 +237,7 @@ mod lua_api {
             let mut i = 0;
             lua.create_function_mut(move |lua, ()| {
                 let val = if i < halfedges.len() {
-                    halfedges[i].to_lua(lua)?
+                    halfedges[i].into_lua(lua)?
                 } else {
                     mlua::Value::Nil
                 };
@@ -259,7 +259,7 @@ mod lua_api {
             let mut i = 0;
             lua.create_function_mut(move |lua, ()| {
                 let val = if i < halfedges.len() {
-                    halfedges[i].to_lua(lua)?
+                    halfedges[i].into_lua(lua)?
                 } else {
                     mlua::Value::Nil
                 };
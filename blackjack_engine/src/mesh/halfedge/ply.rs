@@ -0,0 +1,90 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::Write;
+
+use crate::prelude::*;
+
+/// The encodings supported when writing PLY files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlyFormat {
+    Ascii,
+    /// Much smaller than `Ascii`, which matters for large point clouds.
+    BinaryLittleEndian,
+}
+
+impl HalfEdgeMesh {
+    /// Writes the vertices of this mesh as a PLY point cloud. Positions are
+    /// always written. Normals are written when the mesh has vertex normals,
+    /// and colors when it has a `Vec3` vertex channel named `color`, with
+    /// components in the 0 to 1 range.
+    ///
+    /// Faces are not written, use the OBJ or glTF exporters for those.
+    pub fn write_ply(&self, mut writer: impl Write, format: PlyFormat) -> Result<()> {
+        let conn = self.read_connectivity();
+        let positions = self.read_positions();
+        let normals = self.read_vertex_normals();
+        let colors = self
+            .channels
+            .read_channel_by_name::<VertexId, Vec3>("color")
+            .ok();
+
+        writeln!(writer, "ply")?;
+        match format {
+            PlyFormat::Ascii => writeln!(writer, "format ascii 1.0")?,
+            PlyFormat::BinaryLittleEndian => writeln!(writer, "format binary_little_endian 1.0")?,
+        }
+        writeln!(writer, "comment Generated by Blackjack")?;
+        writeln!(writer, "element vertex {}", conn.num_vertices())?;
+        for prop in ["x", "y", "z"] {
+            writeln!(writer, "property float {prop}")?;
+        }
+        if normals.is_some() {
+            for prop in ["nx", "ny", "nz"] {
+                writeln!(writer, "property float {prop}")?;
+            }
+        }
+        if colors.is_some() {
+            for prop in ["red", "green", "blue"] {
+                writeln!(writer, "property uchar {prop}")?;
+            }
+        }
+        writeln!(writer, "end_header")?;
+
+        for (v_id, _, pos) in conn.iter_vertices_with_channel(&positions) {
+            let mut floats = pos.to_array().to_vec();
+            if let Some(normals) = &normals {
+                floats.extend(normals[v_id].to_array());
+            }
+            let color = colors.as_ref().map(|colors| {
+                (colors[v_id].clamp(Vec3::ZERO, Vec3::ONE) * 255.0)
+                    .round()
+                    .to_array()
+                    .map(|c| c as u8)
+            });
+
+            match format {
+                PlyFormat::Ascii => {
+                    let mut line = floats.iter().map(|x| x.to_string()).collect_vec();
+                    if let Some(color) = color {
+                        line.extend(color.iter().map(|c| c.to_string()));
+                    }
+                    writeln!(writer, "{}", line.join(" "))?;
+                }
+                PlyFormat::BinaryLittleEndian => {
+                    for x in floats {
+                        writer.write_all(&x.to_le_bytes())?;
+                    }
+                    if let Some(color) = color {
+                        writer.write_all(&color)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
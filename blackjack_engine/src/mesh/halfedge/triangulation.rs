@@ -0,0 +1,383 @@
+// Copyright (C) 2022 setzer22 and contributors
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+/// How polygons with more than 3 vertices are split into triangles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TriangulationStrategy {
+    /// Connects the first vertex with every other vertex. This is the fastest
+    /// strategy, but it only works for convex polygons. Concave polygons get
+    /// overlapping triangles.
+    Fan,
+    /// Repeatedly cuts off a triangle from a corner of the polygon. Works for
+    /// any simple polygon, in quadratic time.
+    #[default]
+    EarClipping,
+    /// Splits the polygon into y-monotone pieces, which are then triangulated
+    /// separately. Works for any simple polygon, in quadratic time in the
+    /// worst case.
+    MonotonePartition,
+}
+
+/// Splits the polygon with the given `points` into triangles, using the given
+/// `strategy`. Returns the indices of the points of each triangle, with the
+/// same winding as the polygon. A polygon with N points always gives N - 2
+/// triangles.
+///
+/// The polygon doesn't need to be planar: It's projected to the plane
+/// perpendicular to its normal first. When the polygon is too degenerate to
+/// be triangulated properly, e.g. when it intersects itself, the result falls
+/// back to a fan.
+pub fn triangulate_polygon(points: &[Vec3], strategy: TriangulationStrategy) -> Vec<[usize; 3]> {
+    if points.len() < 3 {
+        return vec![];
+    }
+    if points.len() == 3 || strategy == TriangulationStrategy::Fan {
+        return fan(points.len());
+    }
+    let points = project(points);
+    let triangles = match strategy {
+        TriangulationStrategy::Fan => unreachable!("Handled above"),
+        TriangulationStrategy::EarClipping => ear_clipping(&points),
+        TriangulationStrategy::MonotonePartition => monotone_partition(&points),
+    };
+    triangles.unwrap_or_else(|| fan(points.len()))
+}
+
+fn fan(n: usize) -> Vec<[usize; 3]> {
+    (1..n - 1).map(|i| [0, i, i + 1]).collect()
+}
+
+/// Projects the points to 2D, such that a polygon winding counter-clockwise
+/// around its normal winds counter-clockwise in 2D.
+fn project(points: &[Vec3]) -> Vec<Vec2> {
+    // Newell's method, which works for concave polygons.
+    let mut normal = Vec3::ZERO;
+    for (a, b) in points.iter().circular_tuple_windows() {
+        normal += Vec3::new(
+            (a.y - b.y) * (a.z + b.z),
+            (a.z - b.z) * (a.x + b.x),
+            (a.x - b.x) * (a.y + b.y),
+        );
+    }
+    let normal = normal.normalize_or_zero();
+    let normal = if normal == Vec3::ZERO {
+        Vec3::Z
+    } else {
+        normal
+    };
+    let u = normal.any_orthonormal_vector();
+    let v = normal.cross(u);
+    points
+        .iter()
+        .map(|p| Vec2::new(p.dot(u), p.dot(v)))
+        .collect()
+}
+
+fn cross(o: Vec2, a: Vec2, b: Vec2) -> f32 {
+    (a - o).perp_dot(b - o)
+}
+
+/// Whether `p` is inside the counter-clockwise triangle `abc`, or on its
+/// boundary.
+fn in_triangle(p: Vec2, a: Vec2, b: Vec2, c: Vec2) -> bool {
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
+
+fn ear_clipping(points: &[Vec2]) -> Option<Vec<[usize; 3]>> {
+    let mut remaining = (0..points.len()).collect_vec();
+    let mut triangles = Vec::with_capacity(points.len() - 2);
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (a, b, c) = (
+                remaining[(i + n - 1) % n],
+                remaining[i],
+                remaining[(i + 1) % n],
+            );
+            let (pa, pb, pc) = (points[a], points[b], points[c]);
+            cross(pa, pb, pc) > 0.0
+                && remaining.iter().all(|&other| {
+                    other == a
+                        || other == b
+                        || other == c
+                        || points[other] == pa
+                        || points[other] == pb
+                        || points[other] == pc
+                        || !in_triangle(points[other], pa, pb, pc)
+                })
+        })?;
+        triangles.push([
+            remaining[(ear + n - 1) % n],
+            remaining[ear],
+            remaining[(ear + 1) % n],
+        ]);
+        remaining.remove(ear);
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    Some(triangles)
+}
+
+/// Whether `a` comes before `b` in the sweep, which goes from top to bottom,
+/// and from left to right for points at the same height.
+fn above(a: Vec2, b: Vec2) -> bool {
+    a.y > b.y || (a.y == b.y && a.x < b.x)
+}
+
+/// The kinds of vertices in the sweep of `monotone_partition`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SweepVertex {
+    Start,
+    End,
+    Split,
+    Merge,
+    Regular,
+}
+
+fn monotone_partition(points: &[Vec2]) -> Option<Vec<[usize; 3]>> {
+    let n = points.len();
+    // The algorithm assumes the polygon winds counter-clockwise.
+    let area: f32 = (0..n)
+        .map(|i| points[i].perp_dot(points[(i + 1) % n]))
+        .sum();
+    if area <= 0.0 {
+        return None;
+    }
+    let prev = |i: usize| (i + n - 1) % n;
+    let next = |i: usize| (i + 1) % n;
+    let kinds = (0..n)
+        .map(|i| {
+            let (p, v, q) = (points[prev(i)], points[i], points[next(i)]);
+            let convex = cross(p, v, q) > 0.0;
+            match (above(v, p), above(v, q)) {
+                (true, true) if convex => SweepVertex::Start,
+                (true, true) => SweepVertex::Split,
+                (false, false) if convex => SweepVertex::End,
+                (false, false) => SweepVertex::Merge,
+                _ => SweepVertex::Regular,
+            }
+        })
+        .collect_vec();
+
+    // The edges are identified by their first vertex. Edge `i` goes from
+    // vertex `i` to vertex `i + 1`. Each edge crossing the sweep line is
+    // stored with its helper vertex.
+    // NOTE: Finding the edge left of a vertex scans the whole status, so the
+    // sweep is quadratic rather than O(n log n). An ordered status would only
+    // pay off for polygons much larger than the faces of a typical mesh.
+    let mut status = HashMap::<usize, usize>::new();
+    let left_of = |status: &HashMap<usize, usize>, v: Vec2| {
+        status
+            .keys()
+            .copied()
+            .filter_map(|e| {
+                let (a, b) = (points[e], points[next(e)]);
+                let x = if a.y == b.y {
+                    a.x.min(b.x)
+                } else {
+                    a.x + (v.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                };
+                (x <= v.x).then_some((e, x))
+            })
+            .max_by(|(_, x1), (_, x2)| x1.total_cmp(x2))
+            .map(|(e, _)| e)
+    };
+    let mut diagonals = Vec::new();
+    let order = (0..n)
+        .sorted_by(|a, b| {
+            let (a, b) = (points[*a], points[*b]);
+            b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x))
+        })
+        .collect_vec();
+    for &i in &order {
+        let v = points[i];
+        let fix_up = |status: &mut HashMap<usize, usize>, diagonals: &mut Vec<_>, e| {
+            let helper = *status.get(&e)?;
+            if kinds[helper] == SweepVertex::Merge {
+                diagonals.push((i, helper));
+            }
+            Some(())
+        };
+        match kinds[i] {
+            SweepVertex::Start => {
+                status.insert(i, i);
+            }
+            SweepVertex::End => {
+                fix_up(&mut status, &mut diagonals, prev(i))?;
+                status.remove(&prev(i));
+            }
+            SweepVertex::Split => {
+                let e = left_of(&status, v)?;
+                diagonals.push((i, status[&e]));
+                status.insert(e, i);
+                status.insert(i, i);
+            }
+            SweepVertex::Merge => {
+                fix_up(&mut status, &mut diagonals, prev(i))?;
+                status.remove(&prev(i));
+                let e = left_of(&status, v)?;
+                fix_up(&mut status, &mut diagonals, e)?;
+                status.insert(e, i);
+            }
+            // The interior of the polygon is to the right of the vertex when
+            // the boundary goes down through it.
+            SweepVertex::Regular if above(points[prev(i)], v) => {
+                fix_up(&mut status, &mut diagonals, prev(i))?;
+                status.remove(&prev(i));
+                status.insert(i, i);
+            }
+            SweepVertex::Regular => {
+                let e = left_of(&status, v)?;
+                fix_up(&mut status, &mut diagonals, e)?;
+                status.insert(e, i);
+            }
+        }
+    }
+
+    // Split the polygon along the diagonals, which don't cross each other.
+    let mut pieces = vec![(0..n).collect_vec()];
+    for (a, b) in diagonals {
+        let midpoint = (points[a] + points[b]) * 0.5;
+        let piece = pieces.iter().position(|piece| {
+            piece.contains(&a) && piece.contains(&b) && contains_point(points, piece, midpoint)
+        })?;
+        let piece = pieces.swap_remove(piece);
+        let ia = piece.iter().position(|v| *v == a)?;
+        let ib = piece.iter().position(|v| *v == b)?;
+        let (lo, hi) = (ia.min(ib), ia.max(ib));
+        pieces.push(piece[lo..=hi].to_vec());
+        pieces.push(piece[hi..].iter().chain(&piece[..=lo]).copied().collect());
+    }
+
+    let mut triangles = Vec::with_capacity(n - 2);
+    for piece in pieces {
+        triangles.extend(triangulate_monotone(points, &piece)?);
+    }
+    (triangles.len() == n - 2).then_some(triangles)
+}
+
+/// Whether `p` is inside the polygon made by the points at the `indices`.
+fn contains_point(points: &[Vec2], indices: &[usize], p: Vec2) -> bool {
+    let mut inside = false;
+    for (a, b) in indices.iter().circular_tuple_windows() {
+        let (a, b) = (points[*a], points[*b]);
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Triangulates a y-monotone, counter-clockwise piece of a polygon.
+fn triangulate_monotone(points: &[Vec2], piece: &[usize]) -> Option<Vec<[usize; 3]>> {
+    let n = piece.len();
+    if n == 3 {
+        return Some(vec![[piece[0], piece[1], piece[2]]]);
+    }
+    let order = (0..n)
+        .sorted_by(|a, b| {
+            let (a, b) = (points[piece[*a]], points[piece[*b]]);
+            b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x))
+        })
+        .collect_vec();
+    // Going counter-clockwise from the top vertex walks down the left chain.
+    let (top, bottom) = (order[0], order[n - 1]);
+    let mut on_left = vec![false; n];
+    let mut i = top;
+    while i != bottom {
+        on_left[i] = true;
+        i = (i + 1) % n;
+    }
+
+    let mut triangles = Vec::with_capacity(n - 2);
+    // Triangles are made counter-clockwise, based on the chain of `v`.
+    let mut push = |v: usize, a: usize, b: usize| {
+        let (v, a, b) = (piece[v], piece[a], piece[b]);
+        if cross(points[v], points[a], points[b]) >= 0.0 {
+            triangles.push([v, a, b]);
+        } else {
+            triangles.push([v, b, a]);
+        }
+    };
+    let mut stack = vec![order[0], order[1]];
+    for &v in &order[2..n - 1] {
+        let top = *stack.last()?;
+        if on_left[v] != on_left[top] {
+            while stack.len() > 1 {
+                let a = stack.pop()?;
+                push(v, a, *stack.last()?);
+            }
+            stack.pop();
+            stack.push(top);
+            stack.push(v);
+        } else {
+            let mut last = stack.pop()?;
+            while let Some(&top) = stack.last() {
+                let (pv, pl, pt) = (points[piece[v]], points[piece[last]], points[piece[top]]);
+                let inside = if on_left[v] {
+                    cross(pt, pl, pv) > 0.0
+                } else {
+                    cross(pv, pl, pt) > 0.0
+                };
+                if !inside {
+                    break;
+                }
+                push(v, last, top);
+                last = stack.pop()?;
+            }
+            stack.push(last);
+            stack.push(v);
+        }
+    }
+    let v = order[n - 1];
+    for (a, b) in stack.iter().tuple_windows() {
+        push(v, *a, *b);
+    }
+    Some(triangles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sum of the areas of the triangles, which is the area of the polygon
+    /// when the triangulation is correct.
+    fn area(points: &[Vec3], triangles: &[[usize; 3]]) -> f32 {
+        triangles
+            .iter()
+            .map(|[a, b, c]| (points[*b] - points[*a]).cross(points[*c] - points[*a]).z * 0.5)
+            .sum()
+    }
+
+    #[test]
+    pub fn test_triangulate_concave() {
+        // An arrow pointing up, with its concave vertex at index 4.
+        let arrow = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(2.0, 2.0, 0.0),
+            Vec3::new(1.0, 3.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.0, 3.0, 0.0),
+        ];
+        let polygon_area = 4.5;
+        for strategy in [
+            TriangulationStrategy::EarClipping,
+            TriangulationStrategy::MonotonePartition,
+        ] {
+            let triangles = triangulate_polygon(&arrow, strategy);
+            assert_eq!(triangles.len(), 4);
+            assert!((area(&arrow, &triangles) - polygon_area).abs() < 1e-5);
+            // Every triangle keeps the winding of the polygon.
+            assert!(triangles.iter().all(|t| area(&arrow, &[*t]) > 0.0));
+        }
+        // The fan from vertex 0 covers the area outside the notch.
+        let fan = triangulate_polygon(&arrow, TriangulationStrategy::Fan);
+        assert_eq!(fan.len(), 4);
+        assert!(fan.iter().any(|t| area(&arrow, &[*t]) < 0.0));
+    }
+}
@@ -7,7 +7,7 @@
 use slotmap::SecondaryMap;
 use std::{
     fs::File,
-    io::{BufReader, BufWriter, Write},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::PathBuf,
 };
 use wavefront_rs::obj::{
@@ -19,8 +19,15 @@ use crate::prelude::*;
 
 impl HalfEdgeMesh {
     pub fn to_wavefront_obj(&self, path: impl Into<PathBuf>) -> Result<()> {
-        let mut writer = BufWriter::new(File::create(path.into())?);
+        self.write_wavefront_obj(BufWriter::new(File::create(path.into())?))
+    }
 
+    /// Writes this mesh in the Wavefront OBJ format. Faces are written as
+    /// polygons with any number of vertices, without triangulating them.
+    ///
+    /// Normals are written per vertex when the mesh has smooth normals, and
+    /// per face otherwise. UVs are written when the mesh has them.
+    pub fn write_wavefront_obj(&self, mut writer: impl Write) -> Result<()> {
         // We need to store the mapping between vertex ids and indices in the
         // generated OBJ
         // NOTE: OBJ Wavefront indices start at 1
@@ -53,7 +60,10 @@ impl HalfEdgeMesh {
             writeln!(writer)?;
         }
 
+        // Normals are either indexed by vertex or by face, depending on
+        // whether smooth normals are enabled.
         let mut has_normals = false;
+        let mut f_imap = SecondaryMap::<FaceId, i32>::new();
         if self.gen_config.smooth_normals {
             if let Some(v_normals_ch) = self.read_vertex_normals() {
                 has_normals = true;
@@ -70,9 +80,21 @@ impl HalfEdgeMesh {
                     writeln!(writer)?;
                 }
             }
-        } else {
-            // TODO has_normals = true;
-            println!("TODO: Exporting per-face normals is not yet implemented.")
+        } else if let Some(f_normals_ch) = self.read_face_normals() {
+            has_normals = true;
+            for (idx, (f, _)) in conn.iter_faces().enumerate() {
+                f_imap.insert(f, (idx + 1) as i32);
+                let normal = f_normals_ch[f];
+                obj::format_writer::FormatWriter::write(
+                    &mut writer,
+                    &Entity::VertexNormal {
+                        x: normal.x as f64,
+                        y: normal.y as f64,
+                        z: normal.z as f64,
+                    },
+                );
+                writeln!(writer)?;
+            }
         }
 
         // Since UVs are stored in halfedges, we need the same mapping as `imap`
@@ -103,13 +125,10 @@ impl HalfEdgeMesh {
                 .zip(conn.face_edges(face_id).iter())
                 .map(|(v_id, h_id)| FaceVertex {
                     vertex: imap[*v_id] as i64,
-                    // TODO: For now we rely on emitting one normal per vertex.
-                    // Sometimes there might be less, when we implement flat
-                    // shaded normals.
-                    normal: if has_normals {
-                        Some(imap[*v_id] as i64)
-                    } else {
-                        None
+                    normal: match (has_normals, self.gen_config.smooth_normals) {
+                        (true, true) => Some(imap[*v_id] as i64),
+                        (true, false) => Some(f_imap[face_id] as i64),
+                        (false, _) => None,
                     },
                     texture: if has_uvs {
                         Some(h_imap[*h_id] as i64)
@@ -126,21 +145,76 @@ impl HalfEdgeMesh {
     }
 
     pub fn from_wavefront_obj(path: PathBuf) -> Result<HalfEdgeMesh> {
-        let mut reader = BufReader::new(File::open(path)?);
+        let file = File::open(&path)
+            .with_context(|| format!("Could not open OBJ file at {}", path.display()))?;
+        Self::read_wavefront_obj(BufReader::new(file))
+            .with_context(|| format!("Could not import OBJ file at {}", path.display()))
+    }
+
+    /// Like `from_wavefront_obj`, but fails when the file has lines, points or
+    /// free-form geometry, instead of skipping them. Meant for imports where
+    /// losing part of the file would go unnoticed.
+    pub fn import_wavefront_obj(path: PathBuf) -> Result<HalfEdgeMesh> {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not open OBJ file at {}", path.display()))?;
+        for (i, line) in contents.lines().enumerate() {
+            let keyword = line.split_whitespace().next().unwrap_or("");
+            if matches!(keyword, "l" | "p" | "cstype" | "curv" | "curv2" | "surf") {
+                bail!(
+                    "Could not import OBJ file at {}. Line {}: '{keyword}' statements are \
+                     not supported, only faces can be imported",
+                    path.display(),
+                    i + 1
+                );
+            }
+        }
+        Self::read_wavefront_obj(contents.as_bytes())
+            .with_context(|| format!("Could not import OBJ file at {}", path.display()))
+    }
+
+    /// Reads a mesh in the Wavefront OBJ format. The faces of the mesh are the
+    /// polygons of the file, so quads and n-gons are kept as they are.
+    ///
+    /// Only vertex positions and faces are read. Texture coordinates, normals,
+    /// groups, materials, lines, points and free-form geometry are skipped.
+    /// Faces with relative indices, or referring to undefined vertices, return
+    /// an error.
+    pub fn read_wavefront_obj(mut reader: impl BufRead) -> Result<HalfEdgeMesh> {
         let mut positions = vec![];
         let mut polygons = vec![];
+        let mut error = None;
         obj::read_lexer::ReadLexer::read_to_end(&mut reader, |entity| match entity {
             Entity::Vertex { x, y, z, w: _w } => {
                 positions.push(Vec3::new(x as f32, y as f32, z as f32));
             }
             Entity::Face { vertices } => {
                 // NOTE: OBJ Wavefront indices start at 1
-                let polygon: SVec<usize> =
-                    vertices.iter().map(|v| (v.vertex - 1) as usize).collect();
-                polygons.push(polygon);
+                let polygon: Option<SVec<usize>> = vertices
+                    .iter()
+                    .map(|v| usize::try_from(v.vertex - 1).ok())
+                    .collect();
+                match polygon {
+                    Some(polygon) if polygon.iter().all(|i| *i < positions.len()) => {
+                        polygons.push(polygon)
+                    }
+                    _ => {
+                        error.get_or_insert_with(|| {
+                            anyhow!(
+                                "Face {} refers to a vertex that is not defined before it",
+                                polygons.len() + 1
+                            )
+                        });
+                    }
+                }
             }
             _ => {}
         })?;
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if let Some(i) = polygons.iter().position(|p| p.len() < 3) {
+            bail!("Face {} has less than 3 vertices", i + 1);
+        }
         halfedge::HalfEdgeMesh::build_from_polygons(&positions, &polygons)
     }
 }
@@ -167,6 +241,15 @@ mod lua_api {
     pub fn from_wavefront_obj(path: String) -> Result<HalfEdgeMesh> {
         HalfEdgeMesh::from_wavefront_obj(path.into())
     }
+
+    /// Imports the Wavefront OBJ file at `path` as a `HalfEdgeMesh`, keeping
+    /// its polygons. Unlike `HalfEdgeMesh.from_wavefront_obj`, fails if the
+    /// file uses features other than vertex positions and faces, like lines
+    /// or points. Texture coordinates and normals are ignored.
+    #[lua(under = "bjk")]
+    pub fn import_obj(path: String) -> Result<HalfEdgeMesh> {
+        HalfEdgeMesh::import_wavefront_obj(path.into())
+    }
 }
 
 #[cfg(test)]
@@ -180,4 +263,36 @@ mod tests {
             .to_wavefront_obj("/tmp/wat.obj")
             .unwrap();
     }
+
+    #[test]
+    pub fn test_read_obj() {
+        // A quad and a triangle sharing an edge.
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 2 0 0\nvn 0 0 1\nf 1 2 3 4\nf 2 5 3\n";
+        let mesh = HalfEdgeMesh::read_wavefront_obj(obj.as_bytes()).unwrap();
+        let conn = mesh.read_connectivity();
+        assert_eq!(conn.num_vertices(), 5);
+        assert_eq!(conn.num_faces(), 2);
+        let sides = conn
+            .iter_faces()
+            .map(|(f, _)| conn.face_vertices(f).len())
+            .sorted()
+            .collect_vec();
+        assert_eq!(sides, vec![3, 4]);
+
+        let err = HalfEdgeMesh::read_wavefront_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n".as_bytes());
+        assert!(err.unwrap_err().to_string().contains("Face 1"));
+        let err = HalfEdgeMesh::from_wavefront_obj("/nonexistent/mesh.obj".into());
+        assert!(format!("{:#}", err.unwrap_err()).contains("Could not open"));
+
+        // Loose edges are skipped, unless importing strictly.
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 3\nl 1 3\n";
+        let mesh = HalfEdgeMesh::read_wavefront_obj(obj.as_bytes()).unwrap();
+        assert_eq!(mesh.read_connectivity().num_faces(), 1);
+        let path = std::env::temp_dir().join("blackjack_test_loose_edges.obj");
+        std::fs::write(&path, obj).unwrap();
+        assert!(HalfEdgeMesh::from_wavefront_obj(path.clone()).is_ok());
+        let err = HalfEdgeMesh::import_wavefront_obj(path.clone()).unwrap_err();
+        assert!(err.to_string().contains("'l' statements"));
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -15,6 +15,9 @@ pub struct HeightMap {
     inner: ndarray::Array2<f32>,
 }
 
+/// The distance between neighbouring cells, when rendered as a mesh.
+const CELL_SIZE: f32 = 0.05;
+
 impl HeightMap {
     pub fn from_perlin(
         width: usize,
@@ -55,6 +58,58 @@ impl HeightMap {
         }
     }
 
+    /// Builds a height map with the given size from the height of each of its
+    /// cells, row by row, as returned by `heights`.
+    pub fn from_heights(width: usize, height: usize, heights: Vec<f32>) -> Result<HeightMap> {
+        Ok(Self {
+            inner: ndarray::Array2::from_shape_vec((width, height), heights)?,
+        })
+    }
+
+    /// The size of the height map, in cells, as given when creating it.
+    pub fn size(&self) -> (usize, usize) {
+        self.inner.dim()
+    }
+
+    /// The height of each cell, row by row.
+    pub fn heights(&self) -> Vec<f32> {
+        self.inner.iter().copied().collect()
+    }
+
+    /// Returns the number of vertices in the mesh generated by
+    /// `generate_triangle_buffers`, without generating it.
+    pub fn rendered_vertex_count(&self) -> usize {
+        if self.inner.ncols() < 4 || self.inner.nrows() < 4 {
+            0
+        } else {
+            (self.inner.nrows() - 2) * (self.inner.ncols() - 2)
+        }
+    }
+
+    /// Returns the bounds of the mesh generated by `generate_triangle_buffers`,
+    /// as a pair of min and max corners, without generating it.
+    pub fn bounding_box(&self) -> Option<(Vec3, Vec3)> {
+        if self.rendered_vertex_count() == 0 {
+            return None;
+        }
+        let (nrows, ncols) = (self.inner.nrows(), self.inner.ncols());
+        let (min_y, max_y) = self
+            .inner
+            .slice(ndarray::s![1..nrows - 1, 1..ncols - 1])
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), y| {
+                (min.min(*y), max.max(*y))
+            });
+        Some((
+            Vec3::new(CELL_SIZE, min_y, CELL_SIZE),
+            Vec3::new(
+                (ncols - 2) as f32 * CELL_SIZE,
+                max_y,
+                (nrows - 2) as f32 * CELL_SIZE,
+            ),
+        ))
+    }
+
     pub fn generate_triangle_buffers(&self) -> VertexIndexBuffers {
         // If the terrain is too small to compute normals, return an empty buffer
         if self.inner.ncols() < 4 || self.inner.nrows() < 4 {
@@ -65,7 +120,7 @@ impl HeightMap {
             };
         }
 
-        let scale = 0.05;
+        let scale = CELL_SIZE;
 
         let mut positions = vec![];
         let mut indices = vec![];
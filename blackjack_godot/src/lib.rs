@@ -204,8 +204,11 @@ impl BlackjackApi {
                     return None;
                 }
             };
-            let loaded = SerializedBjkGraph::load_from_string(&contents.to_string())
-                .and_then(|x| x.into_runtime());
+            let loaded =
+                SerializedBjkGraph::load_from_string(&contents.to_string()).and_then(|x| {
+                    x.register_baked_nodes(&runtime.lua_runtime.node_definitions)?;
+                    x.into_runtime()
+                });
             match loaded {
                 Ok((rt_data, _, _)) => {
                     if let Some(params) = rt_data.external_parameters {
@@ -246,6 +249,10 @@ impl BlackjackApi {
                     let new_s = new_value.try_to::<f32>().ok()?;
                     *s = new_s;
                 }
+                blackjack_engine::graph::BlackjackValue::Int(i) => {
+                    let new_i = new_value.try_to::<i64>().ok()?;
+                    *i = new_i;
+                }
                 blackjack_engine::graph::BlackjackValue::String(s) => {
                     let new_s = new_value.try_to::<String>().ok()?;
                     *s = new_s;
@@ -260,7 +267,18 @@ impl BlackjackApi {
                         *sel = None;
                     }
                 }
-                blackjack_engine::graph::BlackjackValue::None => {}
+                blackjack_engine::graph::BlackjackValue::Color(c) => {
+                    let new_c = new_value.try_to::<Color>().ok()?;
+                    *c = Vec4::new(new_c.r, new_c.g, new_c.b, new_c.a);
+                }
+                blackjack_engine::graph::BlackjackValue::Path(p) => {
+                    let new_p = new_value.try_to::<String>().ok()?;
+                    *p = new_p.into();
+                }
+                // TODO: Lists can't be edited from Godot yet.
+                blackjack_engine::graph::BlackjackValue::List(_) => return None,
+                blackjack_engine::graph::BlackjackValue::None
+                | blackjack_engine::graph::BlackjackValue::Unset => {}
             }
             Some(true)
         })
@@ -347,6 +365,12 @@ impl BlackjackApi {
                                 max: *max,
                             })
                         }
+                        (_, BlackjackValue::Int(i)) => params.push(GenericDef {
+                            label,
+                            addr,
+                            typ: "Int".into(),
+                            val: i.to_variant(),
+                        }),
                         (_, BlackjackValue::String(s)) => params.push(GenericDef {
                             label,
                             addr,
@@ -364,6 +388,18 @@ impl BlackjackApi {
                                 .unparse()
                                 .to_variant(),
                         }),
+                        (_, BlackjackValue::Color(c)) => params.push(GenericDef {
+                            label,
+                            addr,
+                            typ: "Color".into(),
+                            val: Color::from_rgba(c.x, c.y, c.z, c.w).to_variant(),
+                        }),
+                        (_, BlackjackValue::Path(p)) => params.push(GenericDef {
+                            label,
+                            addr,
+                            typ: "Path".into(),
+                            val: p.to_string_lossy().into_owned().to_variant(),
+                        }),
                         // TODO: For now this ignore any malformed parameters.
                         _ => continue,
                     }
@@ -929,8 +929,8 @@ local misc = {
     },
 }
 
-NodeLibrary:addNodes(primitives)
-NodeLibrary:addNodes(edit_ops)
-NodeLibrary:addNodes(math_nodes)
-NodeLibrary:addNodes(export)
-NodeLibrary:addNodes(misc)
+NodeLibrary:addNodes(primitives, "Primitives")
+NodeLibrary:addNodes(edit_ops, "Edit ops")
+NodeLibrary:addNodes(math_nodes, "Math")
+NodeLibrary:addNodes(export, "Export")
+NodeLibrary:addNodes(misc, "Miscellaneous")
@@ -12,7 +12,9 @@ use blackjack_engine::graph::BjkGraph;
 use blackjack_engine::graph_interpreter::ExternalParameterValues;
 use blackjack_engine::{
     lua_engine::{LuaRuntime, RenderableThing},
-    prelude::{FaceOverlayBuffers, LineBuffers, PointBuffers, VertexIndexBuffers},
+    prelude::{
+        FaceOverlayBuffers, LineBuffers, PointBuffers, TriangulationStrategy, VertexIndexBuffers,
+    },
 };
 
 use super::gizmo_ui::UiNodeGizmoStates;
@@ -93,6 +95,7 @@ impl ApplicationContext {
     ) -> Result<()> {
         match self.renderable_thing.as_mut() {
             Some(RenderableThing::HalfEdgeMesh(mesh)) => {
+                let triangulation = TriangulationStrategy::default();
                 // Base mesh
                 {
                     if let Some(VertexIndexBuffers {
@@ -102,13 +105,17 @@ impl ApplicationContext {
                     }) = match viewport_settings.face_mode {
                         FaceDrawMode::Real => {
                             if mesh.gen_config.smooth_normals {
-                                Some(mesh.generate_triangle_buffers_smooth(false)?)
+                                Some(mesh.generate_triangle_buffers_smooth(false, triangulation)?)
                             } else {
-                                Some(mesh.generate_triangle_buffers_flat(false)?)
+                                Some(mesh.generate_triangle_buffers_flat(false, triangulation)?)
                             }
                         }
-                        FaceDrawMode::Flat => Some(mesh.generate_triangle_buffers_flat(true)?),
-                        FaceDrawMode::Smooth => Some(mesh.generate_triangle_buffers_smooth(true)?),
+                        FaceDrawMode::Flat => {
+                            Some(mesh.generate_triangle_buffers_flat(true, triangulation)?)
+                        }
+                        FaceDrawMode::Smooth => {
+                            Some(mesh.generate_triangle_buffers_smooth(true, triangulation)?)
+                        }
                         FaceDrawMode::None => None,
                     } {
                         if !positions.is_empty() {
@@ -180,7 +187,7 @@ impl ApplicationContext {
                     );
                 }
             }
-            None => { /* Ignore */ }
+            Some(RenderableThing::Custom(_)) | None => { /* Ignore */ }
         }
         Ok(())
     }
@@ -113,6 +113,10 @@ impl InspectorTabs {
             Some(RenderableThing::HeightMap(_)) => {
                 // TODO: @Heightmap
             }
+            Some(RenderableThing::Custom(_)) => {
+                // Custom renderables are only supported by the hosts that
+                // registered them.
+            }
             None => { /**/ }
         }
     }
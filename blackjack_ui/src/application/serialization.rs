@@ -74,6 +74,7 @@ pub fn load(
     gizmo_states: &UiNodeGizmoStates,
 ) -> Result<(GraphEditorState, CustomGraphState)> {
     let serialized = SerializedBjkGraph::load_from_file(&path)?;
+    serialized.register_baked_nodes(node_definitions)?;
     let (runtime, ui_data, id_idx_mappings) = serialized.into_runtime()?;
 
     if ui_data.is_none() {
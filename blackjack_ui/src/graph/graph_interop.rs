@@ -175,7 +175,9 @@ pub fn blackjack_graph_to_ui_graph(
     for (bjk_node_id, bjk_node) in bjk_nodes {
         for bjk_input in &bjk_node.inputs {
             match &bjk_input.kind {
-                DependencyKind::Connection { node, param_name } => {
+                DependencyKind::Connection {
+                    node, param_name, ..
+                } => {
                     let out_node_id = mapping[*node];
                     let out_id = graph[out_node_id]
                         .get_output(param_name)
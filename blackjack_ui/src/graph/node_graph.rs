@@ -80,8 +80,12 @@ impl DataTypeTrait<CustomGraphState> for DataTypeUi {
             DataType::HeightMap => color_from_hex("#33673b").unwrap(),
             DataType::Vector => color_from_hex("#1A535C").unwrap(),
             DataType::Scalar => color_from_hex("#4ecdc4").unwrap(),
+            DataType::Int => color_from_hex("#2a9d8f").unwrap(),
             DataType::Selection => color_from_hex("#f7fff7").unwrap(),
             DataType::String => color_from_hex("#ffe66d").unwrap(),
+            DataType::Color => color_from_hex("#ff6b6b").unwrap(),
+            DataType::Path => color_from_hex("#ffe66d").unwrap(),
+            DataType::List => color_from_hex("#c490d1").unwrap(),
         }
     }
 
@@ -89,10 +93,14 @@ impl DataTypeTrait<CustomGraphState> for DataTypeUi {
         Cow::Borrowed(match self.0 {
             DataType::Vector => "vector",
             DataType::Scalar => "scalar",
+            DataType::Int => "int",
             DataType::Selection => "selection",
             DataType::Mesh => "mesh",
             DataType::HeightMap => "heightmap",
             DataType::String => "string",
+            DataType::Color => "color",
+            DataType::Path => "path",
+            DataType::List => "list",
         })
     }
 }
@@ -254,10 +262,22 @@ pub fn data_type_to_input_param_kind(data_type: DataType) -> InputParamKind {
     match data_type {
         DataType::Vector => InputParamKind::ConnectionOrConstant,
         DataType::Scalar => InputParamKind::ConnectionOrConstant,
+        DataType::Int => InputParamKind::ConnectionOrConstant,
         DataType::Selection => InputParamKind::ConnectionOrConstant,
         DataType::Mesh => InputParamKind::ConnectionOnly,
         DataType::HeightMap => InputParamKind::ConnectionOnly,
         DataType::String => InputParamKind::ConnectionOrConstant,
+        DataType::Color => InputParamKind::ConnectionOrConstant,
+        DataType::Path => InputParamKind::ConnectionOrConstant,
+        DataType::List => InputParamKind::ConnectionOrConstant,
+    }
+}
+
+/// Shows a native file picker dialog, according to the given `mode`.
+fn pick_file(mode: FilePathMode) -> Option<std::path::PathBuf> {
+    match mode {
+        FilePathMode::Open => rfd::FileDialog::new().pick_file(),
+        FilePathMode::Save => rfd::FileDialog::new().save_file(),
     }
 }
 
@@ -435,6 +455,18 @@ impl WidgetValueTrait for ValueTypeUi {
                     ui.add(drag_value)
                 });
             }
+            (BlackjackValue::Int(value), InputValueConfig::Int { min, max, .. }) => {
+                ui.horizontal(|ui| {
+                    ui.label(param_name);
+                    ui.add(
+                        SmartDragValue::new(value, INT_DRAG_SPEEDS, INT_DRAG_LABELS)
+                            .speed(1.0)
+                            .clamp_range_hard(min.unwrap_or(i64::MIN)..=max.unwrap_or(i64::MAX))
+                            .decimals(0)
+                            .default_range_index(2),
+                    )
+                });
+            }
             (BlackjackValue::String(string), InputValueConfig::Enum { values, .. }) => {
                 egui::ComboBox::from_label(param_name)
                     .selected_text(string.clone())
@@ -448,12 +480,7 @@ impl WidgetValueTrait for ValueTypeUi {
                 ui.label(param_name);
                 ui.horizontal(|ui| {
                     if ui.button("Select").clicked() {
-                        let new_path = match file_path_mode {
-                            FilePathMode::Open => rfd::FileDialog::new().pick_file(),
-                            FilePathMode::Save => rfd::FileDialog::new().save_file(),
-                        };
-
-                        if let Some(new_path) = new_path {
+                        if let Some(new_path) = pick_file(*file_path_mode) {
                             *path = new_path
                                 .into_os_string()
                                 .into_string()
@@ -467,6 +494,21 @@ impl WidgetValueTrait for ValueTypeUi {
                     }
                 });
             }
+            (BlackjackValue::Path(path), InputValueConfig::FilePath { file_path_mode, .. }) => {
+                ui.label(param_name);
+                ui.horizontal(|ui| {
+                    if ui.button("Select").clicked() {
+                        if let Some(new_path) = pick_file(*file_path_mode) {
+                            *path = new_path;
+                        }
+                    }
+                    if !path.as_os_str().is_empty() {
+                        ui.label(path.display().to_string());
+                    } else {
+                        ui.label("No file selected");
+                    }
+                });
+            }
             (BlackjackValue::String(text), InputValueConfig::String { multiline, .. }) => {
                 ui.horizontal(|ui| {
                     ui.label(param_name);
@@ -487,6 +529,19 @@ impl WidgetValueTrait for ValueTypeUi {
                     *selection = SelectionExpression::parse(text).ok();
                 }
             }
+            (BlackjackValue::Color(color), InputValueConfig::Color { .. }) => {
+                ui.horizontal(|ui| {
+                    ui.label(param_name);
+                    let mut rgba = color.to_array();
+                    if ui.color_edit_button_rgba_unmultiplied(&mut rgba).changed() {
+                        *color = glam::Vec4::from_array(rgba);
+                    }
+                });
+            }
+            (BlackjackValue::List(items), InputValueConfig::List { .. }) => {
+                // TODO: Lists can only be edited from Lua for now.
+                ui.label(format!("{param_name}: {} items", items.len()));
+            }
             (BlackjackValue::None, InputValueConfig::None) => {
                 ui.label(param_name);
             }